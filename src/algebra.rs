@@ -1,12 +1,17 @@
-use std::f64::EPSILON;
+use std::fmt;
 use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub};
 
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+
 type Unit = f64;
 
 /// 3 dimentional vector type used in this ray tracer.
 ///
 /// Can also be used as point.
-#[derive(Copy, Clone, Debug, Deserialize)]
+///
+/// Deserializes from a `[x, y, z]` sequence, or from a `{x, y, z}` map.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Vector(pub Unit, pub Unit, pub Unit);
 
 impl Vector {
@@ -44,6 +49,48 @@ impl Vector {
         self.0 * self.0 + self.1 * self.1 + self.2 * self.2
     }
 
+    /// Check whether all components are finite, not NaN or infinite.
+    #[inline]
+    pub fn is_finite(&self) -> bool {
+        self.0.is_finite() && self.1.is_finite() && self.2.is_finite()
+    }
+
+    /// Reflect this direction off a surface with the given unit `normal`.
+    #[inline]
+    pub fn reflect(self, normal: Self) -> Self {
+        self - (normal * self.dot(normal) * 2.0)
+    }
+
+    /// Refract this unit direction through a surface with the given unit `normal`.
+    ///
+    /// The normal must face against this direction. `eta` is the ratio of the refractive index
+    /// being left over the index being entered. Returns `None` on total internal reflection.
+    #[inline]
+    pub fn refract(self, normal: Self, eta: Unit) -> Option<Self> {
+        let cos_i = -self.dot(normal);
+        let k = 1.0 - (eta * eta) * (1.0 - cos_i * cos_i);
+        if k < 0.0 {
+            None
+        } else {
+            Some((normal * cos_i + self) * eta - normal * k.sqrt())
+        }
+    }
+
+    /// Build two unit vectors, orthogonal to each other and to this unit vector.
+    ///
+    /// Together with this vector, these form an orthonormal basis.
+    #[inline]
+    pub fn orthonormal_basis(self) -> (Self, Self) {
+        let helper = if self.0.abs() > 0.9 {
+            Vector(0.0, 1.0, 0.0)
+        } else {
+            Vector(1.0, 0.0, 0.0)
+        };
+        let tangent = helper.cross(self).normalize();
+        let bitangent = self.cross(tangent);
+        (tangent, bitangent)
+    }
+
     /// Get a vector with the minimum for each component.
     #[inline]
     pub fn min_components(self, other: Self) -> Self {
@@ -63,6 +110,16 @@ impl Vector {
             self.2.max(other.2),
         )
     }
+
+    /// Rotate this vector around the given `axis` by `angle` radians.
+    ///
+    /// Uses Rodrigues' rotation formula. Rotations are counter clockwise when looking against the
+    /// axis direction, the axis does not have to be normalized.
+    pub fn rotate_around(self, axis: Self, angle: Unit) -> Self {
+        let axis = axis.normalize();
+        let (sin, cos) = angle.sin_cos();
+        self * cos + axis.cross(self) * sin + axis * (axis.dot(self) * (1.0 - cos))
+    }
 }
 
 impl Add for Vector {
@@ -117,7 +174,7 @@ impl Div<Unit> for Vector {
     type Output = Self;
 
     fn div(self, rhs: Unit) -> Self::Output {
-        if rhs < EPSILON {
+        if rhs < f64::EPSILON {
             Vector::identity()
         } else {
             Vector(self.0 / rhs, self.1 / rhs, self.2 / rhs)
@@ -125,6 +182,146 @@ impl Div<Unit> for Vector {
     }
 }
 
+impl Serialize for Vector {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        tuple.serialize_element(&self.0)?;
+        tuple.serialize_element(&self.1)?;
+        tuple.serialize_element(&self.2)?;
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Vector {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(VectorVisitor)
+    }
+}
+
+/// Visitor to deserialize a `Vector` from a sequence or map.
+struct VectorVisitor;
+
+impl<'de> Visitor<'de> for VectorVisitor {
+    type Value = Vector;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a vector as [x, y, z] or {x, y, z}")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Vector, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut component = |i| {
+            seq.next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))
+        };
+        let vector = Vector(component(0)?, component(1)?, component(2)?);
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(4, &self));
+        }
+        Ok(vector)
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Vector, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let (mut x, mut y, mut z) = (None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            let component = match key.as_str() {
+                "x" => &mut x,
+                "y" => &mut y,
+                "z" => &mut z,
+                _ => return Err(de::Error::unknown_field(&key, &["x", "y", "z"])),
+            };
+            if component.is_some() {
+                return Err(de::Error::custom(format!("duplicate field `{}`", key)));
+            }
+            *component = Some(map.next_value()?);
+        }
+        Ok(Vector(
+            x.ok_or_else(|| de::Error::missing_field("x"))?,
+            y.ok_or_else(|| de::Error::missing_field("y"))?,
+            z.ok_or_else(|| de::Error::missing_field("z"))?,
+        ))
+    }
+}
+
+/// A unit quaternion, representing a rotation.
+///
+/// Rotations compose by multiplication, `a * b` rotates by `b` first and then by `a`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quat {
+    /// Scalar part.
+    pub w: Unit,
+
+    /// Vector part.
+    pub v: Vector,
+}
+
+impl Quat {
+    /// Construct a rotation around the given `axis` by `angle` radians.
+    ///
+    /// The axis does not have to be normalized.
+    pub fn from_axis_angle(axis: Vector, angle: Unit) -> Self {
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        Quat {
+            w: cos,
+            v: axis.normalize() * sin,
+        }
+    }
+
+    /// Normalize this quaternion to unit length, countering accumulated rounding errors.
+    pub fn normalize(self) -> Self {
+        let length = (self.w * self.w + self.v.magnitude_squared()).sqrt();
+        Quat {
+            w: self.w / length,
+            v: self.v / length,
+        }
+    }
+
+    /// Get the inverse rotation.
+    pub fn conjugate(self) -> Self {
+        Quat {
+            w: self.w,
+            v: -self.v,
+        }
+    }
+
+    /// Rotate the given vector by this rotation.
+    pub fn rotate(self, vector: Vector) -> Vector {
+        let t = self.v.cross(vector) * 2.0;
+        vector + t * self.w + self.v.cross(t)
+    }
+}
+
+impl Mul for Quat {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Quat {
+            w: self.w * other.w - self.v.dot(other.v),
+            v: other.v * self.w + self.v * other.w + self.v.cross(other.v),
+        }
+    }
+}
+
+impl Identity for Quat {
+    fn identity() -> Self {
+        Quat {
+            w: 1.0,
+            v: Vector(0.0, 0.0, 0.0),
+        }
+    }
+}
+
 /// Type that has an identity value.
 ///
 /// Will be the zero point for points and vectors.
@@ -143,6 +340,29 @@ impl Identity for Vector {
 mod tests {
     use super::*;
 
+    use std::f64::consts::PI;
+
+    #[test]
+    fn test_deserialize_sequence_and_map() {
+        let sequence: Vector = serde_yaml::from_str("[1, 2.5, -3]").unwrap();
+        let map: Vector = serde_yaml::from_str("{ x: 1, y: 2.5, z: -3 }").unwrap();
+        assert_eq!(sequence, Vector(1.0, 2.5, -3.0));
+        assert_eq!(map, sequence);
+    }
+
+    #[test]
+    fn test_deserialize_wrong_length() {
+        let err = serde_yaml::from_str::<Vector>("[1, 2]").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("invalid length 2, expected a vector as [x, y, z] or {x, y, z}"),
+            "unclear error: {}",
+            err
+        );
+        assert!(serde_yaml::from_str::<Vector>("[1, 2, 3, 4]").is_err());
+        assert!(serde_yaml::from_str::<Vector>("{ x: 1, y: 2 }").is_err());
+    }
+
     #[test]
     fn test_dot() {
         assert_unit_equal(Vector(0.0, 0.0, 0.0).dot(Vector(0.0, 0.0, 0.0)), 0.0);
@@ -179,6 +399,101 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reflect() {
+        assert_vector_equal(
+            Vector(1.0, -1.0, 0.0).reflect(Vector(0.0, 1.0, 0.0)),
+            Vector(1.0, 1.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_refract() {
+        let normal = Vector(0.0, 1.0, 0.0);
+
+        // Straight through at normal incidence
+        let straight = Vector(0.0, -1.0, 0.0).refract(normal, 1.0 / 1.5).unwrap();
+        assert_vector_equal(straight, Vector(0.0, -1.0, 0.0));
+
+        // Bends towards the normal entering a denser medium
+        let incident = Vector(1.0, -1.0, 0.0).normalize();
+        let refracted = incident.refract(normal, 1.0 / 1.5).unwrap();
+        assert_unit_equal_eps(refracted.magnitude(), 1.0);
+        assert_unit_equal_eps(refracted.0, incident.0 / 1.5);
+    }
+
+    #[test]
+    fn test_refract_total_internal_reflection() {
+        let incident = Vector(1.0, -0.2, 0.0).normalize();
+        assert!(incident.refract(Vector(0.0, 1.0, 0.0), 1.5).is_none());
+    }
+
+    #[test]
+    fn test_orthonormal_basis() {
+        let normals = [
+            Vector(0.0, 0.0, 1.0),
+            Vector(1.0, 0.0, 0.0),
+            Vector(1.0, 2.0, 3.0).normalize(),
+            Vector(-0.95, 0.1, 0.3).normalize(),
+        ];
+        for normal in normals.iter() {
+            let (tangent, bitangent) = normal.orthonormal_basis();
+            assert_unit_equal_eps(tangent.magnitude(), 1.0);
+            assert_unit_equal_eps(bitangent.magnitude(), 1.0);
+            assert_unit_equal_eps(tangent.dot(*normal), 0.0);
+            assert_unit_equal_eps(bitangent.dot(*normal), 0.0);
+            assert_unit_equal_eps(tangent.dot(bitangent), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_rotate_around() {
+        let rotated = Vector(1.0, 0.0, 0.0).rotate_around(Vector(0.0, 0.0, 1.0), PI / 2.0);
+        assert_vector_equal_eps(rotated, Vector(0.0, 1.0, 0.0));
+
+        // Components along the axis are kept, the axis does not need to be normalized
+        let rotated = Vector(1.0, 0.0, 2.0).rotate_around(Vector(0.0, 0.0, 3.0), PI);
+        assert_vector_equal_eps(rotated, Vector(-1.0, 0.0, 2.0));
+    }
+
+    #[test]
+    fn test_quat_rotate() {
+        let z = Vector(0.0, 0.0, 1.0);
+        let quarter = Quat::from_axis_angle(z, PI / 2.0);
+        assert_vector_equal_eps(quarter.rotate(Vector(1.0, 0.0, 0.0)), Vector(0.0, 1.0, 0.0));
+        assert_vector_equal_eps(
+            quarter.conjugate().rotate(Vector(0.0, 1.0, 0.0)),
+            Vector(1.0, 0.0, 0.0),
+        );
+
+        // Rotations match Rodrigues' formula around any axis
+        let axis = Vector(1.0, -2.0, 0.5);
+        let vector = Vector(0.3, 0.7, -1.1);
+        assert_vector_equal_eps(
+            Quat::from_axis_angle(axis, 1.3).rotate(vector),
+            vector.rotate_around(axis, 1.3),
+        );
+    }
+
+    #[test]
+    fn test_quat_compose() {
+        let z = Vector(0.0, 0.0, 1.0);
+        let eighth = Quat::from_axis_angle(z, PI / 4.0);
+        let composed = (eighth * eighth).normalize();
+        let quarter = Quat::from_axis_angle(z, PI / 2.0);
+        assert_unit_equal_eps(composed.w, quarter.w);
+        assert_vector_equal_eps(composed.v, quarter.v);
+
+        // Composed rotations apply the right hand side first
+        let x = Quat::from_axis_angle(Vector(1.0, 0.0, 0.0), PI / 2.0);
+        let vector = Vector(0.0, 1.0, 0.0);
+        assert_vector_equal_eps(
+            (quarter * x).rotate(vector),
+            quarter.rotate(x.rotate(vector)),
+        );
+        assert_eq!(Quat::identity().rotate(vector), vector);
+    }
+
     #[test]
     fn test_div_zero() {
         assert_vector_equal(Vector(1.0, 1.0, 1.0) / 0.0, Vector::identity());
@@ -187,19 +502,39 @@ mod tests {
     /// Check whether units are almost equal, taking the epsilon into account.
     fn assert_unit_equal(a: Unit, b: Unit) {
         assert!(
-            (a - b).abs() < EPSILON,
+            (a - b).abs() < f64::EPSILON,
             "floats {} and {} are not almost equal",
             a,
             b
         );
     }
 
+    /// Check whether units are almost equal, with some tolerance for rounding errors.
+    fn assert_unit_equal_eps(a: Unit, b: Unit) {
+        assert!(
+            (a - b).abs() < 1e-12,
+            "floats {} and {} are not almost equal",
+            a,
+            b
+        );
+    }
+
+    /// Check whether vectors are almost equal, with some tolerance for rounding errors.
+    fn assert_vector_equal_eps(a: Vector, b: Vector) {
+        assert!(
+            (a - b).magnitude() < 1e-12,
+            "vectors {:?} and {:?} are not almost equal",
+            a,
+            b
+        );
+    }
+
     /// Check whether units are almost equal, taking the epsilon into account.
     fn assert_vector_equal(a: Vector, b: Vector) {
         assert!(
-            (a.0 - b.0).abs() < EPSILON
-                && (a.1 - b.1).abs() < EPSILON
-                && (a.2 - b.2).abs() < EPSILON,
+            (a.0 - b.0).abs() < f64::EPSILON
+                && (a.1 - b.1).abs() < f64::EPSILON
+                && (a.2 - b.2).abs() < f64::EPSILON,
             "vectors {:?} and {:?} are not almost equal",
             a,
             b
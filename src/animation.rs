@@ -0,0 +1,202 @@
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+use crate::algebra::{Identity, Vector};
+use crate::scene::Scene;
+
+/// A scene animation, keyframed over time `t` in `[0, 1]`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Animation {
+    /// Animation duration in seconds.
+    ///
+    /// If not set, the animation spans the whole rendered frame sequence.
+    #[serde(default)]
+    pub duration: Option<f64>,
+
+    /// Orbit the camera around a point.
+    #[serde(default)]
+    pub orbit: Option<Orbit>,
+
+    /// Entity transforms.
+    #[serde(default)]
+    pub transforms: Vec<Transform>,
+}
+
+impl Animation {
+    /// Get the animation time `t` for the given zero based `frame`.
+    ///
+    /// Without a duration, the animation spans all `frames` and the last frame stops just before
+    /// the end, so looping animations do not repeat the first frame. With a duration, frames are
+    /// `1 / fps` seconds apart and `t` is clamped at the end.
+    pub fn time(&self, frame: u32, frames: u32, fps: f64) -> f64 {
+        match self.duration {
+            Some(duration) if duration > 0.0 => (f64::from(frame) / fps / duration).min(1.0),
+            _ => f64::from(frame) / f64::from(frames.max(1)),
+        }
+    }
+
+    /// Apply this animation to the given scene at time `t`.
+    ///
+    /// Entity transforms are relative to their position in the scene as defined.
+    pub fn apply(&self, scene: &mut Scene, t: f64) {
+        if let Some(ref orbit) = self.orbit {
+            scene.camera.position = orbit.position(t);
+            scene.camera.look_at = Some(orbit.center);
+        }
+
+        for transform in &self.transforms {
+            if let Some(entity) = scene.entities.get_mut(transform.entity) {
+                entity.translate(transform.offset(t));
+            }
+        }
+        scene.time = t;
+        scene.update_bounding_boxes();
+    }
+}
+
+/// A camera orbit around a center point, looking at it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Orbit {
+    /// The point to orbit around in world space.
+    pub center: Vector,
+
+    /// Orbit radius.
+    pub radius: f64,
+
+    /// Camera height above the center.
+    #[serde(default)]
+    pub height: f64,
+
+    /// Number of turns around the center over the animation.
+    #[serde(default = "one")]
+    pub turns: f64,
+}
+
+impl Orbit {
+    /// Get the camera position at time `t`.
+    ///
+    /// At `t = 0` the camera is in front of the center, on the `+Z` side.
+    pub fn position(&self, t: f64) -> Vector {
+        let angle = 2.0 * PI * self.turns * t;
+        self.center
+            + Vector(
+                self.radius * angle.sin(),
+                self.height,
+                self.radius * angle.cos(),
+            )
+    }
+}
+
+/// An entity transform, moving an entity through keyframed offsets.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Transform {
+    /// Index of the entity in the scene.
+    pub entity: usize,
+
+    /// Offset keyframes, sorted by time.
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl Transform {
+    /// Get the entity offset at time `t`, linearly interpolated between keyframes.
+    pub fn offset(&self, t: f64) -> Vector {
+        let next = self.keyframes.iter().position(|k| k.t > t);
+        match next {
+            None => self
+                .keyframes
+                .last()
+                .map(|k| k.offset)
+                .unwrap_or_else(Vector::identity),
+            Some(0) => self.keyframes[0].offset,
+            Some(i) => {
+                let (a, b) = (&self.keyframes[i - 1], &self.keyframes[i]);
+                let f = (t - a.t) / (b.t - a.t);
+                a.offset * (1.0 - f) + b.offset * f
+            }
+        }
+    }
+}
+
+/// An offset keyframe.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Keyframe {
+    /// Time of this keyframe in `[0, 1]`.
+    pub t: f64,
+
+    /// Entity offset at this keyframe.
+    pub offset: Vector,
+}
+
+/// Derive the output path for a frame from the given `output` path.
+///
+/// The one based frame number is appended to the file name, such as `output_0001.png`.
+pub fn frame_path(output: &Path, frame: u32) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match output.extension() {
+        Some(ext) => format!("{}_{:04}.{}", stem, frame + 1, ext.to_string_lossy()),
+        None => format!("{}_{:04}", stem, frame + 1),
+    };
+    output.with_file_name(name)
+}
+
+/// Returns one.
+///
+/// Helper function for serde defaults.
+const fn one() -> f64 {
+    1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_frame_paths_distinct() {
+        let output = Path::new("renders/output.png");
+        let paths: HashSet<PathBuf> = (0..10).map(|f| frame_path(output, f)).collect();
+        assert_eq!(paths.len(), 10);
+        assert!(paths.contains(Path::new("renders/output_0001.png")));
+        assert!(paths.contains(Path::new("renders/output_0010.png")));
+    }
+
+    #[test]
+    fn test_orbit_position() {
+        let orbit = Orbit {
+            center: Vector(0.0, 0.0, -5.0),
+            radius: 2.0,
+            height: 1.0,
+            turns: 1.0,
+        };
+        assert!((orbit.position(0.0) - Vector(0.0, 1.0, -3.0)).magnitude() < 1e-9);
+        assert!((orbit.position(0.25) - Vector(2.0, 1.0, -5.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn test_transform_keyframes_interpolated() {
+        let transform = Transform {
+            entity: 0,
+            keyframes: vec![
+                Keyframe {
+                    t: 0.0,
+                    offset: Vector(0.0, 0.0, 0.0),
+                },
+                Keyframe {
+                    t: 0.5,
+                    offset: Vector(2.0, 0.0, 0.0),
+                },
+                Keyframe {
+                    t: 1.0,
+                    offset: Vector(2.0, 4.0, 0.0),
+                },
+            ],
+        };
+        assert!((transform.offset(0.25) - Vector(1.0, 0.0, 0.0)).magnitude() < 1e-9);
+        assert!((transform.offset(0.75) - Vector(2.0, 2.0, 0.0)).magnitude() < 1e-9);
+        assert!((transform.offset(1.0) - Vector(2.0, 4.0, 0.0)).magnitude() < 1e-9);
+    }
+}
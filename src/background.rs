@@ -0,0 +1,344 @@
+use std::f64::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use image::codecs::hdr::HdrDecoder;
+use image::{ImageBuffer, ImageResult, Rgb};
+
+use crate::algebra::Vector;
+use crate::color::{Color, BLACK, WHITE};
+use crate::material::Wrap;
+use crate::math::Ray;
+use crate::verbosity::Verbosity;
+
+/// A floating point RGB image, for high dynamic range environments.
+type HdrImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+/// The scene background, seen by rays that hit nothing.
+///
+/// In path traced mode, the background also lights the scene.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Background {
+    /// A uniform color.
+    Color {
+        /// The background color.
+        color: Color,
+    },
+
+    /// An equirectangular environment map.
+    Equirect(Equirect),
+
+    /// An infinite checkered floor below a gradient sky, without a floor entity.
+    Floor {
+        /// Height of the floor.
+        #[serde(default)]
+        y: f64,
+
+        /// Color of the first checker squares.
+        #[serde(default = "default_checker_a")]
+        a: Color,
+
+        /// Color of the second checker squares.
+        #[serde(default = "default_checker_b")]
+        b: Color,
+
+        /// Size of a checker square.
+        #[serde(default = "default_checker_scale")]
+        scale: f64,
+
+        /// Sky color at the horizon.
+        #[serde(default = "default_horizon")]
+        horizon: Color,
+
+        /// Sky color straight up.
+        #[serde(default = "default_zenith")]
+        zenith: Color,
+    },
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Color { color: *BLACK }
+    }
+}
+
+impl Background {
+    /// Get the background color seen by the given `ray`, that hit nothing.
+    pub fn color(&self, ray: &Ray) -> Color {
+        match self {
+            Background::Color { color } => *color,
+            Background::Equirect(e) => e.sample(ray.direction),
+            Background::Floor {
+                y,
+                a,
+                b,
+                scale,
+                horizon,
+                zenith,
+            } => {
+                // Rays going down from above the floor hit it, the checker alternates per square
+                let direction = ray.direction.normalize();
+                if ray.origin.1 > *y && direction.1 < 0.0 {
+                    let hit = ray.origin + direction * ((y - ray.origin.1) / direction.1);
+                    let square = (hit.0 / scale).floor() + (hit.2 / scale).floor();
+                    return if square.rem_euclid(2.0) < 1.0 { *a } else { *b };
+                }
+                horizon.lerp(*zenith, direction.1.max(0.0) as f32)
+            }
+        }
+    }
+
+    /// Check whether this background emits any light.
+    pub fn emits_light(&self) -> bool {
+        match self {
+            Background::Color { color } => color.max_channel() > 0.0,
+            Background::Equirect(_) | Background::Floor { .. } => true,
+        }
+    }
+
+    /// Validate this background, returns a list of problems.
+    pub fn validate(&self) -> Vec<String> {
+        match self {
+            Background::Equirect(e) if !e.rotation.is_finite() => {
+                vec![format!(
+                    "environment rotation must be finite, got {}",
+                    e.rotation
+                )]
+            }
+            Background::Floor { y, scale, .. } => {
+                let mut problems = vec![];
+                if !y.is_finite() {
+                    problems.push(format!("floor height must be finite, got {}", y));
+                }
+                if *scale <= 0.0 || !scale.is_finite() {
+                    problems.push(format!("floor scale must be positive, got {}", scale));
+                }
+                problems
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Load any external resources.
+    pub fn load<P: AsRef<Path>>(&mut self, workdir: P, verbosity: Verbosity) {
+        if let Background::Equirect(ref mut e) = self {
+            e.load(workdir, verbosity);
+        }
+    }
+}
+
+/// An equirectangular environment map, wrapping an image around the scene.
+///
+/// The image center is seen looking along `-Z`, the top row straight up. Radiance `.hdr` images
+/// keep their high dynamic range.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Equirect {
+    /// Path to the environment image file to load.
+    pub path: String,
+
+    /// Rotation of the environment around the vertical axis in degrees, counterclockwise seen
+    /// from above.
+    #[serde(default)]
+    pub rotation: f64,
+
+    /// The loaded environment image.
+    #[serde(skip)]
+    image: Option<Arc<HdrImage>>,
+}
+
+impl Equirect {
+    /// Load the environment image.
+    pub fn load<P: AsRef<Path>>(&mut self, workdir: P, verbosity: Verbosity) {
+        // Determine absolute path for relative environment paths
+        let mut path = workdir.as_ref().to_path_buf();
+        path.push(&self.path);
+
+        match load_hdr_image(&path) {
+            Ok(image) => self.image = Some(Arc::new(image)),
+            Err(err) if verbosity.is_normal() => {
+                eprintln!("Failed to load environment, ignoring: {}", err);
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Sample the environment color seen in the given unit `direction`.
+    ///
+    /// Uses bilinear filtering, wrapping around horizontally. Black if the image is not loaded.
+    pub fn sample(&self, direction: Vector) -> Color {
+        let image = match self.image {
+            Some(ref image) => image,
+            None => return *BLACK,
+        };
+        let (width, height) = image.dimensions();
+
+        // Map the direction to texture coordinates, then to texels, the rotation shifts columns
+        let u = 0.5 + direction.0.atan2(-direction.2) / (2.0 * PI) + self.rotation / 360.0;
+        let v = direction.1.clamp(-1.0, 1.0).acos() / PI;
+        let x = u as f32 * width as f32 - 0.5;
+        let y = v as f32 * height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let texel = |x: i64, y: i64| {
+            let pixel = image
+                .get_pixel(Wrap::Repeat.apply(x, width), Wrap::Clamp.apply(y, height))
+                .0;
+            Color::new(pixel[0], pixel[1], pixel[2])
+        };
+
+        let top = texel(x0, y0).lerp(texel(x0 + 1, y0), fx);
+        let bottom = texel(x0, y0 + 1).lerp(texel(x0 + 1, y0 + 1), fx);
+        top.lerp(bottom, fy)
+    }
+}
+
+impl fmt::Debug for Equirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Equirect")
+            .field("path", &self.path)
+            .field("rotation", &self.rotation)
+            .field("loaded", &self.image.is_some())
+            .finish()
+    }
+}
+
+/// Helper function for serde defaults.
+fn default_checker_a() -> Color {
+    *WHITE
+}
+
+/// Helper function for serde defaults.
+fn default_checker_b() -> Color {
+    Color::new(0.2, 0.2, 0.2)
+}
+
+/// Helper function for serde defaults.
+const fn default_checker_scale() -> f64 {
+    1.0
+}
+
+/// Helper function for serde defaults.
+fn default_horizon() -> Color {
+    *WHITE
+}
+
+/// Helper function for serde defaults.
+fn default_zenith() -> Color {
+    Color::new(0.5, 0.7, 1.0)
+}
+
+/// Load an image as floating point RGB.
+///
+/// Radiance `.hdr` files are decoded in high dynamic range, other images are scaled to `[0, 1]`.
+fn load_hdr_image(path: &Path) -> ImageResult<HdrImage> {
+    let is_hdr = path
+        .extension()
+        .map(|e| e.eq_ignore_ascii_case("hdr"))
+        .unwrap_or(false);
+    if !is_hdr {
+        let image = image::open(path)?.to_rgb8();
+        return Ok(HdrImage::from_fn(image.width(), image.height(), |x, y| {
+            let pixel = image.get_pixel(x, y).0;
+            Rgb([
+                f32::from(pixel[0]) / 255.0,
+                f32::from(pixel[1]) / 255.0,
+                f32::from(pixel[2]) / 255.0,
+            ])
+        }));
+    }
+
+    let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+    let metadata = decoder.metadata();
+    let pixels: Vec<f32> = decoder
+        .read_image_hdr()?
+        .into_iter()
+        .flat_map(|p| p.0.to_vec())
+        .collect();
+    Ok(HdrImage::from_raw(metadata.width, metadata.height, pixels)
+        .expect("decoded environment image has unexpected size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equirect_directions() {
+        // Four columns, two rows: the top half is bright, the bottom dark
+        let image = HdrImage::from_fn(4, 2, |x, y| {
+            let value = if y == 0 { 4.0 } else { 0.5 };
+            Rgb([value, x as f32, 0.0])
+        });
+        let equirect = Equirect {
+            path: String::new(),
+            rotation: 0.0,
+            image: Some(Arc::new(image)),
+        };
+
+        // Straight up and down sample the top and bottom rows, keeping high dynamic range
+        let (up, _, _) = equirect.sample(Vector(0.0, 1.0, 0.0)).channels();
+        let (down, _, _) = equirect.sample(Vector(0.0, -1.0, 0.0)).channels();
+        assert!((up - 4.0).abs() < 1e-6);
+        assert!((down - 0.5).abs() < 1e-6);
+
+        // Looking forward is the horizontal image center, looking back wraps around the edges
+        let (_, forward, _) = equirect.sample(Vector(0.0, 0.0, -1.0)).channels();
+        let (_, back, _) = equirect.sample(Vector(0.0, 0.0, 1.0)).channels();
+        assert!((forward - 1.5).abs() < 1e-6);
+        assert!((back - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_equirect_rotation() {
+        // Four columns, each storing its index
+        let image = HdrImage::from_fn(4, 2, |x, _| Rgb([x as f32, 0.0, 0.0]));
+        let mut equirect = Equirect {
+            path: String::new(),
+            rotation: 0.0,
+            image: Some(Arc::new(image)),
+        };
+
+        // These directions look at the centers of the third and first column
+        let front = Vector(1.0, 0.0, -1.0).normalize();
+        let back = Vector(-1.0, 0.0, 1.0).normalize();
+        let column = |equirect: &Equirect, direction| equirect.sample(direction).channels().0;
+        assert!((column(&equirect, front) - 2.0).abs() < 1e-6);
+        assert!((column(&equirect, back) - 0.0).abs() < 1e-6);
+
+        // Turning the environment halfway swaps the texels seen in opposite directions
+        equirect.rotation = 180.0;
+        assert!((column(&equirect, front) - 0.0).abs() < 1e-6);
+        assert!((column(&equirect, back) - 2.0).abs() < 1e-6);
+
+        // A quarter turn counterclockwise moves the third column from front right to front left
+        equirect.rotation = 90.0;
+        let left = Vector(-1.0, 0.0, -1.0).normalize();
+        assert!((column(&equirect, left) - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_floor_checker_and_sky() {
+        let floor: Background = serde_yaml::from_str(
+            "{ type: floor, y: -1, a: [1, 0, 0], b: [0, 0, 1], zenith: [0, 1, 0] }",
+        )
+        .unwrap();
+        let origin = Vector(0.0, 0.0, 0.0);
+
+        // Looking down, neighboring squares alternate between the checker colors
+        let down = |x: f64| floor.color(&Ray::new(origin, Vector(x, -1.0, 0.0).normalize()));
+        assert_eq!(down(0.5).channels(), (1.0, 0.0, 0.0));
+        assert_eq!(down(1.5).channels(), (0.0, 0.0, 1.0));
+        assert_eq!(down(-0.5).channels(), (0.0, 0.0, 1.0));
+
+        // Looking up sees the sky, from the horizon color to the zenith color
+        let up = floor.color(&Ray::new(origin, Vector(0.0, 1.0, 0.0)));
+        assert_eq!(up.channels(), (0.0, 1.0, 0.0));
+        let horizon = floor.color(&Ray::new(origin, Vector(0.0, 0.0, -1.0)));
+        assert_eq!(horizon.channels(), (1.0, 1.0, 1.0));
+    }
+}
@@ -1,6 +1,12 @@
-use std::ops::{Add, Mul};
+use std::fmt;
+use std::ops::{Add, Mul, Sub};
 
-use image::Rgba;
+use image::{Rgb, Rgba};
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+
+/// Ordered dither thresholds of a 4x4 Bayer matrix, in sixteenths.
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
 
 lazy_static! {
     pub static ref BLACK: Color = Color::new(0.0, 0.0, 0.0);
@@ -9,23 +15,139 @@ lazy_static! {
 }
 
 /// An RGB color.
-#[derive(Copy, Clone, Debug, Deserialize)]
+///
+/// Channels are linear, colors are computed in linear space. Use `from_srgb8` and
+/// `to_srgb8` to convert from and to sRGB encoded colors.
+///
+/// Deserializes from a linear `[r, g, b]` sequence, or from an sRGB hex string such as
+/// `"#cc1a1a"`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct Color(f32, f32, f32);
 
 impl Color {
+    /// Construct a color from linear channel values.
     pub fn new(r: f32, g: f32, b: f32) -> Self {
         Self(r, g, b)
     }
 
+    /// Construct a linear color from an 8-bit sRGB encoded color, ignoring alpha.
+    pub fn from_srgb8(rgba: Rgba<u8>) -> Self {
+        let [r, g, b, _] = rgba.0;
+        let encoded = Color::new(f32::from(r), f32::from(g), f32::from(b)) * (1.0 / 255.0);
+        encoded.to_linear()
+    }
+
+    /// Parse an sRGB encoded hex color, such as `#cc1a1a` or the short form `#c11`.
+    ///
+    /// The leading `#` is optional. Returns a human readable message if the string is invalid.
+    pub fn from_hex(hex: &str) -> Result<Self, String> {
+        let digits = hex.strip_prefix('#').unwrap_or(hex);
+        let invalid = || format!("invalid hex color '{}', expected #rgb or #rrggbb", hex);
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let channel = |digits: &str| u8::from_str_radix(digits, 16).map_err(|_| invalid());
+        let [r, g, b] = match digits.len() {
+            3 => {
+                let short = |i: usize| channel(&digits[i..=i]).map(|c| c * 17);
+                [short(0)?, short(1)?, short(2)?]
+            }
+            6 => [
+                channel(&digits[0..2])?,
+                channel(&digits[2..4])?,
+                channel(&digits[4..6])?,
+            ],
+            _ => return Err(invalid()),
+        };
+        Ok(Self::from_srgb8(Rgba([r, g, b, 255])))
+    }
+
+    /// Convert this linear color to an 8-bit sRGB encoded color, clamping channels.
+    pub fn to_srgb8(self) -> Rgba<u8> {
+        let color = Color::from_linear(self.clamp());
+        let encode = |c: f32| (c * 255.0).round() as u8;
+        Rgba([encode(color.0), encode(color.1), encode(color.2), 255])
+    }
+
+    /// Decode this sRGB encoded color to linear.
+    pub fn to_linear(self) -> Color {
+        let decode = |c: f32| {
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        Color::new(decode(self.0), decode(self.1), decode(self.2))
+    }
+
+    /// Encode the given `linear` color to sRGB.
+    pub fn from_linear(linear: Color) -> Color {
+        let encode = |c: f32| {
+            if c <= 0.003_130_8 {
+                c * 12.92
+            } else {
+                1.055 * c.powf(1.0 / 2.4) - 0.055
+            }
+        };
+        Color::new(encode(linear.0), encode(linear.1), encode(linear.2))
+    }
+
     pub fn clamp(&self) -> Color {
         Color::new(
-            self.0.min(1.0).max(0.0),
-            self.1.min(1.0).max(0.0),
-            self.2.min(1.0).max(0.0),
+            self.0.clamp(0.0, 1.0),
+            self.1.clamp(0.0, 1.0),
+            self.2.clamp(0.0, 1.0),
         )
     }
 
-    pub fn to_rgba(&self) -> Rgba<u8> {
+    /// Linearly interpolate between this and the `other` color by `t`.
+    ///
+    /// Gives this color at `t = 0`, and the other color at `t = 1`.
+    pub fn lerp(self, other: Color, t: f32) -> Color {
+        self * (1.0 - t) + other * t
+    }
+
+    /// Get the red, green and blue channel values.
+    pub fn channels(self) -> (f32, f32, f32) {
+        (self.0, self.1, self.2)
+    }
+
+    /// Get the average value of the color channels.
+    pub fn mean_channel(&self) -> f32 {
+        (self.0 + self.1 + self.2) / 3.0
+    }
+
+    /// Get the relative luminance of the color, using Rec. 709 channel weights.
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.0 + 0.7152 * self.1 + 0.0722 * self.2
+    }
+
+    /// Get the value of the largest color channel.
+    pub fn max_channel(&self) -> f32 {
+        self.0.max(self.1).max(self.2)
+    }
+
+    /// Get the smallest of this and the `other` color, per channel.
+    pub fn component_min(self, other: Color) -> Color {
+        Color::new(
+            self.0.min(other.0),
+            self.1.min(other.1),
+            self.2.min(other.2),
+        )
+    }
+
+    /// Get the largest of this and the `other` color, per channel.
+    pub fn component_max(self, other: Color) -> Color {
+        Color::new(
+            self.0.max(other.0),
+            self.1.max(other.1),
+            self.2.max(other.2),
+        )
+    }
+
+    /// Convert to an 8-bit color, writing the linear channel values as is, clamping channels.
+    pub fn to_rgba(self) -> Rgba<u8> {
         // TODO: do not convert between u8/u16 here
         let color = self.clamp();
         Rgba([
@@ -35,6 +157,34 @@ impl Color {
             255,
         ])
     }
+
+    /// Convert to a 16-bit color, writing the linear channel values as is, clamping channels.
+    ///
+    /// Keeps more precision than `to_rgba`, for smooth gradients without banding.
+    pub fn to_rgb16(self) -> Rgb<u16> {
+        let color = self.clamp();
+        let channel = |c: f32| (c * 65535.0) as u16;
+        Rgb([channel(color.0), channel(color.1), channel(color.2)])
+    }
+
+    /// Convert to an 8-bit color like `to_rgba`, with an ordered dither for the pixel at `(x, y)`.
+    ///
+    /// Channels are offset by a fraction of a quantization step before truncating, so smooth
+    /// gradients mix neighboring values instead of showing bands.
+    pub fn to_rgba_dithered(self, x: u32, y: u32) -> Rgba<u8> {
+        let threshold = (f32::from(BAYER[y as usize % 4][x as usize % 4]) + 0.5) / 16.0;
+        let color = self.clamp();
+        let channel = |c: f32| (c * 255.0 + threshold).min(255.0) as u8;
+        Rgba([channel(color.0), channel(color.1), channel(color.2), 255])
+    }
+}
+
+impl Sub for Color {
+    type Output = Color;
+
+    fn sub(self, other: Color) -> Color {
+        Color::new(self.0 - other.0, self.1 - other.1, self.2 - other.2)
+    }
 }
 
 impl Mul for Color {
@@ -66,3 +216,162 @@ impl Add for Color {
         Color::new(self.0 + other.0, self.1 + other.1, self.2 + other.2)
     }
 }
+
+impl Serialize for Color {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let (r, g, b) = self.channels();
+        let mut tuple = serializer.serialize_tuple(3)?;
+        tuple.serialize_element(&r)?;
+        tuple.serialize_element(&g)?;
+        tuple.serialize_element(&b)?;
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ColorVisitor)
+    }
+}
+
+/// Visitor to deserialize a `Color` from a sequence or hex string.
+struct ColorVisitor;
+
+impl<'de> Visitor<'de> for ColorVisitor {
+    type Value = Color;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a color as [r, g, b] or \"#rrggbb\"")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Color, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut channel = |i| {
+            seq.next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))
+        };
+        let color = Color::new(channel(0)?, channel(1)?, channel(2)?);
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(4, &self));
+        }
+        Ok(color)
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Color, E>
+    where
+        E: de::Error,
+    {
+        Color::from_hex(value).map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_helpers() {
+        assert_eq!(Color::new(0.0, 1.0, 0.0).luminance(), 0.7152);
+        assert_eq!(Color::new(0.2, 0.9, 0.5).max_channel(), 0.9);
+
+        let a = Color::new(0.2, 0.9, -0.5);
+        let b = Color::new(0.4, 0.1, 0.0);
+        assert_eq!(a.component_min(b), Color::new(0.2, 0.1, -0.5));
+        assert_eq!(a.component_max(b), Color::new(0.4, 0.9, 0.0));
+    }
+
+    #[test]
+    fn test_dither_mixes_adjacent_values() {
+        let gray = Color::new(0.5, 0.5, 0.5);
+        assert_eq!(gray.to_rgba().0, [127, 127, 127, 255]);
+
+        // A constant region between two byte values dithers to a mix of both
+        let mut values = [0; 256];
+        for x in 0..8 {
+            for y in 0..8 {
+                let [r, g, b, _] = gray.to_rgba_dithered(x, y).0;
+                assert!(r == g && g == b);
+                values[r as usize] += 1;
+            }
+        }
+        assert_eq!(values[127], 32);
+        assert_eq!(values[128], 32);
+
+        // Exact byte values are unchanged
+        assert_eq!(WHITE.to_rgba_dithered(3, 1).0, [255, 255, 255, 255]);
+        assert_eq!(BLACK.to_rgba_dithered(3, 1).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_rgb16_preserves_precision() {
+        let a = Color::new(0.5, 0.5, 0.5);
+        let b = Color::new(0.50001, 0.50001, 0.50001);
+
+        // Both collapse to the same 8-bit value, but stay distinct with 16 bits
+        assert_eq!(a.to_rgba(), b.to_rgba());
+        assert_ne!(a.to_rgb16(), b.to_rgb16());
+        assert_eq!(WHITE.to_rgb16().0, [65535, 65535, 65535]);
+        assert_eq!(BLACK.to_rgb16().0, [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_lerp() {
+        let a = Color::new(0.0, 0.5, 1.0);
+        let b = Color::new(1.0, 0.5, 0.0);
+        assert_eq!(a.lerp(b, 0.0), a);
+        assert_eq!(a.lerp(b, 1.0), b);
+        assert_eq!(a.lerp(b, 0.25), Color::new(0.25, 0.5, 0.75));
+    }
+
+    #[test]
+    fn test_srgb_round_trip() {
+        let table = [(0, 0.0), (128, 0.215_860_5), (255, 1.0)];
+        for &(srgb, linear) in table.iter() {
+            let color = Color::from_srgb8(Rgba([srgb, srgb, srgb, 255]));
+            let (r, g, b) = color.channels();
+            for c in [r, g, b].iter() {
+                assert!((c - linear).abs() < 1e-5, "sRGB {} decoded to {}", srgb, c);
+            }
+            assert_eq!(color.to_srgb8().0, [srgb, srgb, srgb, 255]);
+
+            // Float conversions round trip too
+            let encoded = Color::from_linear(color);
+            assert!((encoded.0 - f32::from(srgb) / 255.0).abs() < 1e-5);
+            assert!((encoded.to_linear().0 - linear).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_from_hex() {
+        assert_eq!(Color::from_hex("#fff"), Ok(Color::new(1.0, 1.0, 1.0)));
+        assert_eq!(Color::from_hex("#ffffff"), Ok(Color::new(1.0, 1.0, 1.0)));
+        assert_eq!(Color::from_hex("000"), Ok(Color::new(0.0, 0.0, 0.0)));
+
+        // Hex colors are sRGB encoded
+        let color = Color::from_hex("#cc1a1a").unwrap();
+        assert_eq!(color.to_srgb8().0, [0xcc, 0x1a, 0x1a, 255]);
+        assert!((color.0 - 0.603_827).abs() < 1e-5);
+
+        assert!(Color::from_hex("#ff").is_err());
+        assert!(Color::from_hex("#gggggg").is_err());
+        assert!(Color::from_hex("#ffé").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_sequence_and_hex() {
+        let sequence: Color = serde_yaml::from_str("[0.8, 0.1, 0.1]").unwrap();
+        assert_eq!(sequence, Color::new(0.8, 0.1, 0.1));
+        let hex: Color = serde_yaml::from_str("'#ffffff'").unwrap();
+        assert_eq!(hex, Color::new(1.0, 1.0, 1.0));
+        assert!(serde_yaml::from_str::<Color>("'#nothex'").is_err());
+        assert!(serde_yaml::from_str::<Color>("[1, 2]").is_err());
+    }
+}
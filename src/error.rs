@@ -0,0 +1,48 @@
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// An error loading a scene or its resources.
+#[derive(Debug, Error)]
+pub enum RaytraceError {
+    /// A file could not be opened or read.
+    #[error("failed to open '{}': {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The scene file could not be parsed.
+    #[error("failed to parse scene file: {0}")]
+    SceneParse(#[from] serde_yaml::Error),
+
+    /// A model file could not be loaded.
+    #[error("failed to load model '{}': {source}", path.display())]
+    ModelLoad {
+        path: PathBuf,
+        #[source]
+        source: tobj::LoadError,
+    },
+
+    /// A model file has an unsupported format.
+    #[error("unsupported model format '{}', expected .obj, .ply or .stl", path.display())]
+    ModelFormat { path: PathBuf },
+
+    /// A .ply or .stl model file could not be parsed.
+    #[error("failed to load model '{}': {message}", path.display())]
+    ModelParse { path: PathBuf, message: String },
+
+    /// An included scene file could not be merged.
+    #[error("failed to include scene files: {0}")]
+    Include(String),
+
+    /// The scene is invalid, with a list of problems.
+    #[error("invalid scene: {}", .0.join(", "))]
+    Validation(Vec<String>),
+
+    /// An entity references an unknown material.
+    #[error("failed to resolve scene materials: {0}")]
+    Material(String),
+}
@@ -1,58 +1,683 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
 use std::mem;
 use std::path::Path;
+use std::sync::Arc;
+
+use rayon::prelude::*;
+use took::Timer;
 
 use crate::algebra::{Identity, Vector};
-use crate::material::Material;
-use crate::math::{Intersectable, Ray};
+use crate::error::RaytraceError;
+use crate::material::{Material, MaterialRef};
+use crate::math::{Hit, Intersectable, Ray, RayType, Transform};
+use crate::ply;
+use crate::verbosity::Verbosity;
 
 // TODO: use bias from scene?
 const EPSILON: f64 = 1e-6;
 
-#[derive(Clone, Debug, Deserialize)]
+/// Number of model triangles above which a slow render warning is shown.
+const TRIANGLE_WARNING: usize = 1_000_000;
+
+/// Maximum number of operand surfaces a ray crosses when intersecting a CSG difference.
+const CSG_MAX_STEPS: usize = 64;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Entity {
     /// A plane entity.
     Plane(Plane),
 
+    /// A finite rectangle entity.
+    Rect(Rect),
+
     /// A spherical entity.
     Sphere(Sphere),
 
+    /// A cylindrical entity.
+    Cylinder(Cylinder),
+
+    /// A torus entity.
+    Torus(Torus),
+
     /// A model entity.
     Model(Model),
+
+    /// The part of entity `a` outside of entity `b`, both must be closed shapes.
+    ///
+    /// The surface carved out by `b` takes the material of `a`. The material of `b` is unused, but
+    /// still resolved and validated like that of any entity.
+    Difference {
+        /// The entity to carve from.
+        a: Box<Entity>,
+
+        /// The entity to carve out.
+        b: Box<Entity>,
+
+        /// Optional name of the difference, to refer to it.
+        #[serde(default)]
+        name: Option<String>,
+    },
+
+    /// A group of child entities, placed together by a transform.
+    ///
+    /// Children are defined in the object space of the group, rays are transformed into it to
+    /// intersect them. This allows placing a reusable assembly of entities as a whole. A group
+    /// has no surface of its own, hits resolve to the child that was hit.
+    Group {
+        /// Transform from the object space of the group to world space.
+        #[serde(default)]
+        transform: Transform,
+
+        /// The entities in this group.
+        children: Vec<Entity>,
+
+        /// Optional name of the group, to refer to it.
+        #[serde(default)]
+        name: Option<String>,
+    },
 }
 
 impl Entity {
+    /// Get the entity material.
+    ///
+    /// Returns `None` for groups, which have no surface of their own. Hits on groups resolve to
+    /// the child that was hit, see `intersect_entity`.
     // TODO: use a trait for this
-    pub fn material(&self) -> Material {
+    pub fn material(&self) -> Option<&Material> {
+        self.material_ref().map(MaterialRef::material)
+    }
+
+    /// Get the entity material, which may be a reference.
+    ///
+    /// Returns `None` for groups.
+    pub fn material_ref(&self) -> Option<&MaterialRef> {
+        match self {
+            Entity::Sphere(ref s) => Some(&s.material),
+            Entity::Cylinder(ref c) => Some(&c.material),
+            Entity::Torus(ref t) => Some(&t.material),
+            Entity::Plane(ref p) => Some(&p.material),
+            Entity::Rect(ref r) => Some(&r.material),
+            Entity::Model(ref m) => Some(&m.material),
+            Entity::Difference { ref a, .. } => a.material_ref(),
+            Entity::Group { .. } => None,
+        }
+    }
+
+    /// Get a mutable reference to the entity material, which may be a reference.
+    ///
+    /// Returns `None` for groups.
+    pub fn material_mut(&mut self) -> Option<&mut MaterialRef> {
+        match self {
+            Entity::Sphere(ref mut s) => Some(&mut s.material),
+            Entity::Cylinder(ref mut c) => Some(&mut c.material),
+            Entity::Torus(ref mut t) => Some(&mut t.material),
+            Entity::Plane(ref mut p) => Some(&mut p.material),
+            Entity::Rect(ref mut r) => Some(&mut r.material),
+            Entity::Model(ref mut m) => Some(&mut m.material),
+            Entity::Difference { ref mut a, .. } => a.material_mut(),
+            Entity::Group { .. } => None,
+        }
+    }
+
+    /// Get the name of this entity, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Entity::Sphere(ref s) => s.name.as_deref(),
+            Entity::Cylinder(ref c) => c.name.as_deref(),
+            Entity::Torus(ref t) => t.name.as_deref(),
+            Entity::Plane(ref p) => p.name.as_deref(),
+            Entity::Rect(ref r) => r.name.as_deref(),
+            Entity::Model(ref m) => m.name.as_deref(),
+            Entity::Difference { ref name, .. } => name.as_deref(),
+            Entity::Group { ref name, .. } => name.as_deref(),
+        }
+    }
+
+    /// Get the ray types this entity is visible to.
+    ///
+    /// Groups are visible to all rays, their children are checked separately.
+    pub fn visibility(&self) -> Visibility {
+        match self {
+            Entity::Sphere(ref s) => s.visibility,
+            Entity::Cylinder(ref c) => c.visibility,
+            Entity::Torus(ref t) => t.visibility,
+            Entity::Plane(ref p) => p.visibility,
+            Entity::Rect(ref r) => r.visibility,
+            Entity::Model(ref m) => m.visibility,
+            Entity::Difference { ref a, .. } => a.visibility(),
+            Entity::Group { .. } => Visibility::default(),
+        }
+    }
+
+    /// Get the texture coordinates at the given `hit` point on the surface of this entity.
+    ///
+    /// Returns `None` for groups, which have no surface of their own.
+    pub fn texture_coords(&self, hit: Vector) -> Option<(f32, f32)> {
+        match self {
+            Entity::Sphere(ref s) => Some(s.texture_coords(hit)),
+            Entity::Cylinder(ref c) => Some(c.texture_coords(hit)),
+            Entity::Torus(ref t) => Some(t.texture_coords(hit)),
+            Entity::Plane(ref p) => Some(p.texture_coords(hit)),
+            Entity::Rect(ref r) => Some(r.texture_coords(hit)),
+            Entity::Model(ref m) => Some(m.texture_coords(hit)),
+            Entity::Difference { ref a, .. } => a.texture_coords(hit),
+            Entity::Group { .. } => None,
+        }
+    }
+
+    /// Get the tangent and bitangent at the given `hit` point, for tangent space normal mapping.
+    ///
+    /// The tangent points towards increasing `u` texture coordinates, the bitangent towards
+    /// decreasing `v`, up in the texture image. Both are perpendicular to the given unit surface
+    /// `normal`. Falls back to an arbitrary basis where the texture mapping is degenerate.
+    pub fn tangent_frame(&self, hit: Vector, normal: Vector) -> (Vector, Vector) {
+        match self.texture_derivatives(hit) {
+            Some(derivatives) => tangent_frame(derivatives, normal),
+            None => normal.orthonormal_basis(),
+        }
+    }
+
+    /// Get the directions in which the `u` and `v` texture coordinates increase at `hit`.
+    ///
+    /// The directions are not normalized. Returns `None` if unknown, for models these are only
+    /// known from the hit triangle.
+    fn texture_derivatives(&self, hit: Vector) -> Option<(Vector, Vector)> {
+        match self {
+            Entity::Sphere(ref s) => Some(s.texture_derivatives(hit)),
+            Entity::Cylinder(ref c) => Some(c.texture_derivatives(hit)),
+            Entity::Torus(ref t) => Some(t.texture_derivatives(hit)),
+            Entity::Plane(ref p) => Some(p.normal.normalize().orthonormal_basis()),
+            Entity::Rect(ref r) => Some((r.u_axis, -r.v_axis)),
+            Entity::Model(_) => None,
+            Entity::Difference { ref a, .. } => a.texture_derivatives(hit),
+            Entity::Group { .. } => None,
+        }
+    }
+
+    /// Get the number of triangles of this entity, only models are built from triangles.
+    pub fn triangles(&self) -> usize {
+        match self {
+            Entity::Model(ref m) => m.meshes.iter().map(Mesh::triangle_count).sum(),
+            Entity::Group { ref children, .. } => children.iter().map(Entity::triangles).sum(),
+            _ => 0,
+        }
+    }
+
+    /// Get the bounding box of this entity in world space.
+    ///
+    /// Returns `None` if the entity is infinite and has no bounds.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        match self {
+            Entity::Sphere(ref s) => Some(s.bounding_box()),
+            Entity::Cylinder(ref c) => Some(c.bounding_box()),
+            Entity::Torus(ref t) => Some(t.bounding_box()),
+            Entity::Plane(_) => None,
+            Entity::Rect(ref r) => Some(r.bounding_box()),
+            Entity::Model(ref m) => m.bounding_box(),
+            Entity::Difference { ref a, .. } => a.bounding_box(),
+            Entity::Group {
+                ref transform,
+                ref children,
+                ..
+            } => children
+                .iter()
+                .map(Entity::bounding_box)
+                .reduce(|a, b| Some(a?.union(&b?)))?
+                .map(|b| transform.aabb_to_world(&b)),
+        }
+    }
+
+    /// Get a sphere enclosing this entity, used to quickly reject rays.
+    ///
+    /// The sphere is not derived from the bounding box, so it rejects rays the box does not.
+    /// Returns `None` for entities without one.
+    pub fn bounding_sphere(&self) -> Option<BoundingSphere> {
+        match self {
+            Entity::Sphere(ref s) => Some(BoundingSphere::new(s.center, s.radius)),
+            Entity::Torus(ref t) => Some(BoundingSphere::new(
+                t.center,
+                t.major_radius + t.minor_radius,
+            )),
+            Entity::Rect(ref r) => Some(BoundingSphere::new(
+                r.center,
+                r.half_width.hypot(r.half_height),
+            )),
+            Entity::Model(ref m) => m.bounding_sphere(),
+            Entity::Difference { ref a, .. } => a.bounding_sphere(),
+            Entity::Group {
+                ref transform,
+                ref children,
+                ..
+            } => children
+                .iter()
+                .map(Entity::bounding_sphere)
+                .reduce(|a, b| Some(a?.union(&b?)))?
+                .map(|s| {
+                    BoundingSphere::new(
+                        transform.point_to_world(s.center),
+                        transform.distance_to_world(s.radius),
+                    )
+                }),
+            Entity::Cylinder(_) | Entity::Plane(_) => None,
+        }
+    }
+
+    /// Validate this entity, returns a list of problems.
+    pub fn validate(&self) -> Vec<String> {
+        if let Entity::Difference { ref a, ref b, .. } = self {
+            let mut problems: Vec<String> = a
+                .validate()
+                .into_iter()
+                .map(|p| format!("difference a: {}", p))
+                .chain(
+                    b.validate()
+                        .into_iter()
+                        .map(|p| format!("difference b: {}", p)),
+                )
+                .collect();
+            if a.material().is_none() {
+                problems.push("difference a must not be a group, its material is used".into());
+            }
+            return problems;
+        }
+        if let Entity::Group {
+            ref transform,
+            ref children,
+            ..
+        } = self
+        {
+            let mut problems = vec![];
+            check_finite(&mut problems, "group translation", transform.translation);
+            if transform.scale <= 0.0 || !transform.scale.is_finite() {
+                problems.push(format!(
+                    "group scale must be positive, got {}",
+                    transform.scale
+                ));
+            }
+            if children.is_empty() {
+                problems.push("group must have at least one child".into());
+            }
+            problems.extend(children.iter().enumerate().flat_map(|(i, c)| {
+                c.validate()
+                    .into_iter()
+                    .map(move |p| format!("group child {}: {}", i, p))
+            }));
+            return problems;
+        }
+
+        let mut problems: Vec<String> = self
+            .material_ref()
+            .map(MaterialRef::validate)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| format!("material {}", p))
+            .collect();
+        match self {
+            Entity::Sphere(ref s) => {
+                check_finite(&mut problems, "sphere center", s.center);
+                if s.radius <= 0.0 || !s.radius.is_finite() {
+                    problems.push(format!("sphere radius must be positive, got {}", s.radius));
+                }
+            }
+            Entity::Cylinder(ref c) => {
+                check_finite(&mut problems, "cylinder base", c.base);
+                check_direction(&mut problems, "cylinder axis", c.axis);
+                if c.radius <= 0.0 || !c.radius.is_finite() {
+                    problems.push(format!(
+                        "cylinder radius must be positive, got {}",
+                        c.radius
+                    ));
+                }
+                if c.height <= 0.0 || !c.height.is_finite() {
+                    problems.push(format!(
+                        "cylinder height must be positive, got {}",
+                        c.height
+                    ));
+                }
+            }
+            Entity::Torus(ref t) => {
+                check_finite(&mut problems, "torus center", t.center);
+                check_direction(&mut problems, "torus axis", t.axis);
+                if t.major_radius <= 0.0 || !t.major_radius.is_finite() {
+                    problems.push(format!(
+                        "torus major radius must be positive, got {}",
+                        t.major_radius
+                    ));
+                }
+                if t.minor_radius <= 0.0 || !t.minor_radius.is_finite() {
+                    problems.push(format!(
+                        "torus minor radius must be positive, got {}",
+                        t.minor_radius
+                    ));
+                }
+            }
+            Entity::Plane(ref p) => {
+                check_finite(&mut problems, "plane center", p.center);
+                check_direction(&mut problems, "plane normal", p.normal);
+            }
+            Entity::Rect(ref r) => {
+                check_finite(&mut problems, "rect center", r.center);
+                check_direction(&mut problems, "rect normal", r.normal);
+                check_direction(&mut problems, "rect u_axis", r.u_axis);
+                check_direction(&mut problems, "rect v_axis", r.v_axis);
+                if problems.is_empty() {
+                    let normal = r.normal.normalize();
+                    for (name, axis) in &[("u_axis", r.u_axis), ("v_axis", r.v_axis)] {
+                        if axis.normalize().dot(normal).abs() > 1e-3 {
+                            problems
+                                .push(format!("rect {} must be perpendicular to the normal", name));
+                        }
+                    }
+                }
+                if r.half_width <= 0.0 || !r.half_width.is_finite() {
+                    problems.push(format!(
+                        "rect half width must be positive, got {}",
+                        r.half_width
+                    ));
+                }
+                if r.half_height <= 0.0 || !r.half_height.is_finite() {
+                    problems.push(format!(
+                        "rect half height must be positive, got {}",
+                        r.half_height
+                    ));
+                }
+            }
+            Entity::Model(ref m) => {
+                check_finite(&mut problems, "model position", m.position);
+                if m.scale <= 0.0 || !m.scale.is_finite() {
+                    problems.push(format!("model scale must be positive, got {}", m.scale));
+                }
+                if m.max_triangles == Some(0) {
+                    problems.push("model max_triangles must be nonzero".into());
+                }
+            }
+            // Operands and children are validated above
+            Entity::Difference { .. } | Entity::Group { .. } => {}
+        }
+        problems
+    }
+
+    /// Resolve material references to the given named `materials`.
+    pub fn resolve_materials(
+        &mut self,
+        materials: &HashMap<String, Material>,
+    ) -> Result<(), String> {
         match self {
-            Entity::Sphere(ref s) => s.material,
-            Entity::Plane(ref p) => p.material,
-            Entity::Model(ref m) => m.material,
+            Entity::Difference {
+                ref mut a,
+                ref mut b,
+                ..
+            } => {
+                a.resolve_materials(materials)?;
+                b.resolve_materials(materials)
+            }
+            Entity::Group {
+                ref mut children, ..
+            } => children
+                .iter_mut()
+                .try_for_each(|c| c.resolve_materials(materials)),
+            _ => self
+                .material_mut()
+                .map_or(Ok(()), |material| material.resolve(materials)),
+        }
+    }
+
+    /// Move this entity by the given `offset` in world space.
+    pub fn translate(&mut self, offset: Vector) {
+        match self {
+            Entity::Sphere(ref mut s) => s.center += offset,
+            Entity::Cylinder(ref mut c) => c.base += offset,
+            Entity::Torus(ref mut t) => t.center += offset,
+            Entity::Plane(ref mut p) => p.center += offset,
+            Entity::Rect(ref mut r) => r.center += offset,
+            Entity::Model(ref mut m) => m.translate(offset),
+            Entity::Difference {
+                ref mut a,
+                ref mut b,
+                ..
+            } => {
+                a.translate(offset);
+                b.translate(offset);
+            }
+            Entity::Group {
+                ref mut transform, ..
+            } => transform.translation += offset,
         }
     }
 
     /// Load any external resources.
-    pub fn load<P: AsRef<Path>>(&mut self, workdir: P) {
+    ///
+    /// Returns the errors of models that failed to load, these are left without meshes.
+    pub fn load<P: AsRef<Path>>(&mut self, workdir: P, verbosity: Verbosity) -> Vec<RaytraceError> {
+        let errors = match self {
+            Entity::Sphere(_) => vec![],
+            Entity::Cylinder(_) => vec![],
+            Entity::Torus(_) => vec![],
+            Entity::Plane(_) => vec![],
+            Entity::Rect(_) => vec![],
+            Entity::Model(ref mut m) => m
+                .load(workdir.as_ref(), verbosity)
+                .err()
+                .into_iter()
+                .collect(),
+            Entity::Difference {
+                ref mut a,
+                ref mut b,
+                ..
+            } => {
+                let mut errors = a.load(workdir.as_ref(), verbosity);
+                errors.extend(b.load(workdir.as_ref(), verbosity));
+                return errors;
+            }
+            Entity::Group {
+                ref mut children, ..
+            } => {
+                return children
+                    .iter_mut()
+                    .flat_map(|child| child.load(workdir.as_ref(), verbosity))
+                    .collect();
+            }
+        };
+        if let Some(material) = self.material_mut() {
+            material.load(workdir, verbosity);
+        }
+        errors
+    }
+
+    /// Resolve relative resource paths against `dir`, instead of the scene working directory.
+    pub fn rebase<P: AsRef<Path>>(&mut self, dir: P) {
+        match self {
+            Entity::Model(ref mut m) => m.rebase(dir.as_ref()),
+            Entity::Difference {
+                ref mut a,
+                ref mut b,
+                ..
+            } => {
+                a.rebase(dir.as_ref());
+                b.rebase(dir.as_ref());
+                return;
+            }
+            Entity::Group {
+                ref mut children, ..
+            } => {
+                for child in children {
+                    child.rebase(dir.as_ref());
+                }
+                return;
+            }
+            _ => {}
+        }
+        if let Some(material) = self.material_mut() {
+            material.rebase(dir);
+        }
+    }
+
+    /// Intersect this entity with a ray of the given `kind`, resolving hits on groups to the child
+    /// that was hit.
+    ///
+    /// Returns the hit along with the intersected entity and its transform to world space.
+    /// Entities not visible to this kind of ray are not hit.
+    pub fn intersect_entity(&self, ray: &Ray, kind: RayType) -> Option<(Hit, &Entity, Transform)> {
+        if !self.visibility().sees(kind) {
+            return None;
+        }
         match self {
-            Entity::Sphere(_) => {}
-            Entity::Plane(_) => {}
-            Entity::Model(ref mut m) => m.load(workdir),
+            Entity::Group {
+                ref transform,
+                ref children,
+                ..
+            } => intersect_group(transform, children, ray, |c, ray| {
+                c.intersect_entity(ray, kind)
+                    .map(|(hit, entity, inner)| (hit, (entity, inner)))
+            })
+            .map(|(hit, (entity, inner))| (hit, entity, transform.compose(&inner))),
+            _ => self
+                .intersect(ray)
+                .map(|hit| (hit, self, Transform::default())),
         }
     }
 }
 
 impl Intersectable for Entity {
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector)> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
         match self {
             Entity::Sphere(ref s) => s.intersect(ray),
+            Entity::Cylinder(ref c) => c.intersect(ray),
+            Entity::Torus(ref t) => t.intersect(ray),
             Entity::Plane(ref p) => p.intersect(ray),
+            Entity::Rect(ref r) => r.intersect(ray),
             Entity::Model(ref m) => m.intersect(ray),
+            Entity::Difference { ref a, ref b, .. } => intersect_difference(a, b, ray),
+            Entity::Group {
+                ref transform,
+                ref children,
+                ..
+            } => intersect_group(transform, children, ray, |c, ray| {
+                c.intersect(ray).map(|hit| (hit, ()))
+            })
+            .map(|(hit, _)| hit),
+        }
+    }
+}
+
+/// Get the tangent and bitangent from the texture derivatives `(du, dv)` at a point with the given
+/// unit surface `normal`.
+///
+/// See `Entity::tangent_frame`.
+pub fn tangent_frame((du, dv): (Vector, Vector), normal: Vector) -> (Vector, Vector) {
+    let tangent = du - normal * du.dot(normal);
+    if tangent.magnitude_squared() <= du.magnitude_squared() * EPSILON {
+        return normal.orthonormal_basis();
+    }
+    let tangent = tangent.normalize();
+
+    // Flip the bitangent for mirrored texture mappings
+    let bitangent = normal.cross(tangent);
+    if bitangent.dot(dv) > 0.0 {
+        (tangent, -bitangent)
+    } else {
+        (tangent, bitangent)
+    }
+}
+
+/// Intersect the part of closed entity `a` outside of closed entity `b`.
+///
+/// Walks along the ray over the surfaces of both entities, telling whether the ray is inside an
+/// entity by whether its next surface is hit from the back. Surfaces of `a` count outside `b`,
+/// surfaces of `b` count inside `a`, with their normal flipped to point into `b`.
+fn intersect_difference(a: &Entity, b: &Entity, ray: &Ray) -> Option<Hit> {
+    let mut offset = 0.0;
+    for _ in 0..CSG_MAX_STEPS {
+        let step = Ray::new(ray.origin + ray.direction * offset, ray.direction);
+        let hit_a = a.intersect(&step);
+        let hit_b = b.intersect(&step);
+        let inside_a = hit_a.is_some_and(|h| !h.front_face);
+        let inside_b = hit_b.is_some_and(|h| !h.front_face);
+
+        let (hit, on_b) = match (hit_a, hit_b) {
+            (Some(hit_a), Some(hit_b)) if hit_b.distance < hit_a.distance => (hit_b, true),
+            (Some(hit_a), _) => (hit_a, false),
+            (None, Some(hit_b)) => (hit_b, true),
+            (None, None) => return None,
+        };
+        let distance = offset + hit.distance;
+        if on_b && inside_a {
+            return Some(Hit::new(ray, distance, -hit.normal, hit.uv));
+        }
+        if !on_b && !inside_b {
+            return Some(Hit { distance, ..hit });
+        }
+
+        // Skip surfaces inside the carved out part, or of `b` outside `a`
+        offset = distance + EPSILON;
+    }
+    None
+}
+
+/// Intersect the `children` of a group placed by `transform`, returns the nearest hit.
+///
+/// The ray is transformed into the object space of the group, where `intersect` intersects a
+/// single child, along with any extra data about the hit. The hit distance is transformed back to
+/// world space, the uniform group scale keeps the hit normal as is.
+fn intersect_group<'a, T>(
+    transform: &Transform,
+    children: &'a [Entity],
+    ray: &Ray,
+    intersect: impl Fn(&'a Entity, &Ray) -> Option<(Hit, T)>,
+) -> Option<(Hit, T)> {
+    let local = transform.ray_to_object(ray);
+    children
+        .iter()
+        .filter_map(|c| intersect(c, &local))
+        .filter(|(hit, _)| hit.distance.is_finite())
+        .min_by(|(a, _), (b, _)| a.distance.total_cmp(&b.distance))
+        .map(|(hit, data)| {
+            let distance = transform.distance_to_world(hit.distance);
+            (Hit { distance, ..hit }, data)
+        })
+}
+
+/// The ray types an entity is visible to, all by default.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct Visibility {
+    /// Whether the entity is seen directly by the camera.
+    pub camera: bool,
+
+    /// Whether the entity casts shadows, and occludes ambient occlusion rays.
+    pub shadow: bool,
+
+    /// Whether the entity is seen in reflections, through refractions and by indirect light.
+    pub reflection: bool,
+}
+
+impl Visibility {
+    /// Check whether the entity is visible to rays of the given `kind`.
+    pub fn sees(&self, kind: RayType) -> bool {
+        match kind {
+            RayType::Primary => self.camera,
+            RayType::Shadow => self.shadow,
+            RayType::Reflection | RayType::Refraction | RayType::Diffuse => self.reflection,
+        }
+    }
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Self {
+            camera: true,
+            shadow: true,
+            reflection: true,
         }
     }
 }
 
 /// A geometric shape, an infinite plane.
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Plane {
     /// Plane center in world space.
     pub center: Vector,
@@ -61,7 +686,15 @@ pub struct Plane {
     pub normal: Vector,
 
     /// Plane material.
-    pub material: Material,
+    pub material: MaterialRef,
+
+    /// Ray types the plane is visible to.
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Optional name of the plane, to refer to it.
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
 impl Plane {
@@ -79,16 +712,117 @@ impl Plane {
         }
         None
     }
+
+    /// Get the texture coordinates at the given `hit` point.
+    ///
+    /// The texture is projected on the plane, and repeats every unit.
+    fn texture_coords(&self, hit: Vector) -> (f32, f32) {
+        let (tangent, bitangent) = self.normal.normalize().orthonormal_basis();
+        let local = hit - self.center;
+        (local.dot(tangent) as f32, local.dot(bitangent) as f32)
+    }
 }
 
 impl Intersectable for Plane {
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector)> {
-        self.intersect_distance(ray).map(|d| (d, -self.normal))
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.intersect_distance(ray)
+            .map(|d| Hit::new(ray, d, -self.normal, None))
+    }
+}
+
+/// A geometric shape, a finite rectangle visible from both sides.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Rect {
+    /// Rectangle center in world space.
+    pub center: Vector,
+
+    /// Rectangle normal, pointing out of its front face.
+    pub normal: Vector,
+
+    /// Direction of the rectangle width, perpendicular to the normal.
+    pub u_axis: Vector,
+
+    /// Direction of the rectangle height, perpendicular to the normal.
+    pub v_axis: Vector,
+
+    /// Half of the rectangle width along the `u_axis`.
+    #[serde(default = "one")]
+    pub half_width: f64,
+
+    /// Half of the rectangle height along the `v_axis`.
+    #[serde(default = "one")]
+    pub half_height: f64,
+
+    /// Rectangle material.
+    pub material: MaterialRef,
+
+    /// Ray types the rectangle is visible to.
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Optional name of the rectangle, to refer to it.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl Rect {
+    /// Get the bounding box of this rectangle.
+    pub fn bounding_box(&self) -> Aabb {
+        let u = self.u_axis.normalize() * self.half_width;
+        let v = self.v_axis.normalize() * self.half_height;
+        let extent = Vector(
+            u.0.abs() + v.0.abs(),
+            u.1.abs() + v.1.abs(),
+            u.2.abs() + v.2.abs(),
+        );
+        Aabb::new(self.center - extent, self.center + extent)
+    }
+
+    /// Get the local coordinates of a point on the rectangle plane, along its axes from the center.
+    fn local_coords(&self, point: Vector) -> (f64, f64) {
+        let local = point - self.center;
+        (
+            local.dot(self.u_axis.normalize()),
+            local.dot(self.v_axis.normalize()),
+        )
+    }
+
+    /// Get the texture coordinates at the given `hit` point.
+    ///
+    /// The texture is stretched over the rectangle once, its top along the `v_axis`.
+    fn texture_coords(&self, hit: Vector) -> (f32, f32) {
+        let (u, v) = self.local_coords(hit);
+        (
+            (0.5 + u / (2.0 * self.half_width)) as f32,
+            (0.5 - v / (2.0 * self.half_height)) as f32,
+        )
+    }
+}
+
+impl Intersectable for Rect {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        // Intersect the plane from either side, then reject hits outside the extents
+        let normal = self.normal.normalize();
+        let denom = normal.dot(ray.direction);
+        if denom.abs() < EPSILON {
+            return None;
+        }
+        let distance = (self.center - ray.origin).dot(normal) / denom;
+        if distance < EPSILON {
+            return None;
+        }
+        let point = ray.origin + ray.direction * distance;
+        let (u, v) = self.local_coords(point);
+        if u.abs() > self.half_width || v.abs() > self.half_height {
+            return None;
+        }
+        let uv = self.texture_coords(point);
+        Some(Hit::new(ray, distance, normal, Some(uv)))
     }
 }
 
 /// A geometric shape, a sphere.
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Sphere {
     /// Sphere center in world space.
     pub center: Vector,
@@ -98,10 +832,24 @@ pub struct Sphere {
     pub radius: f64,
 
     /// Sphere material.
-    pub material: Material,
+    pub material: MaterialRef,
+
+    /// Ray types the sphere is visible to.
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Optional name of the sphere, to refer to it.
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
 impl Sphere {
+    /// Get the bounding box of this sphere.
+    pub fn bounding_box(&self) -> Aabb {
+        let extent = Vector(self.radius, self.radius, self.radius);
+        Aabb::new(self.center - extent, self.center + extent)
+    }
+
     /// Get intersection distance form ray origin.
     fn intersect_distance(&self, ray: &Ray) -> Option<f64> {
         let l: Vector = self.center - ray.origin;
@@ -126,194 +874,1339 @@ impl Sphere {
             Some(distance)
         }
     }
+
+    /// Get the texture coordinates at the given `hit` point.
+    ///
+    /// Uses a spherical mapping, `u` wraps around the Y axis and `v` goes from top to bottom.
+    fn texture_coords(&self, hit: Vector) -> (f32, f32) {
+        let p = (hit - self.center).normalize();
+        let u = (1.0 + p.2.atan2(p.0) / PI) * 0.5;
+        let v = p.1.clamp(-1.0, 1.0).acos() / PI;
+        (u as f32, v as f32)
+    }
+
+    /// Get the directions in which the texture coordinates increase at the given `hit` point.
+    fn texture_derivatives(&self, hit: Vector) -> (Vector, Vector) {
+        let p = (hit - self.center).normalize();
+        (
+            Vector(-p.2, 0.0, p.0),
+            Vector(p.1 * p.0, -(p.0 * p.0 + p.2 * p.2), p.1 * p.2),
+        )
+    }
 }
 
 impl Intersectable for Sphere {
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector)> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
         self.intersect_distance(ray).map(|d| {
             let point = ray.origin + ray.direction * d;
-            (d, (point - self.center).normalize())
+            Hit::new(ray, d, (point - self.center).normalize(), None)
         })
     }
 }
 
-/// Returns one.
-///
-/// Helper function for serde defaults.
-const fn one() -> f64 {
-    1.0
-}
+/// A geometric shape, a cylinder with optional caps.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Cylinder {
+    /// Center of the cylinder base in world space.
+    pub base: Vector,
 
-/// Represents a triangle.
-#[derive(Clone, Debug, Deserialize)]
-pub struct Triangle {
-    positions: [Vector; 3],
-    normals: Option<[Vector; 3]>,
-    // texcoords: [Point; 3],
+    /// Cylinder axis direction, pointing from the base to the top.
+    pub axis: Vector,
+
+    /// Cylinder radius.
+    #[serde(default = "one")]
+    pub radius: f64,
+
+    /// Cylinder height along the axis.
+    #[serde(default = "one")]
+    pub height: f64,
+
+    /// Whether the cylinder is closed with caps at both ends.
+    #[serde(default)]
+    pub capped: bool,
+
+    /// Cylinder material.
+    pub material: MaterialRef,
+
+    /// Ray types the cylinder is visible to.
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Optional name of the cylinder, to refer to it.
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
-impl Triangle {
-    /// Constructor.
-    pub fn new(positions: [Vector; 3], normals: Option<[Vector; 3]>) -> Self {
-        Self { positions, normals }
+impl Cylinder {
+    /// Get the bounding box of this cylinder.
+    pub fn bounding_box(&self) -> Aabb {
+        let axis = self.axis.normalize();
+        let top = self.base + axis * self.height;
+
+        // Extent of the cap disks along each world axis
+        let extent = Vector(
+            self.radius * (1.0 - axis.0 * axis.0).max(0.0).sqrt(),
+            self.radius * (1.0 - axis.1 * axis.1).max(0.0).sqrt(),
+            self.radius * (1.0 - axis.2 * axis.2).max(0.0).sqrt(),
+        );
+        Aabb::new(
+            self.base.min_components(top) - extent,
+            self.base.max_components(top) + extent,
+        )
     }
-}
 
-impl Intersectable for Triangle {
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector)> {
-        // Intersection check with Möller–Trumbore algorithm
-        let v0 = self.positions[0];
-        let v1 = self.positions[1];
-        let v2 = self.positions[2];
-        let edge1 = v1 - v0;
-        let edge2 = v2 - v0;
-        let h = ray.direction.cross(edge2);
-        let a = edge1.dot(h);
-
-        // No intersection if ray is parallel to triangle face
-        if a.abs() < EPSILON {
-            return None;
-        }
-        let f = 1.0 / a;
-        let s = ray.origin - v0;
-        let u = f * s.dot(h);
-        if u < 0.0 || u > 1.0 {
+    /// Get the nearest intersection with the cylinder side, within its height.
+    fn intersect_side(&self, ray: &Ray, axis: Vector) -> Option<(f64, Vector)> {
+        // Project the ray onto the plane perpendicular to the axis
+        let origin = ray.origin - self.base;
+        let d = ray.direction - axis * ray.direction.dot(axis);
+        let o = origin - axis * origin.dot(axis);
+
+        // Solve the quadratic for the infinite cylinder
+        let a = d.dot(d);
+        if a < EPSILON {
             return None;
         }
-        let q = s.cross(edge1);
-        let v = f * ray.direction.dot(q);
-        if v < 0.0 || u + v > 1.0 {
+        let b = 2.0 * d.dot(o);
+        let c = o.dot(o) - self.radius * self.radius;
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
             return None;
         }
-        let t = f * edge2.dot(q);
+        let sqrt = discriminant.sqrt();
+
+        // Take the nearest hit in front of the ray within the height range
+        [(-b - sqrt) / (2.0 * a), (-b + sqrt) / (2.0 * a)]
+            .iter()
+            .filter(|t| **t > EPSILON)
+            .find_map(|t| {
+                let point = origin + ray.direction * *t;
+                let h = point.dot(axis);
+                if h >= 0.0 && h <= self.height {
+                    Some((*t, (point - axis * h).normalize()))
+                } else {
+                    None
+                }
+            })
+    }
 
-        // Ray intersection
-        // This means that there is a line intersection but not a ray intersection.
-        if t <= EPSILON || t >= 1.0 / EPSILON {
+    /// Get the nearest intersection with one of the cap disks.
+    fn intersect_caps(&self, ray: &Ray, axis: Vector) -> Option<(f64, Vector)> {
+        let denom = ray.direction.dot(axis);
+        if denom.abs() < EPSILON {
             return None;
         }
 
-        // Calcualte the normal
-        let normal = match &self.normals {
-            // Interpolate vertex normals for smooth Gouraud normal
-            Some(normals) => normals[0] * (1.0 - u - v) + normals[1] * u + normals[2] * v,
+        let radius2 = self.radius * self.radius;
+        [(self.base, -axis), (self.base + axis * self.height, axis)]
+            .iter()
+            .filter_map(|(center, normal)| {
+                let t = (*center - ray.origin).dot(axis) / denom;
+                if t <= EPSILON {
+                    return None;
+                }
+                let point = ray.origin + ray.direction * t;
+                if (point - *center).magnitude_squared() <= radius2 {
+                    Some((t, *normal))
+                } else {
+                    None
+                }
+            })
+            .min_by(|i1, i2| i1.0.total_cmp(&i2.0))
+    }
 
-            // Calculate face normal
-            None => {
-                let v0: Vector = self.positions[0];
-                let v1: Vector = self.positions[1];
-                let v2: Vector = self.positions[2];
-                (v1 - v0).cross(v2 - v0).normalize()
-            }
-        };
+    /// Get the texture coordinates at the given `hit` point.
+    ///
+    /// Uses a cylindrical mapping, `u` wraps around the axis and `v` goes from top to base.
+    fn texture_coords(&self, hit: Vector) -> (f32, f32) {
+        let axis = self.axis.normalize();
+        let (tangent, bitangent) = axis.orthonormal_basis();
+        let local = hit - self.base;
+        let u = (1.0 + local.dot(bitangent).atan2(local.dot(tangent)) / PI) * 0.5;
+        let v = 1.0 - local.dot(axis) / self.height;
+        (u as f32, v as f32)
+    }
 
-        Some((t, normal))
+    /// Get the directions in which the texture coordinates increase at the given `hit` point.
+    fn texture_derivatives(&self, hit: Vector) -> (Vector, Vector) {
+        let axis = self.axis.normalize();
+        let (tangent, bitangent) = axis.orthonormal_basis();
+        let local = hit - self.base;
+        (
+            bitangent * local.dot(tangent) - tangent * local.dot(bitangent),
+            -axis,
+        )
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct Mesh {
-    /// The mesh triangles.
-    triangles: Vec<Triangle>,
+impl Intersectable for Cylinder {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let axis = self.axis.normalize();
+        let side = self.intersect_side(ray, axis);
+        let caps = if self.capped {
+            self.intersect_caps(ray, axis)
+        } else {
+            None
+        };
 
-    /// The bounds of this mesh in world space.
-    bounds: Bounds,
+        let (distance, normal) = match (side, caps) {
+            (Some(side), Some(caps)) => Some(if side.0 < caps.0 { side } else { caps }),
+            (side, caps) => side.or(caps),
+        }?;
+        Some(Hit::new(ray, distance, normal, None))
+    }
 }
 
-impl Mesh {
-    pub fn new(positions: Vec<Vector>, normals: Vec<Vector>, indices: Vec<u32>) -> Self {
-        let triangles = indices
-            .chunks(3)
-            .map(|i| {
-                let positions = [
-                    positions[i[0] as usize],
-                    positions[i[1] as usize],
-                    positions[i[2] as usize],
-                ];
-                let normals = if !normals.is_empty() {
-                    Some([
-                        normals[i[0] as usize],
-                        normals[i[1] as usize],
-                        normals[i[2] as usize],
-                    ])
-                } else {
-                    None
-                };
-                Triangle::new(positions, normals)
-            })
-            .collect();
+/// A geometric shape, a torus around an axis.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Torus {
+    /// Center of the torus in world space.
+    pub center: Vector,
 
-        // Find the bounds
-        let mut min = positions[0];
-        let mut max = positions[0];
-        positions.into_iter().for_each(|p| {
-            min = min.min_components(p);
-            max = max.max_components(p);
-        });
+    /// Torus axis direction, the ring lies in the plane perpendicular to it.
+    pub axis: Vector,
 
-        Self {
-            triangles,
-            bounds: Bounds { min, max },
-        }
-    }
+    /// Distance from the center to the middle of the tube.
+    #[serde(default = "one")]
+    pub major_radius: f64,
 
-    /// Load a mesh from an .obj file at the given path.
-    pub fn load_obj<'a, P: AsRef<Path>>(
+    /// Radius of the tube.
+    #[serde(default = "default_minor_radius")]
+    pub minor_radius: f64,
+
+    /// Torus material.
+    pub material: MaterialRef,
+
+    /// Ray types the torus is visible to.
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Optional name of the torus, to refer to it.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl Torus {
+    /// Get the bounding box of this torus.
+    pub fn bounding_box(&self) -> Aabb {
+        let axis = self.axis.normalize();
+
+        // Extent of the ring along each world axis, grown by the tube
+        let extent = Vector(
+            self.major_radius * (1.0 - axis.0 * axis.0).max(0.0).sqrt() + self.minor_radius,
+            self.major_radius * (1.0 - axis.1 * axis.1).max(0.0).sqrt() + self.minor_radius,
+            self.major_radius * (1.0 - axis.2 * axis.2).max(0.0).sqrt() + self.minor_radius,
+        );
+        Aabb::new(self.center - extent, self.center + extent)
+    }
+
+    /// Get the local frame of the torus, two vectors in the ring plane and the axis.
+    fn frame(&self) -> (Vector, Vector, Vector) {
+        let axis = self.axis.normalize();
+        let (tangent, bitangent) = axis.orthonormal_basis();
+        (tangent, bitangent, axis)
+    }
+
+    /// Get the given world space `point` in the local frame, with the axis along `Z`.
+    fn to_local(&self, point: Vector) -> Vector {
+        let (tangent, bitangent, axis) = self.frame();
+        let p = point - self.center;
+        Vector(p.dot(tangent), p.dot(bitangent), p.dot(axis))
+    }
+
+    /// Get the point on the center of the tube closest to a local `point`.
+    fn ring_point(&self, point: Vector) -> Vector {
+        let radial = Vector(point.0, point.1, 0.0);
+        let length = radial.magnitude();
+        if length < EPSILON {
+            return Vector(self.major_radius, 0.0, 0.0);
+        }
+        radial * (self.major_radius / length)
+    }
+
+    /// Get the texture coordinates at the given `hit` point.
+    ///
+    /// `u` wraps around the axis, `v` wraps around the tube starting at the outer edge.
+    fn texture_coords(&self, hit: Vector) -> (f32, f32) {
+        let local = self.to_local(hit);
+        let tube = local - self.ring_point(local);
+        let radial = Vector(local.0, local.1, 0.0).normalize();
+        let u = (1.0 + local.1.atan2(local.0) / PI) * 0.5;
+        let v = (1.0 + tube.2.atan2(tube.dot(radial)) / PI) * 0.5;
+        (u as f32, v as f32)
+    }
+
+    /// Get the directions in which the texture coordinates increase at the given `hit` point.
+    fn texture_derivatives(&self, hit: Vector) -> (Vector, Vector) {
+        let (tangent, bitangent, axis) = self.frame();
+        let local = self.to_local(hit);
+        let tube = local - self.ring_point(local);
+        let radial = Vector(local.0, local.1, 0.0).normalize();
+        let around = Vector(-local.1, local.0, 0.0);
+        let along = Vector(0.0, 0.0, 1.0) * tube.dot(radial) - radial * tube.2;
+        let to_world = |v: Vector| tangent * v.0 + bitangent * v.1 + axis * v.2;
+        (to_world(around), to_world(along))
+    }
+}
+
+impl Intersectable for Torus {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        // Intersect in the local frame along a unit direction
+        let (tangent, bitangent, axis) = self.frame();
+        let scale = ray.direction.magnitude();
+        let o = self.to_local(ray.origin);
+        let d = Vector(
+            ray.direction.dot(tangent),
+            ray.direction.dot(bitangent),
+            ray.direction.dot(axis),
+        ) / scale;
+
+        // Only search where the ray is within the bounding sphere
+        let bound = self.major_radius + self.minor_radius;
+        let b = o.dot(d);
+        let discriminant = b * b - (o.dot(o) - bound * bound);
+        if discriminant < 0.0 {
+            return None;
+        }
+        let (near, far) = (-b - discriminant.sqrt(), -b + discriminant.sqrt());
+        if far <= EPSILON {
+            return None;
+        }
+
+        // Solve the quartic (|p|^2 + R^2 - r^2)^2 = 4 R^2 (p.x^2 + p.y^2) along the ray
+        let r2 = self.major_radius * self.major_radius;
+        let c = o.dot(o) + r2 - self.minor_radius * self.minor_radius;
+        let coefficients = [
+            c * c - 4.0 * r2 * (o.0 * o.0 + o.1 * o.1),
+            4.0 * b * c - 8.0 * r2 * (o.0 * d.0 + o.1 * d.1),
+            4.0 * b * b + 2.0 * c - 4.0 * r2 * (d.0 * d.0 + d.1 * d.1),
+            4.0 * b,
+            1.0,
+        ];
+        let t = polynomial_roots(&coefficients, near.max(EPSILON), far)
+            .into_iter()
+            .find(|t| *t > EPSILON)?;
+
+        // The normal points away from the center of the tube
+        let point = o + d * t;
+        let normal = (point - self.ring_point(point)).normalize();
+        let normal = tangent * normal.0 + bitangent * normal.1 + axis * normal.2;
+        Some(Hit::new(ray, t / scale, normal, None))
+    }
+}
+
+/// Find the real roots of a polynomial within `[lo, hi]`, in ascending order.
+///
+/// The `coefficients` are given from the constant term up. Roots are isolated between the roots of
+/// the derivative, where the polynomial is monotonic, and refined by bisection. Roots where the
+/// polynomial touches zero without changing sign may be missed.
+fn polynomial_roots(coefficients: &[f64], lo: f64, hi: f64) -> Vec<f64> {
+    let eval = |x: f64| coefficients.iter().rev().fold(0.0, |acc, c| acc * x + c);
+    if coefficients.len() <= 2 {
+        return match coefficients {
+            [c0, c1] if *c1 != 0.0 => Some(-c0 / c1),
+            _ => None,
+        }
+        .filter(|x| (lo..=hi).contains(x))
+        .into_iter()
+        .collect();
+    }
+
+    // Split the range at the extrema, the polynomial is monotonic in between
+    let derivative: Vec<f64> = coefficients
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, c)| c * i as f64)
+        .collect();
+    let mut bounds = vec![lo];
+    bounds.extend(polynomial_roots(&derivative, lo, hi));
+    bounds.push(hi);
+
+    bounds
+        .windows(2)
+        .filter_map(|range| {
+            let (mut a, mut b) = (range[0], range[1]);
+            let (fa, fb) = (eval(a), eval(b));
+            if fa == 0.0 {
+                return Some(a);
+            }
+            if fa.signum() == fb.signum() {
+                return None;
+            }
+            for _ in 0..100 {
+                let mid = (a + b) * 0.5;
+                if mid <= a || mid >= b {
+                    break;
+                }
+                if eval(mid).signum() == fa.signum() {
+                    a = mid;
+                } else {
+                    b = mid;
+                }
+            }
+            Some((a + b) * 0.5)
+        })
+        .collect()
+}
+
+/// Check the given vector has finite components, adds a problem if not.
+pub fn check_finite(problems: &mut Vec<String>, what: &str, vector: Vector) {
+    if !vector.is_finite() {
+        problems.push(format!("{} must be finite, got {:?}", what, vector));
+    }
+}
+
+/// Check the given vector is a finite nonzero direction, adds a problem if not.
+pub fn check_direction(problems: &mut Vec<String>, what: &str, vector: Vector) {
+    if !vector.is_finite() {
+        check_finite(problems, what, vector);
+    } else if vector.magnitude() < EPSILON {
+        problems.push(format!("{} must be nonzero, got {:?}", what, vector));
+    }
+}
+
+/// Returns one.
+///
+/// Helper function for serde defaults.
+const fn one() -> f64 {
+    1.0
+}
+
+/// The default torus tube radius.
+///
+/// Helper function for serde defaults.
+const fn default_minor_radius() -> f64 {
+    0.25
+}
+
+/// Represents a triangle.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Triangle {
+    positions: [Vector; 3],
+    normals: Option<[Vector; 3]>,
+    texcoords: Option<[(f32, f32); 3]>,
+}
+
+impl Triangle {
+    /// Constructor.
+    pub fn new(
+        positions: [Vector; 3],
+        normals: Option<[Vector; 3]>,
+        texcoords: Option<[(f32, f32); 3]>,
+    ) -> Self {
+        Self {
+            positions,
+            normals,
+            texcoords,
+        }
+    }
+
+    /// Get the barycentric `(u, v)` coordinates of a point on the triangle plane.
+    ///
+    /// Returns `None` if the point is not on or inside the triangle.
+    fn barycentric(&self, point: Vector) -> Option<(f64, f64)> {
+        let v0 = self.positions[0];
+        let edge1 = self.positions[1] - v0;
+        let edge2 = self.positions[2] - v0;
+        let normal = edge1.cross(edge2);
+        let area2 = normal.magnitude_squared();
+        if area2 < EPSILON * EPSILON {
+            return None;
+        }
+
+        // The point must lie on the triangle plane
+        let p = point - v0;
+        if p.dot(normal).abs() > EPSILON * area2.sqrt() {
+            return None;
+        }
+
+        let u = p.cross(edge2).dot(normal) / area2;
+        let v = edge1.cross(p).dot(normal) / area2;
+        if u < -EPSILON || v < -EPSILON || u + v > 1.0 + EPSILON {
+            return None;
+        }
+        Some((u, v))
+    }
+
+    /// Get the texture coordinates at the given `point` on the triangle.
+    ///
+    /// Interpolates the vertex texture coordinates. Returns `None` if the point is not on the
+    /// triangle.
+    fn texture_coords(&self, point: Vector) -> Option<(f32, f32)> {
+        let (u, v) = self.barycentric(point)?;
+        let (u, v) = (u as f32, v as f32);
+        Some(match self.texcoords {
+            Some([t0, t1, t2]) => (
+                t0.0 * (1.0 - u - v) + t1.0 * u + t2.0 * v,
+                t0.1 * (1.0 - u - v) + t1.1 * u + t2.1 * v,
+            ),
+            None => (u, v),
+        })
+    }
+
+    /// Get the directions in which the texture coordinates increase on this triangle.
+    ///
+    /// Returns `None` if the texture coordinates are degenerate.
+    fn texture_derivatives(&self) -> Option<(Vector, Vector)> {
+        let edge1 = self.positions[1] - self.positions[0];
+        let edge2 = self.positions[2] - self.positions[0];
+        let [t0, t1, t2] = match self.texcoords {
+            Some(texcoords) => texcoords,
+            None => return Some((edge1, edge2)),
+        };
+        let (du1, dv1) = (f64::from(t1.0 - t0.0), f64::from(t1.1 - t0.1));
+        let (du2, dv2) = (f64::from(t2.0 - t0.0), f64::from(t2.1 - t0.1));
+        let det = du1 * dv2 - du2 * dv1;
+        if det == 0.0 {
+            return None;
+        }
+
+        // Multiply by the inverse, vector division does not take negative divisors
+        let inverse = 1.0 / det;
+        Some((
+            (edge1 * dv2 - edge2 * dv1) * inverse,
+            (edge2 * du1 - edge1 * du2) * inverse,
+        ))
+    }
+
+    /// Get the hit at the given `distance` and barycentric `(u, v)` coordinates on this triangle.
+    fn hit(&self, distance: f64, (u, v): (f64, f64), front_face: bool) -> Hit {
+        // Calcualte the normal
+        let normal = match &self.normals {
+            // Interpolate vertex normals for smooth Gouraud normal
+            Some(normals) => normals[0] * (1.0 - u - v) + normals[1] * u + normals[2] * v,
+
+            // Calculate face normal
+            None => {
+                let v0: Vector = self.positions[0];
+                let v1: Vector = self.positions[1];
+                let v2: Vector = self.positions[2];
+                (v1 - v0).cross(v2 - v0).normalize()
+            }
+        };
+
+        // Interpolate the vertex texture coordinates, or use the barycentric coordinates
+        let (uf, vf) = (u as f32, v as f32);
+        let uv = match self.texcoords {
+            Some([t0, t1, t2]) => (
+                t0.0 * (1.0 - uf - vf) + t1.0 * uf + t2.0 * vf,
+                t0.1 * (1.0 - uf - vf) + t1.1 * uf + t2.1 * vf,
+            ),
+            None => (uf, vf),
+        };
+
+        Hit {
+            distance,
+            normal,
+            uv: Some(uv),
+            texture_derivatives: self.texture_derivatives(),
+            barycentric: Some((u, v)),
+            front_face,
+        }
+    }
+}
+
+impl Intersectable for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (distance, uv, front_face) = intersect_triangle(&self.positions, ray)?;
+        Some(self.hit(distance, uv, front_face))
+    }
+}
+
+/// A ray hit on a triangle: its distance, barycentric `(u, v)` coordinates and front face flag.
+type TriangleHit = (f64, (f64, f64), bool);
+
+/// Intersect a triangle with the given vertex `positions`, using the Möller–Trumbore algorithm.
+///
+/// Returns the hit distance, barycentric `(u, v)` coordinates and whether the front face was hit.
+/// The front face is wound counter clockwise as seen by the ray.
+#[inline]
+fn intersect_triangle(positions: &[Vector; 3], ray: &Ray) -> Option<TriangleHit> {
+    let v0 = positions[0];
+    let v1 = positions[1];
+    let v2 = positions[2];
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let h = ray.direction.cross(edge2);
+    let a = edge1.dot(h);
+
+    // No intersection if ray is parallel to triangle face
+    if a.abs() < EPSILON {
+        return None;
+    }
+    let f = 1.0 / a;
+    let s = ray.origin - v0;
+    let u = f * s.dot(h);
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(edge1);
+    let v = f * ray.direction.dot(q);
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = f * edge2.dot(q);
+
+    // Ray intersection
+    // This means that there is a line intersection but not a ray intersection.
+    if t <= EPSILON || t >= 1.0 / EPSILON {
+        return None;
+    }
+
+    Some((t, (u, v), a > 0.0))
+}
+
+/// The vertices of a mesh, and its triangles referencing them by index.
+///
+/// Vertices are shared by all triangles using them, instead of being stored for each triangle.
+#[derive(Debug, Default)]
+struct Geometry {
+    /// Vertex positions, in object space.
+    positions: Vec<Vector>,
+
+    /// Vertex normals, empty if the mesh has none.
+    normals: Vec<Vector>,
+
+    /// Vertex texture coordinates, empty if the mesh has none.
+    texcoords: Vec<(f32, f32)>,
+
+    /// The vertex indices of each triangle.
+    triangles: Vec<[u32; 3]>,
+
+    /// Bounding volume hierarchy over the triangles.
+    bvh: Bvh,
+}
+
+impl Geometry {
+    /// Get the triangle with the given vertex `indices`.
+    fn triangle(&self, indices: [u32; 3]) -> Triangle {
+        let [a, b, c] = indices.map(|i| i as usize);
+        Triangle::new(
+            [self.positions[a], self.positions[b], self.positions[c]],
+            if self.normals.is_empty() {
+                None
+            } else {
+                Some([self.normals[a], self.normals[b], self.normals[c]])
+            },
+            if self.texcoords.is_empty() {
+                None
+            } else {
+                Some([self.texcoords[a], self.texcoords[b], self.texcoords[c]])
+            },
+        )
+    }
+
+    /// Get the vertex positions of the triangle with the given vertex `indices`.
+    #[inline]
+    fn positions(&self, indices: [u32; 3]) -> [Vector; 3] {
+        let [a, b, c] = indices;
+        [
+            self.positions[a as usize],
+            self.positions[b as usize],
+            self.positions[c as usize],
+        ]
+    }
+
+    /// Iterate over all triangles.
+    fn triangles(&self) -> impl Iterator<Item = Triangle> + '_ {
+        self.triangles.iter().map(move |&t| self.triangle(t))
+    }
+}
+
+/// Maximum number of triangles in a BVH leaf.
+const BVH_LEAF_SIZE: usize = 4;
+
+/// BVH subtrees with more triangles than this are built in parallel.
+const BVH_PARALLEL_SIZE: usize = 4096;
+
+/// A bounding volume hierarchy over the triangles of a mesh.
+#[derive(Debug, Default, PartialEq)]
+struct Bvh {
+    /// The nodes, depth first. The left child of an inner node directly follows it.
+    nodes: Vec<BvhNode>,
+
+    /// Triangle indices, ordered so that each leaf references a contiguous range.
+    triangles: Vec<u32>,
+}
+
+/// A node of a bounding volume hierarchy.
+#[derive(Debug, PartialEq)]
+struct BvhNode {
+    /// The bounds of all triangles below this node.
+    bounds: Aabb,
+
+    /// The first triangle of a leaf, or the index of the right child of an inner node.
+    offset: u32,
+
+    /// The number of triangles in a leaf, zero for inner nodes.
+    count: u32,
+}
+
+/// A BVH node during construction, before it is flattened.
+enum BvhBuild {
+    Leaf {
+        bounds: Aabb,
+        start: usize,
+        count: usize,
+    },
+    Inner {
+        bounds: Aabb,
+        children: Box<(BvhBuild, BvhBuild)>,
+    },
+}
+
+impl Bvh {
+    /// Build a bounding volume hierarchy over the triangles of the given geometry.
+    ///
+    /// Triangles are split at their median centroid along the longest axis. If `parallel`, large
+    /// subtrees are built on multiple threads. The split choice is deterministic, the tree is the
+    /// same either way.
+    fn build(geometry: &Geometry, parallel: bool) -> Bvh {
+        if geometry.triangles.is_empty() {
+            return Bvh::default();
+        }
+
+        let centroids: Vec<Vector> = geometry
+            .triangles
+            .iter()
+            .map(|&t| {
+                let [a, b, c] = geometry.positions(t);
+                (a + b + c) * (1.0 / 3.0)
+            })
+            .collect();
+        let mut triangles: Vec<u32> = (0..geometry.triangles.len() as u32).collect();
+        let root = Bvh::split(geometry, &centroids, &mut triangles, 0, parallel);
+
+        let mut nodes = Vec::new();
+        root.flatten(&mut nodes);
+        Bvh { nodes, triangles }
+    }
+
+    /// Build the subtree for the given `triangles`, starting at index `start` of all triangles.
+    fn split(
+        geometry: &Geometry,
+        centroids: &[Vector],
+        triangles: &mut [u32],
+        start: usize,
+        parallel: bool,
+    ) -> BvhBuild {
+        let bounds = triangles
+            .iter()
+            .map(|&t| {
+                let [a, b, c] = geometry.positions(geometry.triangles[t as usize]);
+                Aabb::new(
+                    a.min_components(b).min_components(c),
+                    a.max_components(b).max_components(c),
+                )
+            })
+            .reduce(|a, b| a.union(&b))
+            .unwrap();
+        let count = triangles.len();
+        if count <= BVH_LEAF_SIZE {
+            return BvhBuild::Leaf {
+                bounds,
+                start,
+                count,
+            };
+        }
+
+        // Split along the axis the centroids spread the most, ties are broken by triangle index
+        let first = centroids[triangles[0] as usize];
+        let (min, max) = triangles.iter().fold((first, first), |(min, max), &t| {
+            let c = centroids[t as usize];
+            (min.min_components(c), max.max_components(c))
+        });
+        let extent = max - min;
+        let axis = |v: Vector| {
+            if extent.0 >= extent.1 && extent.0 >= extent.2 {
+                v.0
+            } else if extent.1 >= extent.2 {
+                v.1
+            } else {
+                v.2
+            }
+        };
+        let middle = count / 2;
+        triangles.select_nth_unstable_by(middle, |a, b| {
+            axis(centroids[*a as usize])
+                .total_cmp(&axis(centroids[*b as usize]))
+                .then(a.cmp(b))
+        });
+
+        let (left, right) = triangles.split_at_mut(middle);
+        let build = |triangles: &mut [u32], start| {
+            Bvh::split(geometry, centroids, triangles, start, parallel)
+        };
+        let children = if parallel && count > BVH_PARALLEL_SIZE {
+            rayon::join(|| build(left, start), || build(right, start + middle))
+        } else {
+            (build(left, start), build(right, start + middle))
+        };
+        BvhBuild::Inner {
+            bounds,
+            children: Box::new(children),
+        }
+    }
+
+    /// Find the nearest triangle hit by the given object space `ray`.
+    ///
+    /// Returns the vertex indices of the triangle along with its intersection. Of hits at the same
+    /// distance, the triangle listed first in the geometry wins.
+    fn intersect(&self, geometry: &Geometry, ray: &Ray) -> Option<([u32; 3], TriangleHit)> {
+        let mut nearest: Option<(u32, TriangleHit)> = None;
+        let mut stack = [0u32; 64];
+        let mut len = usize::from(!self.nodes.is_empty());
+        while len > 0 {
+            len -= 1;
+            let index = stack[len];
+            let node = &self.nodes[index as usize];
+            let nearest_distance = nearest.map_or(f64::INFINITY, |(_, (d, ..))| d);
+            match node.bounds.distance(ray) {
+                Some(distance) if distance <= nearest_distance => {}
+                _ => continue,
+            }
+
+            if node.count == 0 {
+                stack[len] = node.offset;
+                stack[len + 1] = index + 1;
+                len += 2;
+                continue;
+            }
+            let (start, end) = (node.offset as usize, (node.offset + node.count) as usize);
+            for &t in &self.triangles[start..end] {
+                let hit = match intersect_triangle(
+                    &geometry.positions(geometry.triangles[t as usize]),
+                    ray,
+                ) {
+                    Some(hit) if hit.0.is_finite() => hit,
+                    _ => continue,
+                };
+                let closer = match nearest {
+                    Some((n, (d, ..))) => hit.0 < d || (hit.0 == d && t < n),
+                    None => true,
+                };
+                if closer {
+                    nearest = Some((t, hit));
+                }
+            }
+        }
+        nearest.map(|(t, hit)| (geometry.triangles[t as usize], hit))
+    }
+}
+
+impl BvhBuild {
+    /// Append this subtree to the given depth first list of nodes.
+    fn flatten(self, nodes: &mut Vec<BvhNode>) {
+        match self {
+            BvhBuild::Leaf {
+                bounds,
+                start,
+                count,
+            } => nodes.push(BvhNode {
+                bounds,
+                offset: start as u32,
+                count: count as u32,
+            }),
+            BvhBuild::Inner { bounds, children } => {
+                let index = nodes.len();
+                nodes.push(BvhNode {
+                    bounds,
+                    offset: 0,
+                    count: 0,
+                });
+                let (left, right) = *children;
+                left.flatten(nodes);
+                nodes[index].offset = nodes.len() as u32;
+                right.flatten(nodes);
+            }
+        }
+    }
+}
+
+/// A triangle mesh.
+///
+/// Triangles are stored in object space, and placed in the world by the mesh transform. Instances
+/// of a mesh with a different transform share the same triangles.
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    /// The mesh vertices and triangles, in object space.
+    geometry: Arc<Geometry>,
+
+    /// The bounds of this mesh in object space.
+    bounds: Aabb,
+
+    /// Transform from object space to world space.
+    transform: Transform,
+}
+
+impl Mesh {
+    /// Construct a new mesh from object space vertices, with an identity transform.
+    ///
+    /// Vertices with the exact same position, normal and texture coordinates are welded into a
+    /// single vertex, unreferenced vertices are dropped.
+    pub fn new(
+        positions: Vec<Vector>,
+        normals: Vec<Vector>,
+        texcoords: Vec<(f32, f32)>,
+        indices: Vec<u32>,
+    ) -> Self {
+        let bits = |v: Vector| (v.0.to_bits(), v.1.to_bits(), v.2.to_bits());
+        let mut geometry = Geometry::default();
+        let mut welded = HashMap::new();
+        let mut vertex = |i: u32| {
+            let i = i as usize;
+            let position = positions[i];
+            let normal = if normals.is_empty() {
+                None
+            } else {
+                Some(normals[i])
+            };
+            let texcoord = if texcoords.is_empty() {
+                None
+            } else {
+                Some(texcoords[i])
+            };
+            let key = (
+                bits(position),
+                normal.map(bits),
+                texcoord.map(|(u, v)| (u.to_bits(), v.to_bits())),
+            );
+            *welded.entry(key).or_insert_with(|| {
+                geometry.positions.push(position);
+                geometry.normals.extend(normal);
+                geometry.texcoords.extend(texcoord);
+                (geometry.positions.len() - 1) as u32
+            })
+        };
+        let triangles = indices
+            .chunks(3)
+            .map(|i| [vertex(i[0]), vertex(i[1]), vertex(i[2])])
+            .collect();
+        geometry.triangles = triangles;
+        geometry.bvh = Bvh::build(&geometry, true);
+
+        // Find the bounds
+        let first = geometry
+            .positions
+            .first()
+            .copied()
+            .unwrap_or_else(Vector::identity);
+        let bounds = geometry
+            .positions
+            .iter()
+            .fold(Aabb::new(first, first), |b, &p| {
+                Aabb::new(b.min.min_components(p), b.max.max_components(p))
+            });
+
+        Self {
+            geometry: Arc::new(geometry),
+            bounds,
+            transform: Transform::default(),
+        }
+    }
+
+    /// Get an instance of this mesh placed with the given `transform`, sharing its triangles.
+    pub fn with_transform(&self, transform: Transform) -> Mesh {
+        Mesh {
+            geometry: self.geometry.clone(),
+            bounds: self.bounds.clone(),
+            transform,
+        }
+    }
+
+    /// Get the number of triangles in this mesh.
+    pub fn triangle_count(&self) -> usize {
+        self.geometry.triangles.len()
+    }
+
+    /// Get an instance of this mesh with flat face normals, dropping any vertex normals.
+    pub fn flat(&self) -> Mesh {
+        let triangles = self
+            .geometry
+            .triangles()
+            .map(|t| Triangle { normals: None, ..t })
+            .collect();
+        Mesh::from_triangles(triangles, self.transform)
+    }
+
+    /// Get an instance of this mesh with smooth interpolated vertex normals.
+    ///
+    /// Triangles without vertex normals get the area weighted average of the face normals around
+    /// each vertex position. Vertex normals already present are kept.
+    pub fn smooth(&self) -> Mesh {
+        let key = |p: Vector| (p.0.to_bits(), p.1.to_bits(), p.2.to_bits());
+
+        // Sum the face normals around each vertex, their length weighs them by triangle area
+        let mut sums: HashMap<(u64, u64, u64), Vector> = HashMap::new();
+        for t in self.geometry.triangles() {
+            let [v0, v1, v2] = t.positions;
+            let face = (v1 - v0).cross(v2 - v0);
+            for p in t.positions.iter() {
+                *sums.entry(key(*p)).or_insert_with(Vector::identity) += face;
+            }
+        }
+
+        let triangles = self
+            .geometry
+            .triangles()
+            .map(|t| Triangle {
+                normals: t.normals.or_else(|| {
+                    let normal = |i: usize| sums[&key(t.positions[i])].normalize();
+                    Some([normal(0), normal(1), normal(2)])
+                }),
+                ..t
+            })
+            .collect();
+        Mesh::from_triangles(triangles, self.transform)
+    }
+
+    /// Get the bounds of this mesh in world space.
+    pub fn bounds(&self) -> Aabb {
+        self.transform.aabb_to_world(&self.bounds)
+    }
+
+    /// Get the distance from the given world space `center` to the farthest vertex of this mesh.
+    pub fn radius_around(&self, center: Vector) -> f64 {
+        let center = self.transform.point_to_object(center);
+        let radius = self
+            .geometry
+            .positions
+            .iter()
+            .map(|&p| (p - center).magnitude_squared())
+            .fold(0.0, f64::max)
+            .sqrt();
+        self.transform.distance_to_world(radius)
+    }
+
+    /// Move this mesh by the given `offset`.
+    pub fn translate(&mut self, offset: Vector) {
+        self.transform.translation += offset;
+    }
+
+    /// Find the triangle the given world space `point` is on.
+    ///
+    /// Returns the triangle along with the point in object space.
+    fn triangle_at(&self, point: Vector) -> Option<(Triangle, Vector)> {
+        let point = self.transform.point_to_object(point);
+        self.geometry
+            .triangles
+            .iter()
+            .find(|&&t| {
+                Triangle::new(self.geometry.positions(t), None, None)
+                    .barycentric(point)
+                    .is_some()
+            })
+            .map(|&t| (self.geometry.triangle(t), point))
+    }
+
+    /// Load meshes from the model file at the given path, placed with the given `transform`.
+    ///
+    /// The loader is picked based on the file extension, `.obj`, `.ply` and `.stl` files are
+    /// supported.
+    pub fn load<P: AsRef<Path>>(
         path: P,
-        offset: Vector,
-        scale: f64,
-    ) -> Result<Vec<Mesh>, String> {
-        // Load the obj file
-        let models = match tobj::load_obj(path.as_ref()) {
-            Ok((models, _)) => models,
-            Err(err) => return Err(format!("Failed to load obj file: {}", err)),
+        transform: Transform,
+        verbosity: Verbosity,
+    ) -> Result<Vec<Mesh>, RaytraceError> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let meshes = match extension.as_deref() {
+            Some("obj") => Mesh::load_obj(path, transform, verbosity)?,
+            Some("ply") => Mesh::load_ply(path, transform, verbosity)?,
+            Some("stl") => Mesh::load_stl(path, transform, verbosity)?,
+            _ => {
+                return Err(RaytraceError::ModelFormat {
+                    path: path.to_path_buf(),
+                })
+            }
         };
 
-        Ok(models
-            .into_iter()
-            .map(|m| {
-                println!("Loading model {}...", m.name);
-                let mesh = m.mesh;
-
-                println!("{} has {} triangles", m.name, mesh.indices.len() / 3);
-                let positions = mesh
-                    .positions
-                    .chunks(3)
-                    .map(|p| Vector(p[0] as f64, p[1] as f64, p[2] as f64) * scale + offset)
-                    .collect();
-                let normals = mesh
-                    .normals
-                    .chunks(3)
-                    .map(|p| Vector(p[0] as f64, p[1] as f64, p[2] as f64))
-                    .collect();
-                // let texcoords = mesh
-                //     .texcoords
-                //     .chunks(2)
-                //     .map(|i| Point::new(i[0], i[1]))
-                //     .collect();
-                Mesh::new(positions, normals, mesh.indices)
+        let triangles: usize = meshes.iter().map(Mesh::triangle_count).sum();
+        if triangles > TRIANGLE_WARNING && verbosity.is_normal() {
+            eprintln!(
+                "Warning: model {} has {} triangles, rendering may be slow, set max_triangles to decimate it",
+                path.display(),
+                triangles,
+            );
+        }
+        Ok(meshes)
+    }
+
+    /// Get a simplified instance of this mesh with at most `max_triangles` triangles.
+    ///
+    /// Uses vertex clustering, vertices are snapped to the average position of the grid cell they
+    /// are in and collapsed triangles are dropped. The grid is made coarser until the budget is
+    /// met. Returns the mesh as is if it is within budget already.
+    pub fn decimate(&self, max_triangles: usize) -> Mesh {
+        if self.triangle_count() <= max_triangles {
+            return self.clone();
+        }
+
+        let extent = self.bounds.max - self.bounds.min;
+        let size = extent.0.max(extent.1).max(extent.2).max(EPSILON);
+        let mut resolution = ((max_triangles as f64 / 2.0).sqrt() as usize).max(1);
+        loop {
+            let triangles = self.clustered(size / resolution as f64);
+            if triangles.len() <= max_triangles || resolution == 1 {
+                return Mesh::from_triangles(triangles, self.transform);
+            }
+            resolution = resolution * 3 / 4;
+        }
+    }
+
+    /// Snap triangle vertices to the average position in their grid cell of the given size.
+    ///
+    /// Triangles collapsing to a line or point are dropped.
+    fn clustered(&self, cell: f64) -> Vec<Triangle> {
+        let key = |p: Vector| {
+            let p = (p - self.bounds.min) / cell;
+            (p.0 as i64, p.1 as i64, p.2 as i64)
+        };
+
+        // Average the vertex positions in each cell
+        let mut cells: HashMap<(i64, i64, i64), (Vector, f64)> = HashMap::new();
+        for p in self.geometry.positions.iter() {
+            let entry = cells.entry(key(*p)).or_insert((Vector::identity(), 0.0));
+            entry.0 += *p;
+            entry.1 += 1.0;
+        }
+
+        self.geometry
+            .triangles()
+            .filter_map(|mut t| {
+                let keys = [
+                    key(t.positions[0]),
+                    key(t.positions[1]),
+                    key(t.positions[2]),
+                ];
+                if keys[0] == keys[1] || keys[1] == keys[2] || keys[0] == keys[2] {
+                    return None;
+                }
+                for (position, key) in t.positions.iter_mut().zip(keys.iter()) {
+                    let (sum, count) = cells[key];
+                    *position = sum / count;
+                }
+                Some(t)
             })
+            .collect()
+    }
+
+    /// Construct a mesh from object space triangles, placed with the given `transform`.
+    ///
+    /// Shared vertices are welded again. Vertex normals and texture coordinates are only kept if
+    /// all triangles have them.
+    fn from_triangles(triangles: Vec<Triangle>, transform: Transform) -> Mesh {
+        let positions = triangles.iter().flat_map(|t| t.positions).collect();
+        let normals = triangles
+            .iter()
+            .map(|t| t.normals)
+            .collect::<Option<Vec<_>>>()
+            .map(|n| n.concat())
+            .unwrap_or_default();
+        let texcoords = triangles
+            .iter()
+            .map(|t| t.texcoords)
+            .collect::<Option<Vec<_>>>()
+            .map(|t| t.concat())
+            .unwrap_or_default();
+        let indices = (0..triangles.len() as u32 * 3).collect();
+        Mesh::new(positions, normals, texcoords, indices).with_transform(transform)
+    }
+
+    /// Load a mesh from an .obj file at the given path, placed with the given `transform`.
+    pub fn load_obj<P: AsRef<Path>>(
+        path: P,
+        transform: Transform,
+        verbosity: Verbosity,
+    ) -> Result<Vec<Mesh>, RaytraceError> {
+        // Load the obj file
+        let (models, _) =
+            tobj::load_obj(path.as_ref()).map_err(|source| RaytraceError::ModelLoad {
+                path: path.as_ref().to_path_buf(),
+                source,
+            })?;
+
+        // Convert the models in parallel
+        Ok(models
+            .into_par_iter()
+            .map(|m| Mesh::from_obj(m, transform, verbosity))
             .collect())
     }
-}
 
-impl Intersectable for Mesh {
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector)> {
-        // The ray must intersect the mesh bounding box
-        if !self.bounds.intersects(ray) {
-            return None;
+    /// Load a mesh from a .ply file at the given path, placed with the given `transform`.
+    pub fn load_ply<P: AsRef<Path>>(
+        path: P,
+        transform: Transform,
+        verbosity: Verbosity,
+    ) -> Result<Vec<Mesh>, RaytraceError> {
+        let timer = Timer::new();
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|source| RaytraceError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let ply =
+            ply::read(&mut BufReader::new(file)).map_err(|message| RaytraceError::ModelParse {
+                path: path.to_path_buf(),
+                message,
+            })?;
+
+        let positions = ply
+            .positions
+            .chunks(3)
+            .map(|p| Vector(p[0], p[1], p[2]))
+            .collect();
+        let normals = ply
+            .normals
+            .chunks(3)
+            .map(|n| Vector(n[0], n[1], n[2]))
+            .collect();
+        // Flip V, as image rows go top to bottom
+        let texcoords = ply
+            .texcoords
+            .chunks(2)
+            .map(|t| (t[0], 1.0 - t[1]))
+            .collect();
+        let triangles = ply.indices.len() / 3;
+        let mesh = Mesh::new(positions, normals, texcoords, ply.indices).with_transform(transform);
+
+        if verbosity.is_normal() {
+            eprintln!(
+                "Loaded model {} with {} triangles, took {}",
+                path.display(),
+                triangles,
+                timer.took(),
+            );
+        }
+
+        Ok(vec![mesh])
+    }
+
+    /// Load a mesh from a .stl file at the given path, placed with the given `transform`.
+    ///
+    /// STL files have no shared vertex normals, triangles are shaded with their face normal.
+    pub fn load_stl<P: AsRef<Path>>(
+        path: P,
+        transform: Transform,
+        verbosity: Verbosity,
+    ) -> Result<Vec<Mesh>, RaytraceError> {
+        let timer = Timer::new();
+        let path = path.as_ref();
+        let mut file = File::open(path).map_err(|source| RaytraceError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let stl = stl_io::read_stl(&mut file).map_err(|err| RaytraceError::ModelParse {
+            path: path.to_path_buf(),
+            message: err.to_string(),
+        })?;
+        if stl.faces.is_empty() {
+            return Err(RaytraceError::ModelParse {
+                path: path.to_path_buf(),
+                message: "file has no triangles".into(),
+            });
         }
 
-        // Intersect with mesh triangles
-        self.triangles
+        let positions = stl
+            .vertices
             .iter()
-            .filter_map(|t| t.intersect(ray))
-            .min_by(|i1, i2| i1.0.partial_cmp(&i2.0).unwrap())
+            .map(|v| Vector(v[0].into(), v[1].into(), v[2].into()))
+            .collect();
+        let indices = stl
+            .faces
+            .iter()
+            .flat_map(|f| f.vertices.iter().map(|&i| i as u32))
+            .collect();
+        let mesh = Mesh::new(positions, vec![], vec![], indices).with_transform(transform);
+
+        if verbosity.is_normal() {
+            eprintln!(
+                "Loaded model {} with {} triangles, took {}",
+                path.display(),
+                stl.faces.len(),
+                timer.took(),
+            );
+        }
+
+        Ok(vec![mesh])
+    }
+
+    /// Convert a loaded .obj model into a mesh, reports the conversion time.
+    fn from_obj(model: tobj::Model, transform: Transform, verbosity: Verbosity) -> Mesh {
+        let timer = Timer::new();
+        let mesh = model.mesh;
+        let positions = mesh
+            .positions
+            .chunks(3)
+            .map(|p| Vector(p[0] as f64, p[1] as f64, p[2] as f64))
+            .collect();
+        let normals = mesh
+            .normals
+            .chunks(3)
+            .map(|p| Vector(p[0] as f64, p[1] as f64, p[2] as f64))
+            .collect();
+        // Flip V, as image rows go top to bottom
+        let texcoords = mesh
+            .texcoords
+            .chunks(2)
+            .map(|t| (t[0], 1.0 - t[1]))
+            .collect();
+        let triangles = mesh.indices.len() / 3;
+        let mesh = Mesh::new(positions, normals, texcoords, mesh.indices).with_transform(transform);
+
+        if verbosity.is_normal() {
+            eprintln!(
+                "Loaded model {} with {} triangles, took {}",
+                model.name,
+                triangles,
+                timer.took(),
+            );
+        }
+
+        mesh
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of mesh intersection tests done on this thread.
+    pub(crate) static MESH_INTERSECTIONS: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+impl Intersectable for Mesh {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        #[cfg(test)]
+        MESH_INTERSECTIONS.with(|c| c.set(c.get() + 1));
+
+        // Intersect with mesh triangles in object space, normals are unchanged by a uniform scale
+        // Only the nearest hit is completed with its normal and texture coordinates
+        let ray = self.transform.ray_to_object(ray);
+        let geometry = &self.geometry;
+        geometry
+            .bvh
+            .intersect(geometry, &ray)
+            .map(|(t, (distance, uv, front_face))| {
+                let distance = self.transform.distance_to_world(distance);
+                let hit = geometry.triangle(t).hit(distance, uv, front_face);
+                Hit {
+                    texture_derivatives: hit.texture_derivatives.map(|(du, dv)| {
+                        (
+                            self.transform.vector_to_world(du),
+                            self.transform.vector_to_world(dv),
+                        )
+                    }),
+                    ..hit
+                }
+            })
     }
 }
 
 /// A model.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Model {
     /// Path to the model file to load.
     pub path: String,
@@ -327,81 +2220,1010 @@ pub struct Model {
     pub scale: f64,
 
     /// Model mesh.
-    #[serde(default)]
+    ///
+    /// Not (de)serialized, the meshes are loaded from the model file.
+    #[serde(skip)]
     pub meshes: Vec<Mesh>,
 
+    /// Whether to shade with smooth interpolated vertex normals, or flat face normals.
+    ///
+    /// Missing vertex normals are computed if smooth. The normals of the model file are used if
+    /// not set.
+    #[serde(default)]
+    pub smooth: Option<bool>,
+
+    /// Maximum number of triangles, denser models are decimated to stay within it.
+    ///
+    /// The full model is loaded if not set.
+    #[serde(default)]
+    pub max_triangles: Option<usize>,
+
     /// Model material.
-    pub material: Material,
+    pub material: MaterialRef,
+
+    /// Ray types the model is visible to.
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Optional name of the model, to refer to it.
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
 impl Model {
-    /// Load any external resources.
-    pub fn load<P: AsRef<Path>>(&mut self, workdir: P) {
+    /// Load the model meshes from the model file.
+    pub fn load<P: AsRef<Path>>(
+        &mut self,
+        workdir: P,
+        verbosity: Verbosity,
+    ) -> Result<(), RaytraceError> {
         // Determine absolute path for relative model paths
         let mut path = workdir.as_ref().to_path_buf();
         path.push(&self.path);
 
-        match Mesh::load_obj(&path, self.position, self.scale) {
-            Ok(meshes) => self.meshes = meshes,
-            Err(err) => {
-                eprintln!("Failed to load model, ignoring: {}", err);
+        let transform = Transform::new(self.position, self.scale);
+        self.meshes = Mesh::load(&path, transform, verbosity)?;
+
+        // Decimate to the triangle budget, spread over meshes by their triangle count
+        let counts: Vec<usize> = self.meshes.iter().map(Mesh::triangle_count).collect();
+        let triangles: usize = counts.iter().sum();
+        if let Some(max_triangles) = self.max_triangles.filter(|&max| triangles > max) {
+            let timer = Timer::new();
+            self.meshes = self
+                .meshes
+                .par_iter()
+                .zip(triangle_budgets(&counts, max_triangles))
+                .map(|(m, budget)| m.decimate(budget))
+                .filter(|m| m.triangle_count() > 0)
+                .collect();
+            let decimated: usize = self.meshes.iter().map(Mesh::triangle_count).sum();
+            if verbosity.is_normal() {
+                eprintln!(
+                    "Decimated model {} from {} to {} triangles, took {}",
+                    self.path,
+                    triangles,
+                    decimated,
+                    timer.took(),
+                );
+                if decimated > max_triangles {
+                    eprintln!(
+                        "Warning: model {} has {} triangles after decimation, over its max_triangles of {}",
+                        self.path, decimated, max_triangles,
+                    );
+                }
             }
         }
+
+        // Override the normals of the model file
+        match self.smooth {
+            Some(true) => self.meshes = self.meshes.iter().map(Mesh::smooth).collect(),
+            Some(false) => self.meshes = self.meshes.iter().map(Mesh::flat).collect(),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Resolve a relative model path against `dir`, instead of the scene working directory.
+    pub fn rebase<P: AsRef<Path>>(&mut self, dir: P) {
+        self.path = dir.as_ref().join(&self.path).to_string_lossy().into_owned();
+    }
+
+    /// Move this model and its loaded meshes by the given `offset`.
+    pub fn translate(&mut self, offset: Vector) {
+        self.position += offset;
+        self.meshes.iter_mut().for_each(|m| m.translate(offset));
+    }
+
+    /// Get the bounding box enclosing all loaded meshes.
+    ///
+    /// Returns `None` if no meshes are loaded.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.meshes
+            .iter()
+            .map(|m| m.bounds())
+            .fold(None, |acc: Option<Aabb>, b| match acc {
+                Some(acc) => Some(acc.union(&b)),
+                None => Some(b),
+            })
+    }
+
+    /// Get a sphere around the bounding box center, enclosing all mesh vertices.
+    ///
+    /// Returns `None` if no meshes are loaded.
+    pub fn bounding_sphere(&self) -> Option<BoundingSphere> {
+        let bounds = self.bounding_box()?;
+        let center = (bounds.min + bounds.max) * 0.5;
+        let radius = self
+            .meshes
+            .iter()
+            .map(|m| m.radius_around(center))
+            .fold(0.0, f64::max);
+        Some(BoundingSphere::new(center, radius))
+    }
+
+    /// Get the texture coordinates at the given `hit` point.
+    ///
+    /// Finds the triangle the point is on, and interpolates its vertex texture coordinates.
+    fn texture_coords(&self, hit: Vector) -> (f32, f32) {
+        self.meshes
+            .iter()
+            .find_map(|m| m.triangle_at(hit))
+            .and_then(|(t, point)| t.texture_coords(point))
+            .unwrap_or((0.0, 0.0))
     }
 }
 
 impl Intersectable for Model {
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector)> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
         self.meshes
             .iter()
             .filter_map(|t| t.intersect(ray))
-            .min_by(|i1, i2| i1.0.partial_cmp(&i2.0).unwrap())
+            .filter(|h| h.distance.is_finite())
+            .min_by(|h1, h2| h1.distance.total_cmp(&h2.distance))
     }
 }
 
-/// Defines a bounding box.
-#[derive(Clone, Debug, Deserialize)]
-struct Bounds {
+/// Spread a budget of `max_triangles` over meshes with the given triangle `counts`.
+///
+/// Each mesh gets a share by its triangle count, but at least one triangle so small meshes are not
+/// dropped for rounding down. Many small meshes may therefore exceed the budget together.
+fn triangle_budgets(counts: &[usize], max_triangles: usize) -> Vec<usize> {
+    let total: usize = counts.iter().sum();
+    counts
+        .iter()
+        .map(|count| (max_triangles * count / total.max(1)).max(1))
+        .collect()
+}
+
+/// An axis aligned bounding box.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Aabb {
     pub min: Vector,
     pub max: Vector,
 }
 
-impl Bounds {
-    /// Check whether the given ray intersects with this bounding box.
-    pub fn intersects(&self, ray: &Ray) -> bool {
-        let mut tmin = (self.min.0 - ray.origin.0) / ray.direction.0;
-        let mut tmax = (self.max.0 - ray.origin.0) / ray.direction.0;
+impl Aabb {
+    /// Construct a new bounding box from the given corners.
+    pub fn new(min: Vector, max: Vector) -> Self {
+        Self { min, max }
+    }
+
+    /// Get the smallest bounding box enclosing both this and the `other` box.
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(
+            self.min.min_components(other.min),
+            self.max.max_components(other.max),
+        )
+    }
+
+    /// Check whether the given ray hits this bounding box.
+    ///
+    /// Uses the slab method, a box that is fully behind the ray origin is not hit.
+    pub fn hit(&self, ray: &Ray) -> bool {
+        self.distance(ray).is_some()
+    }
+
+    /// Get the distance along the given ray to where it enters this bounding box.
+    ///
+    /// Returns zero if the ray origin is inside the box, and `None` if the box is not hit.
+    pub fn distance(&self, ray: &Ray) -> Option<f64> {
+        let slabs = [
+            (self.min.0, self.max.0, ray.origin.0, ray.direction.0),
+            (self.min.1, self.max.1, ray.origin.1, ray.direction.1),
+            (self.min.2, self.max.2, ray.origin.2, ray.direction.2),
+        ];
+
+        let mut tmin = f64::NEG_INFINITY;
+        let mut tmax = f64::INFINITY;
+        for (min, max, origin, direction) in slabs.iter() {
+            let mut t0 = (min - origin) / direction;
+            let mut t1 = (max - origin) / direction;
+            if t0 > t1 {
+                mem::swap(&mut t0, &mut t1);
+            }
 
-        if tmin > tmax {
-            mem::swap(&mut tmin, &mut tmax);
+            // NaN slabs (ray origin on slab, parallel ray) are ignored by min/max
+            tmin = tmin.max(t0);
+            tmax = tmax.min(t1);
+            if tmin > tmax {
+                return None;
+            }
         }
 
-        let mut tymin = (self.min.1 - ray.origin.1) / ray.direction.1;
-        let mut tymax = (self.max.1 - ray.origin.1) / ray.direction.1;
+        if tmax >= 0.0 {
+            Some(tmin.max(0.0))
+        } else {
+            None
+        }
+    }
+}
+
+/// A bounding sphere.
+#[derive(Clone, Debug)]
+pub struct BoundingSphere {
+    pub center: Vector,
+    pub radius: f64,
+}
 
-        if tymin > tymax {
-            mem::swap(&mut tymin, &mut tymax);
+impl BoundingSphere {
+    /// Construct a new bounding sphere.
+    pub fn new(center: Vector, radius: f64) -> Self {
+        Self { center, radius }
+    }
+
+    /// Get the smallest sphere enclosing both this and the `other` sphere.
+    pub fn union(&self, other: &BoundingSphere) -> BoundingSphere {
+        let offset = other.center - self.center;
+        let distance = offset.magnitude();
+        if distance + other.radius <= self.radius {
+            return self.clone();
+        }
+        if distance + self.radius <= other.radius {
+            return other.clone();
         }
+        let radius = (distance + self.radius + other.radius) * 0.5;
+        BoundingSphere::new(
+            self.center + offset * ((radius - self.radius) / distance),
+            radius,
+        )
+    }
+
+    /// Check whether the given ray hits this bounding sphere.
+    ///
+    /// A sphere that is fully behind the ray origin is not hit.
+    pub fn hit(&self, ray: &Ray) -> bool {
+        let to_center = self.center - ray.origin;
+        let radius_squared = self.radius * self.radius;
 
-        if (tmin > tymax) || (tymin > tmax) {
+        // Origin inside sphere always hits
+        if to_center.magnitude_squared() <= radius_squared {
+            return true;
+        }
+
+        // Sphere behind origin, or ray passing by
+        let t = to_center.dot(ray.direction) / ray.direction.magnitude_squared();
+        if t < 0.0 {
             return false;
         }
+        (to_center - ray.direction * t).magnitude_squared() <= radius_squared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cylinder_side_hit() {
+        let Hit {
+            distance, normal, ..
+        } = cylinder(true)
+            .intersect(&Ray::new(Vector(0.0, 1.0, 0.0), Vector(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert_almost_equal(distance, 4.0);
+        assert_vector_almost_equal(normal, Vector(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_cylinder_side_miss_above_height() {
+        let ray = Ray::new(Vector(0.0, 3.0, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(cylinder(true).intersect(&ray).is_none());
+    }
+
+    #[test]
+    fn test_cylinder_cap_hit() {
+        let Hit {
+            distance, normal, ..
+        } = cylinder(true)
+            .intersect(&Ray::new(Vector(0.5, 5.0, -5.0), Vector(0.0, -1.0, 0.0)))
+            .unwrap();
+        assert_almost_equal(distance, 3.0);
+        assert_vector_almost_equal(normal, Vector(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn test_cylinder_uncapped_passes_through() {
+        let ray = Ray::new(Vector(0.5, 5.0, -5.0), Vector(0.0, -1.0, 0.0));
+        assert!(cylinder(false).intersect(&ray).is_none());
+    }
+
+    #[test]
+    fn test_sphere_front_face() {
+        let sphere = Sphere {
+            center: Vector(0.0, 0.0, -5.0),
+            radius: 2.0,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
+        };
+
+        // From outside the ray hits the front face, from inside the back face
+        let outside = sphere
+            .intersect(&Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert_almost_equal(outside.distance, 3.0);
+        assert!(outside.front_face);
+        let inside = sphere
+            .intersect(&Ray::new(Vector(0.0, 0.0, -5.0), Vector(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert_almost_equal(inside.distance, 2.0);
+        assert!(!inside.front_face);
+    }
+
+    #[test]
+    fn test_sphere_texture_coords_equator() {
+        let sphere = Entity::Sphere(Sphere {
+            center: Vector(0.0, 0.0, -5.0),
+            radius: 2.0,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
+        });
+        let (u, v) = sphere.texture_coords(Vector(2.0, 0.0, -5.0)).unwrap();
+        assert_almost_equal(u as f64, 0.5);
+        assert_almost_equal(v as f64, 0.5);
+    }
+
+    #[test]
+    fn test_triangle_texture_coords_interpolated() {
+        let triangle = Triangle::new(
+            [
+                Vector(0.0, 0.0, 0.0),
+                Vector(1.0, 0.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+            ],
+            None,
+            Some([(0.0, 1.0), (1.0, 1.0), (0.0, 0.0)]),
+        );
+        let (u, v) = triangle.texture_coords(Vector(0.25, 0.5, 0.0)).unwrap();
+        assert_almost_equal(u as f64, 0.25);
+        assert_almost_equal(v as f64, 0.5);
+
+        // Points off the triangle have no coordinates
+        assert!(triangle.texture_coords(Vector(1.0, 1.0, 0.0)).is_none());
+        assert!(triangle.texture_coords(Vector(0.2, 0.2, 1.0)).is_none());
+    }
+
+    #[test]
+    fn test_triangle_texture_derivatives_mirrored() {
+        // `v` decreases along the second edge, a negative texture coordinate determinant
+        let triangle = Triangle::new(
+            [
+                Vector(0.0, 0.0, 0.0),
+                Vector(1.0, 0.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+            ],
+            None,
+            Some([(0.0, 1.0), (1.0, 1.0), (0.0, 0.0)]),
+        );
+        let (du, dv) = triangle.texture_derivatives().unwrap();
+        assert_vector_almost_equal(du, Vector(1.0, 0.0, 0.0));
+        assert_vector_almost_equal(dv, Vector(0.0, -1.0, 0.0));
+    }
+
+    #[test]
+    fn test_triangle_hit_uv_and_facing() {
+        let triangle = Triangle::new(
+            [
+                Vector(0.0, 0.0, 0.0),
+                Vector(1.0, 0.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+            ],
+            None,
+            Some([(0.0, 1.0), (1.0, 1.0), (0.0, 0.0)]),
+        );
+
+        // Counter clockwise as seen from +z, the front face
+        let front = triangle
+            .intersect(&Ray::new(Vector(0.25, 0.5, 1.0), Vector(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert!(front.front_face);
+        let (u, v) = front.uv.unwrap();
+        assert_almost_equal(u as f64, 0.25);
+        assert_almost_equal(v as f64, 0.5);
+
+        // The hit carries the texture derivatives, `v` increases downwards in the texture
+        let (du, dv) = front.texture_derivatives.unwrap();
+        assert_vector_almost_equal(du, Vector(1.0, 0.0, 0.0));
+        assert_vector_almost_equal(dv, Vector(0.0, -1.0, 0.0));
+
+        let back = triangle
+            .intersect(&Ray::new(Vector(0.25, 0.5, -1.0), Vector(0.0, 0.0, 1.0)))
+            .unwrap();
+        assert!(!back.front_face);
+    }
+
+    #[test]
+    fn test_load_obj_missing_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/models/missing.obj");
+        match Mesh::load_obj(&path, Transform::default(), Verbosity::Quiet) {
+            Err(RaytraceError::ModelLoad { path: err_path, .. }) => assert_eq!(err_path, path),
+            other => panic!(
+                "expected model load error, got {:?}",
+                other.map(|m| m.len())
+            ),
+        }
+    }
+
+    #[test]
+    fn test_load_obj_parallel_preserves_meshes() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/models/quad.obj");
+        let transform = Transform::new(Vector(1.0, 2.0, 3.0), 2.0);
+        let meshes = Mesh::load_obj(&path, transform, Verbosity::Quiet).unwrap();
+
+        // Convert sequentially for reference
+        let (models, _) = tobj::load_obj(&path).unwrap();
+        let sequential: Vec<Mesh> = models
+            .into_iter()
+            .map(|m| Mesh::from_obj(m, transform, Verbosity::Quiet))
+            .collect();
+
+        let counts = |meshes: &[Mesh]| {
+            meshes
+                .iter()
+                .map(|m| m.triangle_count())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(counts(&meshes), vec![2, 2, 1]);
+        assert_eq!(counts(&meshes), counts(&sequential));
+        for (a, b) in meshes.iter().zip(sequential.iter()) {
+            assert_vector_almost_equal(a.bounds().min, b.bounds().min);
+            assert_vector_almost_equal(a.bounds().max, b.bounds().max);
+        }
+    }
+
+    #[test]
+    fn test_mesh_ignores_nan_triangle() {
+        // Huge coordinates overflow in the intersection test, giving a NaN distance
+        let positions = vec![
+            Vector(0.0, 0.0, -5.0),
+            Vector(1e308, 0.0, -5.0),
+            Vector(0.0, 1e308, -5.0),
+            Vector(-1.0, -1.0, -4.0),
+            Vector(1.0, -1.0, -4.0),
+            Vector(0.0, 1.0, -4.0),
+        ];
+        let degenerate = Triangle::new([positions[0], positions[1], positions[2]], None, None);
+        let ray = Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(degenerate.intersect(&ray).unwrap().distance.is_nan());
+
+        let mesh = Mesh::new(positions, vec![], vec![], vec![0, 1, 2, 3, 4, 5]);
+        let Hit { distance, .. } = mesh.intersect(&ray).unwrap();
+        assert_almost_equal(distance, 4.0);
+    }
+
+    #[test]
+    fn test_mesh_welds_shared_vertices() {
+        // A quad as a triangle soup, repeating the vertices on its diagonal
+        let source = "ply
+format ascii 1.0
+element vertex 6
+property float x
+property float y
+property float z
+element face 2
+property list uchar int vertex_indices
+end_header
+-1 -1 0
+1 -1 0
+1 1 0
+-1 -1 0
+1 1 0
+-1 1 0
+3 0 1 2
+3 3 4 5
+";
+        let path =
+            std::env::temp_dir().join(format!("raytrace-rs-test-soup-{}.ply", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let transform = Transform::new(Vector(0.0, 0.0, -5.0), 1.0);
+        let meshes = Mesh::load_ply(&path, transform, Verbosity::Quiet).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Triangles share the welded vertices, and are still hit
+        let mesh = &meshes[0];
+        assert_eq!(mesh.triangle_count(), 2);
+        assert_eq!(mesh.geometry.positions.len(), 4);
+        assert!(mesh.geometry.positions.len() < 3 * mesh.triangle_count());
+        for x in &[-0.5, 0.5] {
+            let ray = Ray::new(Vector(*x, *x * 0.8, 0.0), Vector(0.0, 0.0, -1.0));
+            assert_almost_equal(mesh.intersect(&ray).unwrap().distance, 5.0);
+        }
+    }
+
+    #[test]
+    fn test_mesh_instances_share_triangles() {
+        let positions = vec![
+            Vector(-1.0, -1.0, 0.0),
+            Vector(1.0, -1.0, 0.0),
+            Vector(0.0, 1.0, 0.0),
+        ];
+        let mesh = Mesh::new(positions, vec![], vec![], vec![0, 1, 2]);
+        let near = mesh.with_transform(Transform::new(Vector(0.0, 0.0, -4.0), 1.0));
+        let far = mesh.with_transform(Transform::new(Vector(3.0, 0.0, -10.0), 2.0));
+        assert!(Arc::ptr_eq(&near.geometry, &far.geometry));
+
+        // Rays through the instance origins hit at the translated world positions
+        let ray = Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        let Hit {
+            distance,
+            normal,
+            barycentric,
+            ..
+        } = near.intersect(&ray).unwrap();
+        assert_almost_equal(distance, 4.0);
+        assert_vector_almost_equal(normal, Vector(0.0, 0.0, 1.0));
+        let (u, v) = barycentric.unwrap();
+        assert_almost_equal(u, 0.25);
+        assert_almost_equal(v, 0.5);
+
+        let ray = Ray::new(Vector(3.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        let Hit {
+            distance,
+            texture_derivatives,
+            ..
+        } = far.intersect(&ray).unwrap();
+        assert_almost_equal(distance, 10.0);
+
+        // Texture derivatives scale along, the barycentric mapping follows the triangle edges
+        let (du, dv) = texture_derivatives.unwrap();
+        assert_vector_almost_equal(du, Vector(4.0, 0.0, 0.0));
+        assert_vector_almost_equal(dv, Vector(2.0, 4.0, 0.0));
+
+        // The far instance is scaled, its corner lies twice as far from its origin
+        let ray = Ray::new(Vector(4.9, -1.9, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(far.intersect(&ray).is_some());
+        assert!(near
+            .intersect(&Ray::new(Vector(1.9, -1.9, 0.0), ray.direction))
+            .is_none());
+    }
+
+    #[test]
+    fn test_bounding_sphere_hit() {
+        let sphere = BoundingSphere::new(Vector(0.0, 0.0, -5.0), 3.0f64.sqrt());
+
+        let origin = Vector(0.0, 0.0, 0.0);
+        assert!(sphere.hit(&Ray::new(origin, Vector(0.0, 0.0, -1.0))));
+        assert!(sphere.hit(&Ray::new(origin, Vector(0.3, 0.0, -1.0).normalize())));
+        assert!(!sphere.hit(&Ray::new(origin, Vector(0.0, 0.0, 1.0))));
+        assert!(!sphere.hit(&Ray::new(origin, Vector(1.0, 0.0, -1.0).normalize())));
+
+        // Rays starting inside always hit
+        assert!(sphere.hit(&Ray::new(Vector(0.0, 0.0, -5.0), Vector(0.0, 0.0, 1.0))));
+    }
+
+    #[test]
+    fn test_bounding_sphere_union() {
+        let a = BoundingSphere::new(Vector(-2.0, 0.0, 0.0), 1.0);
+        let b = BoundingSphere::new(Vector(3.0, 0.0, 0.0), 2.0);
+        let union = a.union(&b);
+        assert_vector_almost_equal(union.center, Vector(1.0, 0.0, 0.0));
+        assert_almost_equal(union.radius, 4.0);
+
+        // A sphere inside the other adds nothing
+        let inner = BoundingSphere::new(Vector(3.5, 0.0, 0.0), 1.0);
+        assert_almost_equal(b.union(&inner).radius, 2.0);
+        assert_almost_equal(inner.union(&b).radius, 2.0);
+    }
+
+    #[test]
+    fn test_model_smooth_override() {
+        // An octahedron with vertex normals pointing away from its center, like a sphere
+        let mut source = String::new();
+        let corners = ["1 0 0", "-1 0 0", "0 1 0", "0 -1 0", "0 0 1", "0 0 -1"];
+        for corner in corners.iter() {
+            source.push_str(&format!("v {}\nvn {}\n", corner, corner));
+        }
+        for face in &[
+            [1, 3, 5],
+            [3, 2, 5],
+            [2, 4, 5],
+            [4, 1, 5],
+            [3, 1, 6],
+            [2, 3, 6],
+            [4, 2, 6],
+            [1, 4, 6],
+        ] {
+            source.push_str(&format!(
+                "f {0}//{0} {1}//{1} {2}//{2}\n",
+                face[0], face[1], face[2]
+            ));
+        }
+        let dir = std::env::temp_dir();
+        let file = format!("raytrace-rs-test-octahedron-{}.obj", std::process::id());
+        std::fs::write(dir.join(&file), source).unwrap();
+
+        let normals = |smooth: &str| {
+            let mut model: Model = serde_yaml::from_str(&format!(
+                "{{ path: {}, position: [0, 0, -5], smooth: {}, material: {{ color: [1, 1, 1], albedo: 1 }} }}",
+                file, smooth
+            ))
+            .unwrap();
+            model.load(&dir, Verbosity::Quiet).unwrap();
+            [(0.1, 0.1), (0.4, 0.2)]
+                .iter()
+                .map(|&(x, y)| {
+                    let ray = Ray::new(Vector(x, y, 0.0), Vector(0.0, 0.0, -1.0));
+                    model.intersect(&ray).unwrap().normal.normalize()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Vertex normals vary over a face, flat normals are the face normal everywhere
+        let face = Vector(1.0, 1.0, 1.0).normalize();
+        let smooth = normals("~");
+        assert!((smooth[0] - smooth[1]).magnitude() > 0.1);
+        for normal in normals("false") {
+            assert_vector_almost_equal(normal, face);
+        }
+
+        // Vertex normals computed from the faces match those of the file
+        let mesh = &Mesh::load(dir.join(&file), Transform::default(), Verbosity::Quiet).unwrap()[0];
+        let ray = Ray::new(Vector(0.4, 0.2, 5.0), Vector(0.0, 0.0, -1.0));
+        assert_vector_almost_equal(
+            mesh.flat()
+                .smooth()
+                .intersect(&ray)
+                .unwrap()
+                .normal
+                .normalize(),
+            mesh.intersect(&ray).unwrap().normal.normalize(),
+        );
+        std::fs::remove_file(dir.join(&file)).unwrap();
+    }
+
+    #[test]
+    fn test_load_stl_cube() {
+        // Two triangles for each side of a unit cube
+        let mut source = String::from("solid cube\n");
+        for axis in 0..3 {
+            for &side in &[0.0, 1.0] {
+                let corner = |a: f64, b: f64| {
+                    let mut p = [0.0; 3];
+                    p[axis] = side;
+                    p[(axis + 1) % 3] = a;
+                    p[(axis + 2) % 3] = b;
+                    format!("vertex {} {} {}\n", p[0], p[1], p[2])
+                };
+                for triangle in &[
+                    [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)],
+                    [(0.0, 0.0), (1.0, 1.0), (0.0, 1.0)],
+                ] {
+                    source.push_str("facet normal 0 0 0\nouter loop\n");
+                    triangle
+                        .iter()
+                        .for_each(|&(a, b)| source.push_str(&corner(a, b)));
+                    source.push_str("endloop\nendfacet\n");
+                }
+            }
+        }
+        source.push_str("endsolid cube\n");
+
+        let path =
+            std::env::temp_dir().join(format!("raytrace-rs-test-cube-{}.stl", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let meshes = Mesh::load(&path, Transform::default(), Verbosity::Quiet).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].triangle_count(), 12);
+        assert_vector_almost_equal(meshes[0].bounds().max, Vector(1.0, 1.0, 1.0));
+
+        // Shaded with face normals
+        let ray = Ray::new(Vector(0.5, 0.5, 5.0), Vector(0.0, 0.0, -1.0));
+        let Hit {
+            distance, normal, ..
+        } = meshes[0].intersect(&ray).unwrap();
+        assert_almost_equal(distance, 4.0);
+        assert_almost_equal(normal.2.abs(), 1.0);
+    }
+
+    #[test]
+    fn test_load_unknown_format() {
+        let result = Mesh::load("model.fbx", Transform::default(), Verbosity::Quiet);
+        assert!(matches!(result, Err(RaytraceError::ModelFormat { .. })));
+    }
+
+    /// Get an `n` by `n` quad grid mesh in the XY plane, with a slight bump.
+    fn grid_mesh(n: u32) -> Mesh {
+        let positions = (0..=n)
+            .flat_map(|x| (0..=n).map(move |y| (x, y)))
+            .map(|(x, y)| {
+                let (x, y) = (f64::from(x) / f64::from(n), f64::from(y) / f64::from(n));
+                Vector(x, y, (x * PI).sin() * 0.1)
+            })
+            .collect();
+        let indices = (0..n)
+            .flat_map(|x| (0..n).map(move |y| (x, y)))
+            .flat_map(|(x, y)| {
+                let i = |x: u32, y: u32| x * (n + 1) + y;
+                vec![
+                    i(x, y),
+                    i(x + 1, y),
+                    i(x + 1, y + 1),
+                    i(x, y),
+                    i(x + 1, y + 1),
+                    i(x, y + 1),
+                ]
+            })
+            .collect();
+        Mesh::new(positions, vec![], vec![], indices)
+    }
+
+    #[test]
+    fn test_decimate_grid_mesh() {
+        let mesh = grid_mesh(64);
+        assert_eq!(mesh.triangle_count(), 8192);
+
+        let decimated = mesh.decimate(500);
+        assert!(decimated.triangle_count() > 0);
+        assert!(
+            decimated.triangle_count() <= 500,
+            "{}",
+            decimated.triangle_count()
+        );
+
+        // The bounding box is roughly intact
+        let (before, after) = (mesh.bounds(), decimated.bounds());
+        assert!((after.min - before.min).magnitude() < 0.1, "{:?}", after);
+        assert!((after.max - before.max).magnitude() < 0.1, "{:?}", after);
+
+        // A mesh within budget is unchanged
+        assert!(Arc::ptr_eq(&mesh.decimate(10_000).geometry, &mesh.geometry));
+    }
+
+    #[test]
+    fn test_triangle_budgets() {
+        // The budget is spread by triangle count, small meshes keep at least one triangle
+        assert_eq!(triangle_budgets(&[8192, 8], 100), vec![99, 1]);
+        assert_eq!(triangle_budgets(&[300, 100], 200), vec![150, 50]);
+        assert_eq!(triangle_budgets(&[5, 5, 5], 2), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_parallel_bvh_matches_serial() {
+        let mesh = grid_mesh(100);
+        let serial = Bvh::build(&mesh.geometry, false);
+        let parallel = Bvh::build(&mesh.geometry, true);
+        assert!(mesh.triangle_count() > BVH_PARALLEL_SIZE);
+        assert!(serial.nodes.len() > 1);
+        assert_eq!(serial, parallel);
+
+        // Meshes using either tree see the same hits as testing every triangle
+        let with_bvh = |bvh| Mesh {
+            geometry: Arc::new(Geometry {
+                positions: mesh.geometry.positions.clone(),
+                normals: mesh.geometry.normals.clone(),
+                texcoords: mesh.geometry.texcoords.clone(),
+                triangles: mesh.geometry.triangles.clone(),
+                bvh,
+            }),
+            ..mesh.clone()
+        };
+        let (serial, parallel) = (with_bvh(serial), with_bvh(parallel));
+        let triangles: Vec<Triangle> = mesh.geometry.triangles().collect();
+        for x in -2..=22 {
+            for y in -2..=22 {
+                let target = Vector(f64::from(x) / 20.0, f64::from(y) / 20.0, 0.05);
+                let origin = Vector(0.5, 0.5, 1.0);
+                let ray = Ray::new(origin, (target - origin).normalize());
+                let expected = triangles
+                    .iter()
+                    .filter_map(|t| t.intersect(&ray))
+                    .min_by(|a, b| a.distance.total_cmp(&b.distance));
+                for hit in [serial.intersect(&ray), parallel.intersect(&ray)].iter() {
+                    match (hit, &expected) {
+                        (Some(hit), Some(expected)) => {
+                            assert_eq!(hit.distance, expected.distance);
+                            assert_eq!(hit.normal, expected.normal);
+                            assert_eq!(hit.uv, expected.uv);
+                        }
+                        (None, None) => {}
+                        _ => panic!("{:?} != {:?} at {:?}", hit, expected, target),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_torus_tube_hit() {
+        // Facing the ray, the tube is hit at its near side
+        let ray = Ray::new(Vector(1.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        let Hit {
+            distance, normal, ..
+        } = torus(Vector(0.0, 0.0, 1.0)).intersect(&ray).unwrap();
+        assert_almost_equal(distance, 4.75);
+        assert_vector_almost_equal(normal, Vector(0.0, 0.0, 1.0));
+
+        // Side on, the ray hits the outer edge of the ring
+        let ray = Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        let Hit {
+            distance, normal, ..
+        } = torus(Vector(0.0, 1.0, 0.0)).intersect(&ray).unwrap();
+        assert_almost_equal(distance, 3.75);
+        assert_vector_almost_equal(normal, Vector(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_torus_hole_miss() {
+        let ray = Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(torus(Vector(0.0, 0.0, 1.0)).intersect(&ray).is_none());
+        let ray = Ray::new(Vector(0.6, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(torus(Vector(0.0, 0.0, 1.0)).intersect(&ray).is_none());
+    }
+
+    #[test]
+    fn test_rect_hit_inside() {
+        let ray = Ray::new(Vector(0.9, 0.4, 0.0), Vector(0.0, 0.0, -1.0));
+        let hit = rect().intersect(&ray).unwrap();
+        assert_almost_equal(hit.distance, 5.0);
+        assert_vector_almost_equal(hit.normal, Vector(0.0, 0.0, 1.0));
+        assert!(hit.front_face);
+        let (u, v) = hit.uv.unwrap();
+        assert!((u - 0.95).abs() < 1e-6 && (v - 0.1).abs() < 1e-6);
+
+        // The back face is hit too
+        let ray = Ray::new(Vector(0.0, 0.0, -10.0), Vector(0.0, 0.0, 1.0));
+        assert!(!rect().intersect(&ray).unwrap().front_face);
+    }
+
+    #[test]
+    fn test_rect_miss_outside_corner() {
+        let ray = Ray::new(Vector(1.01, 0.51, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(rect().intersect(&ray).is_none());
+
+        // Just outside one extent while inside the other misses as well
+        let ray = Ray::new(Vector(1.01, 0.49, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(rect().intersect(&ray).is_none());
+        let ray = Ray::new(Vector(0.99, 0.49, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(rect().intersect(&ray).is_some());
+    }
+
+    #[test]
+    fn test_difference_hollow_sphere() {
+        let hollow: Entity = serde_yaml::from_str(
+            "
+            type: difference
+            a: { type: sphere, center: [0, 0, -5], radius: 2, material: { color: [1, 1, 1], albedo: 1 } }
+            b: { type: sphere, center: [0, 0, -5], radius: 1, material: { color: [1, 1, 1], albedo: 1 } }
+            ",
+        )
+        .unwrap();
+        let direction = Vector(0.0, 0.0, -1.0);
+
+        // The ray first enters the outer sphere
+        let outer = hollow
+            .intersect(&Ray::new(Vector(0.0, 0.0, 0.0), direction))
+            .unwrap();
+        assert_almost_equal(outer.distance, 3.0);
+        assert_vector_almost_equal(outer.normal, Vector(0.0, 0.0, 1.0));
+        assert!(outer.front_face);
 
-        if tymin > tmin {
-            tmin = tymin;
+        // Continuing, it leaves the solid shell through the inner cavity wall, facing the cavity
+        let wall = hollow
+            .intersect(&Ray::new(Vector(0.0, 0.0, -3.0 - 1e-3), direction))
+            .unwrap();
+        assert_almost_equal(wall.distance, 1.0 - 1e-3);
+        assert_vector_almost_equal(wall.normal, Vector(0.0, 0.0, -1.0));
+        assert!(!wall.front_face);
+
+        // From the center of the cavity, the far wall is hit from the front
+        let far = hollow
+            .intersect(&Ray::new(Vector(0.0, 0.0, -5.0), direction))
+            .unwrap();
+        assert_almost_equal(far.distance, 1.0);
+        assert_vector_almost_equal(far.normal, Vector(0.0, 0.0, 1.0));
+        assert!(far.front_face);
+
+        // Nothing is left when carving out an enclosing entity
+        let empty: Entity = serde_yaml::from_str(
+            "
+            type: difference
+            a: { type: sphere, center: [0, 0, -5], radius: 1, material: { color: [1, 1, 1], albedo: 1 } }
+            b: { type: sphere, center: [0, 0, -5], radius: 2, material: { color: [1, 1, 1], albedo: 1 } }
+            ",
+        )
+        .unwrap();
+        assert!(empty
+            .intersect(&Ray::new(Vector(0.0, 0.0, 0.0), direction))
+            .is_none());
+    }
+
+    #[test]
+    fn test_group_translated_sphere() {
+        let group: Entity = serde_yaml::from_str(
+            "
+            type: group
+            transform: { translation: [5, 0, 0] }
+            children:
+              - { type: sphere, center: [0, 0, 0], radius: 1, material: { color: [1, 1, 1], albedo: 1 } }
+            ",
+        )
+        .unwrap();
+        let ray = Ray::new(Vector(5.0, 0.0, 5.0), Vector(0.0, 0.0, -1.0));
+
+        // The sphere is hit as if it were at (5, 0, 0)
+        let hit = group.intersect(&ray).unwrap();
+        assert_almost_equal(hit.distance, 4.0);
+        assert_vector_almost_equal(hit.normal, Vector(0.0, 0.0, 1.0));
+        assert!(group
+            .intersect(&Ray::new(Vector(0.0, 0.0, 5.0), Vector(0.0, 0.0, -1.0)))
+            .is_none());
+
+        // Hits resolve to the child, along with its transform to world space
+        let (hit, entity, transform) = group.intersect_entity(&ray, RayType::Primary).unwrap();
+        assert_almost_equal(hit.distance, 4.0);
+        assert!(matches!(entity, Entity::Sphere(_)));
+        assert_vector_almost_equal(
+            transform.point_to_object(Vector(5.0, 0.0, 1.0)),
+            Vector(0.0, 0.0, 1.0),
+        );
+
+        let aabb = group.bounding_box().unwrap();
+        assert_vector_almost_equal(aabb.min, Vector(4.0, -1.0, -1.0));
+        assert_vector_almost_equal(aabb.max, Vector(6.0, 1.0, 1.0));
+
+        // A group has no surface of its own, not even when empty
+        let empty: Entity = serde_yaml::from_str("{ type: group, children: [] }").unwrap();
+        for group in [&group, &empty] {
+            assert!(group.material().is_none());
+            assert!(group.texture_coords(Vector(5.0, 0.0, 1.0)).is_none());
         }
+    }
 
-        if tymax < tmax {
-            tmax = tymax;
+    /// A two by one rectangle at `(0, 0, -5)` facing `+Z`.
+    fn rect() -> Rect {
+        Rect {
+            center: Vector(0.0, 0.0, -5.0),
+            normal: Vector(0.0, 0.0, 1.0),
+            u_axis: Vector(1.0, 0.0, 0.0),
+            v_axis: Vector(0.0, 1.0, 0.0),
+            half_width: 1.0,
+            half_height: 0.5,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
         }
+    }
 
-        let mut tzmin = (self.min.2 - ray.origin.2) / ray.direction.2;
-        let mut tzmax = (self.max.2 - ray.origin.2) / ray.direction.2;
+    /// A torus with a major radius of 1 and minor radius of 0.25 at `(0, 0, -5)`.
+    fn torus(axis: Vector) -> Torus {
+        Torus {
+            center: Vector(0.0, 0.0, -5.0),
+            axis,
+            major_radius: 1.0,
+            minor_radius: 0.25,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
+        }
+    }
 
-        if tzmin > tzmax {
-            mem::swap(&mut tzmin, &mut tzmax);
+    /// A unit radius cylinder of height 2, standing up at `(0, 0, -5)`.
+    fn cylinder(capped: bool) -> Cylinder {
+        Cylinder {
+            base: Vector(0.0, 0.0, -5.0),
+            axis: Vector(0.0, 1.0, 0.0),
+            radius: 1.0,
+            height: 2.0,
+            capped,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
         }
+    }
+
+    fn assert_almost_equal(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} and {} are not almost equal", a, b);
+    }
 
-        (tmin <= tzmax) && (tzmin <= tmax)
+    fn assert_vector_almost_equal(a: Vector, b: Vector) {
+        assert!(
+            (a - b).magnitude() < 1e-9,
+            "vectors {:?} and {:?} are not almost equal",
+            a,
+            b
+        );
     }
 }
@@ -0,0 +1,238 @@
+//! Spherical harmonics irradiance probes, for fast approximate indirect diffuse light.
+//!
+//! Incoming light is projected onto the first nine real spherical harmonics (bands `l <= 2`) at
+//! probes on a coarse 3D grid. Irradiance for any surface normal is then evaluated from the
+//! interpolated coefficients, following Ramamoorthi and Hanrahan, "An Efficient Representation
+//! for Irradiance Environment Maps".
+
+use std::f64::consts::PI;
+
+use crate::algebra::Vector;
+use crate::color::{Color, BLACK};
+use crate::geometric::Aabb;
+
+/// Convolution of each band with the clamped cosine lobe, converting radiance to irradiance.
+const BAND_FACTORS: [f64; 3] = [PI, 2.0 * PI / 3.0, PI / 4.0];
+
+/// The band of each of the nine coefficients.
+const COEFFICIENT_BANDS: [usize; 9] = [0, 1, 1, 1, 2, 2, 2, 2, 2];
+
+/// Light arriving at a point from all directions, as second order spherical harmonics.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SphericalHarmonics {
+    coefficients: [Color; 9],
+}
+
+impl SphericalHarmonics {
+    /// Project incoming radiance onto spherical harmonics.
+    ///
+    /// The `radiance` function is sampled in `samples` directions evenly spread over the sphere.
+    pub fn project<F>(samples: u32, mut radiance: F) -> Self
+    where
+        F: FnMut(Vector) -> Color,
+    {
+        let samples = samples.max(1);
+        let weight = (4.0 * PI / f64::from(samples)) as f32;
+        let mut coefficients = [*BLACK; 9];
+        for i in 0..samples {
+            let direction = sphere_direction(i, samples);
+            let color = radiance(direction) * weight;
+            for (coefficient, basis) in coefficients.iter_mut().zip(basis(direction).iter()) {
+                *coefficient = *coefficient + color * *basis as f32;
+            }
+        }
+        Self { coefficients }
+    }
+
+    /// Get the irradiance received by a surface facing the unit `normal`.
+    ///
+    /// Radiance of `1` from all directions gives an irradiance of `pi`.
+    pub fn irradiance(&self, normal: Vector) -> Color {
+        let irradiance = self
+            .coefficients
+            .iter()
+            .zip(basis(normal).iter())
+            .zip(COEFFICIENT_BANDS.iter())
+            .fold(*BLACK, |sum, ((coefficient, basis), band)| {
+                sum + *coefficient * (basis * BAND_FACTORS[*band]) as f32
+            });
+
+        // Ringing of the truncated series may dip below zero opposite bright light
+        irradiance.component_max(*BLACK)
+    }
+
+    /// Linearly interpolate towards `other` by `t`.
+    fn lerp(&self, other: &SphericalHarmonics, t: f32) -> SphericalHarmonics {
+        let mut coefficients = self.coefficients;
+        for (coefficient, other) in coefficients.iter_mut().zip(other.coefficients.iter()) {
+            *coefficient = coefficient.lerp(*other, t);
+        }
+        SphericalHarmonics { coefficients }
+    }
+}
+
+/// A grid of irradiance probes, spread evenly over a bounding box.
+#[derive(Clone, Debug)]
+pub struct ProbeGrid {
+    /// The box spanned by the outer probes.
+    bounds: Aabb,
+
+    /// Number of probes along each axis.
+    resolution: usize,
+
+    /// The probes, ordered by `x`, then `y`, then `z`.
+    probes: Vec<SphericalHarmonics>,
+}
+
+impl ProbeGrid {
+    /// Bake a grid of `resolution` probes along each axis of `bounds`.
+    ///
+    /// The `probe` function is called to bake the probe at each grid point.
+    pub fn bake<F>(bounds: Aabb, resolution: u32, mut probe: F) -> Self
+    where
+        F: FnMut(Vector) -> SphericalHarmonics,
+    {
+        let resolution = resolution.max(1) as usize;
+        let step = |i: usize| {
+            if resolution > 1 {
+                i as f64 / (resolution - 1) as f64
+            } else {
+                0.5
+            }
+        };
+        let size = bounds.max - bounds.min;
+        let mut probes = Vec::with_capacity(resolution.pow(3));
+        for z in 0..resolution {
+            for y in 0..resolution {
+                for x in 0..resolution {
+                    let offset = Vector(size.0 * step(x), size.1 * step(y), size.2 * step(z));
+                    probes.push(probe(bounds.min + offset));
+                }
+            }
+        }
+        Self {
+            bounds,
+            resolution,
+            probes,
+        }
+    }
+
+    /// Get the irradiance at `point` received by a surface facing the unit `normal`.
+    ///
+    /// The probes around the point are interpolated trilinearly, points outside the grid use the
+    /// nearest probes on its boundary.
+    pub fn irradiance(&self, point: Vector, normal: Vector) -> Color {
+        let last = self.resolution - 1;
+        let size = self.bounds.max - self.bounds.min;
+        let cell = |p: f64, min: f64, size: f64| {
+            let t = if size > 0.0 { (p - min) / size } else { 0.0 };
+            let t = t.clamp(0.0, 1.0) * last as f64;
+            let i = (t.floor() as usize).min(last.saturating_sub(1));
+            (i, (t - i as f64) as f32)
+        };
+        let (x, tx) = cell(point.0, self.bounds.min.0, size.0);
+        let (y, ty) = cell(point.1, self.bounds.min.1, size.1);
+        let (z, tz) = cell(point.2, self.bounds.min.2, size.2);
+
+        let probe = |dx: usize, dy: usize, dz: usize| {
+            let index = |i: usize, d: usize| (i + d).min(last);
+            let n = self.resolution;
+            self.probes[index(x, dx) + index(y, dy) * n + index(z, dz) * n * n]
+        };
+        let along_x = |dy, dz| probe(0, dy, dz).lerp(&probe(1, dy, dz), tx);
+        let along_y = |dz| along_x(0, dz).lerp(&along_x(1, dz), ty);
+        along_y(0).lerp(&along_y(1), tz).irradiance(normal)
+    }
+}
+
+/// Get the nine spherical harmonics basis functions for the unit `direction`.
+fn basis(direction: Vector) -> [f64; 9] {
+    let Vector(x, y, z) = direction;
+    [
+        0.282_095,
+        0.488_603 * y,
+        0.488_603 * z,
+        0.488_603 * x,
+        1.092_548 * x * y,
+        1.092_548 * y * z,
+        0.315_392 * (3.0 * z * z - 1.0),
+        1.092_548 * x * z,
+        0.546_274 * (x * x - y * y),
+    ]
+}
+
+/// Get direction `i` of `count` directions spread evenly over the unit sphere.
+///
+/// Directions lie on a Fibonacci spiral, each covering about the same solid angle.
+fn sphere_direction(i: u32, count: u32) -> Vector {
+    let golden_angle = PI * (3.0 - 5f64.sqrt());
+    let z = 1.0 - (2.0 * f64::from(i) + 1.0) / f64::from(count);
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = golden_angle * f64::from(i);
+    Vector(r * phi.cos(), r * phi.sin(), z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assert two colors are equal within the given `tolerance` per channel.
+    fn assert_color_near(a: Color, b: Color, tolerance: f32) {
+        let (a, b) = (a.channels(), b.channels());
+        assert!(
+            (a.0 - b.0).abs() < tolerance
+                && (a.1 - b.1).abs() < tolerance
+                && (a.2 - b.2).abs() < tolerance,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_constant_environment_irradiance() {
+        let radiance = Color::new(0.2, 0.5, 1.0);
+        let sh = SphericalHarmonics::project(256, |_| radiance);
+        let expected = radiance * PI as f32;
+        for normal in &[
+            Vector(0.0, 1.0, 0.0),
+            Vector(0.0, 0.0, -1.0),
+            Vector(1.0, 1.0, 1.0).normalize(),
+        ] {
+            assert_color_near(sh.irradiance(*normal), expected, 0.02);
+        }
+    }
+
+    #[test]
+    fn test_directional_irradiance() {
+        // Light from the upper hemisphere only
+        let sh = SphericalHarmonics::project(1024, |d| {
+            if d.1 > 0.0 {
+                Color::new(1.0, 1.0, 1.0)
+            } else {
+                *BLACK
+            }
+        });
+        let up = sh.irradiance(Vector(0.0, 1.0, 0.0)).luminance();
+        let side = sh.irradiance(Vector(1.0, 0.0, 0.0)).luminance();
+        let down = sh.irradiance(Vector(0.0, -1.0, 0.0)).luminance();
+        assert!((up - PI as f32).abs() < 0.15);
+        assert!((side - PI as f32 / 2.0).abs() < 0.15);
+        assert!(down < 0.15);
+    }
+
+    #[test]
+    fn test_grid_interpolates_probes() {
+        let bounds = Aabb::new(Vector(0.0, 0.0, 0.0), Vector(2.0, 2.0, 2.0));
+        let grid = ProbeGrid::bake(bounds, 2, |p| {
+            let v = p.0 as f32 / 2.0;
+            SphericalHarmonics::project(64, |_| Color::new(v, v, v))
+        });
+        let normal = Vector(0.0, 1.0, 0.0);
+        let at = |x| grid.irradiance(Vector(x, 1.0, 1.0), normal).luminance();
+        assert!(at(0.0) < 0.01);
+        assert!((at(1.0) - PI as f32 / 2.0).abs() < 0.02);
+        assert!((at(2.0) - PI as f32).abs() < 0.02);
+        assert!((at(5.0) - at(2.0)).abs() < 1e-6);
+    }
+}
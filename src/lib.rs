@@ -0,0 +1,91 @@
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate serde_derive;
+
+use std::fs::File;
+use std::path::Path;
+
+pub use crate::error::RaytraceError;
+use crate::render::RenderOptions;
+use crate::scene::Scene;
+use crate::verbosity::Verbosity;
+
+pub mod algebra;
+pub mod animation;
+pub mod background;
+pub mod color;
+pub mod error;
+pub mod geometric;
+pub mod irradiance;
+pub mod light;
+pub mod material;
+pub mod math;
+pub mod ply;
+pub mod render;
+pub mod rng;
+pub mod sampling;
+pub mod scene;
+pub mod verbosity;
+
+/// Load a scene from the YAML scene file at `path`.
+///
+/// Includes and material references are resolved, the scene is validated, and external resources
+/// such as models and textures are loaded relative to the scene file.
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<Scene, RaytraceError> {
+    load_scene_with(path.as_ref(), Verbosity::Quiet)
+}
+
+/// Load a scene from the YAML scene file at `path`, like `load_scene`.
+///
+/// Warnings and status output are shown if the `verbosity` allows it.
+pub fn load_scene_with(path: &Path, verbosity: Verbosity) -> Result<Scene, RaytraceError> {
+    let mut scene = read_scene(path)?;
+    scene.load(path.parent().unwrap_or_else(|| Path::new("")), verbosity);
+    Ok(scene)
+}
+
+/// Check that the YAML scene file at `path` loads, without rendering it.
+///
+/// The scene is loaded like `load_scene`, but models that fail to load are errors instead of
+/// being ignored. Returns all errors found, loading stops at the first error that is not a model.
+pub fn validate_scene<P: AsRef<Path>>(path: P) -> Result<(), Vec<RaytraceError>> {
+    let path = path.as_ref();
+    let mut scene = read_scene(path).map_err(|err| vec![err])?;
+    let errors = scene.load(
+        path.parent().unwrap_or_else(|| Path::new("")),
+        Verbosity::Quiet,
+    );
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Read the YAML scene file at `path`, and resolve and validate it without loading resources.
+fn read_scene(path: &Path) -> Result<Scene, RaytraceError> {
+    let file = File::open(path).map_err(|source| RaytraceError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let mut scene: Scene = serde_yaml::from_reader(file)?;
+    scene
+        .resolve_includes(path)
+        .map_err(RaytraceError::Include)?;
+    scene.validate().map_err(RaytraceError::Validation)?;
+    scene.resolve_materials().map_err(RaytraceError::Material)?;
+    Ok(scene)
+}
+
+/// Render the given scene to raw pixels.
+///
+/// Returns RGB8 pixel data in row major order, `camera.width * camera.height * 3` bytes long.
+/// Scenes built in code must only use inline materials, or resolve their references first.
+pub fn render_scene(scene: &Scene) -> Vec<u8> {
+    let options = RenderOptions {
+        verbosity: Verbosity::Quiet,
+        ..RenderOptions::default()
+    };
+    render::render(scene, &options, None).to_rgb8().into_raw()
+}
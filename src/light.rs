@@ -1,10 +1,10 @@
 use std::f32::consts::PI;
-use std::f64::INFINITY;
 
 use crate::algebra::Vector;
 use crate::color::Color;
+use crate::geometric::{check_direction, check_finite};
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Light {
     /// A directional light.
@@ -12,6 +12,12 @@ pub enum Light {
 
     /// A spherical point light.
     Spherical(SphericalLight),
+
+    /// A rectangular area light.
+    Quad(QuadLight),
+
+    /// A hemispherical sky dome light.
+    Sky(SkyLight),
 }
 
 impl Light {
@@ -20,13 +26,107 @@ impl Light {
         match self {
             Self::Directional(d) => d.color,
             Self::Spherical(s) => s.color,
+            Self::Quad(q) => q.color,
+            Self::Sky(s) => s.color,
+        }
+    }
+
+    /// Check whether this light casts shadows.
+    ///
+    /// Lights that don't cast shadows light hit points without testing for occluders.
+    pub fn cast_shadows(&self) -> bool {
+        match self {
+            Self::Directional(d) => d.cast_shadows,
+            Self::Spherical(s) => s.cast_shadows,
+            Self::Quad(q) => q.cast_shadows,
+            Self::Sky(s) => s.cast_shadows,
+        }
+    }
+
+    /// Validate this light, returns a list of problems.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        match self {
+            Self::Directional(ref d) => {
+                check_direction(&mut problems, "directional light direction", d.direction);
+                if !(0.0..90.0).contains(&d.angular_radius) {
+                    problems.push(format!(
+                        "directional light angular_radius must be from 0 up to 90 degrees, got {}",
+                        d.angular_radius
+                    ));
+                }
+            }
+            Self::Spherical(ref s) => {
+                check_finite(&mut problems, "spherical light position", s.position);
+                if s.radius < 0.0 || !s.radius.is_finite() {
+                    problems.push(format!(
+                        "spherical light radius must not be negative, got {}",
+                        s.radius
+                    ));
+                }
+                if s.shadow_softness < 0.0 || !s.shadow_softness.is_finite() {
+                    problems.push(format!(
+                        "spherical light shadow_softness must not be negative, got {}",
+                        s.shadow_softness
+                    ));
+                }
+            }
+            Self::Quad(ref q) => {
+                check_finite(&mut problems, "quad light corner", q.corner);
+                check_direction(&mut problems, "quad light edge_a", q.edge_a);
+                check_direction(&mut problems, "quad light edge_b", q.edge_b);
+            }
+            Self::Sky(ref s) => check_direction(&mut problems, "sky light up", s.up),
+        }
+        problems
+    }
+
+    /// Check whether this light has an area.
+    ///
+    /// Area lights must be sampled at multiple points for soft shadows.
+    pub fn is_area(&self) -> bool {
+        match self {
+            Self::Directional(ref d) => d.angular_radius > 0.0,
+            Self::Spherical(ref s) => s.shadow_radius() > 0.0,
+            Self::Quad(_) | Self::Sky(_) => true,
+        }
+    }
+
+    /// Sample the light as seen from the given hit point.
+    ///
+    /// Area lights are sampled at a point on their surface selected by `u` and `v` in `[0,1)`,
+    /// directional lights with an angular size at a direction within their cone, other lights
+    /// ignore these.
+    pub fn sample(&self, hit_point: Vector, u: f64, v: f64) -> LightSample {
+        match self {
+            Self::Directional(ref d) if d.angular_radius > 0.0 => LightSample {
+                direction: d.sample_direction(u, v),
+                distance: f64::INFINITY,
+                intensity: d.intensity,
+            },
+            Self::Spherical(ref s) if s.shadow_radius() > 0.0 => {
+                let to_light = s.sample_point(u, v) - hit_point;
+                LightSample {
+                    direction: to_light.normalize(),
+                    distance: to_light.magnitude(),
+                    intensity: self.intensity(hit_point),
+                }
+            }
+            Self::Quad(ref q) => q.sample_from(q.sample_point(u, v), hit_point),
+            _ => LightSample {
+                direction: self.direction_from(hit_point),
+                distance: self.distance(hit_point),
+                intensity: self.intensity(hit_point),
+            },
         }
     }
 
     pub fn direction_from(&self, hit_point: Vector) -> Vector {
         match self {
-            Self::Directional(ref d) => -d.direction,
+            Self::Directional(ref d) => -d.direction.normalize(),
             Self::Spherical(ref s) => (s.position - hit_point).normalize(),
+            Self::Quad(ref q) => q.sample_from(q.center(), hit_point).direction,
+            Self::Sky(ref s) => s.up.normalize(),
         }
     }
 
@@ -34,33 +134,295 @@ impl Light {
         match self {
             Self::Directional(ref d) => d.intensity,
             Self::Spherical(ref s) => {
-                let r2 = (s.position - hit_point).magnitude() as f32;
-                s.intensity / (4.0 * PI * r2)
+                let r2 = (s.position - hit_point).magnitude_squared() as f32;
+                s.falloff.attenuate(s.intensity / (4.0 * PI), r2)
             }
+            Self::Quad(ref q) => q.sample_from(q.center(), hit_point).intensity,
+            Self::Sky(ref s) => s.intensity,
         }
     }
 
     pub fn distance(&self, hit_point: Vector) -> f64 {
         match self {
-            Self::Directional(_) => INFINITY,
+            Self::Directional(_) | Self::Sky(_) => f64::INFINITY,
             // TODO: is norm here correct, use a unit test for testing this
             Self::Spherical(ref s) => (s.position - hit_point).magnitude(),
+            Self::Quad(ref q) => (q.center() - hit_point).magnitude(),
         }
     }
 }
 
+/// A sampled light, as seen from a hit point.
+#[derive(Copy, Clone, Debug)]
+pub struct LightSample {
+    /// Direction from the hit point to the sampled light point.
+    pub direction: Vector,
+
+    /// Distance from the hit point to the sampled light point.
+    pub distance: f64,
+
+    /// Light intensity received at the hit point.
+    pub intensity: f32,
+}
+
 /// A directional light.
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct DirectionalLight {
+    /// Direction the light travels in, of any length.
     pub direction: Vector,
     pub color: Color,
     pub intensity: f32,
+
+    /// Whether this light casts shadows.
+    #[serde(default = "default_cast_shadows")]
+    pub cast_shadows: bool,
+
+    /// Angular radius of the light source in degrees, used for soft shadows.
+    ///
+    /// The sun seen from earth is about `0.27` degrees. A radius of `0` produces hard shadows.
+    #[serde(default)]
+    pub angular_radius: f64,
+}
+
+impl Default for DirectionalLight {
+    /// A white light shining straight down.
+    fn default() -> Self {
+        Self {
+            direction: Vector(0.0, -1.0, 0.0),
+            color: Color::new(1.0, 1.0, 1.0),
+            intensity: 1.0,
+            cast_shadows: default_cast_shadows(),
+            angular_radius: 0.0,
+        }
+    }
+}
+
+impl From<DirectionalLight> for Light {
+    fn from(light: DirectionalLight) -> Self {
+        Self::Directional(light)
+    }
+}
+
+impl DirectionalLight {
+    /// Get a direction towards the light, within the cone of its angular radius.
+    ///
+    /// Uniformly distributed `u` and `v` in `[0,1)` give directions uniformly distributed over the
+    /// solid angle of the cone.
+    pub fn sample_direction(&self, u: f64, v: f64) -> Vector {
+        let axis = -self.direction.normalize();
+        let (tangent, bitangent) = axis.orthonormal_basis();
+        let cos_theta = 1.0 - u * (1.0 - self.angular_radius.to_radians().cos());
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * v;
+        (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + axis * cos_theta)
+            .normalize()
+    }
 }
 
 /// A spherical point light.
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct SphericalLight {
     pub position: Vector,
     pub color: Color,
     pub intensity: f32,
+
+    /// Light sphere radius, used for soft shadows.
+    ///
+    /// A radius of `0` produces hard shadows.
+    #[serde(default)]
+    pub radius: f64,
+
+    /// Multiplier for the light radius when sampling shadows.
+    ///
+    /// Larger values give softer shadows, `0` gives hard shadows.
+    #[serde(default = "default_shadow_softness")]
+    pub shadow_softness: f64,
+
+    /// Whether this light casts shadows.
+    #[serde(default = "default_cast_shadows")]
+    pub cast_shadows: bool,
+
+    /// How the light intensity falls off with distance.
+    #[serde(default)]
+    pub falloff: Falloff,
+}
+
+impl SphericalLight {
+    /// Get a point on the light sphere surface.
+    ///
+    /// Uniformly distributed `u` and `v` in `[0,1)` give uniformly distributed points.
+    pub fn sample_point(&self, u: f64, v: f64) -> Vector {
+        let z = 1.0 - 2.0 * u;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * v;
+        self.position + Vector(r * phi.cos(), r * phi.sin(), z) * self.shadow_radius()
+    }
+
+    /// Get the radius of the sphere shadows are sampled on, scaled by the shadow softness.
+    pub fn shadow_radius(&self) -> f64 {
+        self.radius * self.shadow_softness
+    }
+}
+
+/// How light intensity falls off with the distance from a point light.
+///
+/// All modes give the same intensity at a distance of one unit.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Falloff {
+    /// Physically correct, falling off with the squared distance.
+    #[default]
+    InverseSquare,
+
+    /// Falling off with the distance, lighting far away surfaces more.
+    Linear,
+
+    /// No falloff, lighting all surfaces equally.
+    None,
+}
+
+impl Falloff {
+    /// Attenuate the `intensity` at unit distance, for the given squared distance `r2`.
+    pub fn attenuate(self, intensity: f32, r2: f32) -> f32 {
+        match self {
+            Falloff::InverseSquare => intensity / r2,
+            Falloff::Linear => intensity / r2.sqrt(),
+            Falloff::None => intensity,
+        }
+    }
+}
+
+/// A rectangular area light.
+///
+/// The quad is spanned by two edges from a corner, and only emits light to the side its normal
+/// `edge_a x edge_b` points to.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct QuadLight {
+    pub corner: Vector,
+    pub edge_a: Vector,
+    pub edge_b: Vector,
+    pub color: Color,
+
+    /// Emitted intensity per unit area.
+    pub intensity: f32,
+
+    /// Whether this light casts shadows.
+    #[serde(default = "default_cast_shadows")]
+    pub cast_shadows: bool,
+}
+
+impl QuadLight {
+    /// Get the center point of the quad.
+    pub fn center(&self) -> Vector {
+        self.corner + self.edge_a * 0.5 + self.edge_b * 0.5
+    }
+
+    /// Get a point on the quad surface, `u` and `v` in `[0,1)` are along the edges.
+    pub fn sample_point(&self, u: f64, v: f64) -> Vector {
+        self.corner + self.edge_a * u + self.edge_b * v
+    }
+
+    /// Sample the light from the given `point` on the quad, as seen from the hit point.
+    ///
+    /// The intensity is weighted by the solid angle the quad covers, with a cosine falloff on the
+    /// quad normal.
+    fn sample_from(&self, point: Vector, hit_point: Vector) -> LightSample {
+        let to_light = point - hit_point;
+        let distance = to_light.magnitude();
+        let direction = to_light.normalize();
+
+        let normal = self.edge_a.cross(self.edge_b);
+        let area = normal.magnitude();
+        let cos_light = normal.normalize().dot(-direction).max(0.0);
+        let solid_angle = area * cos_light / (distance * distance);
+
+        LightSample {
+            direction,
+            distance,
+            intensity: self.intensity * solid_angle as f32,
+        }
+    }
+}
+
+/// A hemispherical sky dome light.
+///
+/// Lights surfaces from all directions of the hemisphere around `up`, in proportion to how much of
+/// it is visible.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct SkyLight {
+    pub color: Color,
+
+    /// Radiance of the sky, a fully visible sky lights white surfaces with this intensity.
+    pub intensity: f32,
+
+    /// Direction towards the top of the sky dome.
+    #[serde(default = "default_up")]
+    pub up: Vector,
+
+    /// Whether this light casts shadows.
+    #[serde(default = "default_cast_shadows")]
+    pub cast_shadows: bool,
+}
+
+/// Helper function for serde defaults.
+const fn default_up() -> Vector {
+    Vector(0.0, 1.0, 0.0)
+}
+
+/// Helper function for serde defaults.
+const fn default_cast_shadows() -> bool {
+    true
+}
+
+/// Helper function for serde defaults.
+const fn default_shadow_softness() -> f64 {
+    1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inverse_square_falloff() {
+        let light: Light = serde_yaml::from_str(
+            "{ type: spherical, position: [0, 0, 0], color: [1, 1, 1], intensity: 100 }",
+        )
+        .unwrap();
+
+        // Doubling the distance quarters the received intensity
+        let near = light.intensity(Vector(0.0, 0.0, -2.0));
+        let far = light.intensity(Vector(0.0, 0.0, -4.0));
+        assert!((near - 100.0 / (4.0 * PI * 4.0)).abs() < 1e-5);
+        assert!((far * 4.0 - near).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_linear_and_no_falloff() {
+        let light = |falloff| {
+            serde_yaml::from_str::<Light>(&format!(
+                "{{ type: spherical, position: [0, 0, 0], color: [1, 1, 1], intensity: 1, falloff: {} }}",
+                falloff
+            ))
+            .unwrap()
+        };
+        let (near, far) = (Vector(2.0, 0.0, 0.0), Vector(4.0, 0.0, 0.0));
+
+        let linear = light("linear");
+        assert!((linear.intensity(far) * 2.0 - linear.intensity(near)).abs() < 1e-6);
+        let none = light("none");
+        assert_eq!(none.intensity(far), none.intensity(near));
+    }
+
+    #[test]
+    fn test_directional_light_direction_normalized() {
+        let light = Light::from(DirectionalLight {
+            direction: Vector(0.0, -10.0, 0.0),
+            ..DirectionalLight::default()
+        });
+        assert_eq!(
+            light.direction_from(Vector(0.0, 0.0, 0.0)),
+            Vector(0.0, 1.0, 0.0)
+        );
+    }
 }
@@ -1,37 +1,390 @@
 #[macro_use]
 extern crate clap;
-#[macro_use]
-extern crate lazy_static;
-#[macro_use]
-extern crate serde_derive;
 
-use std::fs::File;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter};
 use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::mpsc::channel;
-use std::thread;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use clap::{App, Arg};
+use clap::{App, Arg, ErrorKind};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{ColorType, DynamicImage, GenericImageView, ImageEncoder, ImageResult};
 use notify::{DebouncedEvent, RecursiveMode, Watcher};
-use serde_yaml;
 use took::Timer;
 
-use crate::scene::Scene;
+use raytrace::algebra::Vector;
+use raytrace::animation;
+use raytrace::render::{
+    self, Accumulation, Aov, LiveImage, PixelFormat, PixelOrder, ProgressFn, RayCounts,
+    RenderOptions, RenderProgress, RenderStats, TracedRay,
+};
+use raytrace::scene::{RenderMode, Scene};
+use raytrace::verbosity::Verbosity;
+use raytrace::RaytraceError;
+
+#[cfg(feature = "preview")]
+use crate::preview::Preview;
+
+#[cfg(feature = "preview")]
+mod preview;
+
+/// Time without file events after which a burst of scene file changes is finished.
+const WATCH_QUIET: Duration = Duration::from_millis(150);
+
+/// Number of samples rendered between saving progressive render checkpoints.
+const CHECKPOINT_SAMPLES: u32 = 16;
+
+/// Commented example scene, written by `--init`.
+const EXAMPLE_SCENE: &str = include_str!("../scenes/example.yml");
 
-pub mod algebra;
-mod color;
-mod geometric;
-mod light;
-mod material;
-mod math;
-mod render;
-mod scene;
+/// Config file in the working directory providing default CLI options, if no other is given.
+const CONFIG_FILE: &str = "raytrace.toml";
 
 /// Application entrypoint.
 fn main() {
-    // CLI argument handling
-    let matches = App::new(crate_name!())
+    // CLI argument handling, with defaults from the config file
+    let mut args: Vec<String> = env::args().collect();
+    let mut matches = app().get_matches_from(&args);
+    let config_path = matches
+        .value_of("config")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(CONFIG_FILE)).filter(|path| path.is_file()));
+    if let Some(config_path) = config_path {
+        let config_args = fs::read_to_string(&config_path)
+            .map_err(|err| err.to_string())
+            .and_then(|config| config_args(&args, &config));
+        matches = match config_args.and_then(|config_args| {
+            args.extend(config_args);
+            app()
+                .get_matches_from_safe(&args)
+                .map_err(|err| err.message)
+        }) {
+            Ok(matches) => matches,
+            Err(err) => {
+                eprintln!(
+                    "Invalid config file: '{}'\n\nDetails:\n{}",
+                    config_path.to_str().unwrap_or("?"),
+                    err
+                );
+                process::exit(1)
+            }
+        };
+    }
+
+    // Write an example scene instead of rendering
+    let scene_path = PathBuf::from(matches.value_of("SCENE").unwrap());
+    if matches.is_present("init") {
+        if scene_path.exists() {
+            eprintln!(
+                "Not writing example scene, file already exists: '{}'",
+                scene_path.to_str().unwrap_or("?"),
+            );
+            process::exit(1)
+        }
+        if let Err(err) = fs::write(&scene_path, EXAMPLE_SCENE) {
+            eprintln!("Failed to write example scene\n\nDetails:\n{}", err);
+            process::exit(1)
+        }
+        if !matches.is_present("quiet") {
+            eprintln!(
+                "Wrote example scene to '{}', render it with: {} {} render.png",
+                scene_path.to_str().unwrap_or("?"),
+                crate_name!(),
+                scene_path.to_str().unwrap_or("?"),
+            );
+        }
+        process::exit(0)
+    }
+
+    // Validate scene file
+    if !scene_path.is_file() {
+        eprintln!(
+            "Invalid scene file, not an existing file: '{}'",
+            scene_path.to_str().unwrap_or("?"),
+        );
+        process::exit(1)
+    }
+
+    // Check the scene instead of rendering
+    if matches.is_present("validate-only") {
+        match raytrace::validate_scene(&scene_path) {
+            Ok(()) => {
+                if !matches.is_present("quiet") {
+                    eprintln!("Scene is valid");
+                }
+                process::exit(0)
+            }
+            Err(errors) => {
+                let errors: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+                eprintln!("Invalid scene file\n\nDetails:\n- {}", errors.join("\n- "),);
+                process::exit(1)
+            }
+        }
+    }
+
+    // Validate render output file
+    let output_path = PathBuf::from(matches.value_of("OUTPUT").unwrap_or_default());
+    if output_path.is_dir() {
+        eprintln!(
+            "Invalid output file, is an existing directory: '{}'",
+            output_path.to_str().unwrap_or("?"),
+        );
+        process::exit(1)
+    }
+
+    // Create the output directory
+    let output_dir = matches.value_of("output-dir").map(PathBuf::from);
+    if let Some(ref output_dir) = output_dir {
+        if let Err(err) = fs::create_dir_all(output_dir) {
+            eprintln!(
+                "Invalid output directory, could not create: '{}'\n\nDetails:\n{}",
+                output_dir.to_str().unwrap_or("?"),
+                err,
+            );
+            process::exit(1)
+        }
+    }
+
+    // Validate resolution scale factor
+    let scale = match matches.value_of("scale").map(|s| s.parse::<f64>()) {
+        None => None,
+        Some(Ok(scale)) if scale > 0.0 && scale.is_finite() => Some(scale),
+        Some(_) => {
+            eprintln!("Invalid scale factor, must be a positive number");
+            process::exit(1)
+        }
+    };
+
+    // Validate render time budget
+    let max_time = match matches.value_of("max-time").map(|s| s.parse::<f64>()) {
+        None => None,
+        Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+        Some(_) => {
+            eprintln!("Invalid render time budget, must be a positive number of seconds");
+            process::exit(1)
+        }
+    };
+
+    // Validate the pixel to trace
+    let dump_rays = match matches.value_of("dump-rays").map(parse_pixel) {
+        None => None,
+        Some(Some(pixel)) => Some(pixel),
+        Some(None) => {
+            eprintln!("Invalid pixel to trace, must be formatted as X,Y");
+            process::exit(1)
+        }
+    };
+
+    // Validate random seed
+    let seed = match matches.value_of("seed").map(|s| s.parse::<u64>()) {
+        None => None,
+        Some(Ok(seed)) => Some(seed),
+        Some(Err(_)) => {
+            eprintln!("Invalid seed, must be a positive integer");
+            process::exit(1)
+        }
+    };
+
+    // Set up the render thread pool, renders are identical with any number of threads
+    match matches.value_of("threads").map(|n| n.parse::<usize>()) {
+        None => {}
+        Some(Ok(threads)) if threads >= 1 => {
+            if let Err(err) = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build_global()
+            {
+                eprintln!("Failed to set up render threads\n\nDetails:\n{}", err);
+                process::exit(1)
+            }
+        }
+        Some(_) => {
+            eprintln!("Invalid number of threads, must be a positive integer");
+            process::exit(1)
+        }
+    }
+
+    let benchmark = match matches.value_of("benchmark").map(|n| n.parse::<u32>()) {
+        None => None,
+        Some(Ok(runs)) if runs >= 1 => Some(runs),
+        Some(_) => {
+            eprintln!("Invalid number of benchmark runs, must be a positive integer");
+            process::exit(1)
+        }
+    };
+
+    // Select output buffers, for the output file or written to their own path
+    let mut aov = None;
+    let mut outputs = vec![];
+    for spec in matches.values_of("aov").into_iter().flatten() {
+        match parse_aov(spec) {
+            Some((buffer, Some(path))) => outputs.push((buffer, path)),
+            Some((buffer, None)) if aov.is_none() => aov = Some(buffer),
+            Some((_, None)) => {
+                eprintln!(
+                    "Only one buffer can replace the output file, write others as BUFFER:PATH"
+                );
+                process::exit(1)
+            }
+            None => {
+                eprintln!(
+                    "Invalid buffer '{}', must be color, normal or depth, optionally followed by :PATH",
+                    spec
+                );
+                process::exit(1)
+            }
+        }
+    }
+
+    // Validate animation frames
+    let frames = match matches.value_of("frames").map(|f| f.parse::<u32>()) {
+        None => None,
+        Some(Ok(frames)) if frames > 0 => Some(frames),
+        Some(_) => {
+            eprintln!("Invalid number of frames, must be a positive integer");
+            process::exit(1)
+        }
+    };
+    let fps = match matches.value_of("fps").unwrap().parse::<f64>() {
+        Ok(fps) if fps > 0.0 && fps.is_finite() => fps,
+        _ => {
+            eprintln!("Invalid frames per second, must be a positive number");
+            process::exit(1)
+        }
+    };
+
+    // Validate output encoding options
+    let quality = match matches.value_of("quality").map(|q| q.parse::<u8>()) {
+        None => None,
+        Some(Ok(quality)) if (1..=100).contains(&quality) => Some(quality),
+        Some(_) => {
+            eprintln!("Invalid JPEG quality, must be a number from 1 to 100");
+            process::exit(1)
+        }
+    };
+    let ssaa = match matches.value_of("ssaa").map(|n| n.parse::<u32>()) {
+        None => None,
+        Some(Ok(ssaa)) if ssaa >= 1 => Some(ssaa),
+        Some(_) => {
+            eprintln!("Invalid supersampling factor, must be a positive integer");
+            process::exit(1)
+        }
+    };
+    let png_compression = match matches.value_of("png-compression") {
+        Some("fast") => Some(CompressionType::Fast),
+        Some("default") => Some(CompressionType::Default),
+        Some("best") => Some(CompressionType::Best),
+        _ => None,
+    };
+    let pixel_format = match matches.value_of("pixel-format") {
+        Some("rgb16") => PixelFormat::Rgb16,
+        _ => PixelFormat::Rgb8,
+    };
+    let pixel_order = match matches.value_of("pixel-order") {
+        Some("morton") => PixelOrder::Morton,
+        _ => PixelOrder::Linear,
+    };
+
+    // Select verbosity level
+    let verbosity = if matches.is_present("quiet") {
+        Verbosity::Quiet
+    } else if matches.is_present("verbose") {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+
+    // Check whether to open and watch
+    let watch = matches.is_present("watch");
+    let mut options = Options {
+        open: matches.is_present("open"),
+        camera: matches.value_of("camera").map(String::from),
+        auto_camera: matches.is_present("auto-camera"),
+        scale,
+        seed,
+        frames,
+        fps,
+        benchmark,
+        stats: matches.value_of("stats").map(PathBuf::from),
+        checkpoint: matches
+            .value_of("checkpoint")
+            .or_else(|| matches.value_of("resume"))
+            .map(PathBuf::from),
+        resume: matches.is_present("resume"),
+        max_time,
+        stdout: matches.is_present("stdout"),
+        print_scene: matches.is_present("print-scene"),
+        dump_rays,
+        only: matches
+            .values_of("only")
+            .map(|names| names.map(String::from).collect())
+            .unwrap_or_default(),
+        outputs,
+        preview: matches.is_present("preview"),
+        show_progress: !matches.is_present("no-progress"),
+        render: RenderOptions {
+            aov,
+            verbosity,
+            denoise: matches.is_present("denoise"),
+            ssaa,
+            live_image: None,
+            pixel_format,
+            pixel_order,
+        },
+        save: SaveOptions {
+            quality,
+            png_compression,
+        },
+    };
+
+    let template = output_path.to_string_lossy().into_owned();
+    let mut last_hash = file_hash(&scene_path);
+    for n in 1.. {
+        // Render the scene, to a new output file if templated
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let output_path = PathBuf::from(output_file(&template, n, time));
+        let output_path = match output_dir {
+            Some(ref dir) => dir.join(output_path),
+            None => output_path,
+        };
+        render(&scene_path, &output_path, &options);
+
+        // Do not watch, render a single time and quit
+        if !watch {
+            break;
+        }
+
+        // Wait for scene file change, skip changes that keep the same contents
+        loop {
+            wait_on_change(&scene_path);
+            let hash = file_hash(&scene_path);
+            if hash != last_hash {
+                last_hash = hash;
+                break;
+            }
+        }
+
+        // Do not open a second time
+        options.open = false;
+        if verbosity.is_normal() {
+            eprintln!();
+        }
+    }
+}
+
+/// Build the CLI argument parser.
+fn app() -> App<'static, 'static> {
+    App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
@@ -43,10 +396,72 @@ fn main() {
         )
         .arg(
             Arg::with_name("OUTPUT")
-                .help("Image file to output render to")
-                .required(true)
+                .help("Image file to output render to, {n} and {time} are replaced by the render count and timestamp")
+                .required_unless_one(&[
+                    "stdout",
+                    "print-scene",
+                    "dump-rays",
+                    "validate-only",
+                    "init",
+                ])
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("stdout")
+                .long("stdout")
+                .help("Stream the render to stdout as a binary PPM image, row by row as rows finish")
+                .conflicts_with_all(&[
+                    "OUTPUT", "watch", "open", "frames", "aov", "ssaa", "denoise", "preview",
+                    "checkpoint", "resume", "max-time", "benchmark", "stats", "output-dir",
+                ]),
+        )
+        .arg(
+            Arg::with_name("print-scene")
+                .long("print-scene")
+                .help("Print the fully resolved scene as YAML to stdout, without rendering")
+                .conflicts_with_all(&[
+                    "OUTPUT", "stdout", "watch", "open", "frames", "benchmark", "preview",
+                ]),
+        )
+        .arg(
+            Arg::with_name("validate-only")
+                .long("validate-only")
+                .help("Check that the scene and its models load, without rendering")
+                .conflicts_with_all(&[
+                    "OUTPUT", "stdout", "print-scene", "dump-rays", "watch", "open", "frames",
+                    "benchmark", "preview",
+                ]),
+        )
+        .arg(
+            Arg::with_name("init")
+                .long("init")
+                .help("Write a commented example scene to the scene file path, without rendering")
+                .conflicts_with_all(&[
+                    "OUTPUT", "stdout", "print-scene", "dump-rays", "validate-only", "watch",
+                    "open", "frames", "benchmark", "preview",
+                ]),
+        )
+        .arg(
+            Arg::with_name("dump-rays")
+                .long("dump-rays")
+                .value_name("X,Y")
+                .help("Trace a single pixel and print its tree of rays, without rendering")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "OUTPUT", "stdout", "print-scene", "watch", "open", "frames", "aov", "ssaa",
+                    "denoise", "preview", "checkpoint", "resume", "max-time", "benchmark", "stats",
+                    "output-dir",
+                ]),
+        )
+        .arg(
+            Arg::with_name("only")
+                .long("only")
+                .value_name("NAME")
+                .help("Render only the entities with the given name, may be given multiple times")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
         .arg(
             Arg::with_name("watch")
                 .long("watch")
@@ -61,6 +476,160 @@ fn main() {
                 .help("Open rendered scene image")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("scale")
+                .long("scale")
+                .short("s")
+                .value_name("FACTOR")
+                .help("Scale render resolution by factor, for fast previews")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ssaa")
+                .long("ssaa")
+                .value_name("N")
+                .help("Supersample, render at N times the width and height and downsample")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("N")
+                .help("Seed for random sampling, overrides scene seed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .short("j")
+                .value_name("N")
+                .help("Number of threads to render with, defaults to the number of CPU cores")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("camera")
+                .long("camera")
+                .value_name("NAME")
+                .help("Render with the named scene camera")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("auto-camera")
+                .long("auto-camera")
+                .help("Place the camera to frame all scene entities"),
+        )
+        .arg(
+            Arg::with_name("aov")
+                .long("aov")
+                .value_name("BUFFER[:PATH]")
+                .help(
+                    "Render a color, normal or depth buffer instead of the shaded scene, \
+                     or write it to PATH from the same render",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .value_name("N")
+                .help("Render an animation of N frames, numbered after the output file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fps")
+                .long("fps")
+                .value_name("FPS")
+                .help("Animation frames per second")
+                .default_value("24")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("quality")
+                .long("quality")
+                .value_name("N")
+                .help("JPEG output quality, from 1 to 100")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("png-compression")
+                .long("png-compression")
+                .value_name("LEVEL")
+                .help("PNG output compression level")
+                .possible_values(&["fast", "default", "best"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pixel-format")
+                .long("pixel-format")
+                .value_name("FORMAT")
+                .help("Output pixel format, 16-bit images are only written to PNG files")
+                .possible_values(&["rgb8", "rgb16"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pixel-order")
+                .long("pixel-order")
+                .value_name("ORDER")
+                .help("Order to trace pixels in, the render is the same in any order")
+                .possible_values(&["linear", "morton"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("PATH")
+                .help("Render path traced samples progressively, saving checkpoints to the given file")
+                .takes_value(true)
+                .conflicts_with_all(&["resume", "frames", "aov", "ssaa", "denoise", "preview"]),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .value_name("PATH")
+                .help("Resume a progressive render from the given checkpoint file, and keep saving to it")
+                .takes_value(true)
+                .conflicts_with_all(&["frames", "aov", "ssaa", "denoise", "preview"]),
+        )
+        .arg(
+            Arg::with_name("max-time")
+                .long("max-time")
+                .value_name("SECONDS")
+                .help("Stop rendering path traced samples after the given time, and write what has converged")
+                .takes_value(true)
+                .conflicts_with_all(&["aov", "ssaa", "denoise", "preview", "benchmark"]),
+        )
+        .arg(
+            Arg::with_name("benchmark")
+                .long("benchmark")
+                .value_name("N")
+                .help("Render N times without saving, and report timing statistics")
+                .takes_value(true)
+                .conflicts_with_all(&["watch", "open", "frames", "checkpoint", "resume", "preview"]),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .value_name("PATH")
+                .help("Write render statistics as JSON to the given file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("denoise")
+                .long("denoise")
+                .help("Denoise the render, guided by surface normals and depth")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("preview")
+                .long("preview")
+                .help(
+                    "Show the render filling in, in a live preview window kept open until closed",
+                )
+                .conflicts_with_all(&["watch", "frames"])
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("no-progress")
                 .long("no-progress")
@@ -68,61 +637,178 @@ fn main() {
                 .help("Do not show progress bar")
                 .takes_value(false),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .help("Do not show status output, warnings or progress")
+                .conflicts_with("verbose")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("output-dir")
+                .long("output-dir")
+                .value_name("DIR")
+                .help("Directory to write output files to, created if it doesn't exist")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .help("Show additional details")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("PATH")
+                .help("TOML file with default CLI options, defaults to raytrace.toml if it exists")
+                .takes_value(true),
+        )
+}
 
-    // Validate scene file
-    let scene_path = PathBuf::from(matches.value_of("SCENE").unwrap());
-    if !scene_path.is_file() {
-        eprintln!(
-            "Invalid scene file, not an existing file: '{}'",
-            scene_path.to_str().unwrap_or("?"),
-        );
-        process::exit(1)
+/// Get the CLI arguments for the options in the given TOML `config`.
+///
+/// Options are keyed by their long name. Flags are set with `true`, options taking multiple values
+/// with an array. Options given on the command line in `cli_args` override the config, config
+/// options that are already given or that conflict with them are skipped.
+fn config_args(cli_args: &[String], config: &str) -> Result<Vec<String>, String> {
+    let config: toml::value::Table = toml::from_str(config).map_err(|err| err.to_string())?;
+    let matches = app()
+        .get_matches_from_safe(cli_args)
+        .map_err(|err| err.message)?;
+    let mut args = Vec::new();
+    for (name, value) in config {
+        if matches.occurrences_of(&name) > 0 {
+            continue;
+        }
+        let option_args = option_args(&name, value)?;
+
+        // Skip options conflicting with the command line
+        let with_option = cli_args.iter().chain(option_args.iter());
+        match app().get_matches_from_safe(with_option) {
+            Err(err) if err.kind == ErrorKind::ArgumentConflict => continue,
+            _ => args.extend(option_args),
+        }
     }
+    Ok(args)
+}
 
-    // Validate render output file
-    let output_path = PathBuf::from(matches.value_of("OUTPUT").unwrap());
-    if output_path.is_dir() {
-        eprintln!(
-            "Invalid output file, is an existing directory: '{}'",
-            output_path.to_str().unwrap_or("?"),
-        );
-        process::exit(1)
+/// Get the CLI arguments setting the option with the given long `name` to a TOML `value`.
+fn option_args(name: &str, value: toml::Value) -> Result<Vec<String>, String> {
+    let values = match value {
+        toml::Value::Array(values) => values,
+        value => vec![value],
+    };
+    let mut args = Vec::new();
+    for value in values {
+        match value {
+            toml::Value::Boolean(true) => args.push(format!("--{}", name)),
+            toml::Value::Boolean(false) => {}
+            toml::Value::String(value) => args.push(format!("--{}={}", name, value)),
+            toml::Value::Integer(value) => args.push(format!("--{}={}", name, value)),
+            toml::Value::Float(value) => args.push(format!("--{}={}", name, value)),
+            _ => return Err(format!("unsupported value for option '{}'", name)),
+        }
     }
+    Ok(args)
+}
 
-    // Check whether to open and watch
-    let mut open = matches.is_present("open");
-    let watch = matches.is_present("watch");
-    let show_progress = !matches.is_present("no-progress");
+/// Render options.
+struct Options {
+    /// Whether to open the render file when done.
+    open: bool,
 
-    loop {
-        // Render the scene
-        render(open, &scene_path, &output_path, show_progress);
+    /// Name of the scene camera to render with, the default camera if not set.
+    camera: Option<String>,
 
-        // Do not watch, render a single time and quit
-        if !watch {
-            break;
-        }
+    /// Whether to place the camera to frame all scene entities.
+    auto_camera: bool,
 
-        // Wait for scene file change
-        wait_on_change(&scene_path);
+    /// Factor to scale the camera resolution by.
+    scale: Option<f64>,
 
-        // Do not open a second time
-        open = false;
-        eprintln!("");
-    }
+    /// Random seed, overriding the scene seed.
+    seed: Option<u64>,
+
+    /// Number of animation frames to render, a single image is rendered if not set.
+    frames: Option<u32>,
+
+    /// Animation frames per second.
+    fps: f64,
+
+    /// Number of times to render for benchmarking, without saving, if set.
+    benchmark: Option<u32>,
+
+    /// File to write render statistics to as JSON, if set.
+    ///
+    /// For frame sequences, statistics of each frame are written to a numbered file.
+    stats: Option<PathBuf>,
+
+    /// File to save progressive render checkpoints to, if rendering progressively.
+    checkpoint: Option<PathBuf>,
+
+    /// Whether to resume the progressive render from the checkpoint file.
+    resume: bool,
+
+    /// Time budget for path traced renders, after which no new samples are rendered, if set.
+    max_time: Option<Duration>,
+
+    /// Whether to stream the render to stdout, instead of saving it to the output file.
+    stdout: bool,
+
+    /// Whether to print the resolved scene as YAML to stdout, instead of rendering it.
+    print_scene: bool,
+
+    /// Pixel to trace and print the rays of, instead of rendering, if set.
+    dump_rays: Option<(u32, u32)>,
+
+    /// Names of the entities to render in isolation, all entities are rendered if empty.
+    only: Vec<String>,
+
+    /// Extra output buffers written from the same render, with the path to write each to.
+    ///
+    /// For frame sequences, each frame is written to a numbered file.
+    outputs: Vec<(Aov, PathBuf)>,
+
+    /// Whether to show the render filling in, in a live preview window.
+    preview: bool,
+
+    /// Whether to show the progress bar, if the verbosity allows it.
+    show_progress: bool,
+
+    /// Options for rendering the scene itself.
+    render: RenderOptions,
+
+    /// Options for saving the render output.
+    save: SaveOptions,
+}
+
+/// Render output encoding options.
+#[derive(Copy, Clone, Debug, Default)]
+struct SaveOptions {
+    /// JPEG quality from 1 to 100, uses the encoder default if not set.
+    quality: Option<u8>,
+
+    /// PNG compression level, uses the encoder default if not set.
+    png_compression: Option<CompressionType>,
 }
 
 /// Render scene from file.
 ///
-/// This renders the scene at the given `scene_path`, and outputs the render result to
-/// `output_path`.
-fn render(open: bool, scene_path: &Path, output_path: &Path, show_progress: bool) {
+/// This renders the scene at the given `scene_path` with the given `options`, and outputs the
+/// render result to `output_path`.
+fn render(scene_path: &Path, output_path: &Path, options: &Options) {
+    let verbosity = options.render.verbosity;
+
     // Load scene from file
-    eprintln!("Loading scene file...");
-    let scene_file = match File::open(scene_path) {
-        Ok(file) => file,
-        Err(err) => {
+    if verbosity.is_normal() {
+        eprintln!("Loading scene file...");
+    }
+    let mut scene = match raytrace::load_scene_with(scene_path, verbosity) {
+        Ok(scene) => scene,
+        Err(RaytraceError::Io { source: err, .. }) => {
             eprintln!(
                 "Failed to open scene file, could not open file at '{}'\nSkipping this render\n\nDetails:\n{}",
                 scene_path.to_str().unwrap_or("?"),
@@ -130,24 +816,275 @@ fn render(open: bool, scene_path: &Path, output_path: &Path, show_progress: bool
             );
             return;
         }
-    };
-    let mut scene: Scene = match serde_yaml::from_reader(scene_file) {
-        Ok(file) => file,
-        Err(err) => {
+        Err(RaytraceError::SceneParse(err)) => {
             eprintln!(
                 "Failed to parse scene file, skipping this render\n\nDetails:\n{}",
                 err,
             );
             return;
         }
+        Err(RaytraceError::Include(err)) => {
+            eprintln!(
+                "Failed to include scene files, skipping this render\n\nDetails:\n{}",
+                err,
+            );
+            return;
+        }
+        Err(RaytraceError::Validation(problems)) => {
+            eprintln!(
+                "Invalid scene file, skipping this render\n\nDetails:\n- {}",
+                problems.join("\n- "),
+            );
+            return;
+        }
+        Err(RaytraceError::Material(err)) => {
+            eprintln!(
+                "Failed to resolve scene materials, skipping this render\n\nDetails:\n{}",
+                err,
+            );
+            return;
+        }
+        Err(err) => {
+            eprintln!(
+                "Failed to load scene, skipping this render\n\nDetails:\n{}",
+                err,
+            );
+            return;
+        }
+    };
+
+    // Select the camera
+    if let Some(ref name) = options.camera {
+        if let Err(err) = scene.select_camera(name) {
+            eprintln!(
+                "Failed to select camera, skipping this render\n\nDetails:\n{}",
+                err,
+            );
+            return;
+        }
+    }
+
+    // Frame the scene bounds
+    if options.auto_camera {
+        match scene.bounds() {
+            Some(bounds) => scene.camera.frame(&bounds),
+            None => {
+                if verbosity.is_normal() {
+                    eprintln!("Cannot frame scene without finite entities, keeping the camera");
+                }
+            }
+        }
+    }
+
+    // Scale the render resolution
+    if let Some(scale) = options.scale {
+        scene.camera.scale(scale);
+    }
+
+    // Override the random seed
+    if let Some(seed) = options.seed {
+        scene.seed = seed;
+    }
+
+    // Isolate the named entities
+    if !options.only.is_empty() {
+        let names: Vec<&str> = options.only.iter().map(String::as_str).collect();
+        if scene.isolate(&names) == 0 {
+            eprintln!(
+                "No entities named '{}', skipping this render",
+                names.join("', '"),
+            );
+            return;
+        }
+    }
+
+    // Print the resolved scene instead of rendering
+    if options.print_scene {
+        match serde_yaml::to_string(&scene) {
+            Ok(yaml) => print!("{}", yaml),
+            Err(err) => eprintln!("Failed to print scene\n\nDetails:\n{}", err),
+        }
+        return;
+    }
+
+    // Trace a single pixel instead of rendering
+    if let Some((x, y)) = options.dump_rays {
+        if x >= scene.camera.width || y >= scene.camera.height {
+            eprintln!(
+                "Pixel {},{} to trace is outside the {}x{} render",
+                x, y, scene.camera.width, scene.camera.height,
+            );
+            return;
+        }
+        for ray in render::trace_pixel(&scene, x, y) {
+            println!("{}", format_traced_ray(&ray));
+        }
+        return;
+    }
+
+    if verbosity.is_verbose() {
+        eprintln!(
+            "Scene has {} entities and {} lights, rendering at {}x{}",
+            scene.entities.len(),
+            scene.lights.len(),
+            scene.camera.width,
+            scene.camera.height,
+        );
+    }
+
+    // Benchmark, render a number of times without saving
+    if let Some(runs) = options.benchmark {
+        if verbosity.is_normal() {
+            eprintln!(
+                "Benchmarking {} renders on {} threads...",
+                runs,
+                rayon::current_num_threads()
+            );
+        }
+        let benchmark = render::benchmark(&scene, &options.render, runs);
+        println!(
+            "Rendered {} times: min {:.3}s, median {:.3}s, mean {:.3}s, max {:.3}s, {:.2} Mrays/s",
+            benchmark.runs.len(),
+            benchmark.min().as_secs_f64(),
+            benchmark.median().as_secs_f64(),
+            benchmark.mean().as_secs_f64(),
+            benchmark.max().as_secs_f64(),
+            benchmark.mrays_per_second(),
+        );
+        return;
+    }
+
+    // Stream the render to stdout
+    if options.stdout {
+        let stdout = io::stdout();
+        if let Err(err) = render::render_stream(&scene, &options.render, &mut stdout.lock()) {
+            eprintln!("Failed to stream render to stdout\n\nDetails:\n{}", err);
+        }
+        return;
+    }
+
+    // Render a single frame, or a frame sequence
+    let output_path = match options.frames {
+        None => {
+            let rendered = with_preview(&scene, options, |live_image| {
+                render_frame(
+                    &scene,
+                    output_path,
+                    options.stats.as_deref(),
+                    &options.outputs,
+                    options,
+                    live_image,
+                )
+            });
+            if !rendered {
+                return;
+            }
+            output_path.to_path_buf()
+        }
+        Some(frames) => {
+            for frame in 0..frames {
+                let mut frame_scene = scene.clone();
+                if let Some(ref animation) = scene.animation {
+                    animation.apply(&mut frame_scene, animation.time(frame, frames, options.fps));
+                }
+                if verbosity.is_normal() {
+                    eprintln!("Frame {}/{}", frame + 1, frames);
+                }
+                let stats_path = options
+                    .stats
+                    .as_ref()
+                    .map(|path| animation::frame_path(path, frame));
+                let outputs: Vec<(Aov, PathBuf)> = options
+                    .outputs
+                    .iter()
+                    .map(|(buffer, path)| (*buffer, animation::frame_path(path, frame)))
+                    .collect();
+                if !render_frame(
+                    &frame_scene,
+                    &animation::frame_path(output_path, frame),
+                    stats_path.as_deref(),
+                    &outputs,
+                    options,
+                    None,
+                ) {
+                    return;
+                }
+            }
+            animation::frame_path(output_path, 0)
+        }
     };
-    scene.load(scene_path.parent().unwrap());
 
-    // Render scene to an image, save it to a file
-    eprintln!("Rendering scene on {} CPU cores...", num_cpus::get());
+    // Open render file
+    if options.open {
+        if verbosity.is_normal() {
+            eprintln!("Opening render file...");
+        }
+        if let Err(err) = open::that(&output_path) {
+            eprintln!("Failed to open render file\n\nDetails:\n{}", err);
+        }
+    }
+}
+
+/// Render a loaded scene to an image, and save it to `output_path`.
+///
+/// Render statistics are written to `stats_path` if set, extra buffers are written to their paths
+/// in `outputs`. Pixels are written to `live_image` while rendering, if set.
+///
+/// Returns `false` if saving the render failed.
+fn render_frame(
+    scene: &Scene,
+    output_path: &Path,
+    stats_path: Option<&Path>,
+    outputs: &[(Aov, PathBuf)],
+    options: &Options,
+    live_image: Option<Arc<LiveImage>>,
+) -> bool {
+    let verbosity = options.render.verbosity;
+
+    if verbosity.is_normal() {
+        eprintln!(
+            "Rendering scene on {} threads...",
+            rayon::current_num_threads()
+        );
+    }
     let timer = Timer::new();
-    let render = render::render(&scene, show_progress);
-    match render.save(output_path) {
+    let rays = RayCounts::now();
+    let mut buffers = vec![options.render.aov.unwrap_or(Aov::Color)];
+    buffers.extend(outputs.iter().map(|(buffer, _)| *buffer));
+    let progressive = options.checkpoint.is_some()
+        || (options.max_time.is_some() && scene.render_mode == RenderMode::PathTraced);
+    if options.max_time.is_some() && !progressive && verbosity.is_normal() {
+        eprintln!("Ignoring render time budget, it requires a path traced scene");
+    }
+    let mut renders = if progressive {
+        match render_progressive(scene, options.checkpoint.as_deref(), options) {
+            Some(render) => vec![render],
+            None => return false,
+        }
+    } else {
+        let render_options = RenderOptions {
+            live_image,
+            ..options.render.clone()
+        };
+        let progress = RenderProgress::new();
+        let report = |completed, total| progress.report(completed, total);
+        let show_progress = options.show_progress && verbosity.is_normal();
+        let renders = render::render_buffers(
+            scene,
+            &render_options,
+            &buffers,
+            show_progress.then_some(&report as ProgressFn<'_>),
+        );
+        progress.finish();
+        renders
+    };
+    let render = renders.remove(0);
+    let stats = RenderStats::new(
+        RayCounts::now().since(rays),
+        timer.took().into_std(),
+        scene.triangles() as u64,
+    );
+    match save(&render, output_path, options.save) {
         Ok(_) => {}
         Err(err) => {
             eprintln!(
@@ -155,38 +1092,527 @@ fn render(open: bool, scene_path: &Path, output_path: &Path, show_progress: bool
                 output_path.to_str().unwrap_or("?"),
                 err,
             );
-            return;
+            return false;
+        }
+    }
+    if !save_outputs(&renders, outputs, options.save) {
+        return false;
+    }
+    if verbosity.is_normal() {
+        timer.took().describe("Rendering finished,");
+    }
+
+    // Write render statistics
+    if let Some(stats_path) = stats_path {
+        if let Err(err) = write_stats(&stats, stats_path) {
+            eprintln!(
+                "Failed to write render statistics at: '{}'\n\nDetails:\n{}",
+                stats_path.to_str().unwrap_or("?"),
+                err,
+            );
         }
     }
-    timer.took().describe("Rendering finished,");
 
-    // Open render file
-    if open {
-        eprintln!("Opening render file...");
-        open::that(output_path).expect("failed to open render output file");
+    true
+}
+
+/// Run `render` while showing the render in a live preview window, if enabled.
+///
+/// The render runs on a separate thread, the window is updated on this thread. After rendering the
+/// window is kept open until the user closes it. Returns the result of `render`.
+#[cfg(feature = "preview")]
+fn with_preview<F>(scene: &Scene, options: &Options, render: F) -> bool
+where
+    F: FnOnce(Option<Arc<LiveImage>>) -> bool + Send,
+{
+    if !options.preview {
+        return render(None);
+    }
+    let verbosity = options.render.verbosity;
+    let image = Arc::new(LiveImage::new(&scene.camera));
+    let mut preview = match Preview::open(image.clone(), verbosity) {
+        Some(preview) => preview,
+        None => return render(None),
+    };
+    let rendered = preview.show_while(|| render(Some(image)));
+    if verbosity.is_normal() {
+        eprintln!("Close the preview window to continue");
+    }
+    preview.wait_until_closed();
+    rendered
+}
+
+/// Run `render`, without preview window support built in.
+///
+/// A warning is shown if a preview window is enabled.
+#[cfg(not(feature = "preview"))]
+fn with_preview<F>(_scene: &Scene, options: &Options, render: F) -> bool
+where
+    F: FnOnce(Option<Arc<LiveImage>>) -> bool,
+{
+    if options.preview && options.render.verbosity.is_normal() {
+        eprintln!("Warning: no preview window support, build with the 'preview' feature to use it");
+    }
+    render(None)
+}
+
+/// Render a path traced scene progressively, saving a checkpoint after every few samples if a
+/// `checkpoint` file is given.
+///
+/// Continues from the `checkpoint` file if resuming, until the scene sample count is reached or
+/// the render time budget runs out. Returns `None` if the render failed.
+fn render_progressive(
+    scene: &Scene,
+    checkpoint: Option<&Path>,
+    options: &Options,
+) -> Option<DynamicImage> {
+    let verbosity = options.render.verbosity;
+    if scene.render_mode != RenderMode::PathTraced {
+        eprintln!("Progressive rendering requires a path traced scene, skipping this render");
+        return None;
+    }
+
+    // Start a new render, or resume from the checkpoint
+    let mut accumulation = match checkpoint.filter(|_| options.resume) {
+        Some(checkpoint) => match Accumulation::load(checkpoint) {
+            Ok(accumulation) if accumulation.fits(&scene.camera) => accumulation,
+            Ok(_) => {
+                eprintln!("Failed to resume render, checkpoint does not match the camera resolution, skipping this render");
+                return None;
+            }
+            Err(err) => {
+                eprintln!(
+                    "Failed to load render checkpoint at: '{}'\nSkipping this render\n\nDetails:\n{}",
+                    checkpoint.to_str().unwrap_or("?"),
+                    err,
+                );
+                return None;
+            }
+        },
+        None => Accumulation::new(&scene.camera),
+    };
+
+    let deadline = options.max_time.map(|max_time| Instant::now() + max_time);
+    let timed_out = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    while accumulation.samples() < scene.samples {
+        // Without checkpoints, render all remaining samples in one go
+        let remaining = scene.samples - accumulation.samples();
+        let samples = match checkpoint {
+            Some(_) => CHECKPOINT_SAMPLES.min(remaining),
+            None => remaining,
+        };
+        render::render_progressive(
+            scene,
+            &mut accumulation,
+            samples,
+            verbosity,
+            options.show_progress,
+            options.render.pixel_order,
+            deadline,
+        );
+
+        if let Some(checkpoint) = checkpoint {
+            if !save_checkpoint(&accumulation, checkpoint, scene.samples, verbosity) {
+                return None;
+            }
+        }
+        if accumulation.samples() < scene.samples && timed_out() {
+            if verbosity.is_normal() {
+                eprintln!(
+                    "Stopped at the render time budget after {}/{} samples",
+                    accumulation.samples(),
+                    scene.samples
+                );
+            }
+            break;
+        }
+    }
+
+    Some(render::fit_output(
+        accumulation.to_image(options.render.pixel_format, scene.dither),
+        scene,
+    ))
+}
+
+/// Save a progressive render checkpoint, `samples` is the total number of samples rendering to.
+///
+/// Returns `false` if saving failed.
+fn save_checkpoint(
+    accumulation: &Accumulation,
+    checkpoint: &Path,
+    samples: u32,
+    verbosity: Verbosity,
+) -> bool {
+    if let Err(err) = accumulation.save(checkpoint) {
+        eprintln!(
+            "Failed to save render checkpoint at: '{}'\nSkipping this render\n\nDetails:\n{}",
+            checkpoint.to_str().unwrap_or("?"),
+            err,
+        );
+        return false;
+    }
+    if verbosity.is_normal() {
+        eprintln!(
+            "Saved checkpoint at {}/{} samples",
+            accumulation.samples(),
+            samples
+        );
+    }
+    true
+}
+
+/// Write render statistics as JSON to `path`.
+fn write_stats(stats: &RenderStats, path: &Path) -> Result<(), Box<dyn Error>> {
+    let file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(file, stats)?;
+    Ok(())
+}
+
+/// Save rendered output buffers, each to the path it is paired with in `outputs`.
+///
+/// Returns `false` if saving any of them failed.
+fn save_outputs(images: &[DynamicImage], outputs: &[(Aov, PathBuf)], options: SaveOptions) -> bool {
+    for (image, (_, path)) in images.iter().zip(outputs) {
+        if let Err(err) = save(image, path, options) {
+            eprintln!(
+                "Failed to write output buffer, could not write at: '{}'\nSkipping this render\n\nDetails:\n{}",
+                path.to_str().unwrap_or("?"),
+                err,
+            );
+            return false;
+        }
+    }
+    true
+}
+
+/// Parse an output buffer argument, formatted as `BUFFER` or `BUFFER:PATH`.
+///
+/// Returns `None` if the buffer name is unknown or the path is empty.
+fn parse_aov(spec: &str) -> Option<(Aov, Option<PathBuf>)> {
+    let (name, path) = match spec.split_once(':') {
+        Some((_, "")) => return None,
+        Some((name, path)) => (name, Some(PathBuf::from(path))),
+        None => (spec, None),
+    };
+    let buffer = match name {
+        "color" => Aov::Color,
+        "normal" => Aov::Normal,
+        "depth" => Aov::Depth,
+        _ => return None,
+    };
+    Some((buffer, path))
+}
+
+/// Parse a pixel argument, formatted as `X,Y`.
+fn parse_pixel(spec: &str) -> Option<(u32, u32)> {
+    let (x, y) = spec.split_once(',')?;
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+/// Format a traced ray as a line of the ray tree, indented by its depth.
+fn format_traced_ray(ray: &TracedRay) -> String {
+    let Vector(ox, oy, oz) = ray.origin;
+    let Vector(dx, dy, dz) = ray.direction;
+    let (r, g, b) = ray.color.channels();
+    let hit = match (ray.entity, ray.distance) {
+        (Some(entity), Some(distance)) => format!("hit entity {} at {:.4}", entity, distance),
+        _ => "missed".to_string(),
+    };
+    format!(
+        "{}{:?} ray at depth {}, from ({:.4}, {:.4}, {:.4}) towards ({:.4}, {:.4}, {:.4}), {}, color ({:.4}, {:.4}, {:.4})",
+        "  ".repeat(ray.depth as usize),
+        ray.kind,
+        ray.depth,
+        ox, oy, oz,
+        dx, dy, dz,
+        hit,
+        r, g, b,
+    )
+}
+
+/// Save an image to `path`, in the format of the file extension.
+///
+/// JPEG and PNG files are encoded with the given `options`, other formats ignore them. 16-bit
+/// images are only written as is to PNG files, and are converted to 8-bit for other formats.
+fn save(image: &DynamicImage, path: &Path, options: SaveOptions) -> ImageResult<()> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match (extension.as_str(), options.quality, options.png_compression) {
+        ("jpg" | "jpeg", Some(quality), _) => {
+            let mut file = BufWriter::new(File::create(path)?);
+            JpegEncoder::new_with_quality(&mut file, quality).encode_image(&image.to_rgb8())
+        }
+        ("png", _, Some(compression)) => {
+            let file = BufWriter::new(File::create(path)?);
+            let encoder = PngEncoder::new_with_quality(file, compression, FilterType::default());
+
+            // Write an alpha channel only if the image has one
+            let converted;
+            let image = match image.color() {
+                ColorType::Rgb8 | ColorType::Rgba8 | ColorType::Rgb16 => image,
+                color if color.has_alpha() => {
+                    converted = DynamicImage::ImageRgba8(image.to_rgba8());
+                    &converted
+                }
+                _ => {
+                    converted = DynamicImage::ImageRgb8(image.to_rgb8());
+                    &converted
+                }
+            };
+            encoder.write_image(
+                image.as_bytes(),
+                image.width(),
+                image.height(),
+                image.color(),
+            )
+        }
+        (extension, ..) if extension != "png" && image.color() == ColorType::Rgb16 => {
+            DynamicImage::ImageRgb8(image.to_rgb8()).save(path)
+        }
+        _ => image.save(path),
     }
 }
 
 /// Wait for a given file to change.
 ///
-/// This function blocks, until the given file is changed.
+/// This function blocks, until the given file is changed. A burst of changes, such as an editor
+/// saving a file in multiple steps, is coalesced until the file is quiet for a moment.
 fn wait_on_change(path: &Path) {
     // Create scene file watcher, with channel to receive events
     let (tx, rx) = channel();
-    let mut watcher =
-        notify::watcher(tx, Duration::from_secs(1)).expect("failed to create file watcher");
+    let mut watcher = notify::watcher(tx, WATCH_QUIET).expect("failed to create file watcher");
     watcher
-        .watch(&path, RecursiveMode::NonRecursive)
+        .watch(path, RecursiveMode::NonRecursive)
         .expect("failed to configure watcher for file changes");
 
     // Wait for scene file change
     loop {
         match rx.recv().expect("failed to watch file for changes") {
-            DebouncedEvent::NoticeRemove(_) | DebouncedEvent::Write(_) => break,
+            DebouncedEvent::NoticeWrite(_)
+            | DebouncedEvent::NoticeRemove(_)
+            | DebouncedEvent::Write(_) => break,
             _ => {}
         }
     }
 
-    // Wait a little longer, ensure the file written
-    thread::sleep(Duration::from_millis(200));
+    // Coalesce following events, until the file is quiet
+    while rx.recv_timeout(WATCH_QUIET * 2).is_ok() {}
+}
+
+/// Expand the output file name `template` for the `n`th render at the given Unix `time`.
+///
+/// Replaces `{n}` by the render count, and `{time}` by the timestamp in seconds.
+fn output_file(template: &str, n: u32, time: u64) -> String {
+    template
+        .replace("{n}", &n.to_string())
+        .replace("{time}", &time.to_string())
+}
+
+/// Hash the contents of the file at `path`, `None` if it could not be read.
+fn file_hash(path: &Path) -> Option<u64> {
+    fs::read(path).ok().map(|contents| content_hash(&contents))
+}
+
+/// Hash the given file `contents`.
+fn content_hash(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_content_hash() {
+        let contents = b"camera: { width: 8, height: 6 }".to_vec();
+        let mut changed = contents.clone();
+        changed[16] = b'9';
+        assert_eq!(content_hash(&contents), content_hash(&contents.clone()));
+        assert_ne!(content_hash(&contents), content_hash(&changed));
+    }
+
+    #[test]
+    fn test_output_file_template() {
+        let names: Vec<String> = (1..=3)
+            .map(|n| output_file("render-{n}.png", n, 1_600_000_000))
+            .collect();
+        assert_eq!(names, vec!["render-1.png", "render-2.png", "render-3.png"]);
+        assert_eq!(
+            output_file("{time}-{n}.jpg", 7, 1_600_000_000),
+            "1600000000-7.jpg"
+        );
+        assert_eq!(output_file("render.png", 2, 0), "render.png");
+    }
+
+    #[test]
+    fn test_save_jpeg_quality() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8])
+        }));
+        let size = |quality| {
+            let path = std::env::temp_dir().join(format!(
+                "raytrace-rs-test-quality-{}-{}.jpg",
+                quality,
+                process::id()
+            ));
+            let options = SaveOptions {
+                quality: Some(quality),
+                ..SaveOptions::default()
+            };
+            save(&image, &path, options).unwrap();
+            let size = fs::metadata(&path).unwrap().len();
+            fs::remove_file(&path).unwrap();
+            size
+        };
+        assert!(size(10) < size(95));
+    }
+
+    #[test]
+    fn test_save_png_compression_keeps_channels() {
+        let path = std::env::temp_dir().join(format!(
+            "raytrace-rs-test-compression-{}.png",
+            process::id()
+        ));
+        let options = SaveOptions {
+            png_compression: Some(CompressionType::Best),
+            ..SaveOptions::default()
+        };
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, y| {
+            Rgb([(x * 32) as u8, (y * 32) as u8, 0])
+        }));
+        save(&image, &path, options).unwrap();
+        let saved = image::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // No alpha channel is added to an image without one
+        assert_eq!(saved.color(), ColorType::Rgb8);
+        assert_eq!(saved.to_rgb8(), image.to_rgb8());
+    }
+
+    #[test]
+    fn test_config_args_defaults() {
+        let config = "threads = 2\nverbose = true\nonly = [\"a\", \"b\"]";
+        let parse = |args: &[&str], config: &str| {
+            let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            args.extend(config_args(&args, config).unwrap());
+            app().get_matches_from_safe(&args).unwrap()
+        };
+
+        let matches = parse(&["raytrace-rs", "scene.yml", "render.png"], config);
+        assert_eq!(matches.value_of("threads"), Some("2"));
+        assert!(matches.is_present("verbose"));
+        assert_eq!(
+            matches.values_of("only").unwrap().collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+
+        let matches = parse(
+            &["raytrace-rs", "scene.yml", "render.png", "--threads", "4"],
+            config,
+        );
+        assert_eq!(matches.value_of("threads"), Some("4"));
+        let args: Vec<String> = vec!["raytrace-rs".into(), "scene.yml".into(), "out.png".into()];
+        assert!(config_args(&args, "seed = { n = 2 }").is_err());
+    }
+
+    #[test]
+    fn test_config_args_conflicting_with_cli() {
+        let config = "threads = 2\nverbose = true\nopen = true\nframes = 10";
+        let parse = |args: &[&str]| {
+            let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            args.extend(config_args(&args, config).unwrap());
+            app().get_matches_from_safe(&args).unwrap()
+        };
+
+        // Flags on the command line override conflicting config options
+        let matches = parse(&["raytrace-rs", "scene.yml", "--stdout", "-q"]);
+        assert!(matches.is_present("quiet"));
+        assert!(!matches.is_present("verbose"));
+        assert!(!matches.is_present("open"));
+        assert!(!matches.is_present("frames"));
+        assert_eq!(matches.value_of("threads"), Some("2"));
+
+        let matches = parse(&[
+            "raytrace-rs",
+            "scene.yml",
+            "out.png",
+            "--checkpoint",
+            "c.bin",
+        ]);
+        assert!(!matches.is_present("frames"));
+        assert!(matches.is_present("open"));
+    }
+
+    #[test]
+    fn test_example_scene_is_valid() {
+        let mut scene: Scene = serde_yaml::from_str(EXAMPLE_SCENE).unwrap();
+        assert_eq!(scene.validate(), Ok(()));
+        scene.resolve_materials().unwrap();
+        assert_eq!(scene.entities.len(), 4);
+        assert!(!scene.lights.is_empty());
+    }
+
+    #[test]
+    fn test_parse_aov() {
+        assert_eq!(parse_aov("depth"), Some((Aov::Depth, None)));
+        assert_eq!(
+            parse_aov("normal:out/n.png"),
+            Some((Aov::Normal, Some(PathBuf::from("out/n.png"))))
+        );
+        assert_eq!(parse_aov("albedo:a.png"), None);
+        assert_eq!(parse_aov("color:"), None);
+    }
+
+    #[test]
+    fn test_save_color_and_depth_outputs() {
+        let mut scene: Scene = serde_yaml::from_str(
+            r#"
+            camera: { width: 16, height: 16 }
+            entities:
+              - type: sphere
+                center: [0, 0, -3]
+                radius: 1
+                material: { color: [1, 0.2, 0], albedo: 1 }
+            lights:
+              - type: directional
+                direction: [0, 0, -1]
+                color: [1, 1, 1]
+                intensity: 10
+            "#,
+        )
+        .unwrap();
+        scene.resolve_materials().unwrap();
+        scene.update_bounding_boxes();
+
+        let dir = std::env::temp_dir();
+        let outputs: Vec<(Aov, PathBuf)> = ["color", "depth"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(format!("raytrace-rs-test-{}-{}.png", name, process::id()));
+                parse_aov(&format!("{}:{}", name, path.display())).unwrap()
+            })
+            .map(|(buffer, path)| (buffer, path.unwrap()))
+            .collect();
+        let buffers: Vec<Aov> = outputs.iter().map(|(buffer, _)| *buffer).collect();
+        let options = RenderOptions {
+            verbosity: Verbosity::Quiet,
+            ..RenderOptions::default()
+        };
+        let images = render::render_buffers(&scene, &options, &buffers, None);
+        assert!(save_outputs(&images, &outputs, SaveOptions::default()));
+
+        let color = image::open(&outputs[0].1).unwrap().to_rgb8();
+        let depth = image::open(&outputs[1].1).unwrap().to_rgb8();
+        outputs
+            .iter()
+            .for_each(|(_, path)| fs::remove_file(path).unwrap());
+        assert!(color.pixels().any(|p| p[0] != p[2]));
+        assert!(depth.pixels().all(|p| p[0] == p[1] && p[1] == p[2]));
+    }
 }
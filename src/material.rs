@@ -1,4 +1,14 @@
-use crate::color::Color;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use image::RgbImage;
+
+use crate::algebra::Vector;
+use crate::color::{Color, WHITE};
+use crate::geometric::check_direction;
+use crate::verbosity::Verbosity;
 
 // TODO: use some set of predefined materials
 // lazy_static! {
@@ -21,11 +31,75 @@ use crate::color::Color;
 //         .unwrap();
 // }
 
+/// Material of an entity, inline or a reference to a named scene material.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum MaterialRef {
+    /// A reference to a named scene material, resolved with `Scene::resolve_materials`.
+    Ref {
+        /// Name of the scene material.
+        #[serde(rename = "ref")]
+        name: String,
+    },
+
+    /// An inline material.
+    Inline(Box<Material>),
+}
+
+impl MaterialRef {
+    /// Get the material.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this is a reference that has not been resolved.
+    pub fn material(&self) -> &Material {
+        match self {
+            MaterialRef::Inline(ref material) => material,
+            MaterialRef::Ref { ref name } => panic!("material reference '{}' not resolved", name),
+        }
+    }
+
+    /// Resolve a material reference to the named material in `materials`.
+    ///
+    /// Inline materials are kept as is. Fails if the referenced material does not exist.
+    pub fn resolve(&mut self, materials: &HashMap<String, Material>) -> Result<(), String> {
+        if let MaterialRef::Ref { ref name } = self {
+            match materials.get(name) {
+                Some(material) => *self = MaterialRef::Inline(Box::new(material.clone())),
+                None => return Err(format!("Unknown material reference: '{}'", name)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Load any external resources, if this material is resolved.
+    pub fn load<P: AsRef<Path>>(&mut self, workdir: P, verbosity: Verbosity) {
+        if let MaterialRef::Inline(ref mut material) = self {
+            material.load(workdir, verbosity);
+        }
+    }
+
+    /// Resolve relative resource paths against `dir`, if this material is inline.
+    pub fn rebase<P: AsRef<Path>>(&mut self, dir: P) {
+        if let MaterialRef::Inline(ref mut material) = self {
+            material.rebase(dir);
+        }
+    }
+
+    /// Validate this material if it is inline, returns a list of problems.
+    pub fn validate(&self) -> Vec<String> {
+        match self {
+            MaterialRef::Inline(ref material) => material.validate(),
+            MaterialRef::Ref { .. } => vec![],
+        }
+    }
+}
+
 /// Material type for an entity.
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Material {
-    /// Base material color.
-    pub color: Color,
+    /// Base material color, a plain color or texture.
+    pub color: Coloration,
 
     /// Material albedo value.
     pub albedo: f32,
@@ -33,23 +107,329 @@ pub struct Material {
     /// Material surface type.
     #[serde(default)]
     pub surface: Surface,
+
+    /// Light emitted by this material.
+    ///
+    /// Only lights other surfaces in path traced mode.
+    #[serde(default)]
+    pub emission: Color,
+
+    /// Tangent space normal map, perturbing the shading normal.
+    #[serde(default)]
+    pub normal_map: Option<Texture>,
+
+    /// Alpha cutout mask, for foliage and fences.
+    ///
+    /// Rays pass through the surface where the mask is darker than half gray.
+    #[serde(default)]
+    pub alpha: Option<Texture>,
+
+    /// Roughness map, scaling the roughness of a metal surface.
+    ///
+    /// The roughness is multiplied by the map luminance, black is a perfect mirror and white keeps
+    /// the full surface roughness.
+    #[serde(default)]
+    pub roughness_map: Option<Texture>,
+
+    /// Blinn-Phong specular highlight of lights on the diffuse surface.
+    #[serde(default)]
+    pub specular_highlight: Option<SpecularHighlight>,
 }
 
 impl Default for Material {
     fn default() -> Self {
         Material {
-            color: Color::new(1.0, 0.4, 0.0),
+            color: Coloration::Color(Color::new(1.0, 0.4, 0.0)),
             albedo: 0.5,
             surface: Surface::default(),
+            emission: Color::default(),
+            normal_map: None,
+            alpha: None,
+            roughness_map: None,
+            specular_highlight: None,
+        }
+    }
+}
+
+impl Material {
+    /// Load any external resources.
+    pub fn load<P: AsRef<Path>>(&mut self, workdir: P, verbosity: Verbosity) {
+        if let Coloration::Texture(ref mut t) = self.color {
+            t.load(workdir.as_ref(), verbosity);
+        }
+        if let Some(ref mut t) = self.normal_map {
+            t.load(workdir.as_ref(), verbosity);
+        }
+        if let Some(ref mut t) = self.alpha {
+            t.load(workdir.as_ref(), verbosity);
+        }
+        if let Some(ref mut t) = self.roughness_map {
+            t.load(workdir, verbosity);
+        }
+    }
+
+    /// Resolve relative resource paths against `dir`, instead of the scene working directory.
+    pub fn rebase<P: AsRef<Path>>(&mut self, dir: P) {
+        if let Coloration::Texture(ref mut t) = self.color {
+            t.path = dir.as_ref().join(&t.path).to_string_lossy().into_owned();
+        }
+        if let Some(ref mut t) = self.normal_map {
+            t.path = dir.as_ref().join(&t.path).to_string_lossy().into_owned();
+        }
+        if let Some(ref mut t) = self.alpha {
+            t.path = dir.as_ref().join(&t.path).to_string_lossy().into_owned();
+        }
+        if let Some(ref mut t) = self.roughness_map {
+            t.path = dir.as_ref().join(&t.path).to_string_lossy().into_owned();
+        }
+    }
+
+    /// Check whether the surface is cut out by the alpha mask at some texture coordinates.
+    ///
+    /// The texture coordinates are only computed when there is an alpha mask.
+    pub fn is_cut_out<F>(&self, texture_coords: F) -> bool
+    where
+        F: FnOnce() -> (f32, f32),
+    {
+        match self.alpha {
+            Some(ref alpha) => alpha.sample(texture_coords()).luminance() < 0.5,
+            None => false,
+        }
+    }
+
+    /// Get the factor the surface roughness is scaled by at some texture coordinates.
+    ///
+    /// This is `1` without a roughness map. The texture coordinates are only computed when there
+    /// is a roughness map.
+    pub fn roughness_scale<F>(&self, texture_coords: F) -> f32
+    where
+        F: FnOnce() -> (f32, f32),
+    {
+        match self.roughness_map {
+            Some(ref map) => map.sample(texture_coords()).luminance(),
+            None => 1.0,
         }
     }
+
+    /// Validate this material for physically sane parameters, returns a list of problems.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        check_unit(&mut problems, "albedo", self.albedo);
+        if let Some(ref highlight) = self.specular_highlight {
+            if !(highlight.power > 0.0 && highlight.power.is_finite()) {
+                problems.push(format!(
+                    "specular highlight power must be positive, got {}",
+                    highlight.power
+                ));
+            }
+            if !(highlight.intensity >= 0.0 && highlight.intensity.is_finite()) {
+                problems.push(format!(
+                    "specular highlight intensity must not be negative, got {}",
+                    highlight.intensity
+                ));
+            }
+        }
+        match self.surface {
+            Surface::Diffuse => {}
+            Surface::Specular { reflectivity } => {
+                check_unit(&mut problems, "reflectivity", reflectivity);
+            }
+            Surface::Metal {
+                roughness,
+                roughness_u,
+                roughness_v,
+                tangent,
+                ..
+            } => {
+                check_unit(&mut problems, "roughness", roughness);
+                if let Some(roughness_u) = roughness_u {
+                    check_unit(&mut problems, "roughness_u", roughness_u);
+                }
+                if let Some(roughness_v) = roughness_v {
+                    check_unit(&mut problems, "roughness_v", roughness_v);
+                }
+                if let Some(tangent) = tangent {
+                    check_direction(&mut problems, "tangent", tangent);
+                }
+            }
+            Surface::Transparent {
+                index,
+                transparency,
+                absorption,
+            } => {
+                if !(index >= 1.0 && index.is_finite()) {
+                    problems.push(format!(
+                        "refractive index must be at least 1, got {}",
+                        index
+                    ));
+                }
+                check_unit(&mut problems, "transparency", transparency);
+                let (r, g, b) = absorption.channels();
+                if !(r >= 0.0 && g >= 0.0 && b >= 0.0) {
+                    problems.push(format!(
+                        "absorption must not be negative, got {:?}",
+                        absorption
+                    ));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// Check whether a material parameter lies in `[0,1]`, report a problem if not.
+fn check_unit(problems: &mut Vec<String>, what: &str, value: f32) {
+    if !(0.0..=1.0).contains(&value) {
+        problems.push(format!("{} must be between 0 and 1, got {}", what, value));
+    }
+}
+
+/// A Blinn-Phong specular highlight.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SpecularHighlight {
+    /// Specular exponent, higher values give smaller and sharper highlights.
+    pub power: f32,
+
+    /// Highlight intensity, relative to the received light.
+    pub intensity: f32,
+}
+
+/// Coloration of a material, a plain color or a texture.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum Coloration {
+    /// A plain color.
+    Color(Color),
+
+    /// An image texture.
+    Texture(Texture),
+}
+
+impl Coloration {
+    /// Get the color at some texture coordinates.
+    ///
+    /// The texture coordinates are only computed when this is a texture.
+    pub fn color<F>(&self, texture_coords: F) -> Color
+    where
+        F: FnOnce() -> (f32, f32),
+    {
+        match self {
+            Coloration::Color(c) => *c,
+            Coloration::Texture(t) => t.sample(texture_coords()),
+        }
+    }
+}
+
+/// An image texture.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Texture {
+    /// Path to the texture image file to load.
+    pub path: String,
+
+    /// How texture coordinates outside `[0, 1]` are wrapped.
+    #[serde(default)]
+    pub wrap: Wrap,
+
+    /// The loaded texture image.
+    #[serde(skip)]
+    image: Option<Arc<RgbImage>>,
+}
+
+impl Texture {
+    /// Load the texture image.
+    pub fn load<P: AsRef<Path>>(&mut self, workdir: P, verbosity: Verbosity) {
+        // Determine absolute path for relative texture paths
+        let mut path = workdir.as_ref().to_path_buf();
+        path.push(&self.path);
+
+        match image::open(&path) {
+            Ok(image) => self.image = Some(Arc::new(image.to_rgb8())),
+            Err(err) if verbosity.is_normal() => {
+                eprintln!("Failed to load texture, ignoring: {}", err);
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Sample the texture color at the given texture coordinates.
+    ///
+    /// `(0, 0)` is the top left of the image. Uses bilinear filtering between the four nearest
+    /// texels, coordinates outside the image are wrapped by the wrap mode. White is returned if the
+    /// texture is not loaded.
+    pub fn sample(&self, (u, v): (f32, f32)) -> Color {
+        let image = match self.image {
+            Some(ref image) => image,
+            None => return *WHITE,
+        };
+        let (width, height) = image.dimensions();
+
+        // Find the nearest texels, texel centers are at half texel offsets
+        let x = u * width as f32 - 0.5;
+        let y = v * height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let texel = |x: i64, y: i64| {
+            let pixel = image
+                .get_pixel(self.wrap.apply(x, width), self.wrap.apply(y, height))
+                .0;
+            Color::new(
+                f32::from(pixel[0]) / 255.0,
+                f32::from(pixel[1]) / 255.0,
+                f32::from(pixel[2]) / 255.0,
+            )
+        };
+
+        let top = texel(x0, y0).lerp(texel(x0 + 1, y0), fx);
+        let bottom = texel(x0, y0 + 1).lerp(texel(x0 + 1, y0 + 1), fx);
+        top.lerp(bottom, fy)
+    }
+}
+
+/// Texture wrap mode, for texture coordinates outside `[0, 1]`.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Wrap {
+    /// Repeat the texture.
+    #[default]
+    Repeat,
+
+    /// Clamp to the texture edge.
+    Clamp,
+}
+
+impl Wrap {
+    /// Wrap a texel index into `[0, size)`.
+    pub(crate) fn apply(self, index: i64, size: u32) -> u32 {
+        match self {
+            Wrap::Repeat => index.rem_euclid(i64::from(size)) as u32,
+            Wrap::Clamp => index.clamp(0, i64::from(size) - 1) as u32,
+        }
+    }
+}
+
+impl PartialEq for Texture {
+    /// Textures are equal if they load the same image file.
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl fmt::Debug for Texture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Texture")
+            .field("path", &self.path)
+            .field("loaded", &self.image.is_some())
+            .finish()
+    }
 }
 
 /// Surface type for a material.
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Surface {
     /// A diffuse surface.
+    #[default]
     Diffuse,
 
     /// A specular/reflective surface.
@@ -58,6 +438,38 @@ pub enum Surface {
         reflectivity: f32,
     },
 
+    /// A metal/conductor surface.
+    ///
+    /// Reflects with a Fresnel term tinted by the metal color, such as gold or copper.
+    Metal {
+        /// Base reflectance color at normal incidence.
+        color: Color,
+
+        /// Surface roughness, should be in `(0,1)`, 0 is a perfect mirror.
+        #[serde(default)]
+        roughness: f32,
+
+        /// Roughness along the surface tangent for anisotropic reflections, `roughness` if not
+        /// set.
+        ///
+        /// Only used when path tracing.
+        #[serde(default)]
+        roughness_u: Option<f32>,
+
+        /// Roughness along the surface bitangent for anisotropic reflections, `roughness` if not
+        /// set.
+        ///
+        /// Only used when path tracing.
+        #[serde(default)]
+        roughness_v: Option<f32>,
+
+        /// Reference direction of the surface tangent, projected onto the surface.
+        ///
+        /// If not set, the tangent follows the `u` texture coordinate.
+        #[serde(default)]
+        tangent: Option<Vector>,
+    },
+
     /// A transparent surface.
     Transparent {
         /// Refractive index.
@@ -70,11 +482,114 @@ pub enum Surface {
         ///
         /// Should be in `(0,1)`, 0 is opaque, 1 is fully transparent.
         transparency: f32,
+
+        /// Absorption coefficient per color channel, per unit distance travelled inside.
+        ///
+        /// Light is attenuated following the Beer-Lambert law, the default absorbs nothing.
+        #[serde(default)]
+        absorption: Color,
     },
 }
 
-impl Default for Surface {
-    fn default() -> Self {
-        Self::Diffuse
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::Rgb;
+
+    #[test]
+    fn test_validate_material() {
+        let glass = Material {
+            color: Coloration::Color(*WHITE),
+            albedo: 0.18,
+            surface: Surface::Transparent {
+                index: 1.5,
+                transparency: 1.0,
+                absorption: Color::default(),
+            },
+            ..Material::default()
+        };
+        assert!(glass.validate().is_empty());
+
+        let mirror = Material {
+            surface: Surface::Specular { reflectivity: 5.0 },
+            ..Material::default()
+        };
+        assert_eq!(
+            mirror.validate(),
+            vec!["reflectivity must be between 0 and 1, got 5".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_texture_sample_texel_centers() {
+        let mut image = RgbImage::new(2, 2);
+        image.put_pixel(0, 0, Rgb([255, 0, 0]));
+        image.put_pixel(1, 0, Rgb([0, 255, 0]));
+        image.put_pixel(0, 1, Rgb([0, 0, 255]));
+        image.put_pixel(1, 1, Rgb([255, 255, 255]));
+        let texture = Texture {
+            path: String::new(),
+            wrap: Wrap::Repeat,
+            image: Some(Arc::new(image)),
+        };
+
+        assert_eq!(texture.sample((0.25, 0.25)).to_rgba().0, [255, 0, 0, 255]);
+        assert_eq!(texture.sample((0.75, 0.25)).to_rgba().0, [0, 255, 0, 255]);
+        assert_eq!(texture.sample((0.25, 0.75)).to_rgba().0, [0, 0, 255, 255]);
+
+        // Coordinates wrap around
+        assert_eq!(texture.sample((1.75, -0.75)).to_rgba().0, [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn test_roughness_map_scales_roughness() {
+        let mut image = RgbImage::new(2, 1);
+        image.put_pixel(0, 0, Rgb([0, 0, 0]));
+        image.put_pixel(1, 0, Rgb([255, 255, 255]));
+        let material = Material {
+            surface: Surface::Metal {
+                color: *WHITE,
+                roughness: 0.6,
+                roughness_u: None,
+                roughness_v: None,
+                tangent: None,
+            },
+            roughness_map: Some(Texture {
+                path: String::new(),
+                wrap: Wrap::Clamp,
+                image: Some(Arc::new(image)),
+            }),
+            ..Material::default()
+        };
+
+        // The black texel makes a mirror, the white texel keeps the full roughness
+        let roughness = |uv| 0.6 * material.roughness_scale(|| uv);
+        assert!(roughness((0.25, 0.5)) < 1e-6);
+        assert!((roughness((0.75, 0.5)) - 0.6).abs() < 1e-6);
+        assert_eq!(Material::default().roughness_scale(|| unreachable!()), 1.0);
+    }
+
+    #[test]
+    fn test_texture_sample_bilinear() {
+        let mut image = RgbImage::new(2, 1);
+        image.put_pixel(0, 0, Rgb([255, 0, 0]));
+        image.put_pixel(1, 0, Rgb([0, 255, 0]));
+        let mut texture = Texture {
+            path: String::new(),
+            wrap: Wrap::Repeat,
+            image: Some(Arc::new(image)),
+        };
+
+        // Halfway between the texel centers gives the average
+        let color = texture.sample((0.5, 0.5));
+        let difference = color - Color::new(0.5, 0.5, 0.0);
+        assert!((difference * difference).max_channel() < 1e-12);
+
+        // At the image edge, repeating blends with the opposite edge, clamping does not
+        let difference = texture.sample((0.0, 0.5)) - Color::new(0.5, 0.5, 0.0);
+        assert!((difference * difference).max_channel() < 1e-12);
+        texture.wrap = Wrap::Clamp;
+        assert_eq!(texture.sample((0.0, 0.5)).to_rgba().0, [255, 0, 0, 255]);
     }
 }
@@ -1,6 +1,28 @@
-use crate::algebra::{Identity, Vector};
-use crate::geometric::Entity;
-use crate::scene::Scene;
+use crate::algebra::Vector;
+use crate::geometric::{self, Aabb, Entity};
+use crate::material::Material;
+use crate::scene::{Projection, Scene};
+
+/// Type of a cast ray.
+///
+/// Counted separately for render statistics, and entities can be hidden from some types.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RayType {
+    /// A ray from the camera.
+    Primary = 0,
+
+    /// A reflected ray, off a specular, metal or transparent surface.
+    Reflection = 1,
+
+    /// A ray refracted through a transparent surface.
+    Refraction = 2,
+
+    /// An indirect diffuse ray in path traced mode.
+    Diffuse = 3,
+
+    /// A shadow or ambient occlusion ray, testing visibility.
+    Shadow = 4,
+}
 
 /// A 3 dimentoinal ray.
 #[derive(Copy, Clone, Debug)]
@@ -17,25 +39,51 @@ impl Ray {
 
     /// Create a prime ray from the given screen pixel positionray from the given screen pixel
     /// position.
+    ///
+    /// The camera field of view spans the shorter screen axis, so landscape, portrait and square
+    /// cameras are all supported. With an orthographic projection, all prime rays are parallel and
+    /// start on the sensor plane instead.
     pub fn new_prime(x: u32, y: u32, scene: &Scene) -> Self {
+        Self::new_prime_at(x, y, (0.5, 0.5), scene)
+    }
+
+    /// Create a prime ray through the given `offset` within a screen pixel.
+    ///
+    /// The offset is in `[0, 1)` for both axes, `(0.5, 0.5)` is the pixel center.
+    pub fn new_prime_at(x: u32, y: u32, offset: (f64, f64), scene: &Scene) -> Self {
         let camera = scene.camera;
 
-        // TODO: review these values
-        // TODO: is this assert needed?
-        assert!(camera.width > camera.height);
-        let fov_adjustment = (camera.fov.to_radians() / 2.0).tan();
-        let aspect_ratio = f64::from(camera.width) / f64::from(camera.height);
-        let sensor_x = (((f64::from(x) + 0.5) / f64::from(camera.width) * 2.0 - 1.0)
-            * aspect_ratio)
-            * fov_adjustment;
-        let sensor_y =
-            (1.0 - ((f64::from(y) + 0.5) / f64::from(camera.height)) * 2.0) * fov_adjustment;
-
-        // Construct the row
-        Self::new(
-            Vector::identity(),
-            Vector(sensor_x, sensor_y, -1.0).normalize(),
-        )
+        // Scale the longer axis by the aspect ratio
+        let width = f64::from(camera.width);
+        let height = f64::from(camera.height);
+        let (aspect_x, aspect_y) = if width >= height {
+            (width / height, 1.0)
+        } else {
+            (1.0, height / width)
+        };
+        let sensor_x = ((f64::from(x) + offset.0) / width * 2.0 - 1.0) * aspect_x;
+        let sensor_y = (1.0 - ((f64::from(y) + offset.1) / height) * 2.0) * aspect_y;
+
+        // Construct the row, oriented by the camera
+        match camera.projection {
+            Projection::Perspective => {
+                let fov_adjustment = (camera.fov.to_radians() / 2.0).tan();
+                Self::new(
+                    camera.position,
+                    camera
+                        .orient(Vector(
+                            sensor_x * fov_adjustment,
+                            sensor_y * fov_adjustment,
+                            -1.0,
+                        ))
+                        .normalize(),
+                )
+            }
+            Projection::Orthographic { scale } => Self::new(
+                camera.position + camera.orient(Vector(sensor_x * scale, sensor_y * scale, 0.0)),
+                camera.orient(Vector(0.0, 0.0, -1.0)).normalize(),
+            ),
+        }
     }
 
     /// Create a reflection ray.
@@ -55,11 +103,7 @@ impl Ray {
         intersection: Vector,
         bias: f64,
     ) -> Self {
-        Self::new(
-            intersection,
-            incident - (normal * incident.dot(normal) * 2.0),
-        )
-        .bias(bias)
+        Self::new(intersection, incident.reflect(normal)).bias(bias)
     }
 
     /// Create a transmission ray.
@@ -85,27 +129,16 @@ impl Ray {
         let mut ref_n = normal;
         let mut eta_t = f64::from(index);
         let mut eta_i = 1.0;
-        let mut i_dot_n = incident.dot(normal);
-        if i_dot_n < 0.0 {
-            // Outside the surface
-            i_dot_n = -i_dot_n;
-        } else {
+        if incident.dot(normal) >= 0.0 {
             // Inside the surface; invert the normal and swap the indices of refraction
             ref_n = -normal;
             eta_i = eta_t;
             eta_t = 1.0;
         }
 
-        let eta = eta_i / eta_t;
-        let k = 1.0 - (eta * eta) * (1.0 - i_dot_n * i_dot_n);
-        if k < 0.0 {
-            None
-        } else {
-            Some(Self::new(
-                intersection + (ref_n * -bias),
-                (ref_n * i_dot_n + incident) * eta - ref_n * k.sqrt(),
-            ))
-        }
+        incident
+            .refract(ref_n, eta_i / eta_t)
+            .map(|direction| Self::new(intersection + (ref_n * -bias), direction))
     }
 
     /// Bias ray origin by given length.
@@ -119,6 +152,75 @@ impl Ray {
     }
 }
 
+/// An affine transform from object space to world space.
+///
+/// Object space points are uniformly scaled, then translated.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct Transform {
+    /// Translation, the object space origin in world space.
+    pub translation: Vector,
+
+    /// Uniform scale factor.
+    pub scale: f64,
+}
+
+impl Transform {
+    /// Construct a new transform with the given `translation` and uniform `scale`.
+    pub fn new(translation: Vector, scale: f64) -> Self {
+        Self { translation, scale }
+    }
+
+    /// Transform an object space point to world space.
+    pub fn point_to_world(&self, point: Vector) -> Vector {
+        point * self.scale + self.translation
+    }
+
+    /// Transform a world space point to object space.
+    pub fn point_to_object(&self, point: Vector) -> Vector {
+        (point - self.translation) / self.scale
+    }
+
+    /// Transform an object space vector, such as an edge or derivative, to world space.
+    pub fn vector_to_world(&self, vector: Vector) -> Vector {
+        vector * self.scale
+    }
+
+    /// Transform a world space ray to object space.
+    ///
+    /// The ray direction is kept as is, so object space distances must be scaled back with
+    /// `distance_to_world`.
+    pub fn ray_to_object(&self, ray: &Ray) -> Ray {
+        Ray::new(self.point_to_object(ray.origin), ray.direction)
+    }
+
+    /// Transform an object space distance along a ray to world space.
+    pub fn distance_to_world(&self, distance: f64) -> f64 {
+        distance * self.scale
+    }
+
+    /// Transform an object space bounding box to world space.
+    pub fn aabb_to_world(&self, aabb: &Aabb) -> Aabb {
+        Aabb::new(self.point_to_world(aabb.min), self.point_to_world(aabb.max))
+    }
+
+    /// Combine with a transform from the space of a nested object to this object space.
+    ///
+    /// The result transforms from the nested object space to world space directly.
+    pub fn compose(&self, inner: &Transform) -> Transform {
+        Self::new(
+            self.point_to_world(inner.translation),
+            self.scale * inner.scale,
+        )
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::new(Vector(0.0, 0.0, 0.0), 1.0)
+    }
+}
+
 /// Intersection with an entity.
 ///
 /// This represents an intersection with `entity` from a ray.
@@ -130,8 +232,116 @@ pub struct Intersection<'a> {
     /// The hit point normal.
     pub normal: Vector,
 
+    /// Texture coordinates at the hit point, if known from the intersection.
+    ///
+    /// If not set, the texture coordinates are found with the entity texture mapping when needed.
+    pub uv: Option<(f32, f32)>,
+
+    /// Directions in which the texture coordinates increase at the hit point, if known from the
+    /// intersection.
+    pub texture_derivatives: Option<(Vector, Vector)>,
+
+    /// Barycentric `(u, v)` coordinates of the hit point on the intersected triangle, if the
+    /// entity is built from triangles.
+    pub barycentric: Option<(f64, f64)>,
+
+    /// Whether the ray hit the front, outer, face of the surface.
+    pub front_face: bool,
+
     /// The entity that was intersected.
     pub entity: &'a Entity,
+
+    /// Index of the scene entity that was intersected, the group `entity` is part of if any.
+    pub index: usize,
+
+    /// Transform from the object space of `entity` to world space.
+    ///
+    /// This is the identity, unless the entity is a child of a group.
+    pub transform: Transform,
+}
+
+impl<'a> Intersection<'a> {
+    /// Get a world space `hit` point in the object space of the intersected entity.
+    ///
+    /// Entity methods taking a point, such as its texture mapping, expect this space.
+    pub fn local(&self, hit: Vector) -> Vector {
+        self.transform.point_to_object(hit)
+    }
+
+    /// Get the material of the intersected surface.
+    pub fn material(&self) -> &'a Material {
+        self.entity
+            .material()
+            .expect("intersected entity has no surface")
+    }
+
+    /// Get the texture coordinates at the given world space `hit` point.
+    pub fn texture_coords(&self, hit: Vector) -> (f32, f32) {
+        self.uv
+            .or_else(|| self.entity.texture_coords(self.local(hit)))
+            .unwrap_or((0.0, 0.0))
+    }
+
+    /// Get the distance from the hit point to the nearest triangle edge, in barycentric units.
+    ///
+    /// Returns `None` if the intersected entity is not built from triangles.
+    pub fn edge_distance(&self) -> Option<f64> {
+        self.barycentric.map(|(u, v)| u.min(v).min(1.0 - u - v))
+    }
+
+    /// Get the tangent and bitangent at the given world space `hit` point, with the given unit
+    /// surface `normal`.
+    ///
+    /// See `Entity::tangent_frame`, the texture derivatives of the intersection are used if known.
+    pub fn tangent_frame(&self, hit: Vector, normal: Vector) -> (Vector, Vector) {
+        match self.texture_derivatives {
+            Some(derivatives) => geometric::tangent_frame(derivatives, normal),
+            None => self.entity.tangent_frame(self.local(hit), normal),
+        }
+    }
+}
+
+/// A ray hit on a geometric shape.
+#[derive(Copy, Clone, Debug)]
+pub struct Hit {
+    /// Distance to the hit point from the ray origin.
+    pub distance: f64,
+
+    /// The hit point surface normal, pointing out of the front face.
+    pub normal: Vector,
+
+    /// Texture coordinates at the hit point, if they fall out of the intersection test.
+    ///
+    /// Shapes with a texture mapping that is costly to evaluate leave this unset, it is only
+    /// needed for the closest hit.
+    pub uv: Option<(f32, f32)>,
+
+    /// Directions in which the texture coordinates increase at the hit point, if they fall out of
+    /// the intersection test.
+    pub texture_derivatives: Option<(Vector, Vector)>,
+
+    /// Barycentric `(u, v)` coordinates of the hit point on the hit triangle, if the shape is
+    /// built from triangles.
+    pub barycentric: Option<(f64, f64)>,
+
+    /// Whether the ray hit the front, outer, face of the surface.
+    pub front_face: bool,
+}
+
+impl Hit {
+    /// Construct a hit of `ray` at `distance`.
+    ///
+    /// The ray hits the front face if it goes against the outward `normal`.
+    pub fn new(ray: &Ray, distance: f64, normal: Vector, uv: Option<(f32, f32)>) -> Self {
+        Self {
+            distance,
+            normal,
+            uv,
+            texture_derivatives: None,
+            barycentric: None,
+            front_face: ray.direction.dot(normal) <= 0.0,
+        }
+    }
 }
 
 pub trait Intersectable {
@@ -139,6 +349,35 @@ pub trait Intersectable {
     ///
     /// This check whether the given `ray` intersects with this entity, and if there's an
     /// intersection the distance to the hit point from the ray origin along with the hit point
-    /// surface normal is returned.
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vector)>;
+    /// surface normal, texture coordinates and facing is returned.
+    fn intersect(&self, ray: &Ray) -> Option<Hit>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orthographic_prime_rays_parallel() {
+        let scene: Scene = serde_yaml::from_str(
+            r#"
+            camera:
+              width: 8
+              height: 6
+              projection: { type: orthographic, scale: 2 }
+            entities: []
+            lights: []
+            "#,
+        )
+        .unwrap();
+
+        let a = Ray::new_prime(0, 0, &scene);
+        let b = Ray::new_prime(7, 5, &scene);
+        assert!((a.direction - b.direction).magnitude() < 1e-12);
+        assert!((a.direction - Vector(0.0, 0.0, -1.0)).magnitude() < 1e-12);
+        assert!((a.origin - b.origin).magnitude() > 1.0);
+
+        // Origins span the sensor, scaled along the shorter axis
+        assert!((b.origin.1 - a.origin.1 + 2.0 * 2.0 * 5.0 / 6.0).abs() < 1e-12);
+    }
 }
@@ -0,0 +1,372 @@
+//! A minimal reader for Stanford `.ply` polygon files.
+//!
+//! Supports ASCII and binary files, reading vertex positions, normals, texture coordinates and
+//! faces. Faces with more than three vertices are triangulated as a fan, other elements and
+//! properties are skipped.
+
+use std::io::BufRead;
+
+/// Geometry read from a `.ply` file.
+#[derive(Debug, Default)]
+pub struct Ply {
+    /// Vertex positions, three per vertex.
+    pub positions: Vec<f64>,
+
+    /// Vertex normals, three per vertex, empty if the file has none.
+    pub normals: Vec<f64>,
+
+    /// Vertex texture coordinates, two per vertex, empty if the file has none.
+    pub texcoords: Vec<f32>,
+
+    /// Triangle vertex indices, three per triangle.
+    pub indices: Vec<u32>,
+}
+
+/// The encoding of the file body.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+/// A scalar property type.
+#[derive(Clone, Copy, Debug)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return Err(format!("unknown property type '{}'", name)),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+/// An element property, either a single scalar or a list of scalars.
+#[derive(Debug)]
+struct Property {
+    name: String,
+    count: Option<Scalar>,
+    value: Scalar,
+}
+
+/// An element declared in the header, such as `vertex` or `face`.
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Read a `.ply` file from the given reader.
+///
+/// Returns a human readable message if the file is invalid.
+pub fn read<R: BufRead>(reader: &mut R) -> Result<Ply, String> {
+    let (format, elements) = read_header(reader)?;
+    let mut body = Body::new(reader, format);
+    let mut ply = Ply::default();
+
+    for element in &elements {
+        let column = |name: &str| element.properties.iter().position(|p| p.name == name);
+        let positions = [column("x"), column("y"), column("z")];
+        let normals = [column("nx"), column("ny"), column("nz")];
+        let texcoords = match (column("u"), column("v")) {
+            (Some(u), Some(v)) => Some([u, v]),
+            _ => match (column("s"), column("t")) {
+                (Some(s), Some(t)) => Some([s, t]),
+                _ => None,
+            },
+        };
+        let indices = column("vertex_indices").or_else(|| column("vertex_index"));
+
+        for _ in 0..element.count {
+            let values = element
+                .properties
+                .iter()
+                .map(|p| body.property(p))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if element.name == "vertex" {
+                for i in positions.iter() {
+                    let i = i.ok_or("vertex is missing a position")?;
+                    ply.positions.push(values[i][0]);
+                }
+                if let [Some(x), Some(y), Some(z)] = normals {
+                    ply.normals
+                        .extend(&[values[x][0], values[y][0], values[z][0]]);
+                }
+                if let Some([u, v]) = texcoords {
+                    ply.texcoords.push(values[u][0] as f32);
+                    ply.texcoords.push(values[v][0] as f32);
+                }
+            } else if element.name == "face" {
+                let face = &values[indices.ok_or("face is missing vertex indices")?];
+                for i in 1..face.len().saturating_sub(1) {
+                    ply.indices
+                        .extend([face[0], face[i], face[i + 1]].iter().map(|&i| i as u32));
+                }
+            }
+        }
+    }
+
+    // Validate indices, the mesh builder indexes vertices directly
+    let vertices = ply.positions.len() / 3;
+    if let Some(i) = ply.indices.iter().find(|&&i| i as usize >= vertices) {
+        return Err(format!(
+            "face references vertex {}, but there are only {}",
+            i, vertices
+        ));
+    }
+    if ply.positions.is_empty() {
+        return Err("file has no vertices".into());
+    }
+
+    Ok(ply)
+}
+
+/// Read the header, returns the body format and the declared elements.
+fn read_header<R: BufRead>(reader: &mut R) -> Result<(Format, Vec<Element>), String> {
+    let mut line = String::new();
+    let mut next_line = |line: &mut String| -> Result<(), String> {
+        line.clear();
+        match reader.read_line(line) {
+            Ok(0) => Err("unexpected end of header".into()),
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
+    };
+
+    next_line(&mut line)?;
+    if line.trim() != "ply" {
+        return Err("missing 'ply' magic".into());
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    loop {
+        next_line(&mut line)?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["end_header"] => break,
+            ["format", kind, _] => {
+                format = Some(match *kind {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::LittleEndian,
+                    "binary_big_endian" => Format::BigEndian,
+                    _ => return Err(format!("unknown format '{}'", kind)),
+                })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("invalid element count '{}'", count))?,
+                properties: vec![],
+            }),
+            ["property", "list", count, value, name] => elements
+                .last_mut()
+                .ok_or("property declared before element")?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    count: Some(Scalar::parse(count)?),
+                    value: Scalar::parse(value)?,
+                }),
+            ["property", value, name] => elements
+                .last_mut()
+                .ok_or("property declared before element")?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    count: None,
+                    value: Scalar::parse(value)?,
+                }),
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(format!("invalid header line '{}'", line.trim())),
+        }
+    }
+
+    Ok((format.ok_or("missing format")?, elements))
+}
+
+/// Reads property values from the file body.
+struct Body<'a, R: BufRead> {
+    reader: &'a mut R,
+    format: Format,
+
+    /// Remaining words on the current ASCII line.
+    words: std::vec::IntoIter<String>,
+}
+
+impl<'a, R: BufRead> Body<'a, R> {
+    fn new(reader: &'a mut R, format: Format) -> Self {
+        Self {
+            reader,
+            format,
+            words: Vec::new().into_iter(),
+        }
+    }
+
+    /// Read the values of a single property.
+    fn property(&mut self, property: &Property) -> Result<Vec<f64>, String> {
+        match property.count {
+            None => Ok(vec![self.scalar(property.value)?]),
+            Some(count) => {
+                let count = self.scalar(count)? as usize;
+                (0..count).map(|_| self.scalar(property.value)).collect()
+            }
+        }
+    }
+
+    /// Read a single scalar value.
+    fn scalar(&mut self, scalar: Scalar) -> Result<f64, String> {
+        if self.format == Format::Ascii {
+            return self.word()?.parse().map_err(|_| "invalid number".into());
+        }
+
+        let mut bytes = [0u8; 8];
+        let bytes = &mut bytes[..scalar.size()];
+        self.reader
+            .read_exact(bytes)
+            .map_err(|_| "unexpected end of file".to_string())?;
+        if self.format == Format::BigEndian {
+            bytes.reverse();
+        }
+        Ok(match scalar {
+            Scalar::I8 => f64::from(bytes[0] as i8),
+            Scalar::U8 => f64::from(bytes[0]),
+            Scalar::I16 => f64::from(i16::from_le_bytes([bytes[0], bytes[1]])),
+            Scalar::U16 => f64::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+            Scalar::I32 => f64::from(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            Scalar::U32 => f64::from(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            Scalar::F32 => f64::from(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            Scalar::F64 => f64::from_le_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+            ]),
+        })
+    }
+
+    /// Read the next whitespace separated word of an ASCII body.
+    fn word(&mut self) -> Result<String, String> {
+        loop {
+            if let Some(word) = self.words.next() {
+                return Ok(word);
+            }
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return Err("unexpected end of file".into()),
+                Ok(_) => {}
+                Err(err) => return Err(err.to_string()),
+            }
+            self.words = line
+                .split_whitespace()
+                .map(|w| w.to_string())
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_ascii_quad() {
+        let source = "ply
+format ascii 1.0
+comment a unit quad
+element vertex 4
+property float x
+property float y
+property float z
+property float u
+property float v
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 0 0
+1 0 0 1 0
+1 1 0 1 1
+0 1 0 0 1
+4 0 1 2 3
+";
+        let ply = read(&mut source.as_bytes()).unwrap();
+        assert_eq!(ply.positions.len(), 12);
+        assert!(ply.normals.is_empty());
+        assert_eq!(ply.texcoords, vec![0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 0.0, 1.0]);
+        assert_eq!(ply.indices, vec![0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn test_read_binary_triangle() {
+        let mut source = b"ply
+format binary_little_endian 1.0
+element vertex 3
+property float x
+property float y
+property float z
+element face 1
+property list uchar uint vertex_indices
+end_header
+"
+        .to_vec();
+        for v in &[0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            source.extend(&v.to_le_bytes());
+        }
+        source.push(3);
+        for i in &[0u32, 1, 2] {
+            source.extend(&i.to_le_bytes());
+        }
+
+        let ply = read(&mut source.as_slice()).unwrap();
+        assert_eq!(
+            ply.positions,
+            vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+        );
+        assert_eq!(ply.indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_read_invalid_index() {
+        let source = "ply
+format ascii 1.0
+element vertex 1
+property float x
+property float y
+property float z
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0
+3 0 1 2
+";
+        assert!(read(&mut source.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+//! Live preview window, showing a render as it fills in.
+//!
+//! Only built with the `preview` feature. The window is created and updated on the main thread,
+//! as not all platforms support windows on other threads.
+
+use std::panic;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use minifb::{Key, Window, WindowOptions};
+
+use raytrace::render::LiveImage;
+use raytrace::verbosity::Verbosity;
+
+/// Time between window updates.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A window showing a live image.
+pub struct Preview {
+    /// The preview window.
+    window: Window,
+
+    /// The image shown in the window.
+    image: Arc<LiveImage>,
+
+    /// Pixel buffer drawn to the window, in the `0RGB` format.
+    buffer: Vec<u32>,
+}
+
+impl Preview {
+    /// Open a window showing the given live `image`.
+    ///
+    /// Returns `None` and shows a warning if the window cannot be opened.
+    pub fn open(image: Arc<LiveImage>, verbosity: Verbosity) -> Option<Self> {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        match Window::new(
+            "raytrace-rs preview",
+            width,
+            height,
+            WindowOptions::default(),
+        ) {
+            Ok(window) => Some(Self {
+                window,
+                image,
+                buffer: vec![0; width * height],
+            }),
+            Err(err) => {
+                if verbosity.is_normal() {
+                    eprintln!("Warning: failed to open preview window: {}", err);
+                }
+                None
+            }
+        }
+    }
+
+    /// Run `render` on a separate thread, updating the window on this thread until it is done.
+    ///
+    /// Closing the window early does not stop the render.
+    pub fn show_while<T, F>(&mut self, render: F) -> T
+    where
+        F: FnOnce() -> T + Send,
+        T: Send,
+    {
+        thread::scope(|scope| {
+            let render = scope.spawn(render);
+            while !render.is_finished() {
+                self.update();
+                thread::sleep(UPDATE_INTERVAL);
+            }
+            render
+                .join()
+                .unwrap_or_else(|err| panic::resume_unwind(err))
+        })
+    }
+
+    /// Keep showing the final image until the window is closed, or escape is pressed.
+    pub fn wait_until_closed(mut self) {
+        while self.update() && !self.window.is_key_down(Key::Escape) {
+            thread::sleep(UPDATE_INTERVAL);
+        }
+    }
+
+    /// Draw the current image to the window.
+    ///
+    /// Returns `false` if the window is closed.
+    fn update(&mut self) -> bool {
+        if !self.window.is_open() {
+            return false;
+        }
+        self.image.read_0rgb(&mut self.buffer);
+        let (width, height) = (self.image.width() as usize, self.image.height() as usize);
+        self.window
+            .update_with_buffer(&self.buffer, width, height)
+            .is_ok()
+    }
+}
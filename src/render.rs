@@ -1,226 +1,4103 @@
+use std::cell::RefCell;
 use std::f32::consts::PI;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Stdout, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Mutex;
+use std::sync::{Arc, OnceLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use image::{DynamicImage, GenericImage, Rgba};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, Pixel, RgbImage, Rgba};
 use pbr::ProgressBar;
+use rand::seq::SliceRandom;
+use rand::Rng;
 use rayon::prelude::*;
+use took::Timer;
 
-use crate::algebra::Vector;
-use crate::color::{Color, BLACK};
-use crate::geometric::Entity;
+use crate::algebra::{Identity, Vector};
+use crate::color::{Color, BLACK, GRAY, WHITE};
+use crate::geometric::Aabb;
+use crate::irradiance::{ProbeGrid, SphericalHarmonics};
+use crate::light::{Light, SkyLight};
 use crate::material::Surface;
 use crate::math::*;
-use crate::scene::Scene;
+use crate::rng::{self, random};
+use crate::sampling;
+use crate::scene::{set_shutter_time, Camera, Fit, RenderMode, Scene};
+use crate::verbosity::Verbosity;
+
+/// Relative shadow ray bias, scaled by the hit point magnitude.
+const SHADOW_EPSILON: f64 = 1e-9;
+
+/// The smallest GGX roughness alpha, sharper distributions are numerically unstable.
+const GGX_MIN_ALPHA: f64 = 1e-4;
+
+/// Pixel radius of the denoise filter.
+const DENOISE_RADIUS: i64 = 3;
+
+/// Spatial standard deviation of the denoise filter, in pixels.
+const DENOISE_SIGMA_SPATIAL: f32 = 2.0;
+
+/// Normal similarity falloff of the denoise filter.
+const DENOISE_SIGMA_NORMAL: f64 = 0.05;
+
+/// Relative depth standard deviation of the denoise filter.
+const DENOISE_SIGMA_DEPTH: f64 = 0.05;
+
+/// Width of wireframe edge lines, in barycentric units of a triangle.
+const WIREFRAME_EDGE_WIDTH: f64 = 0.03;
+
+/// Magic bytes at the start of a progressive render checkpoint file.
+const CHECKPOINT_MAGIC: &[u8; 8] = b"RTCKPT1\n";
+
+/// Total number of rays cast, including primary, secondary and shadow rays.
+static RAYS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of rays cast per ray type, indexed by `RayType`.
+static RAYS_BY_TYPE: [AtomicU64; 5] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Number of rays cast, per ray type.
+///
+/// Counters are global, so counts include rays of renders running concurrently.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RayCounts {
+    /// Number of primary rays.
+    pub primary: u64,
+
+    /// Number of reflection rays.
+    pub reflection: u64,
+
+    /// Number of refraction rays.
+    pub refraction: u64,
+
+    /// Number of indirect diffuse rays.
+    pub diffuse: u64,
+
+    /// Number of shadow rays.
+    pub shadow: u64,
+}
+
+impl RayCounts {
+    /// Get the number of rays cast so far.
+    pub fn now() -> Self {
+        let count = |kind: RayType| RAYS_BY_TYPE[kind as usize].load(Ordering::Relaxed);
+        RayCounts {
+            primary: count(RayType::Primary),
+            reflection: count(RayType::Reflection),
+            refraction: count(RayType::Refraction),
+            diffuse: count(RayType::Diffuse),
+            shadow: count(RayType::Shadow),
+        }
+    }
+
+    /// Get the number of rays cast since the `earlier` counts.
+    pub fn since(self, earlier: RayCounts) -> Self {
+        RayCounts {
+            primary: self.primary.saturating_sub(earlier.primary),
+            reflection: self.reflection.saturating_sub(earlier.reflection),
+            refraction: self.refraction.saturating_sub(earlier.refraction),
+            diffuse: self.diffuse.saturating_sub(earlier.diffuse),
+            shadow: self.shadow.saturating_sub(earlier.shadow),
+        }
+    }
+
+    /// The total number of rays, of all types.
+    pub fn total(&self) -> u64 {
+        self.primary + self.reflection + self.refraction + self.diffuse + self.shadow
+    }
+}
+
+/// Statistics of a render, for benchmarking.
+#[derive(Clone, Debug, Serialize)]
+pub struct RenderStats {
+    /// Total number of rays cast.
+    pub rays: u64,
+
+    /// Number of primary rays cast.
+    pub primary_rays: u64,
+
+    /// Number of reflection rays cast.
+    pub reflection_rays: u64,
+
+    /// Number of refraction rays cast.
+    pub refraction_rays: u64,
+
+    /// Number of indirect diffuse rays cast.
+    pub diffuse_rays: u64,
+
+    /// Number of shadow rays cast.
+    pub shadow_rays: u64,
+
+    /// Wall clock render time in seconds.
+    pub render_time: f64,
+
+    /// Number of triangles in the scene.
+    pub triangles: u64,
+
+    /// Effective throughput in million rays per second.
+    pub mrays_per_second: f64,
+}
+
+impl RenderStats {
+    /// Build render statistics from the rays cast during a render taking `render_time`.
+    pub fn new(rays: RayCounts, render_time: Duration, triangles: u64) -> Self {
+        let secs = render_time.as_secs_f64();
+        RenderStats {
+            rays: rays.total(),
+            primary_rays: rays.primary,
+            reflection_rays: rays.reflection,
+            refraction_rays: rays.refraction,
+            diffuse_rays: rays.diffuse,
+            shadow_rays: rays.shadow,
+            render_time: secs,
+            triangles,
+            mrays_per_second: if secs > 0.0 {
+                rays.total() as f64 / secs / 1e6
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Timing summary of rendering a scene a number of times, for benchmarking.
+#[derive(Clone, Debug)]
+pub struct Benchmark {
+    /// Duration of each run, in order.
+    pub runs: Vec<Duration>,
+
+    /// Total number of rays cast over all runs.
+    pub rays: u64,
+}
+
+impl Benchmark {
+    /// The fastest run.
+    pub fn min(&self) -> Duration {
+        self.runs.iter().min().copied().unwrap_or_default()
+    }
+
+    /// The slowest run.
+    pub fn max(&self) -> Duration {
+        self.runs.iter().max().copied().unwrap_or_default()
+    }
+
+    /// The mean run duration.
+    pub fn mean(&self) -> Duration {
+        if self.runs.is_empty() {
+            return Duration::default();
+        }
+        self.total() / self.runs.len() as u32
+    }
+
+    /// The median run duration, the mean of the two middle runs for an even number of runs.
+    pub fn median(&self) -> Duration {
+        let mut runs = self.runs.clone();
+        runs.sort();
+        match runs.len() {
+            0 => Duration::default(),
+            n if n % 2 == 1 => runs[n / 2],
+            n => (runs[n / 2 - 1] + runs[n / 2]) / 2,
+        }
+    }
+
+    /// Millions of rays cast per second over all runs.
+    pub fn mrays_per_second(&self) -> f64 {
+        let secs = self.total().as_secs_f64();
+        if secs > 0.0 {
+            self.rays as f64 / secs / 1e6
+        } else {
+            0.0
+        }
+    }
+
+    /// The total duration of all runs.
+    fn total(&self) -> Duration {
+        self.runs.iter().sum()
+    }
+}
+
+/// An output buffer of a render, the shaded scene or an auxiliary buffer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Aov {
+    /// The shaded scene.
+    Color,
+
+    /// Surface normal at the first hit, mapped from `[-1,1]` to `[0,255]` per channel.
+    Normal,
+
+    /// Distance to the first hit, normalized to the farthest hit in the image.
+    Depth,
+}
+
+/// The pixel format of rendered images.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum PixelFormat {
+    /// 8 bits per channel.
+    #[default]
+    Rgb8,
+
+    /// 16 bits per channel, preserving smooth gradients.
+    Rgb16,
+}
+
+/// The order in which pixels are traced.
+///
+/// Each pixel is seeded by its position, so the order does not change the render.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum PixelOrder {
+    /// Tiles row by row and pixels in a tile row by row, progressive passes column by column.
+    #[default]
+    Linear,
+
+    /// Along a Morton (Z-order) curve, tracing nearby pixels close together in time for better
+    /// cache locality.
+    Morton,
+}
+
+/// Options for rendering a scene.
+#[derive(Clone, Debug, Default)]
+pub struct RenderOptions {
+    /// Auxiliary buffer to render instead of the shaded scene.
+    pub aov: Option<Aov>,
+
+    /// Verbosity level for warnings.
+    pub verbosity: Verbosity,
+
+    /// Whether to denoise the render.
+    pub denoise: bool,
+
+    /// Supersampling factor, renders at this multiple of the width and height and downsamples.
+    pub ssaa: Option<u32>,
+
+    /// Image to write pixels to as they are rendered, to preview the render while it fills in.
+    pub live_image: Option<Arc<LiveImage>>,
+
+    /// Pixel format of the rendered images.
+    pub pixel_format: PixelFormat,
+
+    /// Order in which pixels are traced.
+    pub pixel_order: PixelOrder,
+}
+
+/// The traced result of a single pixel.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Fragment {
+    /// Observed color.
+    pub color: Color,
+
+    /// Distance to the first hit, `None` if the primary ray hit nothing.
+    pub distance: Option<f64>,
+
+    /// Surface normal at the first hit, `None` if the primary ray hit nothing.
+    pub normal: Option<Vector>,
+}
+
+/// A render progress callback, invoked with the number of completed and total traced pixels.
+///
+/// It is invoked from the render threads, while rendering. Supersampled renders count the pixels
+/// traced at the supersampled size, so the total is the output pixel count times the square of
+/// the supersampling factor. See `RenderProgress` to show a progress bar.
+pub type ProgressFn<'a> = &'a (dyn Fn(u64, u64) + Sync);
 
 /// Render the given scene.
 ///
-/// This renders the given scene to a newly created dynamic image.
-pub fn render(scene: &Scene, show_progress: bool) -> DynamicImage {
+/// This renders the given scene to a newly created dynamic image, with the given `options`. The
+/// `progress` callback is invoked periodically if given.
+pub fn render(
+    scene: &Scene,
+    options: &RenderOptions,
+    progress: Option<ProgressFn<'_>>,
+) -> DynamicImage {
+    let buffer = options.aov.unwrap_or(Aov::Color);
+    render_buffers(scene, options, &[buffer], progress).remove(0)
+}
+
+/// Render the given scene to several output buffers at once.
+///
+/// The scene is traced a single time, each of the `buffers` is mapped from the same traced
+/// fragments. Returns an image for each buffer, in order. The `aov` option is ignored.
+pub fn render_buffers(
+    scene: &Scene,
+    options: &RenderOptions,
+    buffers: &[Aov],
+    progress: Option<ProgressFn<'_>>,
+) -> Vec<DynamicImage> {
     let camera = scene.camera;
+    let fragments = match options.ssaa {
+        Some(factor) if factor > 1 => {
+            let supersampled = supersampled(scene, factor);
+            let fragments = render_denoised(&supersampled, options, progress);
+            downsample(&camera, &fragments, factor)
+        }
+        _ => render_denoised(scene, options, progress),
+    };
 
-    // Warn if there are no lights
-    if scene.lights.is_empty() {
-        eprintln!("Warning: no lights in scene, you won't be able to see anything");
+    buffers
+        .iter()
+        .map(|&buffer| {
+            let colors = buffer_colors(&fragments, buffer);
+            let dither = buffer == Aov::Color && scene.dither;
+            fit_output(
+                image_from_colors(&camera, colors, options.pixel_format, dither),
+                scene,
+            )
+        })
+        .collect()
+}
+
+/// Render the given scene as a binary PPM image, streaming rows to `out` as they finish.
+///
+/// Rows are rendered in bands of `scene.tile_size` rows, and written in order. Pixels match those
+/// of `render`, but adaptive anti-aliasing and the output resolution are not applied. Progress is
+/// never shown as the progress bar writes to stdout, and the `aov`, `denoise`, `ssaa`,
+/// `live_image` and `pixel_format` options are ignored.
+pub fn render_stream<W: Write>(
+    scene: &Scene,
+    options: &RenderOptions,
+    out: &mut W,
+) -> io::Result<()> {
+    let camera = scene.camera;
+    let verbosity = options.verbosity;
+    warn_unlit(scene, verbosity);
+    if (scene.max_samples > 1 || scene.output.is_some()) && verbosity.is_normal() {
+        eprintln!(
+            "Warning: adaptive anti-aliasing and output resolution are not applied when streaming"
+        );
     }
 
-    // Set up a progress bar if we should show progress
-    let count = camera.pixels() as u64;
-    let mut pb = None;
-    let mut progress = None;
-    if show_progress {
-        let thread_progress = Arc::new(AtomicU64::new(0));
-        let thread_pb = Arc::new(Mutex::new(ProgressBar::new(camera.pixels() as u64)));
-        pb = Some(thread_pb.clone());
-        progress = Some(thread_progress.clone());
-        thread::spawn(move || loop {
-            // Get the current progress value, update progress bar
-            let value = thread_progress.load(Ordering::Relaxed);
-            if let Ok(mut pb) = thread_pb.lock() {
-                pb.set(value);
-            }
+    write!(out, "P6\n{} {}\n255\n", camera.width, camera.height)?;
+    let band = scene.tile_size.max(1);
+    for y in (0..camera.height).step_by(band as usize) {
+        // Trace the band in parallel, seeded like whole image renders
+        let rows = band.min(camera.height - y);
+        let pixels: Vec<[u8; 3]> = (0..rows * camera.width)
+            .into_par_iter()
+            .map(|j| {
+                let (x, y) = (j % camera.width, y + j / camera.width);
+                let i = u64::from(x) * u64::from(camera.height) + u64::from(y);
+                rng::seed(scene.seed ^ i);
+                let fragment = trace_primary(scene, &Ray::new_prime(x, y, scene), None);
+                let [r, g, b, _] = quantize(fragment.color, (x, y), scene.dither).0;
+                [r, g, b]
+            })
+            .collect();
+        out.write_all(&pixels.concat())?;
+        out.flush()?;
+    }
 
-            // Stop when done
-            if value >= count {
-                break;
-            }
+    Ok(())
+}
 
-            thread::sleep(Duration::from_millis(250));
-        });
+/// A ray cast while tracing a single pixel with `trace_pixel`.
+#[derive(Copy, Clone, Debug)]
+pub struct TracedRay {
+    /// The kind of ray.
+    pub kind: RayType,
+
+    /// Recursion depth of the ray, zero for the primary ray.
+    pub depth: u32,
+
+    /// Ray origin.
+    pub origin: Vector,
+
+    /// Ray direction.
+    pub direction: Vector,
+
+    /// Index of the hit entity in the scene, `None` if the ray hit nothing.
+    pub entity: Option<usize>,
+
+    /// Distance to the hit, `None` if the ray hit nothing.
+    pub distance: Option<f64>,
+
+    /// Color observed along the ray.
+    pub color: Color,
+}
+
+/// The rays recorded while tracing a single pixel with `trace_pixel`, `None` if not recording.
+type Tracer<'a> = Option<&'a RefCell<Vec<TracedRay>>>;
+
+/// Trace the single pixel at `(x, y)`, and get the rays cast for it, for debugging.
+///
+/// Rays are listed in the order they are cast, each followed by the rays cast from its hit, so
+/// they form a tree by depth. Shadow rays are not included. The pixel is seeded like in whole image
+/// renders, adaptive anti-aliasing is not applied.
+pub fn trace_pixel(scene: &Scene, x: u32, y: u32) -> Vec<TracedRay> {
+    let trace = RefCell::new(vec![]);
+    let i = u64::from(x) * u64::from(scene.camera.height) + u64::from(y);
+    rng::seed(scene.seed ^ i);
+    trace_primary(scene, &Ray::new_prime(x, y, scene), Some(&trace));
+    trace.into_inner()
+}
+
+/// Record a ray of the given `kind` cast at `depth` with the `tracer`, if any.
+///
+/// Returns the index of the record, to complete it with `trace_result` once observed.
+fn trace_ray(tracer: Tracer, kind: RayType, depth: Depth, ray: &Ray) -> Option<usize> {
+    let mut trace = tracer?.borrow_mut();
+    trace.push(TracedRay {
+        kind,
+        depth: depth.total,
+        origin: ray.origin,
+        direction: ray.direction,
+        entity: None,
+        distance: None,
+        color: *BLACK,
+    });
+    Some(trace.len() - 1)
+}
+
+/// Complete the ray `record` from `trace_ray` with its `intersection` and observed `color`.
+fn trace_result(
+    tracer: Tracer,
+    record: Option<usize>,
+    intersection: Option<&Intersection>,
+    color: Color,
+) {
+    if let (Some(tracer), Some(index)) = (tracer, record) {
+        let traced = &mut tracer.borrow_mut()[index];
+        traced.entity = intersection.map(|i| i.index);
+        traced.distance = intersection.map(|i| i.distance);
+        traced.color = color;
     }
+}
 
-    // Create a pixelmap of pixels
-    let pixels: Vec<Rgba<u8>> = (0..count as u32)
-        .into_par_iter()
-        .map(|i| (i / scene.camera.height, i % scene.camera.height))
-        .map(|(x, y)| {
-            let ray = Ray::new_prime(x, y, scene);
-            let color = observe_ray(scene, &ray, 0).to_rgba();
+/// Map traced fragments of the `scene` to the output pixels of the given `buffer`.
+fn buffer_colors(fragments: &[Fragment], buffer: Aov) -> Vec<Color> {
+    match buffer {
+        Aov::Color => fragments.iter().map(|f| f.color).collect(),
+        Aov::Normal => fragments
+            .iter()
+            .map(|f| match f.normal {
+                Some(n) => Color::new(
+                    ((n.0 + 1.0) / 2.0) as f32,
+                    ((n.1 + 1.0) / 2.0) as f32,
+                    ((n.2 + 1.0) / 2.0) as f32,
+                ),
+                None => *BLACK,
+            })
+            .collect(),
+        Aov::Depth => {
+            let max = fragments
+                .iter()
+                .filter_map(|f| f.distance)
+                .fold(0.0, f64::max);
+            fragments
+                .iter()
+                .map(|f| match f.distance {
+                    Some(d) if max > 0.0 => {
+                        let d = (d / max) as f32;
+                        Color::new(d, d, d)
+                    }
+                    _ => *WHITE,
+                })
+                .collect()
+        }
+    }
+}
 
-            // Update the progress
-            if let Some(progress) = progress.as_ref() {
-                progress.fetch_add(1, Ordering::Relaxed);
-            }
+/// Render the given scene `runs` times and measure each run, discarding the renders.
+///
+/// Progress, preview and warnings are not shown while benchmarking.
+pub fn benchmark(scene: &Scene, options: &RenderOptions, runs: u32) -> Benchmark {
+    let options = RenderOptions {
+        verbosity: Verbosity::Quiet,
+        live_image: None,
+        ..options.clone()
+    };
+    benchmark_with(runs, || {
+        render(scene, &options, None);
+    })
+}
 
-            color
+/// Invoke `render` `runs` times and measure the duration and rays cast of each run.
+fn benchmark_with<F: FnMut()>(runs: u32, mut render: F) -> Benchmark {
+    let rays = RayCounts::now();
+    let runs = (0..runs)
+        .map(|_| {
+            let timer = Timer::new();
+            render();
+            timer.took().into_std()
         })
         .collect();
+    Benchmark {
+        runs,
+        rays: RayCounts::now().since(rays).total(),
+    }
+}
+
+/// Fit a rendered image into the scene output resolution.
+///
+/// The image is resampled, letterboxed or cropped based on the output fit. Returns the image as
+/// is if the scene has no output resolution.
+pub fn fit_output(image: DynamicImage, scene: &Scene) -> DynamicImage {
+    let output = match scene.output {
+        Some(ref output) => output,
+        None => return image,
+    };
+    let (width, height) = image.dimensions();
+    if (width, height) == (output.width, output.height) {
+        return image;
+    }
 
-    // Finish the progress bar
-    if let Some(pb) = pb {
-        pb.lock().unwrap().finish();
+    let scale_x = output.width as f64 / width as f64;
+    let scale_y = output.height as f64 / height as f64;
+    let scaled = |scale: f64| {
+        (
+            ((width as f64 * scale).round() as u32).max(1),
+            ((height as f64 * scale).round() as u32).max(1),
+        )
+    };
+    match output.fit {
+        Fit::Stretch => image.resize_exact(output.width, output.height, FilterType::Triangle),
+        Fit::Contain => {
+            let (w, h) = scaled(scale_x.min(scale_y));
+            let (w, h) = (w.min(output.width), h.min(output.height));
+            let image = image.resize_exact(w, h, FilterType::Triangle);
+            let (x, y) = ((output.width - w) / 2, (output.height - h) / 2);
+            match image {
+                DynamicImage::ImageRgb16(image) => {
+                    let fill = output.fill.to_rgb16();
+                    let mut canvas = ImageBuffer::from_pixel(output.width, output.height, fill);
+                    imageops::overlay(&mut canvas, &image, x, y);
+                    DynamicImage::ImageRgb16(canvas)
+                }
+                image => {
+                    let fill = output.fill.to_rgba().to_rgb();
+                    let mut canvas = RgbImage::from_pixel(output.width, output.height, fill);
+                    imageops::overlay(&mut canvas, &image.to_rgb8(), x, y);
+                    DynamicImage::ImageRgb8(canvas)
+                }
+            }
+        }
+        Fit::Cover => {
+            let (w, h) = scaled(scale_x.max(scale_y));
+            let (w, h) = (w.max(output.width), h.max(output.height));
+            image.resize_exact(w, h, FilterType::Triangle).crop_imm(
+                (w - output.width) / 2,
+                (h - output.height) / 2,
+                output.width,
+                output.height,
+            )
+        }
     }
+}
 
-    // Build the dynamic image from the pixels
+/// Build an image in the given pixel `format` from colors in column major order.
+///
+/// 8-bit images are dithered if `dither` is set, 16-bit images are precise enough without.
+fn image_from_colors(
+    camera: &Camera,
+    colors: Vec<Color>,
+    format: PixelFormat,
+    dither: bool,
+) -> DynamicImage {
     // TODO: find more efficient method, render directly to image buffer
-    pixels
+    let image = match format {
+        PixelFormat::Rgb8 => DynamicImage::new_rgb8(camera.width, camera.height),
+        PixelFormat::Rgb16 => DynamicImage::new_rgb16(camera.width, camera.height),
+    };
+    colors
         .into_iter()
         .enumerate()
-        .map(|(i, pixel)| {
-            (
-                (i as u32) / camera.height,
-                (i as u32) % camera.height,
-                pixel,
-            )
+        .map(|(i, color)| {
+            let (x, y) = pixel_position(camera, i as u64);
+            (x, y, color)
+        })
+        .fold(image, |mut image, (x, y, color)| {
+            match image {
+                DynamicImage::ImageRgb16(ref mut buffer) => {
+                    buffer.put_pixel(x, y, color.to_rgb16())
+                }
+                _ => image.put_pixel(x, y, quantize(color, (x, y), dither)),
+            }
+            image
         })
-        .fold(
-            DynamicImage::new_rgb8(camera.width, camera.height),
-            |mut image, (x, y, pixel)| {
-                image.put_pixel(x, y, pixel);
-                image
-            },
-        )
 }
 
-/// Cast a ray in the scene, get observed color.
+/// Accumulated samples of a progressive render.
 ///
-/// A current depth should be given to limit ray recursion.
-/// For prime rays, simply give a depth of `0`.
-fn observe_ray(scene: &Scene, ray: &Ray, depth: u32) -> Color {
-    // We're just seeing black if max ray recursion is reached
-    if depth >= scene.depth {
-        return *BLACK;
+/// Holds the sum of all sampled colors for each pixel in column major order, along with the
+/// number of samples taken per pixel. It can be saved to a checkpoint file, and loaded again to
+/// continue rendering more samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Accumulation {
+    /// Image width in pixels.
+    width: u32,
+
+    /// Image height in pixels.
+    height: u32,
+
+    /// Number of samples taken per pixel.
+    samples: u32,
+
+    /// Sum of the sampled colors per pixel.
+    sum: Vec<Color>,
+}
+
+impl Accumulation {
+    /// Construct an empty accumulation for the image of `camera`.
+    pub fn new(camera: &Camera) -> Self {
+        Accumulation {
+            width: camera.width,
+            height: camera.height,
+            samples: 0,
+            sum: vec![*BLACK; camera.pixels() as usize],
+        }
     }
 
-    // Find ray intersection, get intersection color
-    scene
-        .intersect(&ray)
-        .map(|i| observe_intersection(scene, &ray, &i, depth))
-        .unwrap_or(*BLACK)
+    /// Get the number of samples taken per pixel.
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Check whether this accumulation matches the image size of `camera`.
+    pub fn fits(&self, camera: &Camera) -> bool {
+        self.width == camera.width && self.height == camera.height
+    }
+
+    /// Build an image in the given pixel `format` from the mean color of each pixel.
+    ///
+    /// 8-bit images are dithered if `dither` is set.
+    pub fn to_image(&self, format: PixelFormat, dither: bool) -> DynamicImage {
+        let camera = Camera::new(self.width, self.height);
+        let scale = 1.0 / self.samples.max(1) as f32;
+        let colors = self.sum.iter().map(|c| *c * scale).collect();
+        image_from_colors(&camera, colors, format, dither)
+    }
+
+    /// Load an accumulation from a checkpoint file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(invalid("not a render checkpoint file"));
+        }
+
+        let mut read_u32 = || -> io::Result<u32> {
+            let mut bytes = [0; 4];
+            file.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+        let (width, height, samples) = (read_u32()?, read_u32()?, read_u32()?);
+        let sum = (0..u64::from(width) * u64::from(height))
+            .map(|_| {
+                let r = f32::from_bits(read_u32()?);
+                let g = f32::from_bits(read_u32()?);
+                let b = f32::from_bits(read_u32()?);
+                Ok(Color::new(r, g, b))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Accumulation {
+            width,
+            height,
+            samples,
+            sum,
+        })
+    }
+
+    /// Save this accumulation to a checkpoint file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(CHECKPOINT_MAGIC)?;
+        for value in &[self.width, self.height, self.samples] {
+            file.write_all(&value.to_le_bytes())?;
+        }
+        for color in &self.sum {
+            let (r, g, b) = color.channels();
+            for channel in &[r, g, b] {
+                file.write_all(&channel.to_bits().to_le_bytes())?;
+            }
+        }
+        file.flush()
+    }
 }
 
-/// Get observed color at given intersection.
+/// Render `samples` more path traced samples for each pixel, adding them to `accumulation`.
 ///
-/// This calculates the observed color from a ray at the given intersection.
+/// Each sample is seeded separately, so rendering in multiple steps gives the same result as
+/// rendering all samples at once. The accumulation must fit the scene camera.
 ///
-/// A current depth should be given to limit ray recursion.
-/// For prime rays, simply give a depth of `0`.
-fn observe_intersection(
+/// If a `deadline` is given, no new sample passes are started once it has passed, leaving fewer
+/// samples in the accumulation. At least one pass is always rendered. Pixels are traced in the
+/// given `order`.
+pub fn render_progressive(
     scene: &Scene,
-    ray: &Ray,
-    intersection: &Intersection,
-    depth: u32,
-) -> Color {
-    let hit = ray.origin + (ray.direction * intersection.distance);
-    let normal = intersection.normal;
+    accumulation: &mut Accumulation,
+    samples: u32,
+    verbosity: Verbosity,
+    show_progress: bool,
+    order: PixelOrder,
+    deadline: Option<Instant>,
+) {
+    let camera = scene.camera;
+    assert!(
+        accumulation.fits(&camera),
+        "accumulation does not fit camera"
+    );
 
-    let material = intersection.entity.material();
-    match material.surface {
-        Surface::Diffuse => shade_diffuse(scene, intersection.entity, hit, normal),
-        Surface::Specular { reflectivity } => {
-            let mut color = shade_diffuse(scene, intersection.entity, hit, normal);
-            let reflection_ray = Ray::create_reflection(normal, ray.direction, hit, scene.bias);
-            color = color * (1.0 - reflectivity);
-            color = color + (observe_ray(scene, &reflection_ray, depth + 1) * reflectivity);
+    // Trace a single path per sample
+    let mut sample_scene = scene.clone();
+    sample_scene.samples = 1;
+    let first = accumulation.samples;
+
+    // Render one sample for all pixels per pass, so every pixel has the same sample count when
+    // stopping early
+    let progress = Progress::start_if(
+        camera.pixels() * u64::from(samples),
+        verbosity,
+        show_progress,
+    );
+    let morton = match order {
+        PixelOrder::Linear => None,
+        PixelOrder::Morton => Some(pixel_indices(&camera, order)),
+    };
+    for sample in first..first + samples {
+        let trace = |i: u64| {
+            let (x, y) = pixel_position(&camera, i);
+            let ray = Ray::new_prime(x, y, &sample_scene);
+            rng::seed(scene.seed ^ i ^ (u64::from(sample) << 40));
+            let color = trace_primary(&sample_scene, &ray, None).color;
+
+            if let Some(progress) = progress.as_ref() {
+                progress.completed.fetch_add(1, Ordering::Relaxed);
+            }
             color
-        }
-        Surface::Transparent {
-            index,
-            transparency,
-        } => {
-            let mut refraction_color = *BLACK;
-            let kr = fresnel(ray.direction, normal, index) as f32;
-            // TODO: textured coordinates:
-            // let surface_color = material
-            //     .coloration
-            //     .color(&intersection.entity.texture_coords(&hit));
-            let surface_color = material.color;
+        };
+        match morton {
+            // Accumulate in place, in the order of the accumulation
+            None => accumulation
+                .sum
+                .par_iter_mut()
+                .enumerate()
+                .for_each(|(i, sum)| *sum = *sum + trace(i as u64)),
 
-            if kr < 1.0 {
-                let transmission_ray =
-                    Ray::create_transmission(normal, ray.direction, hit, index, scene.bias)
-                        .unwrap();
-                refraction_color = observe_ray(scene, &transmission_ray, depth + 1);
+            // Trace along the curve, and accumulate the colors afterwards
+            Some(ref indices) => {
+                let colors: Vec<Color> = indices.par_iter().map(|&i| trace(i)).collect();
+                for (&i, color) in indices.iter().zip(colors) {
+                    let sum = &mut accumulation.sum[i as usize];
+                    *sum = *sum + color;
+                }
             }
+        }
+        accumulation.samples += 1;
 
-            let reflection_ray = Ray::create_reflection(normal, ray.direction, hit, scene.bias);
-            let reflection_color = observe_ray(scene, &reflection_ray, depth + 1);
-            let mut color = reflection_color * kr + refraction_color * (1.0 - kr);
-            color = color * transparency * surface_color;
-            color
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
         }
     }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
 }
 
-/// Shade hit point on diffuse surface.
-///
-/// Calculate the observed color at a diffuse surface point.
+/// Render the given scene to fragments, denoised if requested.
+fn render_denoised(
+    scene: &Scene,
+    options: &RenderOptions,
+    progress: Option<ProgressFn<'_>>,
+) -> Vec<Fragment> {
+    let fragments = render_fragments(scene, options, progress);
+    if options.denoise {
+        denoise(&scene.camera, &fragments)
+    } else {
+        fragments
+    }
+}
+
+/// Render the given scene to fragments.
 ///
-/// The hit `entity`, specific `hit` and entity surface normal must be given.
-fn shade_diffuse(scene: &Scene, entity: &Entity, hit: Vector, surface_normal: Vector) -> Color {
-    // TODO: textured coordinates:
-    // let texture_coords = entity.texture_coords(&hit);
+/// This traces a primary ray for each pixel, fragments are in column major order. The `progress`
+/// callback is invoked after each tile, and once more when all pixels are traced.
+fn render_fragments(
+    scene: &Scene,
+    options: &RenderOptions,
+    callback: Option<ProgressFn<'_>>,
+) -> Vec<Fragment> {
+    let camera = scene.camera;
+    warn_unlit(scene, options.verbosity);
 
-    let mut color = *BLACK;
-    for light in &scene.lights {
-        let direction_to_light = light.direction_from(hit);
+    // Trace a fragment for each pixel, tile by tile
+    let count = camera.pixels();
+    let completed = AtomicU64::new(0);
+    let tiles: Vec<Vec<(u64, Fragment)>> = tiles(&camera, scene.tile_size, options.pixel_order)
+        .into_par_iter()
+        .map(|tile| {
+            let pixels: Vec<(u64, Fragment)> = tile
+                .pixels(options.pixel_order)
+                .into_iter()
+                .map(|(x, y)| {
+                    // Seed random generator per pixel for reproducible renders, the seed only
+                    // depends on the pixel so the output does not depend on the thread count
+                    let i = u64::from(x) * u64::from(camera.height) + u64::from(y);
+                    rng::seed(scene.seed ^ i);
+                    let fragment = trace_primary(scene, &Ray::new_prime(x, y, scene), None);
 
-        let shadow_ray = Ray {
-            origin: hit + (surface_normal * scene.bias),
-            direction: direction_to_light,
-        };
-        let shadow_intersection = scene.intersect(&shadow_ray);
-        let in_light = shadow_intersection.is_none()
-            || shadow_intersection.unwrap().distance > light.distance(hit);
+                    // Update the live image
+                    if let Some(live_image) = options.live_image.as_ref() {
+                        live_image.set(&camera, x, y, fragment.color);
+                    }
 
-        let light_intensity = if in_light { light.intensity(hit) } else { 0.0 };
-        let material = entity.material();
-        let light_power =
-            (surface_normal.dot(direction_to_light) as f32).max(0.0) * light_intensity;
-        let light_reflected = material.albedo / PI;
+                    (i, fragment)
+                })
+                .collect();
+
+            // Report progress, the final call is left until all tiles are done
+            if let Some(callback) = callback {
+                let done = completed.fetch_add(pixels.len() as u64, Ordering::Relaxed);
+                let done = done + pixels.len() as u64;
+                if done < count {
+                    callback(done, count);
+                }
+            }
+            pixels
+        })
+        .collect();
+    if let Some(callback) = callback {
+        callback(count, count);
+    }
 
-        let light_color = light.color() * light_power * light_reflected;
+    // Gather the tiles in column major order
+    let mut fragments = vec![Fragment::default(); count as usize];
+    for (i, fragment) in tiles.into_iter().flatten() {
+        fragments[i as usize] = fragment;
+    }
 
-        // TODO: textured coordinates:
-        // color = color + (material.coloration.color(&texture_coords) * light_color);
-        color = color + (material.color * light_color);
+    // Adaptively sample pixels again, where neighbors differ a lot
+    if scene.max_samples > 1 {
+        refine_fragments(scene, &mut fragments);
     }
 
-    color.clamp()
+    fragments
 }
 
-/// Calcualte fresnel lens value.
-fn fresnel(incident: Vector, normal: Vector, index: f32) -> f64 {
-    let i_dot_n = incident.dot(normal);
-    let mut eta_i = 1.0;
-    let mut eta_t = f64::from(index);
-    if i_dot_n > 0.0 {
-        eta_i = eta_t;
-        eta_t = 1.0;
+/// Warn if the scene needs lights but has none.
+///
+/// Ambient occlusion and lighting by the background do not need any.
+fn warn_unlit(scene: &Scene, verbosity: Verbosity) {
+    let needs_lights = match scene.render_mode {
+        RenderMode::AmbientOcclusion { .. } | RenderMode::Wireframe => false,
+        RenderMode::PathTraced | RenderMode::Irradiance { .. } => !scene.background.emits_light(),
+        RenderMode::Whitted => true,
+    };
+    if needs_lights && scene.lights.is_empty() && verbosity.is_normal() {
+        eprintln!("Warning: no lights in scene, you won't be able to see anything");
     }
+}
 
-    let sin_t = eta_i / eta_t * (1.0 - i_dot_n * i_dot_n).max(0.0).sqrt();
-    if sin_t > 1.0 {
-        // Total internal reflection
-        1.0
-    } else {
-        let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
-        let cos_i = cos_t.abs();
+/// Clone the scene with its camera resolution multiplied by `factor`, for supersampling.
+fn supersampled(scene: &Scene, factor: u32) -> Scene {
+    let mut scene = scene.clone();
+    scene.camera.width *= factor;
+    scene.camera.height *= factor;
+    scene
+}
+
+/// Box downsample supersampled fragments by `factor` to the resolution of `camera`.
+///
+/// Colors are averaged, as are the distances and normals of the fragments that hit something.
+/// Fragments are in column major order.
+fn downsample(camera: &Camera, fragments: &[Fragment], factor: u32) -> Vec<Fragment> {
+    let height = u64::from(camera.height * factor);
+    (0..camera.pixels())
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = pixel_position(camera, i);
+            let mut color = *BLACK;
+            let (mut distance, mut normal, mut hits) = (0.0, Vector(0.0, 0.0, 0.0), 0);
+            for sx in x * factor..(x + 1) * factor {
+                for sy in y * factor..(y + 1) * factor {
+                    let sample = fragments[(u64::from(sx) * height + u64::from(sy)) as usize];
+                    color = color + sample.color;
+                    if let (Some(d), Some(n)) = (sample.distance, sample.normal) {
+                        distance += d;
+                        normal += n;
+                        hits += 1;
+                    }
+                }
+            }
+
+            Fragment {
+                color: color * (1.0 / (factor * factor) as f32),
+                distance: Some(distance / f64::from(hits)).filter(|_| hits > 0),
+                normal: Some(normal.normalize()).filter(|_| hits > 0),
+            }
+        })
+        .collect()
+}
+
+/// Denoise fragment colors with a bilateral filter, guided by surface normals and depth.
+///
+/// Each color is averaged with its neighbors, weighted by their screen distance, and by how
+/// similar their surface normal and depth are. This smooths noise on flat surfaces while
+/// preserving geometric edges. Fragments are in column major order.
+fn denoise(camera: &Camera, fragments: &[Fragment]) -> Vec<Fragment> {
+    let (width, height) = (camera.width as i64, camera.height as i64);
+    (0..fragments.len())
+        .into_par_iter()
+        .map(|i| {
+            let center = fragments[i];
+            let (x, y) = pixel_position(camera, i as u64);
+            let (x, y) = (i64::from(x), i64::from(y));
+
+            let mut sum = *BLACK;
+            let mut total = 0.0;
+            for nx in (x - DENOISE_RADIUS).max(0)..=(x + DENOISE_RADIUS).min(width - 1) {
+                for ny in (y - DENOISE_RADIUS).max(0)..=(y + DENOISE_RADIUS).min(height - 1) {
+                    let other = fragments[(nx * height + ny) as usize];
+                    let weight = denoise_weight(&center, &other, (nx - x, ny - y));
+                    sum = sum + other.color * weight;
+                    total += weight;
+                }
+            }
+
+            Fragment {
+                color: sum * (1.0 / total),
+                ..center
+            }
+        })
+        .collect()
+}
+
+/// Get the bilateral filter weight of the `other` fragment at `offset` pixels from `center`.
+fn denoise_weight(center: &Fragment, other: &Fragment, offset: (i64, i64)) -> f32 {
+    let distance2 = (offset.0 * offset.0 + offset.1 * offset.1) as f32;
+    let spatial = (-distance2 / (2.0 * DENOISE_SIGMA_SPATIAL.powi(2))).exp();
+
+    // Only blend surfaces with surfaces, and misses with misses
+    let guide = match (center.normal, other.normal, center.distance, other.distance) {
+        (Some(n1), Some(n2), Some(d1), Some(d2)) => {
+            let normal = (-(1.0 - n1.dot(n2)).max(0.0) / DENOISE_SIGMA_NORMAL).exp();
+            let depth = (d1 - d2) / d1.max(f64::EPSILON);
+            let depth = (-(depth * depth) / (2.0 * DENOISE_SIGMA_DEPTH.powi(2))).exp();
+            (normal * depth) as f32
+        }
+        (None, None, _, _) => 1.0,
+        _ => 0.0,
+    };
+
+    spatial * guide
+}
+
+/// A rectangular tile of pixels in the image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Tile {
+    /// Left pixel column, inclusive.
+    x: u32,
+
+    /// Top pixel row, inclusive.
+    y: u32,
+
+    /// Tile width in pixels.
+    width: u32,
+
+    /// Tile height in pixels.
+    height: u32,
+}
+
+impl Tile {
+    /// Get the `(x, y)` pixel positions in this tile, in the given `order`.
+    fn pixels(self, order: PixelOrder) -> Vec<(u32, u32)> {
+        match order {
+            PixelOrder::Linear => (self.y..self.y + self.height)
+                .flat_map(move |y| (self.x..self.x + self.width).map(move |x| (x, y)))
+                .collect(),
+            PixelOrder::Morton => morton_order(self.width, self.height)
+                .into_iter()
+                .map(|(x, y)| (self.x + x, self.y + y))
+                .collect(),
+        }
+    }
+}
+
+/// Split the camera image into square tiles of `size` pixels, in the given `order`.
+///
+/// Tiles at the right and bottom edges are cropped to the image.
+fn tiles(camera: &Camera, size: u32, order: PixelOrder) -> Vec<Tile> {
+    let size = size.max(1);
+    let columns = camera.width.div_ceil(size);
+    let rows = camera.height.div_ceil(size);
+    let tile = |(column, row): (u32, u32)| {
+        let (x, y) = (column * size, row * size);
+        Tile {
+            x,
+            y,
+            width: size.min(camera.width - x),
+            height: size.min(camera.height - y),
+        }
+    };
+    match order {
+        PixelOrder::Linear => (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .map(tile)
+            .collect(),
+        PixelOrder::Morton => morton_order(columns, rows).into_iter().map(tile).collect(),
+    }
+}
+
+/// Get the index of every camera pixel, as used by `pixel_position`, in the given `order`.
+fn pixel_indices(camera: &Camera, order: PixelOrder) -> Vec<u64> {
+    match order {
+        PixelOrder::Linear => (0..camera.pixels()).collect(),
+        PixelOrder::Morton => morton_order(camera.width, camera.height)
+            .into_iter()
+            .map(|(x, y)| u64::from(x) * u64::from(camera.height) + u64::from(y))
+            .collect(),
+    }
+}
+
+/// Get all `(x, y)` positions in a `width` by `height` grid, along a Morton (Z-order) curve.
+///
+/// The curve visits the positions of each aligned power of two square before moving on to the
+/// next. Positions outside the grid are skipped, so any grid size is supported.
+fn morton_order(width: u32, height: u32) -> Vec<(u32, u32)> {
+    let mut positions: Vec<(u32, u32)> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .collect();
+    positions.sort_unstable_by_key(|&(x, y)| morton_code(x, y));
+    positions
+}
+
+/// Interleave the bits of `x` and `y` into a Morton code, `x` in the lowest bit.
+fn morton_code(x: u32, y: u32) -> u64 {
+    let spread = |v: u32| {
+        let mut v = u64::from(v);
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+        v
+    };
+    spread(x) | (spread(y) << 1)
+}
+
+/// Convert the shaded `color` of the pixel at `(x, y)` to 8-bit, dithered if `dither` is set.
+fn quantize(color: Color, (x, y): (u32, u32), dither: bool) -> Rgba<u8> {
+    if dither {
+        color.to_rgba_dithered(x, y)
+    } else {
+        color.to_rgba()
+    }
+}
+
+/// Get the `(x, y)` position of the pixel at index `i`, in column major order.
+fn pixel_position(camera: &Camera, i: u64) -> (u32, u32) {
+    let height = u64::from(camera.height);
+    ((i / height) as u32, (i % height) as u32)
+}
+
+/// Refine fragments with adaptive anti-aliasing.
+///
+/// Pixels with a color variance among their neighbors above `scene.variance_threshold` are
+/// sampled again with jittered rays, up to `scene.max_samples` in total. Returns the number of
+/// samples taken for each pixel.
+fn refine_fragments(scene: &Scene, fragments: &mut Vec<Fragment>) -> Vec<u32> {
+    let count = fragments.len() as u64;
+    let samples = adaptive_samples(scene, fragments);
+
+    fragments
+        .par_iter_mut()
+        .zip(samples.par_iter())
+        .enumerate()
+        .filter(|(_, (_, samples))| **samples > 1)
+        .for_each(|(i, (fragment, samples))| {
+            // Seed as a pixel beyond the image, to not repeat the first pass random sequence
+            let i = i as u64;
+            rng::seed(scene.seed ^ (count + i));
+            let (x, y) = pixel_position(&scene.camera, i);
+
+            // Include the first pass sample in the average
+            let color = jittered_samples(samples - 1)
+                .into_iter()
+                .map(|offset| {
+                    trace_primary(scene, &Ray::new_prime_at(x, y, offset, scene), None).color
+                })
+                .fold(fragment.color, |sum, color| sum + color);
+            fragment.color = color * (1.0 / *samples as f32);
+        });
+
+    samples
+}
+
+/// Determine the number of samples to take for each fragment in adaptive anti-aliasing.
+///
+/// This is `scene.max_samples` for fragments with a color variance among their 4-neighborhood
+/// above `scene.variance_threshold`, and `1` otherwise.
+fn adaptive_samples(scene: &Scene, fragments: &[Fragment]) -> Vec<u32> {
+    let (width, height) = (scene.camera.width, scene.camera.height);
+    let column = height as usize;
+    (0..fragments.len())
+        .map(|i| {
+            let (x, y) = pixel_position(&scene.camera, i as u64);
+
+            // Collect the pixel with its neighbors, in column major order
+            let mut colors = vec![fragments[i].color];
+            if x > 0 {
+                colors.push(fragments[i - column].color);
+            }
+            if x + 1 < width {
+                colors.push(fragments[i + column].color);
+            }
+            if y > 0 {
+                colors.push(fragments[i - 1].color);
+            }
+            if y + 1 < height {
+                colors.push(fragments[i + 1].color);
+            }
+
+            if color_variance(&colors) > scene.variance_threshold {
+                scene.max_samples
+            } else {
+                1
+            }
+        })
+        .collect()
+}
+
+/// Get the color variance of the given colors, averaged over the color channels.
+fn color_variance(colors: &[Color]) -> f32 {
+    let n = colors.len() as f32;
+    let mean = colors.iter().fold(*BLACK, |sum, c| sum + *c) * (1.0 / n);
+    colors
+        .iter()
+        .map(|c| {
+            let d = *c - mean;
+            (d * d).mean_channel()
+        })
+        .sum::<f32>()
+        / n
+}
+
+/// Render progress, shown in a progress bar updated by a separate thread.
+struct Progress {
+    /// Number of completed pixels.
+    completed: Arc<AtomicU64>,
+
+    /// The progress bar.
+    pb: Arc<Mutex<ProgressBar<Stdout>>>,
+}
+
+impl Progress {
+    /// Start showing progress for rendering `count` pixels.
+    ///
+    /// This spawns the thread periodically updating the progress bar.
+    fn start(count: u64) -> Self {
+        let mut bar = ProgressBar::new(count);
+        bar.show_speed = false;
+        bar.show_time_left = false;
+        let progress = Self {
+            completed: Arc::new(AtomicU64::new(0)),
+            pb: Arc::new(Mutex::new(bar)),
+        };
+
+        let thread_completed = progress.completed.clone();
+        let thread_pb = progress.pb.clone();
+        let start = Instant::now();
+        let start_rays = RAYS.load(Ordering::Relaxed);
+        thread::spawn(move || loop {
+            // Get the current progress value and throughput, update progress bar
+            let value = thread_completed.load(Ordering::Relaxed);
+            let rays = RAYS.load(Ordering::Relaxed).saturating_sub(start_rays);
+            let message = progress_message(value, count, rays, start.elapsed());
+            if let Ok(mut pb) = thread_pb.lock() {
+                pb.message(&message);
+                pb.set(value);
+            }
+
+            // Stop when done
+            if value >= count {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(250));
+        });
+
+        progress
+    }
+
+    /// Start showing progress, only if `show_progress` is set and the `verbosity` allows it.
+    ///
+    /// No updater thread is spawned if progress is not shown.
+    fn start_if(count: u64, verbosity: Verbosity, show_progress: bool) -> Option<Self> {
+        if show_progress && verbosity.is_normal() {
+            Some(Self::start(count))
+        } else {
+            None
+        }
+    }
+
+    /// Finish the progress bar.
+    fn finish(self) {
+        self.pb.lock().unwrap().finish();
+    }
+}
+
+/// A progress bar for a render, fed by the render progress callback.
+///
+/// The bar is started on the first report, once the total number of pixels is known.
+#[derive(Default)]
+pub struct RenderProgress {
+    /// The progress bar, once started.
+    progress: OnceLock<Progress>,
+}
+
+impl RenderProgress {
+    /// Construct a progress bar, not shown until progress is reported.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report `completed` of `total` traced pixels, to call from a `ProgressFn`.
+    pub fn report(&self, completed: u64, total: u64) {
+        // Reports from different threads may arrive out of order
+        self.progress
+            .get_or_init(|| Progress::start(total))
+            .completed
+            .fetch_max(completed, Ordering::Relaxed);
+    }
+
+    /// Finish the progress bar, if it was started.
+    pub fn finish(self) {
+        if let Some(progress) = self.progress.into_inner() {
+            progress.finish();
+        }
+    }
+}
+
+/// Pixels of a render in progress, readable from other threads while rendering.
+///
+/// Used to show a live preview of a render. Pixels not rendered yet are black. Renders of a
+/// different size, such as supersampled renders, are scaled to fit.
+#[derive(Debug)]
+pub struct LiveImage {
+    /// Pixels rendered so far in row major order, in the `0RGB` format.
+    pixels: Vec<AtomicU32>,
+
+    /// Width in pixels.
+    width: u32,
+
+    /// Height in pixels.
+    height: u32,
+}
+
+impl LiveImage {
+    /// Construct a black live image, the size of renders with the given `camera`.
+    pub fn new(camera: &Camera) -> Self {
+        Self {
+            pixels: (0..camera.pixels()).map(|_| AtomicU32::new(0)).collect(),
+            width: camera.width,
+            height: camera.height,
+        }
+    }
+
+    /// Get the width in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Get the height in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Copy the pixels rendered so far to `buffer` in row major order, in the `0RGB` format.
+    pub fn read_0rgb(&self, buffer: &mut [u32]) {
+        buffer
+            .iter_mut()
+            .zip(self.pixels.iter())
+            .for_each(|(b, p)| *b = p.load(Ordering::Relaxed));
+    }
+
+    /// Set the color of the pixel at `(x, y)` of a render with the given `camera`.
+    fn set(&self, camera: &Camera, x: u32, y: u32, color: Color) {
+        let x = u64::from(x) * u64::from(self.width) / u64::from(camera.width);
+        let y = u64::from(y) * u64::from(self.height) / u64::from(camera.height);
+        let i = (y * u64::from(self.width) + x) as usize;
+        self.pixels[i].store(to_0rgb(color.to_rgba()), Ordering::Relaxed);
+    }
+}
+
+/// Convert an RGB pixel to the `0RGB` format used by preview windows.
+fn to_0rgb(pixel: Rgba<u8>) -> u32 {
+    u32::from(pixel[0]) << 16 | u32::from(pixel[1]) << 8 | u32::from(pixel[2])
+}
+
+/// Build the progress bar message, showing the estimated time remaining and throughput.
+///
+/// The time remaining is estimated from the pixel rate so far.
+fn progress_message(pixels: u64, total: u64, rays: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    if pixels == 0 || secs <= 0.0 {
+        return "ETA -, ".into();
+    }
+    let pixel_rate = pixels as f64 / secs;
+    let eta = total.saturating_sub(pixels) as f64 / pixel_rate;
+    format!(
+        "ETA {:.0}s, {:.2} Mrays/s, {:.0} px/s ",
+        eta,
+        rays as f64 / secs / 1e6,
+        pixel_rate,
+    )
+}
+
+/// Count a cast ray, for throughput reporting and render statistics.
+#[inline]
+fn count_ray(kind: RayType) {
+    RAYS.fetch_add(1, Ordering::Relaxed);
+    RAYS_BY_TYPE[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Trace a primary ray in the scene, recording the rays cast with the `tracer` if any.
+///
+/// Like `observe_ray`, but also returns first hit information. In path traced mode, the color is
+/// averaged over `scene.samples` paths. If the camera shutter is open, each path is traced at its
+/// own random time within the shutter.
+fn trace_primary(scene: &Scene, ray: &Ray, tracer: Tracer) -> Fragment {
+    let shutter = scene.camera.shutter;
+    if shutter <= 0.0 {
+        return trace_primary_samples(scene, ray, scene.samples, tracer);
+    }
+
+    let samples = match scene.render_mode {
+        RenderMode::PathTraced => scene.samples.max(1),
+        _ => 1,
+    };
+    let fragments: Vec<Fragment> = (0..samples)
+        .map(|_| {
+            set_shutter_time(random::<f64>() * shutter);
+            trace_primary_samples(scene, ray, 1, tracer)
+        })
+        .collect();
+    Fragment {
+        color: fragments.iter().fold(*BLACK, |sum, f| sum + f.color) * (1.0 / samples as f32),
+        ..fragments[0]
+    }
+}
+
+/// Trace a primary ray in the scene, at the shutter time set for this thread.
+///
+/// In path traced mode, the color is averaged over the given number of `samples` paths.
+fn trace_primary_samples(scene: &Scene, ray: &Ray, samples: u32, tracer: Tracer) -> Fragment {
+    count_ray(RayType::Primary);
+    let record = trace_ray(tracer, RayType::Primary, Depth::default(), ray);
+    let intersection = scene.intersect(ray, RayType::Primary);
+    let fragment = match intersection {
+        Some(ref i) => {
+            let color = match scene.render_mode {
+                _ if scene.depth == 0 => *BLACK,
+                RenderMode::Whitted | RenderMode::Irradiance { .. } => {
+                    observe_intersection(scene, ray, i, Depth::default(), *WHITE, tracer)
+                }
+                RenderMode::PathTraced => {
+                    let samples = samples.max(1);
+                    (0..samples)
+                        .map(|_| {
+                            observe_intersection(scene, ray, i, Depth::default(), *WHITE, tracer)
+                        })
+                        .map(|color| clamp_luminance(color, scene.max_luminance))
+                        .fold(*BLACK, |sum, color| sum + color)
+                        * (1.0 / samples as f32)
+                }
+                RenderMode::AmbientOcclusion { samples, radius } => {
+                    let hit = ray.origin + (ray.direction * i.distance);
+                    let occlusion = ambient_occlusion(scene, ray, hit, i.normal, samples, radius);
+                    Color::new(occlusion, occlusion, occlusion)
+                }
+                RenderMode::Wireframe => wireframe(ray, i),
+            };
+            Fragment {
+                color: fogged(scene, color, Some(i.distance)),
+                distance: Some(i.distance),
+                normal: Some(i.normal),
+            }
+        }
+        None => Fragment {
+            color: fogged(scene, scene.background.color(ray), None),
+            distance: None,
+            normal: None,
+        },
+    };
+    trace_result(tracer, record, intersection.as_ref(), fragment.color);
+    fragment
+}
+
+/// Scale a path traced sample down to the given maximum luminance, preserving its hue.
+fn clamp_luminance(color: Color, max: Option<f32>) -> Color {
+    match max {
+        Some(max) if color.luminance() > max => color * (max / color.luminance()),
+        _ => color,
+    }
+}
+
+/// Get the wireframe color of a primary `intersection`.
+///
+/// Pixels near a triangle edge are black, others are gray, shaded by how much the surface faces
+/// the ray.
+fn wireframe(ray: &Ray, intersection: &Intersection) -> Color {
+    match intersection.edge_distance() {
+        Some(distance) if distance < WIREFRAME_EDGE_WIDTH => *BLACK,
+        _ => *GRAY * (ray.direction.dot(intersection.normal).abs() as f32),
+    }
+}
+
+/// Cast a ray in the scene, get observed color.
+///
+/// A current depth should be given to limit ray recursion.
+/// For prime rays, simply give a depth of `0`.
+///
+/// The `kind` of ray is counted for render statistics.
+///
+/// The `throughput` is the fraction of the observed color that reaches the camera along the
+/// path so far. For prime rays, simply give white. In path traced mode, it is used to terminate
+/// low contribution paths with Russian roulette.
+///
+/// Rays cast are recorded with the `tracer`, if any.
+fn observe_ray(
+    scene: &Scene,
+    ray: &Ray,
+    kind: RayType,
+    depth: Depth,
+    throughput: Color,
+    tracer: Tracer,
+) -> Color {
+    // We're just seeing black if max ray recursion is reached
+    if depth.exceeds(scene) {
+        return *BLACK;
+    }
+
+    // Russian roulette, randomly terminate paths based on their throughput, and boost surviving
+    // paths to compensate
+    let mut throughput = throughput;
+    let mut weight = 1.0;
+    if scene.render_mode == RenderMode::PathTraced && depth.total >= scene.roulette_depth {
+        let survival = throughput.max_channel().min(1.0);
+        if survival <= 0.0 || random::<f32>() >= survival {
+            return *BLACK;
+        }
+        weight = 1.0 / survival;
+        throughput = throughput * weight;
+    }
+
+    // Find ray intersection, get intersection color
+    count_ray(kind);
+    let record = trace_ray(tracer, kind, depth, ray);
+    let intersection = scene.intersect(ray, kind);
+    let color = match intersection {
+        Some(ref i) => fogged(
+            scene,
+            observe_intersection(scene, ray, i, depth, throughput, tracer),
+            Some(i.distance),
+        ),
+        None => fogged(scene, scene.background.color(ray), None),
+    } * weight;
+    trace_result(tracer, record, intersection.as_ref(), color);
+    color
+}
+
+/// Ray recursion depth along a path, counted in total and per kind of bounce.
+///
+/// Primary rays have the default depth of zero.
+#[derive(Copy, Clone, Debug, Default)]
+struct Depth {
+    /// Number of bounces of any kind.
+    total: u32,
+
+    /// Number of reflection bounces.
+    reflection: u32,
+
+    /// Number of refraction bounces.
+    refraction: u32,
+}
+
+impl Depth {
+    /// Get the depth of a ray of the given `kind`, cast from a hit at this depth.
+    fn next(self, kind: RayType) -> Self {
+        Self {
+            total: self.total + 1,
+            reflection: self.reflection + (kind == RayType::Reflection) as u32,
+            refraction: self.refraction + (kind == RayType::Refraction) as u32,
+        }
+    }
+
+    /// Check whether this depth exceeds the recursion limits of the `scene`.
+    fn exceeds(self, scene: &Scene) -> bool {
+        self.total >= scene.depth
+            || scene
+                .reflection_depth
+                .is_some_and(|max| self.reflection > max)
+            || scene
+                .refraction_depth
+                .is_some_and(|max| self.refraction > max)
+    }
+}
+
+/// Apply the scene fog, if any, to a color observed at `distance`.
+///
+/// A `None` distance is a ray that hit nothing, which is fully fogged.
+fn fogged(scene: &Scene, color: Color, distance: Option<f64>) -> Color {
+    match scene.fog {
+        Some(ref fog) => fog.apply(color, distance),
+        None => color,
+    }
+}
+
+/// Get observed color at given intersection.
+///
+/// This calculates the observed color from a ray at the given intersection.
+///
+/// A current depth should be given to limit ray recursion.
+/// For prime rays, simply give a depth of `0`, and a white `throughput`.
+fn observe_intersection(
+    scene: &Scene,
+    ray: &Ray,
+    intersection: &Intersection,
+    depth: Depth,
+    throughput: Color,
+    tracer: Tracer,
+) -> Color {
+    let hit = ray.origin + (ray.direction * intersection.distance);
+    let normal = shading_normal(intersection, hit);
+
+    let material = intersection.material();
+    let color = match material.surface {
+        Surface::Diffuse => {
+            shade_diffuse(scene, ray, intersection, normal, depth, throughput, tracer)
+        }
+        Surface::Specular { reflectivity } => {
+            let mut color = shade_diffuse(
+                scene,
+                ray,
+                intersection,
+                normal,
+                depth,
+                throughput * (1.0 - reflectivity),
+                tracer,
+            );
+            let reflection_ray = Ray::create_reflection(normal, ray.direction, hit, scene.bias);
+            color = color * (1.0 - reflectivity);
+            color = color
+                + (observe_ray(
+                    scene,
+                    &reflection_ray,
+                    RayType::Reflection,
+                    depth.next(RayType::Reflection),
+                    throughput * reflectivity,
+                    tracer,
+                ) * reflectivity);
+            color
+        }
+        Surface::Metal {
+            color,
+            roughness,
+            roughness_u,
+            roughness_v,
+            tangent,
+        } if scene.render_mode == RenderMode::PathTraced
+            && (roughness > 0.0 || roughness_u.is_some() || roughness_v.is_some()) =>
+        {
+            // Orient the roughness along the reference tangent, or the texture tangent
+            let scale = material.roughness_scale(|| intersection.texture_coords(hit));
+            let roughness = (
+                roughness_u.unwrap_or(roughness) * scale,
+                roughness_v.unwrap_or(roughness) * scale,
+            );
+            let frame = if roughness.0 == roughness.1 {
+                normal.orthonormal_basis()
+            } else {
+                surface_frame(intersection, hit, normal, tangent)
+            };
+            let ggx = Ggx::new(frame, normal, roughness);
+            match reflect_ggx(scene, -ray.direction, hit, &ggx, color) {
+                Some((reflection_ray, reflectance)) => {
+                    observe_ray(
+                        scene,
+                        &reflection_ray,
+                        RayType::Reflection,
+                        depth.next(RayType::Reflection),
+                        throughput * reflectance,
+                        tracer,
+                    ) * reflectance
+                }
+                None => *BLACK,
+            }
+        }
+        Surface::Metal {
+            color, roughness, ..
+        } => {
+            let reflection_ray = Ray::create_reflection(normal, ray.direction, hit, scene.bias);
+            let roughness =
+                roughness * material.roughness_scale(|| intersection.texture_coords(hit));
+            let reflection_ray = Ray::new(
+                reflection_ray.origin,
+                perturb(reflection_ray.direction, normal, roughness),
+            );
+            let cos_theta = -ray.direction.dot(normal);
+            let reflectance = fresnel_schlick(cos_theta, color);
+            observe_ray(
+                scene,
+                &reflection_ray,
+                RayType::Reflection,
+                depth.next(RayType::Reflection),
+                throughput * reflectance,
+                tracer,
+            ) * reflectance
+        }
+        Surface::Transparent {
+            index,
+            transparency,
+            absorption,
+        } => {
+            let mut refraction_color = *BLACK;
+            let kr = fresnel(ray.direction, normal, index) as f32;
+            let surface_color = material.color.color(|| intersection.texture_coords(hit));
+
+            // Rays hitting the surface from inside travelled through the medium, absorbing light
+            let absorbed = if !intersection.front_face {
+                beer_lambert(absorption, intersection.distance)
+            } else {
+                *WHITE
+            };
+            let throughput = throughput * transparency * surface_color * absorbed;
+
+            if kr < 1.0 {
+                let transmission_ray =
+                    Ray::create_transmission(normal, ray.direction, hit, index, scene.bias)
+                        .unwrap();
+                refraction_color = observe_ray(
+                    scene,
+                    &transmission_ray,
+                    RayType::Refraction,
+                    depth.next(RayType::Refraction),
+                    throughput * (1.0 - kr),
+                    tracer,
+                );
+            }
+
+            let reflection_ray = Ray::create_reflection(normal, ray.direction, hit, scene.bias);
+            let reflection_color = observe_ray(
+                scene,
+                &reflection_ray,
+                RayType::Reflection,
+                depth.next(RayType::Reflection),
+                throughput * kr,
+                tracer,
+            );
+            let mut color = reflection_color * kr + refraction_color * (1.0 - kr);
+            color = color * transparency * surface_color * absorbed;
+            color
+        }
+    };
+
+    color + material.emission
+}
+
+/// Get the fraction of light transmitted through a medium with the given `absorption`, over
+/// `distance`.
+///
+/// Follows the Beer-Lambert law, light decays exponentially with the distance travelled.
+fn beer_lambert(absorption: Color, distance: f64) -> Color {
+    let (r, g, b) = absorption.channels();
+    let distance = distance as f32;
+    Color::new(
+        (-r * distance).exp(),
+        (-g * distance).exp(),
+        (-b * distance).exp(),
+    )
+}
+
+/// Get the shading normal at the `intersection` world space `hit` point, perturbed by the entity
+/// normal map if it has any.
+///
+/// Normal map colors are decoded as tangent space normals, where `(128, 128, 255)` is flat and
+/// leaves the geometric normal unchanged.
+fn shading_normal(intersection: &Intersection, hit: Vector) -> Vector {
+    let normal = intersection.normal;
+    let normal_map = match intersection.material().normal_map {
+        Some(ref normal_map) => normal_map,
+        None => return normal,
+    };
+    let (x, y, z) = normal_map
+        .sample(intersection.texture_coords(hit))
+        .channels();
+    let decode = |c: f32| f64::from((c * 255.0 - 128.0) / 127.0);
+
+    let (tangent, bitangent) = intersection.tangent_frame(hit, normal);
+    let perturbed = tangent * decode(x) + bitangent * decode(y) + normal * decode(z);
+    if perturbed.dot(normal) > 0.0 {
+        perturbed.normalize()
+    } else {
+        normal
+    }
+}
+
+/// Shade hit point on diffuse surface.
+///
+/// Calculate the observed color at a diffuse surface point. In path traced mode, this includes
+/// indirect light bounced from other surfaces, in irradiance mode indirect light approximated by
+/// the irradiance probes.
+///
+/// The `ray` and its `intersection`, and the entity surface normal at the hit point must be given.
+fn shade_diffuse(
+    scene: &Scene,
+    ray: &Ray,
+    intersection: &Intersection,
+    surface_normal: Vector,
+    depth: Depth,
+    throughput: Color,
+    tracer: Tracer,
+) -> Color {
+    let hit = ray.origin + (ray.direction * intersection.distance);
+    let direct = shade_direct(scene, intersection, hit, surface_normal, -ray.direction);
+    match scene.render_mode {
+        RenderMode::Whitted | RenderMode::AmbientOcclusion { .. } | RenderMode::Wireframe => direct,
+        RenderMode::PathTraced => {
+            direct
+                + shade_indirect(
+                    scene,
+                    intersection,
+                    hit,
+                    surface_normal,
+                    depth,
+                    throughput,
+                    tracer,
+                )
+        }
+        RenderMode::Irradiance {
+            resolution,
+            samples,
+        } => {
+            let probes = scene.irradiance_probes(|| bake_irradiance(scene, resolution, samples));
+            let material = intersection.material();
+            let reflected =
+                material.color.color(|| intersection.texture_coords(hit)) * (material.albedo / PI);
+            direct + probes.irradiance(hit, surface_normal) * reflected
+        }
+    }
+}
+
+/// Shade hit point on diffuse surface with direct light.
+///
+/// Calculate the observed color at a diffuse surface point, lit directly by the scene lights.
+/// Materials with a specular highlight add a Blinn-Phong highlight for each light, seen from the
+/// `view` direction.
+fn shade_direct(
+    scene: &Scene,
+    intersection: &Intersection,
+    hit: Vector,
+    surface_normal: Vector,
+    view: Vector,
+) -> Color {
+    let material = intersection.material();
+    let surface_color = material.color.color(|| intersection.texture_coords(hit));
+
+    let mut color = *BLACK;
+    for (light, weight) in sample_lights(scene) {
+        let light_power = receive_light(scene, light, hit, surface_normal);
+        let light_reflected = material.albedo / PI;
+
+        let light_color = light.color() * light_power * light_reflected * weight;
+
+        color = color + (surface_color * light_color);
+
+        if let Some(ref highlight) = material.specular_highlight {
+            let half = (light.direction_from(hit) + view).normalize();
+            let alignment = (surface_normal.dot(half) as f32).max(0.0);
+            let specular = highlight.intensity * alignment.powf(highlight.power);
+            color = color + light.color() * light_power * specular * weight;
+        }
+    }
+
+    color.clamp()
+}
+
+/// Select the scene lights to sample at a shading point, along with their contribution weight.
+///
+/// All lights are selected with a weight of `1` unless the scene has more lights than
+/// `scene.light_samples`. In that case, the lights are split into that many strata, and a single
+/// random light is picked from each. Its contribution is weighted by the stratum size, so the
+/// result matches sampling all lights in expectation.
+fn sample_lights(scene: &Scene) -> Vec<(&Light, f32)> {
+    let count = scene.lights.len();
+    let samples = match scene.light_samples {
+        Some(samples) if (samples as usize) < count => samples.max(1) as usize,
+        _ => return scene.lights.iter().map(|light| (light, 1.0)).collect(),
+    };
+
+    (0..samples)
+        .map(|stratum| {
+            let start = stratum * count / samples;
+            let end = (stratum + 1) * count / samples;
+            let index = rng::with_rng(|rng| rng.gen_range(start, end));
+            (&scene.lights[index], (end - start) as f32)
+        })
+        .collect()
+}
+
+/// Shade hit point on diffuse surface with indirect light.
+///
+/// Casts a single cosine weighted ray into the hemisphere around the surface normal, and
+/// recursively observes the light coming from it. The cosine term and sample probability cancel
+/// out against the Lambertian BRDF, leaving just the albedo.
+fn shade_indirect(
+    scene: &Scene,
+    intersection: &Intersection,
+    hit: Vector,
+    surface_normal: Vector,
+    depth: Depth,
+    throughput: Color,
+    tracer: Tracer,
+) -> Color {
+    let material = intersection.material();
+    let (direction, _) =
+        rng::with_rng(|rng| sampling::cosine_weighted_hemisphere(surface_normal, rng));
+    let ray = Ray::new(hit + (surface_normal * scene.bias), direction);
+    let reflected = material.color.color(|| intersection.texture_coords(hit)) * material.albedo;
+    observe_ray(
+        scene,
+        &ray,
+        RayType::Diffuse,
+        depth.next(RayType::Diffuse),
+        throughput * reflected,
+        tracer,
+    ) * reflected
+}
+
+/// Bake the irradiance probes of the scene, with `resolution` probes along each axis of the scene
+/// bounds and `samples` rays per probe.
+///
+/// Random numbers are drawn from a generator seeded with the scene seed, so the probes do not
+/// depend on which pixel is rendered first.
+fn bake_irradiance(scene: &Scene, resolution: u32, samples: u32) -> ProbeGrid {
+    let bounds = scene
+        .bounds()
+        .unwrap_or_else(|| Aabb::new(Vector::identity(), Vector::identity()));
+    rng::with_seed(scene.seed, || {
+        ProbeGrid::bake(bounds, resolution, |point| {
+            SphericalHarmonics::project(samples, |direction| {
+                probe_radiance(scene, &Ray::new(point, direction))
+            })
+        })
+    })
+}
+
+/// Get the light seen by an irradiance probe ray.
+///
+/// This is the background, or the direct light and emission of the surface the ray hits.
+fn probe_radiance(scene: &Scene, ray: &Ray) -> Color {
+    count_ray(RayType::Diffuse);
+    let intersection = match scene.intersect(ray, RayType::Diffuse) {
+        Some(intersection) => intersection,
+        None => return scene.background.color(ray),
+    };
+    let hit = ray.origin + (ray.direction * intersection.distance);
+    let normal = if intersection.normal.dot(ray.direction) > 0.0 {
+        -intersection.normal
+    } else {
+        intersection.normal
+    };
+    shade_direct(scene, &intersection, hit, normal, -ray.direction)
+        + intersection.material().emission
+}
+
+/// Calculate the ambient occlusion at a hit point, seen by `ray`.
+///
+/// Casts `samples` cosine weighted rays into the hemisphere around the surface normal facing the
+/// ray. Returns the fraction of rays that travel `radius` without hitting geometry, `1` being
+/// fully unoccluded.
+fn ambient_occlusion(
+    scene: &Scene,
+    ray: &Ray,
+    hit: Vector,
+    normal: Vector,
+    samples: u32,
+    radius: f64,
+) -> f32 {
+    let normal = if normal.dot(ray.direction) > 0.0 {
+        -normal
+    } else {
+        normal
+    };
+    let samples = samples.max(1);
+    let open = (0..samples)
+        .filter(|_| {
+            let (direction, _) =
+                rng::with_rng(|rng| sampling::cosine_weighted_hemisphere(normal, rng));
+            !is_occluded(scene, hit, normal, direction, radius)
+        })
+        .count();
+    open as f32 / samples as f32
+}
+
+/// Calculate the light power received from a light at a hit point.
+///
+/// This is the cosine weighted light intensity, with occluded light blocked if the light casts
+/// shadows. Area lights, and directional lights with an angular size, are sampled at
+/// `scene.samples` jittered points on the light for soft shadows. In path traced mode a single
+/// point is sampled, as the pixel is sampled multiple times already.
+fn receive_light(scene: &Scene, light: &Light, hit: Vector, surface_normal: Vector) -> f32 {
+    let samples = match scene.render_mode {
+        _ if !light.is_area() => 1,
+        RenderMode::Whitted
+        | RenderMode::AmbientOcclusion { .. }
+        | RenderMode::Wireframe
+        | RenderMode::Irradiance { .. } => scene.samples.max(1),
+        RenderMode::PathTraced => 1,
+    };
+
+    if let Light::Sky(ref sky) = light {
+        return receive_sky(scene, sky, hit, surface_normal, samples);
+    }
+
+    let received: f32 = jittered_samples(samples)
+        .into_iter()
+        .map(|(u, v)| {
+            let sample = light.sample(hit, u, v);
+            if light.cast_shadows()
+                && is_occluded(
+                    scene,
+                    hit,
+                    surface_normal,
+                    sample.direction,
+                    sample.distance,
+                )
+            {
+                0.0
+            } else {
+                (surface_normal.dot(sample.direction) as f32).max(0.0) * sample.intensity
+            }
+        })
+        .sum();
+    received / samples as f32
+}
+
+/// Calculate the light power received from a sky light at a hit point.
+///
+/// Casts `samples` cosine weighted rays into the hemisphere around the surface normal, rays going
+/// up into the sky that are not occluded receive its light. A fully visible sky gives the sky
+/// radiance integrated over the hemisphere.
+fn receive_sky(
+    scene: &Scene,
+    sky: &SkyLight,
+    hit: Vector,
+    surface_normal: Vector,
+    samples: u32,
+) -> f32 {
+    if sky.intensity == 0.0 {
+        return 0.0;
+    }
+
+    let up = sky.up.normalize();
+    let visible = jittered_samples(samples)
+        .into_iter()
+        .filter(|&(u, v)| {
+            let direction = sampling::cosine_weighted_direction(surface_normal, u, v);
+            direction.dot(up) > 0.0
+                && !(sky.cast_shadows
+                    && is_occluded(scene, hit, surface_normal, direction, f64::INFINITY))
+        })
+        .count();
+    PI * sky.intensity * visible as f32 / samples as f32
+}
+
+/// Generate `count` jittered sample positions in the unit square.
+///
+/// Uses N-rooks sampling, each row and column of a `count` by `count` grid holds exactly one
+/// sample at a random position within its cell.
+fn jittered_samples(count: u32) -> Vec<(f64, f64)> {
+    let mut rows: Vec<u32> = (0..count).collect();
+    rng::with_rng(|rng| rows.shuffle(rng));
+    rows.into_iter()
+        .enumerate()
+        .map(|(column, row)| {
+            (
+                (column as f64 + random::<f64>()) / f64::from(count),
+                (f64::from(row) + random::<f64>()) / f64::from(count),
+            )
+        })
+        .collect()
+}
+
+/// Check whether a light in `direction` at `distance` is occluded from the hit point.
+///
+/// To prevent self shadowing acne, the shadow ray origin is offset along both the surface normal
+/// and light direction by the shadow bias. Only occluders in front of the light count.
+fn is_occluded(
+    scene: &Scene,
+    hit: Vector,
+    surface_normal: Vector,
+    direction: Vector,
+    distance: f64,
+) -> bool {
+    let bias = shadow_bias(scene, hit);
+    let shadow_ray = Ray::new(hit + (surface_normal * bias), direction).bias(bias);
+    count_ray(RayType::Shadow);
+    scene
+        .intersect(&shadow_ray, RayType::Shadow)
+        .map(|i| i.distance < distance - bias)
+        .unwrap_or(false)
+}
+
+/// Get the shadow ray bias for the given hit point.
+///
+/// This is the scene bias, but grows with the hit point magnitude as float precision decreases
+/// further away from the origin.
+fn shadow_bias(scene: &Scene, hit: Vector) -> f64 {
+    let magnitude = hit.0.abs().max(hit.1.abs()).max(hit.2.abs());
+    scene.bias.max(magnitude * SHADOW_EPSILON)
+}
+
+/// Randomly perturb a reflected `direction` within a cone, for rough surfaces.
+///
+/// Directions perturbed below the surface are not perturbed.
+fn perturb(direction: Vector, normal: Vector, roughness: f32) -> Vector {
+    if roughness <= 0.0 {
+        return direction;
+    }
+
+    // Sample a random point in the unit sphere
+    let offset = loop {
+        let p = Vector(
+            random::<f64>() * 2.0 - 1.0,
+            random::<f64>() * 2.0 - 1.0,
+            random::<f64>() * 2.0 - 1.0,
+        );
+        if p.magnitude_squared() <= 1.0 {
+            break p;
+        }
+    };
+
+    let perturbed = (direction + offset * f64::from(roughness)).normalize();
+    if perturbed.dot(normal) > 0.0 {
+        perturbed
+    } else {
+        direction
+    }
+}
+
+/// Sample a glossy reflection ray off a rough metal surface, for path tracing.
+///
+/// Importance samples a single reflected direction from the GGX microfacet distribution. Returns
+/// the ray along with the weight of its observed color, the BRDF over the sample pdf, or `None` if
+/// the sample is absorbed.
+fn reflect_ggx(
+    scene: &Scene,
+    view: Vector,
+    hit: Vector,
+    ggx: &Ggx,
+    color: Color,
+) -> Option<(Ray, Color)> {
+    let (direction, pdf) = match ggx.sample(view, random(), random()) {
+        Some(sample) if sample.1 > 0.0 => sample,
+        _ => return None,
+    };
+
+    // Cook-Torrance BRDF with the GGX distribution and Smith shadowing
+    let half = (view + direction).normalize();
+    let n_dot_v = ggx.normal.dot(view).max(f64::EPSILON);
+    let n_dot_l = ggx.normal.dot(direction);
+    let distribution = ggx.distribution(half);
+    let shadowing = ggx.smith_g1(view) * ggx.smith_g1(direction);
+    let brdf = distribution * shadowing / (4.0 * n_dot_v * n_dot_l);
+    let reflectance = fresnel_schlick(view.dot(half), color) * (brdf * n_dot_l / pdf) as f32;
+
+    Some((Ray::new(hit, direction).bias(scene.bias), reflectance))
+}
+
+/// Get the tangent and bitangent at the `intersection` world space `hit` point, with the given
+/// unit `normal`.
+///
+/// The tangent follows the reference `tangent` projected onto the surface if given, or the
+/// texture tangent of the intersection otherwise.
+fn surface_frame(
+    intersection: &Intersection,
+    hit: Vector,
+    normal: Vector,
+    tangent: Option<Vector>,
+) -> (Vector, Vector) {
+    match tangent.map(|t| t - normal * t.dot(normal)) {
+        Some(t) if t.magnitude_squared() > 1e-12 => {
+            let t = t.normalize();
+            (t, normal.cross(t))
+        }
+        _ => intersection.tangent_frame(hit, normal),
+    }
+}
+
+/// A GGX microfacet distribution around a surface normal, optionally anisotropic.
+struct Ggx {
+    /// Unit surface tangent, along which the `alpha_u` roughness applies.
+    tangent: Vector,
+
+    /// Unit surface bitangent, along which the `alpha_v` roughness applies.
+    bitangent: Vector,
+
+    /// Unit surface normal.
+    normal: Vector,
+
+    /// Distribution width along the tangent, the squared roughness.
+    alpha_u: f64,
+
+    /// Distribution width along the bitangent, the squared roughness.
+    alpha_v: f64,
+}
+
+impl Ggx {
+    /// Construct a distribution for the given surface frame and roughness along its axes.
+    ///
+    /// Equal roughnesses give an isotropic distribution, for which the frame does not matter.
+    fn new(frame: (Vector, Vector), normal: Vector, roughness: (f32, f32)) -> Self {
+        let alpha = |roughness: f32| f64::from(roughness * roughness).max(GGX_MIN_ALPHA);
+        Self {
+            tangent: frame.0,
+            bitangent: frame.1,
+            normal,
+            alpha_u: alpha(roughness.0),
+            alpha_v: alpha(roughness.1),
+        }
+    }
+
+    /// Get the coordinates of a `direction` in the surface frame.
+    fn local(&self, direction: Vector) -> (f64, f64, f64) {
+        (
+            direction.dot(self.tangent),
+            direction.dot(self.bitangent),
+            direction.dot(self.normal),
+        )
+    }
+
+    /// Sample a reflected direction of `view` for `u` and `v` in `[0,1)`.
+    ///
+    /// A microfacet normal is sampled proportional to its projected area, by stretching a sampled
+    /// unit roughness slope, and `view` is reflected about it. Returns the direction with its
+    /// pdf, or `None` if the direction points below the surface.
+    fn sample(&self, view: Vector, u: f64, v: f64) -> Option<(Vector, f64)> {
+        let slope = (u / (1.0 - u)).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * v;
+        let half = (self.tangent * (self.alpha_u * slope * phi.cos())
+            + self.bitangent * (self.alpha_v * slope * phi.sin())
+            + self.normal)
+            .normalize();
+
+        let v_dot_h = view.dot(half);
+        let direction = half * (2.0 * v_dot_h) - view;
+        if direction.dot(self.normal) <= 0.0 || v_dot_h <= 0.0 {
+            return None;
+        }
+        let pdf = self.distribution(half) * self.normal.dot(half) / (4.0 * v_dot_h);
+        Some((direction, pdf))
+    }
+
+    /// The density of microfacets with the unit normal `half`.
+    fn distribution(&self, half: Vector) -> f64 {
+        let (x, y, z) = self.local(half);
+        let d = (x / self.alpha_u).powi(2) + (y / self.alpha_v).powi(2) + z * z;
+        1.0 / (std::f64::consts::PI * self.alpha_u * self.alpha_v * d * d)
+    }
+
+    /// The Smith masking function, for a unit `direction` above the surface.
+    fn smith_g1(&self, direction: Vector) -> f64 {
+        let (x, y, z) = self.local(direction);
+        let z = z.max(f64::EPSILON);
+        let tan2 = ((x * self.alpha_u).powi(2) + (y * self.alpha_v).powi(2)) / (z * z);
+        2.0 / (1.0 + (1.0 + tan2).sqrt())
+    }
+}
+
+/// Calculate the Schlick Fresnel approximation for a conductor.
+///
+/// The reflectance rises from the base reflectance color `f0` at normal incidence towards white
+/// at grazing angles. `cos_theta` is the cosine of the angle between the incident ray and normal.
+fn fresnel_schlick(cos_theta: f64, f0: Color) -> Color {
+    let weight = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5) as f32;
+    f0.lerp(*WHITE, weight)
+}
+
+/// Calcualte fresnel lens value.
+fn fresnel(incident: Vector, normal: Vector, index: f32) -> f64 {
+    let i_dot_n = incident.dot(normal);
+    let mut eta_i = 1.0;
+    let mut eta_t = f64::from(index);
+    if i_dot_n > 0.0 {
+        eta_i = eta_t;
+        eta_t = 1.0;
+    }
+
+    let sin_t = eta_i / eta_t * (1.0 - i_dot_n * i_dot_n).max(0.0).sqrt();
+    if sin_t > 1.0 {
+        // Total internal reflection
+        1.0
+    } else {
+        let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
+        let cos_i = cos_t.abs();
         let r_s = ((eta_t * cos_i) - (eta_i * cos_t)) / ((eta_t * cos_i) + (eta_i * cos_t));
         let r_p = ((eta_i * cos_i) - (eta_t * cos_t)) / ((eta_i * cos_i) + (eta_t * cos_t));
         (r_s * r_s + r_p * r_p) / 2.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::geometric::{Entity, Mesh, Model, Visibility};
+    use crate::material::MaterialRef;
+
+    #[test]
+    fn test_soft_shadow_partial() {
+        let source = |blocker_radius| {
+            format!(
+                r#"
+                samples: 256
+                camera: {{ width: 4, height: 3 }}
+                entities:
+                  - type: sphere
+                    center: [0, 5, 0]
+                    radius: {}
+                    material: {{ color: [1, 1, 1], albedo: 0.5 }}
+                lights:
+                  - type: spherical
+                    position: [0, 10, 0]
+                    color: [1, 1, 1]
+                    intensity: 100
+                    radius: 4
+                "#,
+                blocker_radius
+            )
+        };
+        let receive = |scene: &Scene| {
+            receive_light(
+                scene,
+                &scene.lights[0],
+                Vector(0.0, 0.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+            )
+        };
+
+        // A blocker partially occludes the light, a large blocker fully occludes it
+        let unblocked = receive(&scene(&source(0.0)));
+        let partial = receive(&scene(&source(1.0)));
+        let blocked = receive(&scene(&source(4.0)));
+        assert!(
+            partial > 0.0 && partial < unblocked * 0.95,
+            "light {} is not partially occluded, unoccluded is {}",
+            partial,
+            unblocked
+        );
+        assert_eq!(blocked, 0.0);
+    }
+
+    #[test]
+    fn test_light_samples_unbiased() {
+        // Twenty lights of increasing intensity, so the sampled subset matters
+        let source = |light_samples: u32| {
+            let lights: String = (0..20)
+                .map(|i| {
+                    format!(
+                        "\n  - {{ type: directional, direction: [0, -1, 0], color: [1, 1, 1], intensity: {} }}",
+                        f64::from(i + 1) * 0.01,
+                    )
+                })
+                .collect();
+            format!(
+                "light_samples: {}\ncamera: {{ width: 4, height: 3 }}\nentities:\n  - {{ type: plane, center: [0, 0, 0], normal: [0, -1, 0], material: {{ color: [1, 1, 1], albedo: 0.5 }} }}\nlights:{}",
+                light_samples, lights,
+            )
+        };
+        let shade = |scene: &Scene| {
+            let intersection = Intersection {
+                distance: 1.0,
+                normal: Vector(0.0, 1.0, 0.0),
+                uv: None,
+                texture_derivatives: None,
+                barycentric: None,
+                front_face: true,
+                entity: &scene.entities[0],
+                index: 0,
+                transform: Transform::default(),
+            };
+            shade_direct(
+                scene,
+                &intersection,
+                Vector(0.0, 0.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+            )
+        };
+
+        // All lights are sampled if there are no more lights than light samples
+        let all = shade(&scene(&source(20))).mean_channel();
+
+        // A subset of lights varies per sample, but matches all lights on average
+        let subset = scene(&source(4));
+        let runs = 4000;
+        rng::seed(1);
+        let samples: Vec<f32> = (0..runs).map(|_| shade(&subset).mean_channel()).collect();
+        let mean = samples.iter().sum::<f32>() / runs as f32;
+        assert!(samples.iter().any(|s| (s - all).abs() > 1e-3));
+        assert!(
+            (mean - all).abs() < all * 0.02,
+            "subset mean {} differs from all lights {}",
+            mean,
+            all
+        );
+    }
+
+    #[test]
+    fn test_ambient_occlusion_crevice() {
+        // A floor, with two walls forming a narrow crevice along the Z axis
+        let scene = scene(
+            r#"
+            render_mode: { ambient_occlusion: { samples: 256, radius: 10 } }
+            camera: { width: 4, height: 3 }
+            entities:
+              - { type: plane, center: [0, 0, 0], normal: [0, -1, 0], material: { color: [1, 1, 1], albedo: 0.5 } }
+              - { type: sphere, center: [-10.5, 0, 0], radius: 10, material: { color: [1, 1, 1], albedo: 0.5 } }
+              - { type: sphere, center: [10.5, 0, 0], radius: 10, material: { color: [1, 1, 1], albedo: 0.5 } }
+            lights: []
+            "#,
+        );
+        let occlusion = |x| {
+            let ray = Ray::new(Vector(x, 5.0, 0.0), Vector(0.0, -1.0, 0.0));
+            ambient_occlusion(
+                &scene,
+                &ray,
+                Vector(x, 0.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+                256,
+                10.0,
+            )
+        };
+
+        // Far from the walls the floor is open, in the crevice it is mostly occluded
+        let open = occlusion(1000.0);
+        let crevice = occlusion(0.0);
+        assert!(open > 0.99, "open floor occlusion is {}", open);
+        assert!(crevice < 0.5, "crevice occlusion is {}", crevice);
+    }
+
+    #[test]
+    fn test_flat_normal_map_unchanged() {
+        let dir =
+            std::env::temp_dir().join(format!("raytrace-rs-test-normal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([128, 128, 255]))
+            .save(dir.join("flat.png"))
+            .unwrap();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([200, 128, 200]))
+            .save(dir.join("tilted.png"))
+            .unwrap();
+
+        let normals = |map: &str| {
+            let mut scene = scene(&format!(
+                r#"
+                camera: {{ width: 4, height: 3 }}
+                entities:
+                  - type: sphere
+                    center: [0, 0, -5]
+                    material: {{ color: [1, 1, 1], albedo: 0.5, normal_map: {{ path: {} }} }}
+                  - type: plane
+                    center: [0, -1, 0]
+                    normal: [0, -1, 0]
+                    material: {{ color: [1, 1, 1], albedo: 0.5, normal_map: {{ path: {} }} }}
+                lights: []
+                "#,
+                map, map
+            ));
+            scene.load(&dir, Verbosity::Quiet);
+            let sphere_normal = Vector(0.3, 0.5, 0.6).normalize();
+            let rays = [
+                Ray::new(Vector(0.0, 0.0, -5.0) + sphere_normal * 2.0, -sphere_normal),
+                Ray::new(Vector(2.0, 0.0, -3.0), Vector(0.0, -1.0, 0.0)),
+            ];
+            rays.iter()
+                .map(|ray| {
+                    let intersection = scene.intersect(ray, RayType::Primary).unwrap();
+                    let hit = ray.origin + ray.direction * intersection.distance;
+                    (shading_normal(&intersection, hit), intersection.normal)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // A flat normal map keeps the geometric normal, a tilted map perturbs it
+        for (shading, geometric) in normals("flat.png") {
+            assert!((shading - geometric).magnitude() < 1e-9);
+        }
+        for (shading, geometric) in normals("tilted.png") {
+            assert!(shading.dot(geometric) < 0.8);
+            assert!((shading.magnitude() - 1.0).abs() < 1e-9);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_alpha_cutout_passes_through() {
+        let dir =
+            std::env::temp_dir().join(format!("raytrace-rs-test-alpha-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut mask = image::RgbImage::new(4, 1);
+        for x in 2..4 {
+            mask.put_pixel(x, 0, image::Rgb([255, 255, 255]));
+        }
+        mask.save(dir.join("mask.png")).unwrap();
+
+        // The left half of the rectangle is cut out, the right half is opaque
+        let mut scene = scene(
+            r#"
+            camera: { width: 2, height: 1 }
+            background: { type: color, color: [1, 0, 0] }
+            entities:
+              - type: rect
+                center: [0, 0, -2]
+                normal: [0, 0, 1]
+                u_axis: [1, 0, 0]
+                v_axis: [0, 1, 0]
+                half_width: 4
+                material: { color: [1, 1, 1], albedo: 1, alpha: { path: mask.png } }
+            lights:
+              - { type: directional, direction: [0, 0, -1], color: [1, 1, 1], intensity: 1 }
+            "#,
+        );
+        scene.load(&dir, Verbosity::Quiet);
+        let image = render(&scene, &quiet(), None).to_rgb8();
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0]);
+        assert_ne!(image.get_pixel(1, 0).0, [255, 0, 0]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_tiled_render_identical() {
+        let mut scene = scene(
+            r#"
+            samples: 4
+            camera: { width: 45, height: 30 }
+            entities:
+              - { type: sphere, center: [0, 0, -5], material: { color: [1, 0.4, 0], albedo: 0.5 } }
+              - { type: plane, center: [0, -1, 0], normal: [0, -1, 0], material: { color: [1, 1, 1], albedo: 0.5 } }
+            lights:
+              - { type: spherical, position: [2, 5, -3], color: [1, 1, 1], intensity: 500, radius: 1 }
+            "#,
+        );
+
+        // Reference render, tracing pixel by pixel in column major order
+        let reference: Vec<Color> = (0..scene.camera.pixels())
+            .map(|i| {
+                rng::seed(scene.seed ^ i);
+                let (x, y) = pixel_position(&scene.camera, i);
+                trace_primary(&scene, &Ray::new_prime(x, y, &scene), None).color
+            })
+            .collect();
+
+        for &tile_size in &[1, 7, 32, 1000] {
+            scene.tile_size = tile_size;
+            let colors: Vec<Color> = render_fragments(&scene, &quiet(), None)
+                .iter()
+                .map(|f| f.color)
+                .collect();
+            assert!(colors == reference, "tile size {} differs", tile_size);
+        }
+    }
+
+    #[test]
+    fn test_ssaa_downsamples_to_camera() {
+        let scene = scene(
+            r#"
+            camera: { width: 5, height: 3 }
+            entities:
+              - { type: sphere, center: [0, 0, -5], material: { color: [1, 1, 1], albedo: 0.5 } }
+            lights:
+              - { type: directional, direction: [0, 0, -1], color: [1, 1, 1], intensity: 1 }
+            "#,
+        );
+
+        // Supersampling renders four times the pixels
+        let supersampled = supersampled(&scene, 2);
+        let fragments = render_fragments(&supersampled, &quiet(), None);
+        assert_eq!(fragments.len() as u64, 4 * scene.camera.pixels());
+
+        // Each pixel averages its block of samples
+        let downsampled = downsample(&scene.camera, &fragments, 2);
+        assert_eq!(downsampled.len() as u64, scene.camera.pixels());
+        let (x, y) = (2, 1);
+        let block: Vec<Color> = [(4, 2), (4, 3), (5, 2), (5, 3)]
+            .iter()
+            .map(|&(sx, sy)| fragments[sx * 6 + sy].color)
+            .collect();
+        let average = (block[0] + block[1] + block[2] + block[3]) * 0.25;
+        assert_eq!(downsampled[x * 3 + y].color, average);
+
+        let options = RenderOptions {
+            ssaa: Some(2),
+            ..RenderOptions::default()
+        };
+        let image = render(&scene, &options, None).to_rgb8();
+        assert_eq!((image.width(), image.height()), (5, 3));
+    }
+
+    #[test]
+    fn test_clamp_luminance_preserves_hue() {
+        let color = Color::new(4.0, 2.0, 1.0);
+        assert_eq!(clamp_luminance(color, None).channels(), color.channels());
+        assert_eq!(
+            clamp_luminance(color, Some(10.0)).channels(),
+            color.channels()
+        );
+
+        let (r, g, b) = clamp_luminance(color, Some(1.0)).channels();
+        assert!((Color::new(r, g, b).luminance() - 1.0).abs() < 1e-6);
+        assert!((r / g - 2.0).abs() < 1e-5);
+        assert!((g / b - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_ggx_concentrates_around_mirror() {
+        let normal = Vector(0.0, 1.0, 0.0);
+        let view = Vector(1.0, 1.0, 0.0).normalize();
+        let mirror = Vector(-1.0, 1.0, 0.0).normalize();
+
+        // Mean angular deviation from the mirror direction over stratified samples
+        let spread = |roughness: f32| {
+            let ggx = Ggx::new(normal.orthonormal_basis(), normal, (roughness, roughness));
+            let samples: Vec<Vector> = (0..256)
+                .filter_map(|i| {
+                    let (u, v) = ((i / 16) as f64 + 0.5, (i % 16) as f64 + 0.5);
+                    ggx.sample(view, u / 16.0, v / 16.0)
+                })
+                .inspect(|(_, pdf)| assert!(*pdf > 0.0))
+                .map(|(direction, _)| direction)
+                .collect();
+            samples
+                .iter()
+                .map(|d| d.dot(mirror).min(1.0).acos())
+                .sum::<f64>()
+                / samples.len() as f64
+        };
+
+        let spreads: Vec<f64> = [0.8, 0.4, 0.1, 0.01].iter().map(|&r| spread(r)).collect();
+        assert!(spreads.windows(2).all(|w| w[1] < w[0]), "{:?}", spreads);
+        assert!(spreads[3] < 1e-3, "{:?}", spreads);
+    }
+
+    #[test]
+    fn test_ggx_anisotropic_stretch() {
+        let normal = Vector(0.0, 1.0, 0.0);
+        let frame = (Vector(1.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        let view = Vector(0.0, 1.0, 0.0);
+
+        // Mean absolute offset of reflected directions along the tangent and bitangent
+        let spread = |roughness| {
+            let ggx = Ggx::new(frame, normal, roughness);
+            let samples: Vec<Vector> = (0..256)
+                .filter_map(|i| {
+                    let (u, v) = ((i / 16) as f64 + 0.5, (i % 16) as f64 + 0.5);
+                    ggx.sample(view, u / 16.0, v / 16.0)
+                })
+                .map(|(direction, _)| direction)
+                .collect();
+            let mean = |axis: Vector| {
+                samples.iter().map(|d| d.dot(axis).abs()).sum::<f64>() / samples.len() as f64
+            };
+            (mean(frame.0), mean(frame.1))
+        };
+
+        // Rougher along the tangent spreads reflections along it more
+        let (along_u, along_v) = spread((0.6, 0.15));
+        assert!(along_u > along_v * 3.0, "{} {}", along_u, along_v);
+        let (along_u, along_v) = spread((0.15, 0.6));
+        assert!(along_v > along_u * 3.0, "{} {}", along_u, along_v);
+
+        // Equal roughnesses are isotropic, matching the isotropic GGX distribution
+        let (along_u, along_v) = spread((0.4, 0.4));
+        assert!((along_u - along_v).abs() < 0.02, "{} {}", along_u, along_v);
+        let ggx = Ggx::new(frame, normal, (0.4, 0.4));
+        let alpha2 = f64::from(0.4f32 * 0.4).powi(2);
+        for &cos in &[1.0f64, 0.9, 0.5] {
+            let half = Vector((1.0 - cos * cos).sqrt(), cos, 0.0);
+            let d = cos * cos * (alpha2 - 1.0) + 1.0;
+            let isotropic = alpha2 / (std::f64::consts::PI * d * d);
+            assert!((ggx.distribution(half) / isotropic - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_render_independent_of_threads() {
+        let scene = scene(
+            r#"
+            max_samples: 4
+            samples: 4
+            variance_threshold: 0.001
+            camera: { width: 24, height: 16, fov: 40 }
+            entities:
+              - { type: sphere, center: [0, 0, -4], radius: 1, material: { color: [1, 0.5, 0.2], albedo: 0.8, surface: { type: metal, color: [0.9, 0.9, 0.9], roughness: 0.4 } } }
+              - { type: plane, center: [0, -1, 0], normal: [0, -1, 0], material: { color: [1, 1, 1], albedo: 0.5 } }
+            lights:
+              - { type: spherical, position: [2, 3, -2], color: [1, 1, 1], intensity: 200, radius: 0.5 }
+            "#,
+        );
+
+        // Jittered anti-aliasing, soft shadows and glossy reflections are all sampled randomly
+        let render_with = |scene: &Scene, threads: usize| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| render(scene, &quiet(), None).to_rgb8().into_raw())
+        };
+        assert_eq!(render_with(&scene, 1), render_with(&scene, 4));
+
+        let mut path_traced = scene.clone();
+        path_traced.render_mode = RenderMode::PathTraced;
+        path_traced.samples = 8;
+        assert_eq!(render_with(&path_traced, 1), render_with(&path_traced, 4));
+    }
+
+    #[test]
+    fn test_output_contain_letterbox() {
+        let scene = scene(
+            r#"
+            background: { type: color, color: [1, 1, 1] }
+            camera: { width: 4, height: 4 }
+            output: { width: 8, height: 4, fit: contain, fill: [1, 0, 0] }
+            entities: []
+            lights: []
+            "#,
+        );
+        let image = render(&scene, &RenderOptions::default(), None).to_rgb8();
+        assert_eq!((image.width(), image.height()), (8, 4));
+
+        // Equal width bars of the fill color on both sides
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let expected = if (2..6).contains(&x) {
+                [255, 255, 255]
+            } else {
+                [255, 0, 0]
+            };
+            assert_eq!(pixel.0, expected, "pixel ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn test_progressive_resume() {
+        let scene = scene(
+            r#"
+            render_mode: path_traced
+            seed: 7
+            camera: { width: 4, height: 3, fov: 40 }
+            entities:
+              - { type: sphere, center: [0, 0, -4], radius: 1, material: { color: [1, 0.5, 0.2], albedo: 0.8 } }
+              - { type: plane, center: [0, -1, 0], normal: [0, -1, 0], material: { color: [1, 1, 1], albedo: 0.5 } }
+            lights:
+              - { type: spherical, position: [2, 3, -2], color: [1, 1, 1], intensity: 200, radius: 0.5 }
+            "#,
+        );
+
+        // Render 100 samples, checkpoint, and resume to 200
+        let path = std::env::temp_dir().join(format!(
+            "raytrace-rs-test-resume-{}.ckpt",
+            std::process::id()
+        ));
+        let mut first = Accumulation::new(&scene.camera);
+        render_progressive(
+            &scene,
+            &mut first,
+            100,
+            Verbosity::Quiet,
+            false,
+            PixelOrder::Linear,
+            None,
+        );
+        first.save(&path).unwrap();
+        let mut resumed = Accumulation::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(resumed, first);
+        render_progressive(
+            &scene,
+            &mut resumed,
+            100,
+            Verbosity::Quiet,
+            false,
+            PixelOrder::Linear,
+            None,
+        );
+
+        // Render 200 samples at once
+        let mut single = Accumulation::new(&scene.camera);
+        render_progressive(
+            &scene,
+            &mut single,
+            200,
+            Verbosity::Quiet,
+            false,
+            PixelOrder::Linear,
+            None,
+        );
+
+        assert_eq!(resumed.samples(), 200);
+        assert_eq!(resumed, single);
+        assert_eq!(
+            resumed.to_image(PixelFormat::Rgb8, false).to_bytes(),
+            single.to_image(PixelFormat::Rgb8, false).to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_progressive_deadline() {
+        let scene = scene(
+            r#"
+            render_mode: path_traced
+            camera: { width: 4, height: 3, fov: 40 }
+            entities:
+              - { type: sphere, center: [0, 0, -4], radius: 1, material: { color: [1, 1, 1], albedo: 0.8 } }
+            lights: []
+            background: { type: color, color: [1, 1, 1] }
+            "#,
+        );
+
+        // A deadline that already passed stops after the first sample pass
+        let mut accumulation = Accumulation::new(&scene.camera);
+        let deadline = Some(Instant::now());
+        render_progressive(
+            &scene,
+            &mut accumulation,
+            100,
+            Verbosity::Quiet,
+            false,
+            PixelOrder::Linear,
+            deadline,
+        );
+        assert_eq!(accumulation.samples(), 1);
+
+        let image = accumulation.to_image(PixelFormat::Rgb8, false).to_rgba8();
+        assert_eq!(image.dimensions(), (4, 3));
+        assert!(image.pixels().all(|p| p.0[0] > 0));
+    }
+
+    #[test]
+    fn test_wireframe_edges() {
+        let mut scene = Scene::new(Camera::new(4, 3));
+        scene.render_mode = RenderMode::Wireframe;
+        let positions = vec![
+            Vector(-1.0, -1.0, -5.0),
+            Vector(1.0, -1.0, -5.0),
+            Vector(0.0, 1.0, -5.0),
+        ];
+        scene.entities.push(Entity::Model(Model {
+            path: String::new(),
+            position: Vector(0.0, 0.0, 0.0),
+            scale: 1.0,
+            meshes: vec![Mesh::new(positions, vec![], vec![], vec![0, 1, 2])],
+            smooth: None,
+            max_triangles: None,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
+        }));
+        scene.update_bounding_boxes();
+        let direction = Vector(0.0, 0.0, -1.0);
+
+        // Near the bottom edge the edge color is drawn, at the center the fill color
+        let edge = trace_primary(&scene, &Ray::new(Vector(0.0, -0.99, 0.0), direction), None);
+        assert_eq!(edge.color, *BLACK);
+        let center = trace_primary(&scene, &Ray::new(Vector(0.0, -0.3, 0.0), direction), None);
+        assert_eq!(center.color, *GRAY);
+    }
+
+    #[test]
+    fn test_absorption_over_path_length() {
+        let transmitted = |radius: f64| {
+            let scene = scene(&format!(
+                r#"
+                background: {{ type: color, color: [1, 1, 1] }}
+                camera: {{ width: 4, height: 3 }}
+                entities:
+                  - type: sphere
+                    center: [0, 0, -10]
+                    radius: {}
+                    material:
+                      color: [1, 1, 1]
+                      albedo: 0.5
+                      surface: {{ type: transparent, index: 1, transparency: 1, absorption: [0.1, 0.5, 1] }}
+                lights: []
+                "#,
+                radius
+            ));
+            let ray = Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+            observe_ray(
+                &scene,
+                &ray,
+                RayType::Primary,
+                Depth::default(),
+                *WHITE,
+                None,
+            )
+        };
+
+        // Light passing straight through decays over the diameter of the sphere
+        let thin = transmitted(0.1);
+        let thick = transmitted(1.0);
+        let (r, g, b) = thick.channels();
+        assert!((r - (-0.2f32).exp()).abs() < 1e-3, "red {}", r);
+        assert!((g - (-1.0f32).exp()).abs() < 1e-3, "green {}", g);
+        assert!((b - (-2.0f32).exp()).abs() < 1e-3, "blue {}", b);
+        assert!(thick.max_channel() < thin.max_channel());
+        assert!(thin.mean_channel() > 0.8);
+    }
+
+    #[test]
+    fn test_tiles_cover_image() {
+        let camera = Camera::new(45, 30);
+        let tiles = tiles(&camera, 32, PixelOrder::Linear);
+        assert_eq!(tiles.len(), 2);
+        assert_eq!(
+            tiles[1],
+            Tile {
+                x: 32,
+                y: 0,
+                width: 13,
+                height: 30
+            }
+        );
+        let mut pixels: Vec<(u32, u32)> = tiles
+            .iter()
+            .flat_map(|t| t.pixels(PixelOrder::Linear))
+            .collect();
+        pixels.sort_unstable();
+        pixels.dedup();
+        assert_eq!(pixels.len() as u64, camera.pixels());
+    }
+
+    #[test]
+    fn test_morton_order_is_bijection() {
+        let camera = Camera::new(45, 30);
+        let mut indices = pixel_indices(&camera, PixelOrder::Morton);
+        assert_eq!(indices.len() as u64, camera.pixels());
+        assert_eq!(&indices[..4], &[0, 30, 1, 31]);
+        indices.sort_unstable();
+        assert!(indices.iter().copied().eq(0..camera.pixels()));
+
+        // Tiles in Morton order still cover every pixel exactly once
+        let mut pixels: Vec<(u32, u32)> = tiles(&camera, 8, PixelOrder::Morton)
+            .iter()
+            .flat_map(|t| t.pixels(PixelOrder::Morton))
+            .collect();
+        assert_eq!(pixels.len() as u64, camera.pixels());
+        pixels.sort_unstable();
+        pixels.dedup();
+        assert_eq!(pixels.len() as u64, camera.pixels());
+        assert!(pixels
+            .iter()
+            .all(|&(x, y)| x < camera.width && y < camera.height));
+    }
+
+    #[test]
+    fn test_pixel_order_does_not_change_render() {
+        let mut scene = scene(
+            r#"
+            samples: 4
+            tile_size: 4
+            camera: { width: 13, height: 7 }
+            entities:
+              - { type: sphere, center: [0, 0, -4], radius: 1, material: { color: [1, 0.5, 0], albedo: 0.8 } }
+            lights:
+              - { type: spherical, position: [2, 3, 0], color: [1, 1, 1], intensity: 100, radius: 0.5 }
+            "#,
+        );
+        let options = |pixel_order| RenderOptions {
+            pixel_order,
+            ..quiet()
+        };
+        let linear = render(&scene, &options(PixelOrder::Linear), None);
+        let morton = render(&scene, &options(PixelOrder::Morton), None);
+        assert_eq!(linear.to_bytes(), morton.to_bytes());
+
+        scene.render_mode = RenderMode::PathTraced;
+        let progressive = |order| {
+            let mut accumulation = Accumulation::new(&scene.camera);
+            render_progressive(
+                &scene,
+                &mut accumulation,
+                2,
+                Verbosity::Quiet,
+                false,
+                order,
+                None,
+            );
+            accumulation
+        };
+        assert_eq!(
+            progressive(PixelOrder::Linear),
+            progressive(PixelOrder::Morton)
+        );
+    }
+
+    #[test]
+    fn test_path_traced_environment_light() {
+        let scene = scene(
+            r#"
+            render_mode: path_traced
+            samples: 16
+            background: { type: color, color: [0.2, 0.5, 0.8] }
+            camera: { width: 9, height: 9, fov: 30 }
+            entities:
+              - { type: sphere, center: [0, 0, -5], material: { color: [1, 1, 1], albedo: 1 } }
+            lights: []
+            "#,
+        );
+
+        // Without lights, the white sphere reflects the environment all around it
+        let image = render(&scene, &RenderOptions::default(), None).to_rgb8();
+        let center = image.get_pixel(4, 4).0;
+        let expected = [51, 127, 204];
+        for (channel, expected) in center.iter().zip(expected.iter()) {
+            assert!(
+                (i32::from(*channel) - expected).abs() <= 3,
+                "sphere color {:?} does not match environment {:?}",
+                center,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_hard_shadow_without_radius() {
+        let scene = scene(
+            r#"
+            samples: 16
+            camera: { width: 4, height: 3 }
+            entities:
+              - type: sphere
+                center: [0, 5, 0]
+                radius: 1
+                material: { color: [1, 1, 1], albedo: 0.5 }
+            lights:
+              - type: spherical
+                position: [0, 10, 0]
+                color: [1, 1, 1]
+                intensity: 100
+            "#,
+        );
+        let light = &scene.lights[0];
+        let normal = Vector(0.0, 1.0, 0.0);
+        assert_eq!(
+            receive_light(&scene, light, Vector(0.0, 0.0, 0.0), normal),
+            0.0
+        );
+        assert!(receive_light(&scene, light, Vector(5.0, 0.0, 0.0), normal) > 0.0);
+    }
+
+    #[test]
+    fn test_light_without_shadows() {
+        let source = |cast_shadows: bool| {
+            format!(
+                r#"
+                camera: {{ width: 4, height: 3 }}
+                entities:
+                  - {{ type: sphere, center: [0, 5, 0], radius: 1, material: {{ color: [1, 1, 1], albedo: 0.5 }} }}
+                lights:
+                  - {{ type: directional, direction: [0, -1, 0], color: [1, 1, 1], intensity: 1, cast_shadows: {} }}
+                "#,
+                cast_shadows
+            )
+        };
+        let normal = Vector(0.0, 1.0, 0.0);
+
+        // The point below the sphere is fully occluded, unless the light casts no shadows
+        let shadowed = scene(&source(true));
+        assert_eq!(
+            receive_light(
+                &shadowed,
+                &shadowed.lights[0],
+                Vector(0.0, 0.0, 0.0),
+                normal
+            ),
+            0.0
+        );
+        let unshadowed = scene(&source(false));
+        assert_eq!(
+            receive_light(
+                &unshadowed,
+                &unshadowed.lights[0],
+                Vector(0.0, 0.0, 0.0),
+                normal
+            ),
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_sky_light_from_above() {
+        let scene = scene(
+            r#"
+            samples: 16
+            camera: { width: 4, height: 3 }
+            entities: []
+            lights:
+              - { type: sky, color: [0.5, 0.7, 1], intensity: 1, up: [0, 1, 0] }
+            "#,
+        );
+        let sky = &scene.lights[0];
+        let hit = Vector(0.0, 0.0, 0.0);
+
+        // An upward facing surface sees the whole sky, a downward facing one none of it
+        let up = receive_light(&scene, sky, hit, Vector(0.0, 1.0, 0.0));
+        let down = receive_light(&scene, sky, hit, Vector(0.0, -1.0, 0.0));
+        assert!(
+            (up - PI).abs() < 1e-5,
+            "upward facing surface received {}",
+            up
+        );
+        assert!(up > down);
+        assert_eq!(down, 0.0);
+    }
+
+    #[test]
+    fn test_specular_highlight() {
+        let source = |highlight: &str| {
+            format!(
+                r#"
+                camera: {{ width: 4, height: 3 }}
+                entities:
+                  - {{ type: plane, center: [0, 0, 0], normal: [0, -1, 0], material: {{ color: [1, 1, 1], albedo: 0.5{} }} }}
+                lights:
+                  - {{ type: directional, direction: [0, -1, 0], color: [1, 1, 1], intensity: 1 }}
+                "#,
+                highlight
+            )
+        };
+        let shade = |scene: &Scene, view: Vector| {
+            let intersection = Intersection {
+                distance: 1.0,
+                normal: Vector(0.0, 1.0, 0.0),
+                uv: None,
+                texture_derivatives: None,
+                barycentric: None,
+                front_face: true,
+                entity: &scene.entities[0],
+                index: 0,
+                transform: Transform::default(),
+            };
+            shade_direct(
+                scene,
+                &intersection,
+                Vector(0.0, 0.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+                view.normalize(),
+            )
+            .mean_channel()
+        };
+        let aligned = Vector(0.0, 1.0, 0.0);
+        let off_angle = Vector(1.0, 0.3, 0.0);
+
+        // Without a highlight, the view direction doesn't matter
+        let plain = scene(&source(""));
+        assert_eq!(shade(&plain, aligned), shade(&plain, off_angle));
+
+        // The highlight peaks where the half vector aligns with the normal
+        let glossy = scene(&source(
+            ", specular_highlight: { power: 50, intensity: 0.5 }",
+        ));
+        assert!(shade(&glossy, aligned) - shade(&plain, aligned) > 0.45);
+        assert!(shade(&glossy, off_angle) - shade(&plain, off_angle) < 0.01);
+    }
+
+    #[test]
+    fn test_directional_light_direction_normalized() {
+        let shade = |direction: &str| {
+            let scene = scene(&format!(
+                r#"
+                camera: {{ width: 4, height: 3 }}
+                entities:
+                  - {{ type: plane, center: [0, 0, 0], normal: [0, -1, 0], material: {{ color: [1, 1, 1], albedo: 0.5 }} }}
+                lights:
+                  - {{ type: directional, direction: {}, color: [1, 1, 1], intensity: 1 }}
+                "#,
+                direction
+            ));
+            let intersection = Intersection {
+                distance: 1.0,
+                normal: Vector(0.0, 1.0, 0.0),
+                uv: None,
+                texture_derivatives: None,
+                barycentric: None,
+                front_face: true,
+                entity: &scene.entities[0],
+                index: 0,
+                transform: Transform::default(),
+            };
+            shade_direct(
+                &scene,
+                &intersection,
+                Vector(0.0, 0.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+                Vector(0.0, 1.0, 0.0),
+            )
+        };
+
+        // The length of the authored direction doesn't scale the lighting
+        assert_eq!(shade("[0, -10, 0]"), shade("[0, -1, 0]"));
+        assert!(shade("[0, -1, 0]").mean_channel() > 0.0);
+    }
+
+    #[test]
+    fn test_grazing_light_no_acne() {
+        // A flat plane, and a huge sphere that is locally flat where floats are imprecise
+        let grounds = [
+            "{ type: plane, center: [0, -1, 0], normal: [0, -1, 0], material: { color: [1, 1, 1], albedo: 1 } }",
+            "{ type: sphere, center: [0, -100001, 0], radius: 100000, material: { color: [1, 1, 1], albedo: 1 } }",
+        ];
+        for ground in grounds.iter() {
+            let scene = scene(&format!(
+                r#"
+                camera: {{ width: 80, height: 40 }}
+                entities:
+                  - {}
+                lights:
+                  - type: directional
+                    direction: [0.999, -0.02, 0]
+                    color: [1, 1, 1]
+                    intensity: 50
+                "#,
+                ground
+            ));
+
+            // The lower half of the image sees the lit ground, it must not have shadow pixels
+            let image = render(&scene, &RenderOptions::default(), None).to_rgb8();
+            let shadowed = image
+                .enumerate_pixels()
+                .filter(|(_, y, p)| *y >= 25 && p.0 == [0, 0, 0])
+                .count();
+            assert_eq!(shadowed, 0, "shadow acne on {}", ground);
+        }
+    }
+
+    #[test]
+    fn test_quad_light_facing() {
+        let scene = scene(
+            r#"
+            samples: 64
+            camera: { width: 4, height: 3 }
+            entities: []
+            lights:
+              - type: quad
+                corner: [-1, 5, -1]
+                edge_a: [2, 0, 0]
+                edge_b: [0, 0, 2]
+                color: [1, 1, 1]
+                intensity: 100
+            "#,
+        );
+        let light = &scene.lights[0];
+        let hit = Vector(0.0, 0.0, 0.0);
+        let facing = receive_light(&scene, light, hit, Vector(0.0, 1.0, 0.0));
+        let steep = receive_light(&scene, light, hit, Vector(1.0, 0.2, 0.0).normalize());
+        assert!(facing > 0.0);
+        assert!(
+            facing > steep * 2.0,
+            "facing surface receives {}, steep surface receives {}",
+            facing,
+            steep
+        );
+
+        // The quad does not emit light upwards
+        let above = receive_light(
+            &scene,
+            light,
+            Vector(0.0, 10.0, 0.0),
+            Vector(0.0, -1.0, 0.0),
+        );
+        assert_eq!(above, 0.0);
+    }
+
+    #[test]
+    fn test_jittered_samples_stratified() {
+        let samples = jittered_samples(8);
+        assert_eq!(samples.len(), 8);
+        for axis in 0..2 {
+            let mut cells: Vec<u32> = samples
+                .iter()
+                .map(|s| if axis == 0 { s.0 } else { s.1 })
+                .map(|c| (c * 8.0) as u32)
+                .collect();
+            cells.sort_unstable();
+            assert_eq!(cells, (0..8).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_square_camera_centered() {
+        assert_sphere_centered(30, 30);
+    }
+
+    #[test]
+    fn test_portrait_camera_centered() {
+        assert_sphere_centered(20, 30);
+    }
+
+    #[test]
+    fn test_landscape_camera_centered() {
+        assert_sphere_centered(30, 20);
+    }
+
+    #[test]
+    fn test_aov_normal_facing_camera() {
+        let scene = scene(
+            r#"
+            camera: { width: 20, height: 20 }
+            entities:
+              - type: sphere
+                center: [0, 0, -2]
+                radius: 1.5
+                material: { color: [1, 1, 1], albedo: 1 }
+            lights:
+              - type: directional
+                direction: [0, 0, -1]
+                color: [1, 1, 1]
+                intensity: 10
+            "#,
+        );
+        let image = render(
+            &scene,
+            &RenderOptions {
+                aov: Some(Aov::Normal),
+                ..RenderOptions::default()
+            },
+            None,
+        )
+        .to_rgb8();
+
+        // Normals facing the camera (+z) are predominantly blue
+        let sum = image.pixels().fold([0u64; 3], |mut sum, p| {
+            sum.iter_mut()
+                .zip(p.0.iter())
+                .for_each(|(s, c)| *s += *c as u64);
+            sum
+        });
+        assert!(sum[2] > sum[0] && sum[2] > sum[1], "not predominantly blue");
+        let center = image.get_pixel(10, 10).0;
+        assert!(center[2] > 250, "center normal is not +z: {:?}", center);
+    }
+
+    #[test]
+    fn test_aov_depth_normalized() {
+        let scene = scene(
+            r#"
+            camera: { width: 20, height: 20 }
+            entities:
+              - type: plane
+                center: [0, 0, -4]
+                normal: [0, 0, -1]
+                material: { color: [1, 1, 1], albedo: 1 }
+            lights: []
+            "#,
+        );
+        let image = render(
+            &scene,
+            &RenderOptions {
+                aov: Some(Aov::Depth),
+                ..RenderOptions::default()
+            },
+            None,
+        )
+        .to_rgb8();
+
+        // The plane is farthest at the corners, nearest in the center
+        let corner = image.get_pixel(0, 0).0;
+        let center = image.get_pixel(10, 10).0;
+        assert_eq!(corner, [255, 255, 255]);
+        assert!(center[0] < corner[0]);
+        assert!(center[0] == center[1] && center[1] == center[2]);
+    }
+
+    #[test]
+    fn test_directional_angular_radius_penumbra() {
+        // A sphere hovering over the floor, lit from straight above
+        let received = |angular_radius: f64| -> Vec<f32> {
+            let scene = scene(&format!(
+                r#"
+                camera: {{ width: 4, height: 4 }}
+                samples: 64
+                entities:
+                  - type: sphere
+                    center: [0, 2, 0]
+                    radius: 1
+                    material: {{ color: [1, 1, 1], albedo: 1 }}
+                lights:
+                  - type: directional
+                    direction: [0, -1, 0]
+                    color: [1, 1, 1]
+                    intensity: 1
+                    angular_radius: {}
+                "#,
+                angular_radius
+            ));
+            let up = Vector(0.0, 1.0, 0.0);
+            (0..=30)
+                .map(|i| Vector(i as f64 * 0.05, 0.0, 0.0))
+                .map(|hit| receive_light(&scene, &scene.lights[0], hit, up))
+                .collect()
+        };
+        let graded = |light: &[f32]| light.iter().filter(|&&l| l > 0.01 && l < 0.99).count();
+
+        let hard = received(0.0);
+        assert_eq!(graded(&hard), 0, "hard shadow has a penumbra: {:?}", hard);
+
+        let soft = received(5.0);
+        assert_eq!(soft[0], 0.0);
+        assert!(soft[30] > 0.99);
+        assert!(graded(&soft) >= 3, "no graded penumbra: {:?}", soft);
+        assert!(
+            soft.windows(2).all(|w| w[1] >= w[0] - 0.1),
+            "penumbra not increasing outwards: {:?}",
+            soft
+        );
+    }
+
+    #[test]
+    fn test_camera_invisible_entity_casts_shadow() {
+        // A red sphere hidden from the camera, hovering over a white floor lit from above
+        let scene = scene(
+            r#"
+            camera: { width: 4, height: 4 }
+            entities:
+              - type: sphere
+                center: [0, 2, 0]
+                radius: 1
+                material: { color: [1, 0, 0], albedo: 1 }
+                visibility: { camera: false }
+              - type: plane
+                center: [0, 0, 0]
+                normal: [0, -1, 0]
+                material: { color: [1, 1, 1], albedo: 1 }
+            lights:
+              - type: directional
+                direction: [0, -1, 0]
+                color: [1, 1, 1]
+                intensity: 10
+            "#,
+        );
+        let ray = Ray::new(Vector(0.0, 6.0, 0.0), Vector(0.0, -1.0, 0.0));
+        let hit = scene.intersect(&ray, RayType::Primary).unwrap();
+        assert!(
+            (hit.distance - 6.0).abs() < 1e-9,
+            "camera ray hit the sphere"
+        );
+        assert!(scene.intersect(&ray, RayType::Shadow).unwrap().distance < 6.0);
+
+        // The floor below the sphere is in its shadow, not showing the sphere itself
+        let up = Vector(0.0, 1.0, 0.0);
+        assert!(is_occluded(
+            &scene,
+            Vector(0.0, 0.0, 0.0),
+            up,
+            up,
+            f64::INFINITY
+        ));
+        let (r, g, b) = observe_ray(
+            &scene,
+            &ray,
+            RayType::Primary,
+            Depth::default(),
+            *WHITE,
+            None,
+        )
+        .channels();
+        assert_eq!((r, g, b), (0.0, 0.0, 0.0));
+        let lit = Ray::new(Vector(3.0, 6.0, 0.0), Vector(0.0, -1.0, 0.0));
+        let (r, g, b) = observe_ray(
+            &scene,
+            &lit,
+            RayType::Primary,
+            Depth::default(),
+            *WHITE,
+            None,
+        )
+        .channels();
+        assert!(r > 0.0 && r == g && g == b);
+    }
+
+    #[test]
+    fn test_render_buffers_color_and_depth() {
+        let scene = scene(
+            r#"
+            camera: { width: 20, height: 20 }
+            entities:
+              - type: sphere
+                center: [0, 0, -3]
+                radius: 1
+                material: { color: [1, 0, 0], albedo: 1 }
+            lights:
+              - type: directional
+                direction: [0, 0, -1]
+                color: [1, 1, 1]
+                intensity: 10
+            "#,
+        );
+        let images = render_buffers(&scene, &quiet(), &[Aov::Color, Aov::Depth], None);
+        assert_eq!(images.len(), 2);
+
+        let color = render(&scene, &quiet(), None).to_rgb8();
+        assert_eq!(images[0].to_rgb8().into_raw(), color.into_raw());
+        let depth = images[1].to_rgb8();
+        assert!(depth.pixels().all(|p| p[0] == p[1] && p[1] == p[2]));
+        assert!(depth.get_pixel(10, 10)[0] < depth.get_pixel(0, 0)[0]);
+    }
+
+    #[test]
+    fn test_path_traced_indirect_light() {
+        // A closed box with an emissive ceiling and no lights, seen from the inside
+        let source = |mode| {
+            format!(
+                r#"
+                render_mode: {}
+                samples: 64
+                depth: 4
+                camera: {{ width: 3, height: 3 }}
+                entities:
+                  - {{ type: plane, center: [-1, 0, 0], normal: [-1, 0, 0], material: {{ color: [1, 1, 1], albedo: 0.8 }} }}
+                  - {{ type: plane, center: [1, 0, 0], normal: [1, 0, 0], material: {{ color: [1, 1, 1], albedo: 0.8 }} }}
+                  - {{ type: plane, center: [0, -1, 0], normal: [0, -1, 0], material: {{ color: [1, 1, 1], albedo: 0.8 }} }}
+                  - {{ type: plane, center: [0, 0, -1], normal: [0, 0, -1], material: {{ color: [1, 1, 1], albedo: 0.8 }} }}
+                  - {{ type: plane, center: [0, 0, 1], normal: [0, 0, 1], material: {{ color: [1, 1, 1], albedo: 0.8 }} }}
+                  - type: plane
+                    center: [0, 1, 0]
+                    normal: [0, 1, 0]
+                    material: {{ color: [1, 1, 1], albedo: 0.8, emission: [1, 1, 1] }}
+                lights: []
+                "#,
+                mode
+            )
+        };
+
+        // The back wall receives no direct light
+        let ray = Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        let whitted = trace_primary(&scene(&source("whitted")), &ray, None).color;
+        assert_eq!(whitted.to_rgba().0, [0, 0, 0, 255]);
+
+        // Path traced, the back wall is lit indirectly by the ceiling
+        let path_traced = trace_primary(&scene(&source("path_traced")), &ray, None).color;
+        assert!(
+            path_traced.to_rgba().0[0] > 0,
+            "back wall is not lit indirectly"
+        );
+    }
+
+    #[test]
+    fn test_russian_roulette_unbiased() {
+        // A closed box with an emissive ceiling, so paths bounce many times
+        let source = |roulette_depth| {
+            format!(
+                r#"
+                render_mode: path_traced
+                samples: 20000
+                depth: 8
+                roulette_depth: {}
+                camera: {{ width: 3, height: 3 }}
+                entities:
+                  - {{ type: plane, center: [-1, 0, 0], normal: [-1, 0, 0], material: {{ color: [1, 1, 1], albedo: 0.7 }} }}
+                  - {{ type: plane, center: [1, 0, 0], normal: [1, 0, 0], material: {{ color: [1, 1, 1], albedo: 0.7 }} }}
+                  - {{ type: plane, center: [0, -1, 0], normal: [0, -1, 0], material: {{ color: [1, 1, 1], albedo: 0.7 }} }}
+                  - {{ type: plane, center: [0, 0, -1], normal: [0, 0, -1], material: {{ color: [1, 1, 1], albedo: 0.7 }} }}
+                  - {{ type: plane, center: [0, 0, 1], normal: [0, 0, 1], material: {{ color: [1, 1, 1], albedo: 0.7 }} }}
+                  - type: plane
+                    center: [0, 1, 0]
+                    normal: [0, 1, 0]
+                    material: {{ color: [1, 1, 1], albedo: 0.7, emission: [0.2, 0.2, 0.2] }}
+                lights: []
+                "#,
+                roulette_depth
+            )
+        };
+
+        // Compare the back wall color with and without early termination
+        let ray = Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        let fixed = trace_primary(&scene(&source(8)), &ray, None)
+            .color
+            .max_channel();
+        let roulette = trace_primary(&scene(&source(1)), &ray, None)
+            .color
+            .max_channel();
+        assert!(fixed > 0.0);
+        assert!(
+            (fixed - roulette).abs() < fixed * 0.05,
+            "fixed depth color {} differs from russian roulette color {}",
+            fixed,
+            roulette
+        );
+    }
+
+    #[test]
+    fn test_render_stream_ppm() {
+        let scene = scene(
+            r#"
+            camera: { width: 7, height: 5, fov: 40 }
+            tile_size: 2
+            entities:
+              - { type: sphere, center: [0, 0, -4], radius: 1, material: { color: [1, 0.5, 0.2], albedo: 0.8 } }
+            lights:
+              - { type: directional, direction: [0, -1, -1], color: [1, 1, 1], intensity: 2 }
+            "#,
+        );
+        let mut out = vec![];
+        render_stream(&scene, &quiet(), &mut out).unwrap();
+
+        // The header matches the camera, followed by the rows of pixels
+        let header = b"P6\n7 5\n255\n";
+        assert!(out.starts_with(header));
+        assert_eq!(out.len(), header.len() + 7 * 5 * 3);
+
+        // Pixels are the same as a whole image render
+        let image = render(&scene, &quiet(), None).to_rgb8();
+        assert_eq!(&out[header.len()..], image.as_raw().as_slice());
+    }
+
+    #[test]
+    fn test_shutter_motion_blur() {
+        let column = |shutter: f64| {
+            let scene = scene(&format!(
+                r#"
+                camera: {{ width: 9, height: 9, fov: 40, shutter: {} }}
+                render_mode: path_traced
+                samples: 16
+                background: {{ type: color, color: [1, 1, 1] }}
+                entities:
+                  - {{ type: sphere, center: [0, 0, -5], material: {{ color: [0, 0, 0], albedo: 0 }} }}
+                lights: []
+                animation:
+                  transforms:
+                    - {{ entity: 0, keyframes: [{{ t: 0, offset: [0, 0, 0] }}, {{ t: 1, offset: [0, 2, 0] }}] }}
+                "#,
+                shutter
+            ));
+            let image = render(&scene, &quiet(), None).to_rgb8();
+            (0..9)
+                .map(|y| image.get_pixel(4, y).0[0])
+                .collect::<Vec<_>>()
+        };
+
+        // Without a shutter the sphere edge is sharp, with it the moving edge is blurred
+        assert!(column(0.0).iter().all(|&v| v == 0 || v == 255));
+        assert!(column(0.5).iter().any(|&v| v > 0 && v < 255));
+    }
+
+    #[test]
+    fn test_trace_pixel_reflection() {
+        let scene = scene(
+            r#"
+            camera: { width: 3, height: 3, fov: 30 }
+            entities:
+              - type: sphere
+                center: [0, 0, -4]
+                material: { color: [1, 1, 1], albedo: 0.5, surface: { type: specular, reflectivity: 0.8 } }
+            lights:
+              - { type: directional, direction: [0, -1, -1], color: [1, 1, 1], intensity: 1 }
+            "#,
+        );
+        let rays = trace_pixel(&scene, 1, 1);
+
+        // The primary ray hits the sphere, and a reflection ray is cast from it
+        assert_eq!(rays[0].kind, RayType::Primary);
+        assert_eq!(rays[0].entity, Some(0));
+        assert!(rays
+            .iter()
+            .any(|r| r.kind == RayType::Reflection && r.depth == 1));
+
+        // The traced color is the rendered pixel color
+        let image = render(&scene, &quiet(), None);
+        assert_eq!(rays[0].color.to_rgba(), image.get_pixel(1, 1));
+    }
+
+    #[test]
+    fn test_trace_pixel_group_child() {
+        let scene = scene(
+            r#"
+            camera: { width: 3, height: 3, fov: 30 }
+            entities:
+              - { type: sphere, center: [0, 5, -4], material: { color: [1, 1, 1], albedo: 1 } }
+              - type: group
+                transform: { translation: [0, 0, -4] }
+                children:
+                  - { type: sphere, center: [5, 0, 0], material: { color: [1, 0, 0], albedo: 1 } }
+                  - { type: sphere, center: [0, 0, 0], material: { color: [0, 0, 1], albedo: 1 } }
+            lights:
+              - { type: directional, direction: [0, 0, -1], color: [1, 1, 1], intensity: 1 }
+            "#,
+        );
+        let rays = trace_pixel(&scene, 1, 1);
+
+        // A hit on a group child is recorded as a hit on the group, shaded by the child material
+        assert_eq!(rays[0].entity, Some(1));
+        let color = rays[0].color.to_rgba();
+        assert!(color[2] > 0);
+        assert_eq!(color[0], 0);
+    }
+
+    #[test]
+    fn test_reflection_and_refraction_depth() {
+        // Two mirrors guide the camera ray to a light panel, two glass panels stand in front of
+        // another light panel
+        let scene = |extra: &str| {
+            scene(&format!(
+                r#"
+                {}
+                camera: {{ width: 4, height: 3, fov: 40 }}
+                materials:
+                  mirror: {{ color: [0, 0, 0], albedo: 0, surface: {{ type: specular, reflectivity: 1 }} }}
+                  glass: {{ color: [1, 1, 1], albedo: 0, surface: {{ type: transparent, index: 1.5, transparency: 1 }} }}
+                  light: {{ color: [0, 0, 0], albedo: 0, emission: [1, 1, 1] }}
+                entities:
+                  - {{ type: rect, center: [0, 0, -5], normal: [1, 0, 1], u_axis: [0, 1, 0], v_axis: [1, 0, -1], material: {{ ref: mirror }} }}
+                  - {{ type: rect, center: [5, 0, -5], normal: [-1, 0, -1], u_axis: [0, 1, 0], v_axis: [1, 0, -1], material: {{ ref: mirror }} }}
+                  - {{ type: rect, center: [5, 0, -10], normal: [0, 0, 1], u_axis: [1, 0, 0], v_axis: [0, 1, 0], material: {{ ref: light }} }}
+                  - {{ type: rect, center: [0, 5, -3], normal: [0, 0, 1], u_axis: [1, 0, 0], v_axis: [0, 1, 0], material: {{ ref: glass }} }}
+                  - {{ type: rect, center: [0, 5, -4], normal: [0, 0, 1], u_axis: [1, 0, 0], v_axis: [0, 1, 0], material: {{ ref: glass }} }}
+                  - {{ type: rect, center: [0, 5, -6], normal: [0, 0, 1], u_axis: [1, 0, 0], v_axis: [0, 1, 0], material: {{ ref: light }} }}
+                lights: []
+                "#,
+                extra
+            ))
+        };
+        let brightness = |scene: &Scene, origin| {
+            let ray = Ray::new(origin, Vector(0.0, 0.0, -1.0));
+            observe_ray(
+                scene,
+                &ray,
+                RayType::Primary,
+                Depth::default(),
+                *WHITE,
+                None,
+            )
+            .channels()
+            .0
+        };
+        let (mirrors, glass) = (Vector(0.0, 0.0, 0.0), Vector(0.0, 5.0, 0.0));
+
+        // Without per kind limits, both light panels are seen
+        let unlimited = scene("");
+        assert!(brightness(&unlimited, mirrors) > 0.9);
+        assert!(brightness(&unlimited, glass) > 0.8);
+
+        // A single reflection bounce reaches the second mirror but not the light, refraction
+        // still passes both glass panels
+        let limited = scene("reflection_depth: 1");
+        assert_eq!(brightness(&limited, mirrors), 0.0);
+        assert!(brightness(&limited, glass) > 0.8);
+
+        // Limiting refraction instead blocks the second glass panel
+        let limited = scene("refraction_depth: 1");
+        assert!(brightness(&limited, mirrors) > 0.9);
+        assert!(brightness(&limited, glass) < 0.1);
+    }
+
+    #[test]
+    fn test_fresnel_schlick_grazing() {
+        let gold = Color::new(1.0, 0.71, 0.29);
+        let normal = fresnel_schlick(1.0, gold);
+        let oblique = fresnel_schlick(0.5, gold);
+        let grazing = fresnel_schlick(0.01, gold);
+
+        // Normal incidence reflects the base color, rising towards white at grazing angles
+        assert!((normal.max_channel() - 1.0).abs() < 1e-6);
+        assert_eq!(normal.to_rgba(), gold.to_rgba());
+        assert!(oblique.to_rgba().0[2] > normal.to_rgba().0[2]);
+        assert!(grazing.to_rgba().0[2] > oblique.to_rgba().0[2]);
+        assert!(grazing.to_rgba().0.iter().all(|c| *c >= 240));
+    }
+
+    #[test]
+    fn test_seed_reproducible() {
+        let source = |seed| {
+            format!(
+                r#"
+                seed: {}
+                samples: 4
+                camera: {{ width: 16, height: 12 }}
+                entities:
+                  - type: sphere
+                    center: [0, 0, -4]
+                    radius: 1
+                    material: {{ color: [1, 1, 1], albedo: 1 }}
+                  - type: plane
+                    center: [0, -1, 0]
+                    normal: [0, -1, 0]
+                    material: {{ color: [1, 1, 1], albedo: 1 }}
+                lights:
+                  - type: spherical
+                    position: [0, 5, -4]
+                    color: [1, 1, 1]
+                    intensity: 1000
+                    radius: 2
+                "#,
+                seed
+            )
+        };
+
+        // Rendering with a fixed seed gives identical results
+        let a = render(&scene(&source(42)), &RenderOptions::default(), None).to_bytes();
+        let b = render(&scene(&source(42)), &RenderOptions::default(), None).to_bytes();
+        assert_eq!(a, b);
+
+        // A different seed gives a different soft shadow
+        let c = render(&scene(&source(43)), &RenderOptions::default(), None).to_bytes();
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_ray_counter_reflections() {
+        let scene = scene(
+            r#"
+            camera: { width: 16, height: 12, fov: 30 }
+            entities:
+              - type: sphere
+                center: [0, 0, -4]
+                radius: 1
+                material:
+                  color: [1, 1, 1]
+                  albedo: 1
+                  surface: { type: specular, reflectivity: 0.8 }
+            lights:
+              - type: directional
+                direction: [0, -1, 0]
+                color: [1, 1, 1]
+                intensity: 1
+            "#,
+        );
+
+        // Every pixel hits the sphere, reflections and shadows cast more rays than pixels
+        let before = RAYS.load(Ordering::Relaxed);
+        render(&scene, &RenderOptions::default(), None);
+        let rays = RAYS.load(Ordering::Relaxed) - before;
+        assert!(rays > scene.camera.pixels());
+    }
+
+    #[test]
+    fn test_render_stats() {
+        let scene = scene(
+            r#"
+            camera: { width: 16, height: 12, fov: 30 }
+            entities:
+              - type: sphere
+                center: [0, 0, -4]
+                radius: 1
+                material:
+                  color: [1, 1, 1]
+                  albedo: 1
+                  surface: { type: specular, reflectivity: 0.8 }
+            lights:
+              - { type: directional, direction: [0, -1, 0], color: [1, 1, 1], intensity: 1 }
+            "#,
+        );
+
+        let before = RayCounts::now();
+        render(&scene, &RenderOptions::default(), None);
+        let rays = RayCounts::now().since(before);
+        assert!(rays.primary > 0);
+        assert!(rays.reflection > 0);
+        assert!(rays.shadow > 0);
+
+        let stats = RenderStats::new(rays, Duration::from_millis(10), scene.triangles() as u64);
+        let json = serde_json::to_value(&stats).unwrap();
+        for field in &[
+            "rays",
+            "primary_rays",
+            "reflection_rays",
+            "refraction_rays",
+            "diffuse_rays",
+            "shadow_rays",
+            "render_time",
+            "triangles",
+            "mrays_per_second",
+        ] {
+            assert!(json.get(field).is_some(), "missing stats field {}", field);
+        }
+        assert_eq!(json["rays"], rays.total());
+        assert!(stats.mrays_per_second > 0.0);
+    }
+
+    #[test]
+    fn test_adaptive_samples_edge() {
+        let scene = scene(
+            r#"
+            max_samples: 8
+            camera: { width: 32, height: 32, fov: 90 }
+            entities:
+              - type: sphere
+                center: [0, 0, -4]
+                radius: 2
+                material: { color: [1, 1, 1], albedo: 1 }
+            lights:
+              - type: directional
+                direction: [0, 0, -1]
+                color: [1, 1, 1]
+                intensity: 10
+            "#,
+        );
+        let mut first_pass = scene.clone();
+        first_pass.max_samples = 1;
+        let mut fragments = render_fragments(&first_pass, &RenderOptions::default(), None);
+        let samples = refine_fragments(&scene, &mut fragments);
+        let index = |x: u32, y: u32| (x * scene.camera.height + y) as usize;
+
+        // The sphere edge crosses row 16 near x = 7, the flat sphere center and background are
+        // not sampled again
+        let edge = (6..11).map(|x| samples[index(x, 16)]).max().unwrap();
+        assert_eq!(edge, 8);
+        assert_eq!(samples[index(16, 16)], 1);
+        assert_eq!(samples[index(1, 1)], 1);
+    }
+
+    #[test]
+    fn test_denoise_smooths_flat_keeps_edges() {
+        let camera = Camera {
+            width: 16,
+            height: 16,
+            fov: 90.0,
+            position: Vector(0.0, 0.0, 0.0),
+            look_at: None,
+            projection: Default::default(),
+            shutter: 0.0,
+        };
+
+        // Two flat surfaces facing different ways, dark noisy left half and bright right half
+        let fragments: Vec<Fragment> = (0..camera.pixels())
+            .map(|i| {
+                let (x, y) = pixel_position(&camera, i);
+                let noise = if (x + y) % 2 == 0 { 0.1 } else { -0.1 };
+                let (color, normal) = if x < 8 {
+                    (0.2 + noise, Vector(0.0, 0.0, 1.0))
+                } else {
+                    (0.9, Vector(1.0, 0.0, 0.0))
+                };
+                Fragment {
+                    color: Color::new(color, color, color),
+                    distance: Some(5.0),
+                    normal: Some(normal),
+                }
+            })
+            .collect();
+        let denoised = denoise(&camera, &fragments);
+
+        // The noisy flat region is smoothed
+        let variance = |fragments: &[Fragment]| {
+            let colors: Vec<Color> = (2..6)
+                .flat_map(|x| (2..14).map(move |y| (x, y)))
+                .map(|(x, y)| fragments[x * 16 + y].color)
+                .collect();
+            color_variance(&colors)
+        };
+        assert!(variance(&denoised) < variance(&fragments) * 0.1);
+
+        // The edge between the surfaces stays sharp
+        let contrast = denoised[8 * 16 + 8].color - denoised[7 * 16 + 8].color;
+        assert!(contrast.mean_channel() > 0.6);
+    }
+
+    #[test]
+    fn test_progress_disabled() {
+        assert!(Progress::start_if(100, Verbosity::Normal, false).is_none());
+        assert!(Progress::start_if(100, Verbosity::Quiet, true).is_none());
+        assert!(Progress::start_if(100, Verbosity::Verbose, false).is_none());
+    }
+
+    #[test]
+    fn test_coincident_surfaces_first_entity_wins() {
+        let sphere = |color| {
+            format!(
+                "{{ type: sphere, center: [0, 0, -5], radius: 2, material: {{ color: {}, albedo: 1 }} }}",
+                color
+            )
+        };
+        let scene = |first, second| {
+            scene(&format!(
+                r#"
+                camera: {{ width: 8, height: 8 }}
+                entities:
+                  - {}
+                  - {}
+                lights:
+                  - {{ type: directional, direction: [0, 0, -1], color: [1, 1, 1], intensity: 5 }}
+                "#,
+                sphere(first),
+                sphere(second),
+            ))
+        };
+        let ray = Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+
+        // Of two identical spheres the first is hit, whichever it is
+        let red_first = scene("[1, 0, 0]", "[0, 1, 0]");
+        let hit = red_first.intersect(&ray, RayType::Primary).unwrap();
+        assert!(std::ptr::eq(hit.entity, &red_first.entities[0]));
+        let green_first = scene("[0, 1, 0]", "[1, 0, 0]");
+        let hit = green_first.intersect(&ray, RayType::Primary).unwrap();
+        assert!(std::ptr::eq(hit.entity, &green_first.entities[0]));
+
+        let center = *render(&red_first, &quiet(), None).to_rgb8().get_pixel(4, 4);
+        assert!(center[0] > 0 && center[1] == 0);
+        let center = *render(&green_first, &quiet(), None)
+            .to_rgb8()
+            .get_pixel(4, 4);
+        assert!(center[0] == 0 && center[1] > 0);
+    }
+
+    #[test]
+    fn test_irradiance_lights_by_background() {
+        let render_mode = |mode| {
+            let mut scene = scene(
+                r#"
+                camera: { width: 8, height: 8 }
+                background: { type: color, color: [1, 1, 1] }
+                entities:
+                  - { type: sphere, center: [0, 0, -5], radius: 2, material: { color: [1, 1, 1], albedo: 0.5 } }
+                lights: []
+                "#,
+            );
+            scene.render_mode = mode;
+            render(&scene, &quiet(), None).to_rgb8()
+        };
+
+        // Without lights only the probes light the sphere, up to its albedo under a white sky
+        let whitted = render_mode(RenderMode::Whitted);
+        let irradiance = RenderMode::Irradiance {
+            resolution: 3,
+            samples: 64,
+        };
+        let lit = render_mode(irradiance);
+        assert_eq!(whitted.get_pixel(4, 4)[0], 0);
+        assert!(lit.get_pixel(4, 4)[0] > 0 && lit.get_pixel(4, 4)[0] <= 188);
+        assert_eq!(lit, render_mode(irradiance));
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let scene = scene(
+            r#"
+            camera: { width: 40, height: 30 }
+            tile_size: 8
+            entities: []
+            lights: []
+            "#,
+        );
+        let calls = Mutex::new(vec![]);
+        render(
+            &scene,
+            &quiet(),
+            Some(&|completed, total| calls.lock().unwrap().push((completed, total))),
+        );
+
+        // Progress is reported per tile, the last call covers all pixels
+        let calls = calls.into_inner().unwrap();
+        assert!(calls.len() > 1);
+        assert!(calls
+            .iter()
+            .all(|&(completed, total)| completed <= total && total == 1200));
+        assert_eq!(calls.last(), Some(&(1200, 1200)));
+    }
+
+    #[test]
+    fn test_benchmark_runs() {
+        let mut calls = 0;
+        let benchmark = benchmark_with(5, || {
+            calls += 1;
+            thread::sleep(Duration::from_millis(calls));
+        });
+        assert_eq!(calls, 5);
+        assert_eq!(benchmark.runs.len(), 5);
+
+        // Runs sleep for 1 to 5 milliseconds
+        assert!(benchmark.min() >= Duration::from_millis(1));
+        assert!(benchmark.min() <= benchmark.median());
+        assert!(benchmark.median() <= benchmark.max());
+        assert!(benchmark.max() >= Duration::from_millis(5));
+        assert!(benchmark.min() <= benchmark.mean() && benchmark.mean() <= benchmark.max());
+    }
+
+    #[test]
+    fn test_preview_0rgb() {
+        assert_eq!(to_0rgb(Rgba([0x12, 0x34, 0x56, 0xff])), 0x0012_3456);
+        assert_eq!(to_0rgb(Rgba([0xff, 0, 0, 0])), 0x00ff_0000);
+        assert_eq!(to_0rgb(WHITE.to_rgba()), 0x00ff_ffff);
+    }
+
+    #[test]
+    fn test_live_image_scales_pixels() {
+        let image = LiveImage::new(&Camera::new(4, 2));
+        image.set(&Camera::new(4, 2), 1, 0, *WHITE);
+        image.set(&Camera::new(8, 4), 7, 3, *WHITE);
+        let mut buffer = vec![1; 8];
+        image.read_0rgb(&mut buffer);
+        assert_eq!(buffer, vec![0, 0x00ff_ffff, 0, 0, 0, 0, 0, 0x00ff_ffff]);
+    }
+
+    #[test]
+    fn test_progress_message_eta() {
+        let message = progress_message(25, 100, 2_000_000, Duration::from_secs(1));
+        assert_eq!(message, "ETA 3s, 2.00 Mrays/s, 25 px/s ");
+    }
+
+    /// Render a centered sphere with the given camera size, assert it is centered in the image.
+    fn assert_sphere_centered(width: u32, height: u32) {
+        let scene = scene(&format!(
+            r#"
+            camera: {{ width: {}, height: {} }}
+            entities:
+              - type: sphere
+                center: [0, 0, -5]
+                radius: 1
+                material: {{ color: [1, 1, 1], albedo: 1 }}
+            lights:
+              - type: directional
+                direction: [0, 0, -1]
+                color: [1, 1, 1]
+                intensity: 10
+            "#,
+            width, height,
+        ));
+        let image = render(&scene, &RenderOptions::default(), None).to_rgb8();
+        assert_eq!(image.dimensions(), (width, height));
+
+        // Find the bounds of the lit sphere pixels
+        let lit: Vec<(u32, u32)> = image
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p.0 != [0, 0, 0])
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        assert!(!lit.is_empty(), "sphere is not visible");
+        let min_x = lit.iter().map(|p| p.0).min().unwrap();
+        let max_x = lit.iter().map(|p| p.0).max().unwrap();
+        let min_y = lit.iter().map(|p| p.1).min().unwrap();
+        let max_y = lit.iter().map(|p| p.1).max().unwrap();
+        assert_eq!(min_x + max_x, width - 1, "sphere not centered horizontally");
+        assert_eq!(min_y + max_y, height - 1, "sphere not centered vertically");
+
+        // The sphere must be round, not stretched
+        assert_eq!(max_x - min_x, max_y - min_y, "sphere is stretched");
+    }
+
+    /// Render options without any status output.
+    fn quiet() -> RenderOptions {
+        RenderOptions {
+            verbosity: Verbosity::Quiet,
+            ..RenderOptions::default()
+        }
+    }
+
+    /// Parse a scene from the given YAML source.
+    pub(crate) fn scene(source: &str) -> Scene {
+        let mut scene: Scene = serde_yaml::from_str(source).expect("failed to parse test scene");
+        scene
+            .resolve_materials()
+            .expect("failed to resolve test scene materials");
+        scene.update_bounding_boxes();
+        scene
+    }
+}
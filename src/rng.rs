@@ -0,0 +1,48 @@
+use std::cell::RefCell;
+
+use rand::distributions::{Distribution, Standard};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+thread_local! {
+    /// Random number generator for the current thread.
+    static RNG: RefCell<SmallRng> = RefCell::new(SmallRng::seed_from_u64(0));
+}
+
+/// Seed the random number generator of the current thread.
+///
+/// The generator is reseeded for each pixel, so renders are reproducible no matter what thread
+/// renders what pixel.
+pub fn seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = SmallRng::seed_from_u64(seed));
+}
+
+/// Invoke the given closure with the random number generator of the current thread seeded with
+/// `seed`, restoring the generator afterwards.
+///
+/// Random numbers drawn outside the closure are unaffected by it.
+pub fn with_seed<F, T>(seed: u64, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let outer = RNG.with(|rng| rng.replace(SmallRng::seed_from_u64(seed)));
+    let result = f();
+    RNG.with(|rng| *rng.borrow_mut() = outer);
+    result
+}
+
+/// Generate a random value with the random number generator of the current thread.
+pub fn random<T>() -> T
+where
+    Standard: Distribution<T>,
+{
+    with_rng(|rng| rng.gen())
+}
+
+/// Invoke the given closure with the random number generator of the current thread.
+pub fn with_rng<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut SmallRng) -> T,
+{
+    RNG.with(|rng| f(&mut rng.borrow_mut()))
+}
@@ -0,0 +1,92 @@
+//! Sampling of random directions, for Monte Carlo integration.
+//!
+//! Samplers return directions along with their probability density, so estimates can be weighted
+//! correctly.
+
+use std::f64::consts::PI;
+
+use rand::Rng;
+
+use crate::algebra::Vector;
+
+/// Sample a random cosine weighted direction in the hemisphere around the unit `normal`.
+///
+/// Returns the direction with its probability density, `cos(theta) / pi`.
+pub fn cosine_weighted_hemisphere<R: Rng + ?Sized>(normal: Vector, rng: &mut R) -> (Vector, f64) {
+    let direction = cosine_weighted_direction(normal, rng.gen(), rng.gen());
+    (direction, direction.dot(normal).max(0.0) / PI)
+}
+
+/// Get the cosine weighted direction in the hemisphere around `normal` for `u` and `v` in `[0,1)`.
+///
+/// Uniformly distributed `u` and `v` give cosine weighted directions.
+pub fn cosine_weighted_direction(normal: Vector, u: f64, v: f64) -> Vector {
+    let (tangent, bitangent) = normal.orthonormal_basis();
+
+    // Sample a disk, project it onto the hemisphere
+    let r = u.sqrt();
+    let phi = 2.0 * PI * v;
+    let z = (1.0 - r * r).max(0.0).sqrt();
+    (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * z).normalize()
+}
+
+/// Sample a random direction in the hemisphere around the unit `normal`, uniformly by solid angle.
+///
+/// Returns the direction with its probability density, `1 / (2 pi)`.
+pub fn uniform_hemisphere<R: Rng + ?Sized>(normal: Vector, rng: &mut R) -> (Vector, f64) {
+    let (tangent, bitangent) = normal.orthonormal_basis();
+    let z: f64 = rng.gen();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let direction =
+        (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * z).normalize();
+    (direction, 1.0 / (2.0 * PI))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    /// Sample many directions, returns the mean cosine to the normal.
+    fn mean_cosine<F>(normal: Vector, mut sample: F) -> f64
+    where
+        F: FnMut(&mut SmallRng) -> (Vector, f64),
+    {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let count = 20_000;
+        let sum: f64 = (0..count)
+            .map(|_| {
+                let (direction, pdf) = sample(&mut rng);
+                assert!(direction.dot(normal) >= 0.0, "below the hemisphere");
+                assert!((direction.magnitude() - 1.0).abs() < 1e-9);
+                assert!(pdf >= 0.0 && pdf.is_finite());
+                direction.dot(normal)
+            })
+            .sum();
+        sum / count as f64
+    }
+
+    #[test]
+    fn test_cosine_weighted_hemisphere() {
+        // The mean cosine of a cosine weighted hemisphere is 2/3, pulled towards the normal
+        let normal = Vector(1.0, 2.0, -0.5).normalize();
+        let mean = mean_cosine(normal, |rng| cosine_weighted_hemisphere(normal, rng));
+        assert!((mean - 2.0 / 3.0).abs() < 0.01, "mean cosine {}", mean);
+
+        // The density matches the cosine
+        let mut rng = SmallRng::seed_from_u64(2);
+        let (direction, pdf) = cosine_weighted_hemisphere(normal, &mut rng);
+        assert!((pdf - direction.dot(normal) / PI).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_uniform_hemisphere() {
+        // The mean cosine of a uniform hemisphere is 1/2
+        let normal = Vector(0.0, 1.0, 0.0);
+        let mean = mean_cosine(normal, |rng| uniform_hemisphere(normal, rng));
+        assert!((mean - 0.5).abs() < 0.01, "mean cosine {}", mean);
+    }
+}
@@ -1,12 +1,43 @@
-use std::path::Path;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 
-use crate::geometric::Entity;
+use crate::algebra::{Identity, Vector};
+use crate::animation::Animation;
+use crate::background::Background;
+use crate::color::Color;
+use crate::error::RaytraceError;
+use crate::geometric::{check_direction, check_finite, Aabb, BoundingSphere, Entity};
+use crate::irradiance::ProbeGrid;
 use crate::light::Light;
-use crate::math::{Intersectable, Intersection, Ray};
+use crate::material::Material;
+use crate::math::{Intersection, Ray, RayType};
+use crate::verbosity::Verbosity;
+
+/// Maximum number of alpha cut out surfaces a ray passes through, before it is considered a miss.
+const CUTOUT_MAX_STEPS: usize = 64;
+
+thread_local! {
+    /// Time within the camera shutter of the sample traced on this thread, after the frame time.
+    static SHUTTER_TIME: Cell<f64> = const { Cell::new(0.0) };
+}
+
+/// Set the time within the camera shutter of the sample traced on this thread.
+///
+/// Animated entities are intersected at their position at this time after the frame time.
+pub(crate) fn set_shutter_time(time: f64) {
+    SHUTTER_TIME.with(|t| t.set(time));
+}
 
 /// Defines a scene to render.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Scene {
+    /// The background seen by rays that hit nothing, lighting the scene in path traced mode.
+    #[serde(default)]
+    pub background: Background,
+
     /// The shadow/reflect/transform bias length.
     #[serde(default = "default_bias")]
     pub bias: f64,
@@ -15,39 +46,664 @@ pub struct Scene {
     #[serde(default = "default_ray_depth")]
     pub depth: u32,
 
+    /// Whether to dither the output colors, to prevent banding in smooth gradients.
+    #[serde(default)]
+    pub dither: bool,
+
+    /// Number of lights sampled per shading point, for scenes with many lights.
+    ///
+    /// If the scene has more lights than this, a random stratified subset of this many lights is
+    /// sampled at each shading point instead of all of them. If not set, all lights are sampled.
+    #[serde(default)]
+    pub light_samples: Option<u32>,
+
+    /// Maximum luminance of a single path traced sample, to suppress fireflies.
+    ///
+    /// Brighter samples are scaled down to this luminance before they are averaged, preserving
+    /// their hue. This trades a little energy for a lot less noise. If not set, samples are not
+    /// clamped.
+    #[serde(default)]
+    pub max_luminance: Option<f32>,
+
+    /// Maximum number of samples per pixel for adaptive anti-aliasing.
+    ///
+    /// Pixels with a high color variance among their neighbors are sampled again, up to this
+    /// number of jittered samples. Set to `1` to disable.
+    #[serde(default = "default_samples")]
+    pub max_samples: u32,
+
+    /// Maximum number of reflection bounces along a path, only limited by `depth` if not set.
+    #[serde(default)]
+    pub reflection_depth: Option<u32>,
+
+    /// Maximum number of refraction bounces along a path, only limited by `depth` if not set.
+    #[serde(default)]
+    pub refraction_depth: Option<u32>,
+
+    /// The rendering method.
+    #[serde(default)]
+    pub render_mode: RenderMode,
+
+    /// Minimum path depth before paths may be terminated early with Russian roulette.
+    ///
+    /// Only used in path traced mode, set to `depth` or higher to disable.
+    #[serde(default = "default_roulette_depth")]
+    pub roulette_depth: u32,
+
+    /// Number of samples taken for stochastic effects, such as soft shadows.
+    ///
+    /// In path traced mode, this is the number of paths traced per pixel.
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+
+    /// Seed for random sampling, renders with the same seed are identical.
+    #[serde(default)]
+    pub seed: u64,
+
+    /// Size in pixels of the square tiles the image is rendered in.
+    ///
+    /// Pixels in a tile are rendered together for better cache locality, tiles are rendered in
+    /// parallel. The render result does not depend on the tile size.
+    #[serde(default = "default_tile_size")]
+    pub tile_size: u32,
+
+    /// Optional distance based fog.
+    #[serde(default)]
+    pub fog: Option<Fog>,
+
+    /// Optional output resolution, the render is fit into it.
+    ///
+    /// Defaults to the camera dimensions.
+    #[serde(default)]
+    pub output: Option<Output>,
+
+    /// Color variance threshold for adaptive anti-aliasing.
+    ///
+    /// Pixels with a higher color variance among their neighbors get additional samples.
+    #[serde(default = "default_variance_threshold")]
+    pub variance_threshold: f32,
+
     /// Scene camera configuration.
     pub camera: Camera,
 
+    /// Named alternative cameras, one may be selected to render with instead of `camera`.
+    #[serde(default)]
+    pub cameras: HashMap<String, Camera>,
+
+    /// Other scene files to include, relative to this scene file.
+    ///
+    /// Their materials, entities and lights are merged into this scene.
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    /// Named materials, entities may reference these by name.
+    #[serde(default)]
+    pub materials: HashMap<String, Material>,
+
     /// Entities in this scene.
     pub entities: Vec<Entity>,
 
     /// Lights in this scene.
     pub lights: Vec<Light>,
+
+    /// Optional animation, applied when rendering a frame sequence.
+    #[serde(default)]
+    pub animation: Option<Animation>,
+
+    /// Animation time `t` of the rendered frame, set when the animation is applied.
+    #[serde(skip)]
+    pub time: f64,
+
+    /// Cached entity bounding boxes, in the same order as `entities`.
+    ///
+    /// Computed in `load`, `None` means the entity has infinite bounds.
+    #[serde(skip)]
+    bounding_boxes: Vec<Option<Aabb>>,
+
+    /// Cached entity bounding spheres, in the same order as `entities`.
+    ///
+    /// Computed in `load`, `None` means the entity has no bounding sphere.
+    #[serde(skip)]
+    bounding_spheres: Vec<Option<BoundingSphere>>,
+
+    /// Cached entity motion over the camera shutter, in the same order as `entities`.
+    ///
+    /// Computed along with the bounding boxes, `None` means the entity is not animated.
+    #[serde(skip)]
+    motions: Vec<Option<Motion>>,
+
+    /// Cached irradiance probes, baked on first use in irradiance mode.
+    ///
+    /// Cleared when the bounding boxes are updated, as entities may have changed.
+    #[serde(skip)]
+    irradiance: OnceLock<Arc<ProbeGrid>>,
 }
 
 impl Scene {
-    /// Cast a ray in the scene, and get the first intersection.
-    pub fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+    /// Construct an empty scene viewed through the given `camera`, with default settings.
+    pub fn new(camera: Camera) -> Self {
+        Scene {
+            background: Background::default(),
+            bias: default_bias(),
+            depth: default_ray_depth(),
+            dither: false,
+            light_samples: None,
+            max_luminance: None,
+            max_samples: default_samples(),
+            reflection_depth: None,
+            refraction_depth: None,
+            render_mode: RenderMode::default(),
+            roulette_depth: default_roulette_depth(),
+            samples: default_samples(),
+            seed: 0,
+            tile_size: default_tile_size(),
+            fog: None,
+            output: None,
+            variance_threshold: default_variance_threshold(),
+            camera,
+            cameras: HashMap::new(),
+            include: Vec::new(),
+            materials: HashMap::new(),
+            entities: Vec::new(),
+            lights: Vec::new(),
+            animation: None,
+            time: 0.0,
+            bounding_boxes: Vec::new(),
+            bounding_spheres: Vec::new(),
+            motions: Vec::new(),
+            irradiance: OnceLock::new(),
+        }
+    }
+
+    /// Cast a ray of the given `kind` in the scene, and get the first intersection.
+    ///
+    /// Entities that are not visible to this kind of ray are skipped. Hits on surfaces cut out by
+    /// their material alpha mask are passed through, continuing the ray just beyond them.
+    pub fn intersect(&self, ray: &Ray, kind: RayType) -> Option<Intersection<'_>> {
+        let mut ray = *ray;
+        let mut travelled = 0.0;
+        for _ in 0..CUTOUT_MAX_STEPS {
+            let i = self.intersect_nearest(&ray, kind)?;
+            let hit = ray.origin + (ray.direction * i.distance);
+            if !i.material().is_cut_out(|| i.texture_coords(hit)) {
+                return Some(Intersection {
+                    distance: travelled + i.distance,
+                    ..i
+                });
+            }
+            ray = ray.bias(i.distance + self.bias);
+            travelled += i.distance + self.bias;
+        }
+        None
+    }
+
+    /// Cast a ray of the given `kind` in the scene, and get the nearest intersection.
+    ///
+    /// Like `intersect`, but alpha cutouts are not passed through. Of coincident surfaces at the
+    /// same distance, the entity with the lowest index is hit, so renders are stable.
+    fn intersect_nearest(&self, ray: &Ray, kind: RayType) -> Option<Intersection<'_>> {
         self.entities
             .iter()
-            .filter_map(|s| {
-                s.intersect(ray).map(|(d, n)| Intersection {
-                    distance: d,
-                    normal: n,
-                    entity: s,
-                })
+            .enumerate()
+            .map(|(i, e)| match self.motion(i) {
+                // Intersect moving entities at their frame position, with the ray moved back
+                Some(offset) => (i, e, Ray::new(ray.origin - offset, ray.direction)),
+                None => (i, e, *ray),
+            })
+            .filter(|(i, _, ray)| match self.bounding_spheres.get(*i) {
+                Some(Some(sphere)) => sphere.hit(ray),
+                _ => true,
             })
-            .min_by(|i1, i2| i1.distance.partial_cmp(&i2.distance).unwrap())
+            .filter(|(i, _, ray)| match self.bounding_boxes.get(*i) {
+                Some(Some(aabb)) => aabb.hit(ray),
+                _ => true,
+            })
+            .filter_map(|(index, s, ray)| {
+                s.intersect_entity(&ray, kind)
+                    .map(|(hit, entity, transform)| Intersection {
+                        distance: hit.distance,
+                        normal: hit.normal,
+                        uv: hit.uv,
+                        texture_derivatives: hit.texture_derivatives,
+                        barycentric: hit.barycentric,
+                        front_face: hit.front_face,
+                        entity,
+                        index,
+                        transform,
+                    })
+            })
+            .filter(|i| i.distance.is_finite())
+            // Of equal elements the first is the minimum, the lowest entity index
+            .min_by(|i1, i2| i1.distance.total_cmp(&i2.distance))
+    }
+
+    /// Get how far the entity at `index` moved from its frame position at the shutter time of the
+    /// sample traced on this thread.
+    ///
+    /// Returns `None` if the entity is not animated, or the camera shutter is closed.
+    fn motion(&self, index: usize) -> Option<Vector> {
+        if self.camera.shutter <= 0.0 {
+            return None;
+        }
+        let motion = self.motions.get(index)?.as_ref()?;
+        let transforms = &self.animation.as_ref()?.transforms;
+        let time = self.time + SHUTTER_TIME.with(Cell::get);
+        Some(
+            motion
+                .transforms
+                .iter()
+                .fold(-motion.frame_offset, |offset, &t| {
+                    offset + transforms[t].offset(time)
+                }),
+        )
+    }
+
+    /// Validate this scene.
+    ///
+    /// Checks for problems that would otherwise break rendering in confusing ways, such as an
+    /// empty camera, non positive sizes, zero directions and non finite coordinates. Returns a
+    /// list of human readable problems if invalid.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+
+        problems.extend(
+            self.camera
+                .validate()
+                .into_iter()
+                .map(|p| format!("camera {}", p)),
+        );
+        let mut names: Vec<&String> = self.cameras.keys().collect();
+        names.sort();
+        for name in names {
+            problems.extend(
+                self.cameras[name]
+                    .validate()
+                    .into_iter()
+                    .map(|p| format!("camera '{}' {}", name, p)),
+            );
+        }
+        if let Some(ref output) = self.output {
+            if output.width == 0 || output.height == 0 {
+                problems.push(format!(
+                    "output size must be nonzero, got {}x{}",
+                    output.width, output.height,
+                ));
+            }
+        }
+        if let Some(max) = self.max_luminance {
+            if max <= 0.0 || !max.is_finite() {
+                problems.push(format!("max luminance must be positive, got {}", max));
+            }
+        }
+        if let RenderMode::AmbientOcclusion { samples, radius } = self.render_mode {
+            if samples == 0 {
+                problems.push("ambient occlusion samples must be nonzero".into());
+            }
+            if radius <= 0.0 || !radius.is_finite() {
+                problems.push(format!(
+                    "ambient occlusion radius must be positive, got {}",
+                    radius
+                ));
+            }
+        }
+        if let RenderMode::Irradiance {
+            resolution,
+            samples,
+        } = self.render_mode
+        {
+            if resolution == 0 {
+                problems.push("irradiance probe resolution must be nonzero".into());
+            }
+            if samples == 0 {
+                problems.push("irradiance probe samples must be nonzero".into());
+            }
+        }
+
+        let mut names: Vec<&String> = self.materials.keys().collect();
+        names.sort();
+        for name in names {
+            problems.extend(
+                self.materials[name]
+                    .validate()
+                    .into_iter()
+                    .map(|p| format!("material '{}' {}", name, p)),
+            );
+        }
+
+        for (i, entity) in self.entities.iter().enumerate() {
+            problems.extend(
+                entity
+                    .validate()
+                    .into_iter()
+                    .map(|p| format!("entity {}: {}", i, p)),
+            );
+        }
+        problems.extend(
+            self.background
+                .validate()
+                .into_iter()
+                .map(|p| format!("background {}", p)),
+        );
+        for (i, light) in self.lights.iter().enumerate() {
+            problems.extend(
+                light
+                    .validate()
+                    .into_iter()
+                    .map(|p| format!("light {}: {}", i, p)),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Select the named camera from `cameras` to render with, replacing `camera`.
+    ///
+    /// Fails if there is no camera with the given `name`.
+    pub fn select_camera(&mut self, name: &str) -> Result<(), String> {
+        match self.cameras.get(name) {
+            Some(camera) => {
+                self.camera = *camera;
+                Ok(())
+            }
+            None => Err(format!("Unknown camera: '{}'", name)),
+        }
+    }
+
+    /// Resolve scene includes, merging included scene files into this scene.
+    ///
+    /// `path` is the path of the file this scene was loaded from, includes are relative to it.
+    /// Includes are resolved recursively. Included materials, entities and lights are appended,
+    /// any other included settings are ignored. Named materials defined in this scene take
+    /// precedence. Fails if an included file cannot be loaded, or if includes are circular.
+    pub fn resolve_includes(&mut self, path: &Path) -> Result<(), String> {
+        let include = std::mem::take(&mut self.include);
+        let mut stack = vec![canonical_path(path)?];
+        self.merge_includes(&include, &mut stack)
+    }
+
+    /// Merge the given `include` paths into this scene.
+    ///
+    /// The `stack` holds the chain of files currently being included, the last being the file
+    /// that includes `include`.
+    fn merge_includes(
+        &mut self,
+        include: &[String],
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<(), String> {
+        let dir = stack
+            .last()
+            .and_then(|p| p.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        for path in include {
+            let path = canonical_path(&dir.join(path))?;
+            if stack.contains(&path) {
+                let chain: Vec<String> = stack
+                    .iter()
+                    .chain(std::iter::once(&path))
+                    .map(|p| format!("'{}'", p.display()))
+                    .collect();
+                return Err(format!("Circular scene include: {}", chain.join(" -> ")));
+            }
+
+            let file = File::open(&path).map_err(|err| {
+                format!(
+                    "Failed to open included scene file '{}': {}",
+                    path.display(),
+                    err
+                )
+            })?;
+            let mut included: SceneInclude = serde_yaml::from_reader(file).map_err(|err| {
+                format!(
+                    "Failed to parse included scene file '{}': {}",
+                    path.display(),
+                    err
+                )
+            })?;
+
+            // Keep resource paths relative to the included file
+            let included_dir = path.parent().unwrap_or(&dir);
+            included
+                .materials
+                .values_mut()
+                .for_each(|m| m.rebase(included_dir));
+            included
+                .entities
+                .iter_mut()
+                .for_each(|e| e.rebase(included_dir));
+
+            for (name, material) in included.materials {
+                self.materials.entry(name).or_insert(material);
+            }
+            self.entities.append(&mut included.entities);
+            self.lights.append(&mut included.lights);
+
+            stack.push(path);
+            self.merge_includes(&included.include, stack)?;
+            stack.pop();
+        }
+        Ok(())
+    }
+
+    /// Resolve entity material references to the named scene materials.
+    ///
+    /// Must be called before rendering. Fails if an entity references an unknown material.
+    pub fn resolve_materials(&mut self) -> Result<(), String> {
+        let materials = &self.materials;
+        self.entities
+            .iter_mut()
+            .try_for_each(|e| e.resolve_materials(materials))
     }
 
     /// Load external resources.
-    pub fn load<P: AsRef<Path> + Copy>(&mut self, workdir: P) {
-        self.entities.iter_mut().for_each(|e| e.load(workdir))
+    ///
+    /// Models that fail to load are ignored, returns their errors.
+    pub fn load<P: AsRef<Path> + Copy>(
+        &mut self,
+        workdir: P,
+        verbosity: Verbosity,
+    ) -> Vec<RaytraceError> {
+        self.background.load(workdir, verbosity);
+        let errors: Vec<RaytraceError> = self
+            .entities
+            .iter_mut()
+            .flat_map(|e| e.load(workdir, verbosity))
+            .collect();
+        if verbosity.is_normal() {
+            for err in &errors {
+                eprintln!("Failed to load model, ignoring: {}", err);
+            }
+        }
+        self.update_bounding_boxes();
+        errors
+    }
+
+    /// Get the total number of triangles in the scene.
+    pub fn triangles(&self) -> usize {
+        self.entities.iter().map(|e| e.triangles()).sum()
+    }
+
+    /// Get the bounding box enclosing all finite entities in the scene.
+    ///
+    /// Entities with infinite bounds, such as planes, are excluded. Returns `None` if there are no
+    /// finite entities.
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.entities
+            .iter()
+            .filter_map(|e| e.bounding_box())
+            .fold(None, |acc: Option<Aabb>, b| match acc {
+                Some(acc) => Some(acc.union(&b)),
+                None => Some(b),
+            })
+    }
+
+    /// Keep only the entities named one of the given `names`, to render them in isolation.
+    ///
+    /// Lights and cameras are unaffected. Returns the number of entities kept.
+    pub fn isolate(&mut self, names: &[&str]) -> usize {
+        self.entities
+            .retain(|e| e.name().is_some_and(|name| names.contains(&name)));
+        self.update_bounding_boxes();
+        self.entities.len()
+    }
+
+    /// Compute and cache the bounding box, bounding sphere and motion of each entity.
+    ///
+    /// Must be called again when entities are changed after loading, or the animation is applied.
+    pub fn update_bounding_boxes(&mut self) {
+        self.bounding_boxes = self.entities.iter().map(|e| e.bounding_box()).collect();
+        self.bounding_spheres = self.entities.iter().map(|e| e.bounding_sphere()).collect();
+        self.motions = (0..self.entities.len())
+            .map(|index| Motion::new(self.animation.as_ref()?, index, self.time))
+            .collect();
+        self.irradiance = OnceLock::new();
+    }
+
+    /// Get the irradiance probes of this scene, baking them with `bake` on first use.
+    pub(crate) fn irradiance_probes<F>(&self, bake: F) -> &ProbeGrid
+    where
+        F: FnOnce() -> ProbeGrid,
+    {
+        self.irradiance.get_or_init(|| Arc::new(bake()))
     }
 }
 
+/// The parts of a scene file that are merged when it is included by another scene.
+#[derive(Deserialize)]
+struct SceneInclude {
+    /// Other scene files to include, relative to this scene file.
+    #[serde(default)]
+    include: Vec<String>,
+
+    /// Named materials.
+    #[serde(default)]
+    materials: HashMap<String, Material>,
+
+    /// Entities to include.
+    #[serde(default)]
+    entities: Vec<Entity>,
+
+    /// Lights to include.
+    #[serde(default)]
+    lights: Vec<Light>,
+}
+
+/// Get the canonical absolute form of the given scene file `path`.
+fn canonical_path(path: &Path) -> Result<PathBuf, String> {
+    fs::canonicalize(path)
+        .map_err(|err| format!("Failed to find scene file '{}': {}", path.display(), err))
+}
+
+/// Scene rendering method.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderMode {
+    /// Whitted style ray tracing, with direct lighting, reflection and refraction.
+    #[default]
+    Whitted,
+
+    /// Path tracing, with indirect diffuse light bounces for global illumination.
+    PathTraced,
+
+    /// Ambient occlusion only, ignoring lights and materials.
+    ///
+    /// Renders grayscale, the fraction of `samples` hemisphere rays around each primary hit that
+    /// travel `radius` without hitting geometry.
+    AmbientOcclusion {
+        /// Number of hemisphere rays per primary hit.
+        samples: u32,
+
+        /// Distance within which geometry occludes.
+        radius: f64,
+    },
+
+    /// Whitted style ray tracing, with approximate indirect diffuse light from irradiance probes.
+    ///
+    /// Before rendering, probes are baked on a grid spanning the scene bounds. Each probe samples
+    /// the background and the directly lit geometry around it, and stores the incoming light as
+    /// spherical harmonics. Diffuse surfaces add the irradiance interpolated from nearby probes.
+    /// This is much faster than path tracing, but blurs indirect light and may leak it through
+    /// thin walls.
+    Irradiance {
+        /// Number of probes along each axis of the grid.
+        resolution: u32,
+
+        /// Number of rays sampled by each probe.
+        samples: u32,
+    },
+
+    /// Wireframe debug rendering, ignoring lights and materials.
+    ///
+    /// Surfaces are filled gray, shaded by how much they face the camera. Model triangle edges are
+    /// drawn as black lines.
+    Wireframe,
+}
+
+/// Distance based fog, attenuating far away colors towards the fog color.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct Fog {
+    /// The fog color.
+    pub color: Color,
+
+    /// The fog density, higher is thicker.
+    pub density: f64,
+}
+
+impl Fog {
+    /// Apply fog to a color observed at `distance`.
+    ///
+    /// Blends towards the fog color by `1 - exp(-density * distance)`. A `None` distance is at
+    /// infinity, and gives the fog color.
+    pub fn apply(&self, color: Color, distance: Option<f64>) -> Color {
+        match distance {
+            Some(distance) => {
+                let amount = 1.0 - (-self.density * distance).exp();
+                color.lerp(self.color, amount as f32)
+            }
+            None => self.color,
+        }
+    }
+}
+
+/// Output resolution, overriding the camera dimensions.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct Output {
+    /// The output width in pixels.
+    pub width: u32,
+
+    /// The output height in pixels.
+    pub height: u32,
+
+    /// How to fit the render into the output if the aspect ratio differs.
+    #[serde(default)]
+    pub fit: Fit,
+
+    /// Color of the bars added when letterboxing.
+    #[serde(default)]
+    pub fill: Color,
+}
+
+/// How to fit a render into an output of a different aspect ratio.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fit {
+    /// Scale to the output size, distorting the aspect ratio.
+    #[default]
+    Stretch,
+
+    /// Scale to fit inside the output, filling the remaining space with bars.
+    Contain,
+
+    /// Scale to cover the output, cropping what falls outside.
+    Cover,
+}
+
 /// Scene camera configuration.
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct Camera {
     /// The screen width in pixels.
     pub width: u32,
@@ -55,15 +711,152 @@ pub struct Camera {
     /// The screen height in pixels.
     pub height: u32,
 
-    /// The camera field of view in degrees.
+    /// The camera field of view in degrees, spanning the shorter screen axis.
     #[serde(default = "default_fov")]
     pub fov: f64,
+
+    /// The camera position in world space.
+    #[serde(default = "Vector::identity")]
+    pub position: Vector,
+
+    /// The point the camera looks at, the camera looks along `-Z` if not set.
+    #[serde(default)]
+    pub look_at: Option<Vector>,
+
+    /// The camera projection.
+    #[serde(default)]
+    pub projection: Projection,
+
+    /// Exposure time after the frame time, in animation time `t`.
+    ///
+    /// Each sample is traced at a random time within the shutter, blurring moving entities. There
+    /// is no motion blur if zero.
+    #[serde(default)]
+    pub shutter: f64,
+}
+
+/// Camera projection.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Projection {
+    /// Perspective projection, with the camera field of view.
+    #[default]
+    Perspective,
+
+    /// Orthographic parallel projection, the field of view is ignored.
+    Orthographic {
+        /// Half the sensor size along the shorter screen axis, in world units.
+        scale: f64,
+    },
 }
 
 impl Camera {
+    /// Construct a perspective camera at the origin looking along `-Z`, with the given size.
+    pub fn new(width: u32, height: u32) -> Self {
+        Camera {
+            width,
+            height,
+            fov: default_fov(),
+            position: Vector::identity(),
+            look_at: None,
+            projection: Projection::default(),
+            shutter: 0.0,
+        }
+    }
+
+    /// Validate this camera, returns a list of problems.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.width == 0 || self.height == 0 {
+            problems.push(format!(
+                "size must be nonzero, got {}x{}",
+                self.width, self.height
+            ));
+        }
+        if !(self.fov > 0.0 && self.fov < 180.0) {
+            problems.push(format!(
+                "fov must be between 0 and 180 degrees, got {}",
+                self.fov
+            ));
+        }
+        check_finite(&mut problems, "position", self.position);
+        if !(self.shutter >= 0.0 && self.shutter.is_finite()) {
+            problems.push(format!(
+                "shutter must not be negative, got {}",
+                self.shutter
+            ));
+        }
+        if let Some(look_at) = self.look_at {
+            check_direction(&mut problems, "look_at direction", look_at - self.position);
+        }
+        if let Projection::Orthographic { scale } = self.projection {
+            if scale <= 0.0 || !scale.is_finite() {
+                problems.push(format!(
+                    "orthographic scale must be positive, got {}",
+                    scale
+                ));
+            }
+        }
+        problems
+    }
+
     /// The the total number of pixels this camera covers.
-    pub fn pixels(&self) -> u32 {
-        self.width * self.height
+    pub fn pixels(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+
+    /// Orient a camera space direction to world space.
+    ///
+    /// In camera space the camera looks along `-Z`, with `+Y` up. If a `look_at` point is set, the
+    /// direction is rotated to look at it, keeping the world `+Y` axis up.
+    pub fn orient(&self, direction: Vector) -> Vector {
+        let target = match self.look_at {
+            Some(target) => target,
+            None => return direction,
+        };
+
+        // Build the camera basis, pick another up axis when looking straight up or down
+        let forward = (target - self.position).normalize();
+        let up = if forward.1.abs() > 0.999 {
+            Vector(0.0, 0.0, -1.0)
+        } else {
+            Vector(0.0, 1.0, 0.0)
+        };
+        let right = forward.cross(up).normalize();
+        let up = right.cross(forward);
+
+        right * direction.0 + up * direction.1 - forward * direction.2
+    }
+
+    /// Place the camera to frame the given bounds, looking at their center.
+    ///
+    /// The camera keeps its viewing direction, and is moved back until the sphere enclosing the
+    /// bounds fits within the field of view. An orthographic camera is scaled to fit instead.
+    pub fn frame(&mut self, bounds: &Aabb) {
+        let center = (bounds.min + bounds.max) * 0.5;
+        let radius = ((bounds.max - bounds.min).magnitude() * 0.5).max(f64::EPSILON);
+        let back = match self.look_at {
+            Some(target) => (self.position - target).normalize(),
+            None => Vector(0.0, 0.0, 1.0),
+        };
+
+        let distance = match self.projection {
+            Projection::Perspective => radius / (self.fov.to_radians() / 2.0).sin(),
+            Projection::Orthographic { ref mut scale } => {
+                *scale = radius;
+                radius * 2.0
+            }
+        };
+        self.position = center + back * distance;
+        self.look_at = Some(center);
+    }
+
+    /// Scale the camera resolution by the given factor.
+    ///
+    /// The aspect ratio is kept, each dimension is rounded and at least one pixel.
+    pub fn scale(&mut self, factor: f64) {
+        self.width = ((f64::from(self.width) * factor).round() as u32).max(1);
+        self.height = ((f64::from(self.height) * factor).round() as u32).max(1);
     }
 }
 
@@ -74,6 +867,34 @@ const fn default_ray_depth() -> u32 {
     16
 }
 
+/// The default minimum path depth for Russian roulette.
+///
+/// Helper function for serde defaults.
+const fn default_roulette_depth() -> u32 {
+    3
+}
+
+/// The default number of samples for stochastic effects.
+///
+/// Helper function for serde defaults.
+const fn default_samples() -> u32 {
+    1
+}
+
+/// The default render tile size in pixels.
+///
+/// Helper function for serde defaults.
+const fn default_tile_size() -> u32 {
+    32
+}
+
+/// The default color variance threshold for adaptive anti-aliasing.
+///
+/// Helper function for serde defaults.
+const fn default_variance_threshold() -> f32 {
+    0.01
+}
+
 /// The default shadow/reflect/transform bias length.
 ///
 /// Helper function for serde defaults.
@@ -87,3 +908,543 @@ const fn default_bias() -> f64 {
 const fn default_fov() -> f64 {
     90.0
 }
+
+/// The animation transforms moving an entity, to move it over the camera shutter.
+#[derive(Clone, Debug)]
+struct Motion {
+    /// Indices of the animation transforms of the entity.
+    transforms: Vec<usize>,
+
+    /// Offset of the entity by its transforms at the frame time, where it is placed.
+    frame_offset: Vector,
+}
+
+impl Motion {
+    /// Get the motion of the entity at `index` by the `animation`, placed at frame `time`.
+    ///
+    /// Returns `None` if the entity is not animated.
+    fn new(animation: &Animation, index: usize, time: f64) -> Option<Self> {
+        let transforms: Vec<usize> = animation
+            .transforms
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.entity == index)
+            .map(|(i, _)| i)
+            .collect();
+        if transforms.is_empty() {
+            return None;
+        }
+        let frame_offset = transforms.iter().fold(Vector::identity(), |offset, &t| {
+            offset + animation.transforms[t].offset(time)
+        });
+        Some(Self {
+            transforms,
+            frame_offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometric::{Mesh, Model, Plane, Sphere, Visibility, MESH_INTERSECTIONS};
+    use crate::material::MaterialRef;
+    use crate::math::Transform;
+
+    #[test]
+    fn test_resolve_materials_shared() {
+        let mut scene: Scene = serde_yaml::from_str(
+            r#"
+            camera: { width: 8, height: 6 }
+            materials:
+              glass:
+                color: [1, 1, 1]
+                albedo: 0.2
+                surface: { type: transparent, index: 1.5, transparency: 0.9 }
+            entities:
+              - { type: sphere, center: [-1, 0, -5], material: { ref: glass } }
+              - { type: sphere, center: [1, 0, -5], material: { ref: glass } }
+              - { type: sphere, center: [0, 2, -5], material: { color: [1, 0, 0], albedo: 1 } }
+            lights: []
+            "#,
+        )
+        .unwrap();
+        scene.resolve_materials().unwrap();
+
+        let glass = &scene.materials["glass"];
+        assert_eq!(scene.entities[0].material().unwrap(), glass);
+        assert_eq!(scene.entities[1].material().unwrap(), glass);
+        assert_eq!(scene.entities[0].material(), scene.entities[1].material());
+        assert_ne!(scene.entities[2].material().unwrap(), glass);
+    }
+
+    #[test]
+    fn test_resolve_materials_unknown() {
+        let mut scene: Scene = serde_yaml::from_str(
+            r#"
+            camera: { width: 8, height: 6 }
+            entities:
+              - { type: sphere, center: [0, 0, -5], material: { ref: missing } }
+            lights: []
+            "#,
+        )
+        .unwrap();
+        let err = scene.resolve_materials().unwrap_err();
+        assert!(err.contains("missing"));
+    }
+
+    #[test]
+    fn test_resolve_materials_difference() {
+        let difference = |b_material: &str| {
+            let mut scene: Scene = serde_yaml::from_str(&format!(
+                r#"
+                camera: {{ width: 8, height: 6 }}
+                materials:
+                  red: {{ color: [1, 0, 0], albedo: 0.5 }}
+                  blue: {{ color: [0, 0, 1], albedo: 0.5 }}
+                entities:
+                  - type: difference
+                    a: {{ type: sphere, center: [0, 0, -5], material: {{ ref: red }} }}
+                    b: {{ type: sphere, center: [0, 0, -4], material: {} }}
+                lights: []
+                "#,
+                b_material
+            ))
+            .unwrap();
+            scene.resolve_materials().map(|_| scene)
+        };
+
+        // Both operands are resolved, the difference takes the material of `a`
+        let scene = difference("{ ref: blue }").unwrap();
+        assert_eq!(
+            scene.entities[0].material().unwrap(),
+            &scene.materials["red"]
+        );
+        match scene.entities[0] {
+            Entity::Difference { ref b, .. } => {
+                assert_eq!(b.material().unwrap(), &scene.materials["blue"])
+            }
+            _ => panic!("expected a difference"),
+        }
+        assert!(difference("{ ref: missing }")
+            .unwrap_err()
+            .contains("missing"));
+
+        // The unused material of `b` is validated too
+        let scene = difference("{ color: [1, 1, 1], albedo: -1 }").unwrap();
+        assert!(scene.entities[0]
+            .validate()
+            .iter()
+            .any(|p| p.starts_with("difference b: material")));
+    }
+
+    #[test]
+    fn test_resolve_includes_merged() {
+        let dir = temp_dir("merged");
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        fs::write(
+            dir.join("a.yml"),
+            "include: [parts/b.yml]\ncamera: { width: 8, height: 6 }\nentities:\n  - { type: sphere, center: [0, 0, -5], material: { ref: red } }\nlights: []\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("parts/b.yml"),
+            "materials:\n  red: { color: [1, 0, 0], albedo: 0.5 }\nentities:\n  - { type: sphere, center: [3, 0, -5], material: { ref: red } }\n  - { type: model, path: monkey.obj, material: { ref: red } }\nlights:\n  - { type: directional, direction: [0, -1, 0], color: [1, 1, 1], intensity: 1 }\n",
+        )
+        .unwrap();
+
+        let mut scene: Scene =
+            serde_yaml::from_reader(File::open(dir.join("a.yml")).unwrap()).unwrap();
+        scene.resolve_includes(&dir.join("a.yml")).unwrap();
+        scene.resolve_materials().unwrap();
+        assert_eq!(scene.entities.len(), 3);
+        assert_eq!(scene.lights.len(), 1);
+        match scene.entities[1] {
+            Entity::Sphere(ref s) => assert_eq!(s.center, Vector(3.0, 0.0, -5.0)),
+            _ => panic!("included sphere not merged"),
+        }
+
+        // Model paths stay relative to the included file
+        match scene.entities[2] {
+            Entity::Model(ref m) => assert_eq!(
+                Path::new(&m.path),
+                fs::canonicalize(dir.join("parts"))
+                    .unwrap()
+                    .join("monkey.obj")
+            ),
+            _ => panic!("included model not merged"),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_includes_circular() {
+        let dir = temp_dir("circular");
+        fs::create_dir_all(&dir).unwrap();
+        let source =
+            "include: [b.yml]\ncamera: { width: 8, height: 6 }\nentities: []\nlights: []\n";
+        fs::write(dir.join("a.yml"), source).unwrap();
+        fs::write(dir.join("b.yml"), "include: [a.yml]\n").unwrap();
+
+        let mut scene: Scene = serde_yaml::from_str(source).unwrap();
+        let err = scene.resolve_includes(&dir.join("a.yml")).unwrap_err();
+        assert!(err.contains("Circular scene include"), "{}", err);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_intersect_rejects_by_bounding_box() {
+        let mut scene = scene_with_sphere(Vector(0.0, 0.0, -5.0));
+        scene.update_bounding_boxes();
+
+        // Move the sphere without updating the cached bounding box, a ray hitting the moved
+        // sphere misses the cached box and must be rejected before the sphere is tested
+        if let Entity::Sphere(ref mut s) = scene.entities[0] {
+            s.center = Vector(10.0, 0.0, -5.0);
+        }
+        let ray = Ray::new(Vector(10.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(scene.intersect(&ray, RayType::Primary).is_none());
+
+        // The sphere is hit once the bounding boxes are updated
+        scene.update_bounding_boxes();
+        assert!(scene.intersect(&ray, RayType::Primary).is_some());
+    }
+
+    #[test]
+    fn test_intersect_through_bounding_box() {
+        let mut scene = scene_with_sphere(Vector(0.0, 0.0, -5.0));
+        scene.update_bounding_boxes();
+
+        let hit = scene.intersect(
+            &Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0)),
+            RayType::Primary,
+        );
+        assert!((hit.unwrap().distance - 4.0).abs() < 1e-9);
+        let miss = scene.intersect(
+            &Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, 1.0)),
+            RayType::Primary,
+        );
+        assert!(miss.is_none());
+    }
+
+    #[test]
+    fn test_intersect_rejects_model_by_bounding_sphere() {
+        // An octahedron, its vertices touch a unit sphere inside its bounding box
+        let positions = vec![
+            Vector(1.0, 0.0, 0.0),
+            Vector(-1.0, 0.0, 0.0),
+            Vector(0.0, 1.0, 0.0),
+            Vector(0.0, -1.0, 0.0),
+            Vector(0.0, 0.0, 1.0),
+            Vector(0.0, 0.0, -1.0),
+        ];
+        let indices = vec![
+            0, 2, 4, 2, 1, 4, 1, 3, 4, 3, 0, 4, 2, 0, 5, 1, 2, 5, 3, 1, 5, 0, 3, 5,
+        ];
+        let mesh = Mesh::new(positions, vec![], vec![], indices);
+        let mut scene = Scene::new(Camera::new(8, 6));
+        scene.entities.push(Entity::Model(Model {
+            path: String::new(),
+            position: Vector(0.0, 0.0, -5.0),
+            scale: 1.0,
+            meshes: vec![mesh.with_transform(Transform::new(Vector(0.0, 0.0, -5.0), 1.0))],
+            smooth: None,
+            max_triangles: None,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
+        }));
+        scene.update_bounding_boxes();
+
+        // A ray through a corner of the bounding box misses the sphere, and never tests the mesh
+        let tests = || MESH_INTERSECTIONS.with(|c| c.get());
+        let before = tests();
+        let corner = Ray::new(Vector(0.9, 0.9, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(scene.bounding_boxes[0].as_ref().unwrap().hit(&corner));
+        assert!(scene.intersect(&corner, RayType::Primary).is_none());
+        assert_eq!(tests(), before);
+
+        // A ray towards the model does
+        let towards = Ray::new(Vector(0.0, 0.0, 0.0), Vector(0.0, 0.0, -1.0));
+        assert!(
+            (scene
+                .intersect(&towards, RayType::Primary)
+                .unwrap()
+                .distance
+                - 4.0)
+                .abs()
+                < 1e-9
+        );
+        assert_eq!(tests(), before + 1);
+    }
+
+    #[test]
+    fn test_bounds_of_spheres() {
+        let mut scene = scene_with_sphere(Vector(0.0, 0.0, -5.0));
+        scene.entities.push(Entity::Sphere(Sphere {
+            center: Vector(3.0, 1.0, -8.0),
+            radius: 2.0,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
+        }));
+        scene.entities.push(Entity::Plane(Plane {
+            center: Vector(0.0, -1.0, 0.0),
+            normal: Vector(0.0, -1.0, 0.0),
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
+        }));
+
+        // The infinite plane is excluded
+        let bounds = scene.bounds().unwrap();
+        assert_eq!(bounds.min, Vector(-1.0, -1.0, -10.0));
+        assert_eq!(bounds.max, Vector(5.0, 3.0, -4.0));
+    }
+
+    #[test]
+    fn test_camera_frame_bounds() {
+        let mut camera = Camera::new(8, 6);
+        camera.fov = 60.0;
+        camera.frame(&Aabb::new(Vector(-1.0, -1.0, -6.0), Vector(1.0, 1.0, -4.0)));
+
+        // The bounding sphere touches the field of view, at twice its radius for 60 degrees
+        assert_eq!(camera.look_at, Some(Vector(0.0, 0.0, -5.0)));
+        let distance = 2.0 * 3.0f64.sqrt();
+        assert!((camera.position - Vector(0.0, 0.0, -5.0 + distance)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn test_select_named_camera() {
+        let mut scene: Scene = serde_yaml::from_str(
+            r#"
+            camera: { width: 8, height: 6 }
+            cameras:
+              top: { width: 40, height: 30, position: [0, 10, 0], look_at: [0, 0, -1] }
+            entities: []
+            lights: []
+            "#,
+        )
+        .unwrap();
+        assert!(scene.validate().is_ok());
+
+        scene.select_camera("top").unwrap();
+        assert_eq!((scene.camera.width, scene.camera.height), (40, 30));
+        assert!(scene.select_camera("missing").is_err());
+    }
+
+    #[test]
+    fn test_camera_scale() {
+        let mut camera = Camera {
+            width: 800,
+            height: 600,
+            fov: default_fov(),
+            position: Vector::identity(),
+            look_at: None,
+            projection: Projection::default(),
+            shutter: 0.0,
+        };
+        camera.scale(0.5);
+        assert_eq!((camera.width, camera.height), (400, 300));
+    }
+
+    #[test]
+    fn test_camera_pixels_no_overflow() {
+        let camera = Camera {
+            width: 100_000,
+            height: 100_000,
+            fov: default_fov(),
+            position: Vector::identity(),
+            look_at: None,
+            projection: Projection::default(),
+            shutter: 0.0,
+        };
+        assert_eq!(camera.pixels(), 10_000_000_000);
+    }
+
+    #[test]
+    fn test_camera_scale_minimum() {
+        let mut camera = Camera {
+            width: 800,
+            height: 600,
+            fov: default_fov(),
+            position: Vector::identity(),
+            look_at: None,
+            projection: Projection::default(),
+            shutter: 0.0,
+        };
+        camera.scale(0.0001);
+        assert_eq!((camera.width, camera.height), (1, 1));
+    }
+
+    #[test]
+    fn test_camera_orient_look_at() {
+        let camera = Camera {
+            width: 8,
+            height: 6,
+            fov: default_fov(),
+            position: Vector(5.0, 0.0, -5.0),
+            look_at: Some(Vector(0.0, 0.0, -5.0)),
+            projection: Projection::default(),
+            shutter: 0.0,
+        };
+        let forward = camera.orient(Vector(0.0, 0.0, -1.0));
+        assert!((forward - Vector(-1.0, 0.0, 0.0)).magnitude() < 1e-9);
+        let up = camera.orient(Vector(0.0, 1.0, 0.0));
+        assert!((up - Vector(0.0, 1.0, 0.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn test_intersect_ignores_nan_distance() {
+        let mut scene = scene_with_sphere(Vector(0.0, 0.0, -5.0));
+        scene.entities.push(Entity::Sphere(Sphere {
+            center: Vector(0.0, 0.0, -3.0),
+            radius: f64::NAN,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
+        }));
+        scene.update_bounding_boxes();
+
+        let ray = Ray::new(Vector::identity(), Vector(0.0, 0.0, -1.0));
+        let intersection = scene.intersect(&ray, RayType::Primary).unwrap();
+        assert!((intersection.distance - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_validate_zero_width_camera() {
+        let mut scene = scene_with_sphere(Vector(0.0, 0.0, -5.0));
+        scene.camera.width = 0;
+        assert_eq!(
+            scene.validate().unwrap_err(),
+            vec!["camera size must be nonzero, got 0x6".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_validate_negative_sphere_radius() {
+        let mut scene = scene_with_sphere(Vector(0.0, 0.0, -5.0));
+        if let Entity::Sphere(ref mut sphere) = scene.entities[0] {
+            sphere.radius = -1.0;
+        }
+        assert_eq!(
+            scene.validate().unwrap_err(),
+            vec!["entity 0: sphere radius must be positive, got -1".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_validate_valid() {
+        assert!(scene_with_sphere(Vector(0.0, 0.0, -5.0)).validate().is_ok());
+    }
+
+    #[test]
+    fn test_fog_apply() {
+        let fog = Fog {
+            color: Color::new(0.5, 0.5, 0.5),
+            density: 0.1,
+        };
+        let color = Color::new(1.0, 0.0, 0.0);
+        assert_eq!(fog.apply(color, Some(0.0)), color);
+        assert_eq!(fog.apply(color, None), fog.color);
+
+        // Far away hits approach the fog color
+        let difference = fog.apply(color, Some(1000.0)) - fog.color;
+        assert!((difference * difference).max_channel() < 1e-12);
+    }
+
+    #[test]
+    fn test_isolate_named_entity() {
+        let mut scene: Scene = serde_yaml::from_str(
+            r#"
+            camera: { width: 8, height: 6 }
+            entities:
+              - { type: sphere, name: left, center: [-1, 0, -5], material: { color: [1, 0, 0], albedo: 1 } }
+              - { type: sphere, name: right, center: [1, 0, -5], material: { color: [0, 0, 1], albedo: 1 } }
+              - { type: plane, center: [0, -1, 0], normal: [0, -1, 0], material: { color: [1, 1, 1], albedo: 1 } }
+            lights:
+              - { type: directional, direction: [0, -1, 0], color: [1, 1, 1], intensity: 1 }
+            "#,
+        )
+        .unwrap();
+        scene.update_bounding_boxes();
+
+        assert_eq!(scene.isolate(&["right"]), 1);
+        assert_eq!(scene.entities.len(), 1);
+        assert_eq!(scene.entities[0].name(), Some("right"));
+        assert_eq!(scene.bounding_boxes.len(), 1);
+        assert_eq!(scene.lights.len(), 1);
+    }
+
+    #[test]
+    fn test_motion_of_animated_entities() {
+        let mut scene: Scene = serde_yaml::from_str(
+            r#"
+            camera: { width: 8, height: 6, shutter: 0.5 }
+            entities:
+              - { type: sphere, center: [-1, 0, -5], material: { color: [1, 0, 0], albedo: 1 } }
+              - { type: sphere, center: [1, 0, -5], material: { color: [0, 0, 1], albedo: 1 } }
+            lights: []
+            animation:
+              transforms:
+                - { entity: 1, keyframes: [{ t: 0, offset: [0, 0, 0] }, { t: 1, offset: [4, 0, 0] }] }
+                - { entity: 1, keyframes: [{ t: 0, offset: [0, 0, 0] }, { t: 1, offset: [0, 2, 0] }] }
+            "#,
+        )
+        .unwrap();
+        let animation = scene.animation.clone().unwrap();
+        animation.apply(&mut scene, 0.5);
+
+        // Only animated entities move, by all their transforms from the frame time
+        set_shutter_time(0.25);
+        assert!(scene.motion(0).is_none());
+        assert_eq!(scene.motion(1), Some(Vector(1.0, 0.5, 0.0)));
+        set_shutter_time(0.0);
+        assert_eq!(scene.motion(1), Some(Vector(0.0, 0.0, 0.0)));
+
+        // With the shutter closed nothing moves
+        scene.camera.shutter = 0.0;
+        set_shutter_time(0.25);
+        assert!(scene.motion(1).is_none());
+        set_shutter_time(0.0);
+    }
+
+    #[test]
+    fn test_serialize_defaults() {
+        let scene: Scene = serde_yaml::from_str(
+            r#"
+            camera: { width: 8, height: 6 }
+            entities:
+              - { type: sphere, center: [0, 0, -5], material: { color: [1, 0, 0], albedo: 1 } }
+            lights: []
+            "#,
+        )
+        .unwrap();
+        let yaml = serde_yaml::to_string(&scene).unwrap();
+        assert!(yaml.contains("bias: 1e-13"), "{}", yaml);
+        assert!(yaml.contains("depth: 16"), "{}", yaml);
+        assert!(yaml.contains("fov: 90"), "{}", yaml);
+
+        // The printed scene loads back the same
+        let printed: Scene = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(serde_yaml::to_string(&printed).unwrap(), yaml);
+    }
+
+    /// Get an unique temporary directory path for the given test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("raytrace-rs-test-{}-{}", name, std::process::id()))
+    }
+
+    /// Build a scene containing a single unit sphere at `center`.
+    fn scene_with_sphere(center: Vector) -> Scene {
+        let mut scene = Scene::new(Camera::new(8, 6));
+        scene.entities.push(Entity::Sphere(Sphere {
+            center,
+            radius: 1.0,
+            material: MaterialRef::Inline(Box::default()),
+            visibility: Visibility::default(),
+            name: None,
+        }));
+        scene
+    }
+}
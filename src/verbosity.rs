@@ -0,0 +1,27 @@
+/// Verbosity level for status output.
+///
+/// Errors are always reported, no matter the level.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Do not show any status output, warnings or progress.
+    Quiet,
+
+    /// Show status output, warnings and progress.
+    #[default]
+    Normal,
+
+    /// Show everything, including additional details.
+    Verbose,
+}
+
+impl Verbosity {
+    /// Check whether status output and warnings should be shown.
+    pub fn is_normal(self) -> bool {
+        self >= Verbosity::Normal
+    }
+
+    /// Check whether additional details should be shown.
+    pub fn is_verbose(self) -> bool {
+        self >= Verbosity::Verbose
+    }
+}
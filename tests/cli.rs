@@ -0,0 +1,45 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+/// Write a scene file with the given entities to a temporary file, returns its path.
+fn scene_file(name: &str, entities: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("raytrace-rs-test-{}-{}.yml", name, process::id()));
+    let scene = format!(
+        "camera: {{ width: 8, height: 6 }}\nentities:\n{}\nlights: []\n",
+        entities
+    );
+    fs::write(&path, scene).unwrap();
+    path
+}
+
+#[test]
+fn test_validate_only() {
+    let validate = |path: &PathBuf| {
+        Command::new(env!("CARGO_BIN_EXE_raytrace-rs"))
+            .arg(path)
+            .arg("--validate-only")
+            .output()
+            .unwrap()
+    };
+
+    let good = scene_file(
+        "valid",
+        "  - { type: sphere, center: [0, 0, -3], radius: 1, material: { color: [1, 1, 1], albedo: 1 } }",
+    );
+    let broken = scene_file(
+        "missing-model",
+        "  - { type: model, path: missing.obj, material: { color: [1, 1, 1], albedo: 1 } }",
+    );
+    let good_output = validate(&good);
+    let broken_output = validate(&broken);
+    fs::remove_file(&good).unwrap();
+    fs::remove_file(&broken).unwrap();
+
+    assert!(good_output.status.success());
+    assert!(!broken_output.status.success());
+    let stderr = String::from_utf8_lossy(&broken_output.stderr);
+    assert!(stderr.contains("failed to load model"), "{}", stderr);
+    assert!(stderr.contains("missing.obj"), "{}", stderr);
+}
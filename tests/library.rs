@@ -0,0 +1,37 @@
+use raytrace::algebra::Vector;
+use raytrace::color::Color;
+use raytrace::geometric::{Entity, Sphere, Visibility};
+use raytrace::light::{DirectionalLight, Light};
+use raytrace::material::{Coloration, Material, MaterialRef};
+use raytrace::scene::{Camera, Scene};
+
+#[test]
+fn test_render_scene_to_memory() {
+    let mut scene = Scene::new(Camera::new(16, 12));
+    scene.entities.push(Entity::Sphere(Sphere {
+        center: Vector(0.0, 0.0, -3.0),
+        radius: 1.0,
+        material: MaterialRef::Inline(Box::new(Material {
+            color: Coloration::Color(Color::new(1.0, 0.0, 0.0)),
+            ..Material::default()
+        })),
+        visibility: Visibility::default(),
+        name: None,
+    }));
+    scene.lights.push(Light::Directional(DirectionalLight {
+        direction: Vector(0.0, 0.0, -1.0),
+        color: Color::new(1.0, 1.0, 1.0),
+        intensity: 10.0,
+        cast_shadows: true,
+        angular_radius: 0.0,
+    }));
+
+    let pixels = raytrace::render_scene(&scene);
+    assert_eq!(pixels.len(), 16 * 12 * 3);
+
+    // The lit red sphere is in the center, the background is black
+    let pixel = |x: usize, y: usize| &pixels[(y * 16 + x) * 3..(y * 16 + x + 1) * 3];
+    assert!(pixel(8, 6)[0] > 128);
+    assert_eq!(pixel(8, 6)[1], 0);
+    assert_eq!(pixel(0, 0), [0, 0, 0]);
+}